@@ -4,6 +4,7 @@ set -e
 cd "`dirname $0`"
 
 cargo +stable build --all --target wasm32-unknown-unknown --release
-cp $TARGET/wasm32-unknown-unknown/release/kt.wasm ./res/
+# kt.wasm with the embedded ABI, along with kt_abi.json (requires cargo-near)
+cargo near build --release --embed-abi --manifest-path kt/Cargo.toml --out-dir ./res
 cp $TARGET/wasm32-unknown-unknown/release/ft.wasm ./res/
 cp $TARGET/wasm32-unknown-unknown/release/oracle.wasm ./res/
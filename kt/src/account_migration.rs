@@ -0,0 +1,168 @@
+//! Moving the KT of an account to another account of the same owner, e.g. on a key rotation.
+//! Unlike a transfer, the whole balance moves with its cost basis and the accrued rewards.
+//! The old account proposes the migration and the new account confirms it.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::{assert_one_yocto, env, near_bindgen, require, AccountId, IntoStorageKey};
+
+use crate::events::KtEvent;
+use crate::{Contract, ContractExt};
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct AccountMigrations {
+    /// Old AccountID -> New AccountID.
+    proposed: LookupMap<AccountId, AccountId>,
+}
+
+impl AccountMigrations {
+    pub fn new<S>(prefix: S) -> Self
+    where
+        S: IntoStorageKey,
+    {
+        Self {
+            proposed: LookupMap::new(prefix),
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Proposes to move the caller's balance to the new account, which has to confirm it.
+    #[payable]
+    pub fn migrate_account(&mut self, new_account_id: AccountId) {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        require!(
+            account_id != new_account_id,
+            "The new account should be different"
+        );
+        self.compliance.assert_not_frozen(&account_id);
+        self.account_migrations
+            .proposed
+            .insert(&account_id, &new_account_id);
+        KtEvent::AccountMigrationProposed {
+            account_id: &account_id,
+            new_account_id: &new_account_id,
+        }
+        .emit();
+    }
+
+    /// Moves the balance, its cost basis and the accrued rewards of the old account
+    /// to the caller. The migration is charged like a transfer, e.g. the storage fee of
+    /// an unregistered caller is burned from the moved balance.
+    #[payable]
+    pub fn confirm_account_migration(&mut self, old_account_id: AccountId) {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        require!(
+            self.account_migrations
+                .proposed
+                .get(&old_account_id)
+                .as_ref()
+                == Some(&account_id),
+            "The migration isn't proposed to this account"
+        );
+        self.account_migrations.proposed.remove(&old_account_id);
+
+        let amount = self
+            .token
+            .internal_unwrap_balance_of(&old_account_id)
+            .amount;
+        require!(amount > 0, "Nothing to migrate");
+        self.internal_before_transfer(&old_account_id, &account_id, amount);
+        let balance = self.token.internal_unwrap_balance_of(&old_account_id);
+        self.token.internal_transfer_at(
+            &old_account_id,
+            &account_id,
+            balance.amount,
+            balance.price,
+            Some("migration".to_string()),
+        );
+        self.token
+            .internal_move_rewards(&old_account_id, &account_id);
+
+        KtEvent::AccountMigrated {
+            old_account_id: &old_account_id,
+            account_id: &account_id,
+            amount: &balance.amount.into(),
+            price: &balance.price.into(),
+        }
+        .emit();
+    }
+
+    pub fn get_proposed_migration(&self, account_id: AccountId) -> Option<AccountId> {
+        self.account_migrations.proposed.get(&account_id)
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_contract_standards::fungible_token::core::FungibleTokenCore;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, ONE_YOCTO};
+
+    use crate::Contract;
+
+    fn setup_contract(context: &mut VMContextBuilder) -> Contract {
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract
+            .token
+            .internal_deposit(&accounts(1), 100, 1_000_000);
+        contract
+            .token
+            .internal_deposit(&accounts(2), 100, 3_000_000);
+        contract.token.internal_distribute_rewards(20);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        contract.migrate_account(accounts(3));
+        contract
+    }
+
+    #[test]
+    fn test_migrate_account() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.confirm_account_migration(accounts(1));
+        let balance = contract.ft_balance_detail(accounts(3));
+        assert_eq!(balance.amount.0, 100);
+        assert_eq!(balance.price.0, 1_000_000);
+        assert_eq!(contract.get_accrued_rewards(accounts(3)).0, 10);
+        assert_eq!(contract.get_accrued_rewards(accounts(1)).0, 0);
+        assert!(contract.get_proposed_migration(accounts(1)).is_none());
+    }
+
+    #[test]
+    fn test_migrate_account_storage_fee() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.set_account_storage_fee(10.into());
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        contract.confirm_account_migration(accounts(1));
+        let balance = contract.ft_balance_detail(accounts(3));
+        assert_eq!(balance.amount.0, 90);
+        assert_eq!(balance.price.0, 1_000_000);
+        assert_eq!(contract.ft_total_supply().0, 190);
+    }
+
+    #[test]
+    #[should_panic(expected = "The migration isn't proposed to this account")]
+    fn test_migrate_account_not_proposed() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.confirm_account_migration(accounts(1));
+    }
+}
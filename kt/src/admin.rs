@@ -0,0 +1,320 @@
+//! Owner operations applied together in one transaction, so a multi-step listing
+//! doesn't leave the contract half-configured between the transactions.
+
+use near_contract_standards::fungible_token::metadata::FungibleTokenMetadata;
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{
+    env, ext_contract, near_bindgen, require, serde_json, AccountId, Balance, Promise,
+    PromiseOrValue, PromiseResult,
+};
+use schemars::JsonSchema;
+
+use crate::fees::FeeSplit;
+use crate::treasury::AssetStatus;
+use crate::{Contract, ContractExt};
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub enum AdminAction {
+    /// Adds the asset like `add_asset`, the decimals are checked against its metadata.
+    /// `storage_deposit` of the attached deposit registers the contract on the asset.
+    AddAsset {
+        asset_id: AccountId,
+        decimals: Option<u8>,
+        price_decimals: Option<u8>,
+        storage_deposit: U128,
+    },
+    SetAssetStatus {
+        asset_id: AccountId,
+        status: AssetStatus,
+    },
+    SetAssetLimits {
+        asset_id: AccountId,
+        min_buy: U128,
+        max_buy: Option<U128>,
+        min_sell: U128,
+        max_sell: Option<U128>,
+    },
+    SetAssetCap {
+        asset_id: AccountId,
+        cap: Option<U128>,
+    },
+    SetBuyFee {
+        fee: u16,
+        referral_share: u16,
+    },
+    SetFeeSplit {
+        split: FeeSplit,
+    },
+    SetTargetWeights {
+        weights: Vec<(AccountId, u16)>,
+    },
+}
+
+impl Contract {
+    /// Applies the actions in order, the listings of the added assets
+    /// are the promise results in the order of the actions.
+    fn internal_admin_batch(&mut self, actions: Vec<AdminAction>) {
+        let mut listing_index = 0;
+        for action in actions {
+            match action {
+                AdminAction::AddAsset {
+                    asset_id,
+                    decimals,
+                    price_decimals,
+                    storage_deposit: _,
+                } => {
+                    let metadata = match env::promise_result(listing_index) {
+                        PromiseResult::Successful(value) => {
+                            serde_json::from_slice::<FungibleTokenMetadata>(&value).ok()
+                        }
+                        _ => None,
+                    }
+                    .unwrap_or_else(|| {
+                        env::panic_str(
+                            format!("Metadata of asset {} is not available", asset_id).as_str(),
+                        )
+                    });
+                    let registered = matches!(
+                        env::promise_result(listing_index + 1),
+                        PromiseResult::Successful(_)
+                    );
+                    listing_index += 2;
+                    self.internal_list_asset(
+                        &asset_id,
+                        decimals,
+                        price_decimals,
+                        &metadata,
+                        registered,
+                    );
+                }
+                AdminAction::SetAssetStatus { asset_id, status } => {
+                    self.internal_set_asset_status(&asset_id, status)
+                }
+                AdminAction::SetAssetLimits {
+                    asset_id,
+                    min_buy,
+                    max_buy,
+                    min_sell,
+                    max_sell,
+                } => self.treasury.set_asset_limits(
+                    &asset_id,
+                    min_buy.into(),
+                    max_buy.map(Into::into),
+                    min_sell.into(),
+                    max_sell.map(Into::into),
+                ),
+                AdminAction::SetAssetCap { asset_id, cap } => {
+                    self.treasury.set_asset_cap(&asset_id, cap.map(Into::into))
+                }
+                AdminAction::SetBuyFee {
+                    fee,
+                    referral_share,
+                } => self.internal_set_buy_fee(fee, referral_share),
+                AdminAction::SetFeeSplit { split } => self.internal_set_fee_split(split),
+                AdminAction::SetTargetWeights { weights } => {
+                    self.treasury.set_target_weights(&weights)
+                }
+            }
+        }
+    }
+
+    /// Applies the actions, after listing the assets they add if any. The attached deposit
+    /// above the storage deposits of the assets is refunded to the caller.
+    pub(crate) fn internal_start_admin_batch(
+        &mut self,
+        actions: Vec<AdminAction>,
+    ) -> PromiseOrValue<()> {
+        let storage_deposits: Vec<(AccountId, Balance)> = actions
+            .iter()
+            .filter_map(|action| match action {
+                AdminAction::AddAsset {
+                    asset_id,
+                    storage_deposit,
+                    ..
+                } => Some((asset_id.clone(), storage_deposit.0)),
+                _ => None,
+            })
+            .collect();
+        let total = storage_deposits
+            .iter()
+            .fold(0, |total: Balance, (_, deposit)| {
+                total.saturating_add(*deposit)
+            });
+        let deposit = env::attached_deposit();
+        require!(
+            total <= deposit,
+            "Attached deposit doesn't cover the storage deposits"
+        );
+        if deposit > total {
+            Promise::new(env::predecessor_account_id()).transfer(deposit - total);
+        }
+        if storage_deposits.is_empty() {
+            self.internal_admin_batch(actions);
+            return PromiseOrValue::Value(());
+        }
+        let resolve_gas = self.gas.resolve_add_asset * storage_deposits.len() as u64;
+        storage_deposits
+            .iter()
+            .map(|(asset_id, deposit)| self.asset_listing(asset_id, *deposit))
+            .reduce(Promise::and)
+            .unwrap()
+            .then(
+                ext_admin_resolver::ext(env::current_account_id())
+                    .with_static_gas(resolve_gas)
+                    .resolve_admin_batch(actions),
+            )
+            .into()
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Applies the owner actions in order, any failing action reverts all of them.
+    /// Batches adding assets are applied once the assets are listed like with `add_asset`,
+    /// the attached deposit above their storage deposits is refunded.
+    #[payable]
+    pub fn admin_batch(&mut self, actions: Vec<AdminAction>) -> PromiseOrValue<()> {
+        self.assert_owner();
+        self.internal_start_admin_batch(actions)
+    }
+}
+
+#[ext_contract(ext_admin_resolver)]
+#[allow(dead_code)]
+pub trait AdminResolver {
+    fn resolve_admin_batch(&mut self, actions: Vec<AdminAction>);
+}
+
+#[near_bindgen]
+impl AdminResolver for Contract {
+    #[private]
+    fn resolve_admin_batch(&mut self, actions: Vec<AdminAction>) {
+        self.internal_admin_batch(actions);
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_contract_standards::fungible_token::metadata::{
+        FungibleTokenMetadata, FT_METADATA_SPEC,
+    };
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{serde_json, testing_env, PromiseResult, RuntimeFeesConfig, VMConfig};
+
+    use crate::admin::{AdminAction, AdminResolver};
+    use crate::treasury::AssetStatus;
+    use crate::Contract;
+
+    fn setup_contract() -> Contract {
+        let mut context = VMContextBuilder::new();
+        context
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1_000);
+        testing_env!(context.build());
+        Contract::new(accounts(0), accounts(4), None, None)
+    }
+
+    fn add_asset(decimals: Option<u8>) -> AdminAction {
+        AdminAction::AddAsset {
+            asset_id: accounts(3),
+            decimals,
+            price_decimals: Some(10),
+            storage_deposit: 1_000.into(),
+        }
+    }
+
+    /// Resolves the batch with the listing results of the asset with 6 decimals.
+    fn resolve_admin_batch(contract: &mut Contract, actions: Vec<AdminAction>) {
+        let metadata = FungibleTokenMetadata {
+            spec: FT_METADATA_SPEC.to_string(),
+            name: "USD Coin".to_string(),
+            symbol: "USDC".to_string(),
+            icon: None,
+            reference: None,
+            reference_hash: None,
+            decimals: 6,
+        };
+        let mut context = VMContextBuilder::new();
+        testing_env!(
+            context
+                .current_account_id(accounts(0))
+                .predecessor_account_id(accounts(0))
+                .build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![
+                PromiseResult::Successful(serde_json::to_vec(&metadata).unwrap()),
+                PromiseResult::Successful(b"{}".to_vec()),
+            ],
+        );
+        contract.resolve_admin_batch(actions);
+    }
+
+    #[test]
+    fn test_admin_batch() {
+        let mut contract = setup_contract();
+        let actions = || {
+            vec![
+                add_asset(Some(6)),
+                AdminAction::SetAssetLimits {
+                    asset_id: accounts(3),
+                    min_buy: 10.into(),
+                    max_buy: None,
+                    min_sell: 10.into(),
+                    max_sell: None,
+                },
+                AdminAction::SetAssetStatus {
+                    asset_id: accounts(3),
+                    status: AssetStatus::SellOnly,
+                },
+                AdminAction::SetBuyFee {
+                    fee: 30,
+                    referral_share: 0,
+                },
+            ]
+        };
+        contract.admin_batch(actions());
+        assert!(contract.treasury.get(&accounts(3)).is_none());
+
+        resolve_admin_batch(&mut contract, actions());
+        let asset = contract.treasury.assert_asset(&accounts(3));
+        assert_eq!(asset.price_decimals, Some(10));
+        assert_eq!(asset.min_buy, 10);
+        assert_eq!(asset.status, AssetStatus::SellOnly);
+        assert_eq!(contract.get_buy_fee(), (30, 0));
+    }
+
+    #[test]
+    fn test_admin_batch_without_assets() {
+        let mut contract = setup_contract();
+        contract.admin_batch(vec![AdminAction::SetBuyFee {
+            fee: 30,
+            referral_share: 0,
+        }]);
+        assert_eq!(contract.get_buy_fee(), (30, 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "Asset decimals 8 don't match the metadata decimals 6")]
+    fn test_admin_batch_decimals() {
+        let mut contract = setup_contract();
+        resolve_admin_batch(&mut contract, vec![add_asset(Some(8))]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Attached deposit doesn't cover the storage deposits")]
+    fn test_admin_batch_storage_deposit() {
+        let mut contract = setup_contract();
+        contract.admin_batch(vec![AdminAction::AddAsset {
+            asset_id: accounts(3),
+            decimals: None,
+            price_decimals: None,
+            storage_deposit: 1_001.into(),
+        }]);
+    }
+}
@@ -0,0 +1,271 @@
+//! Allowances letting spenders, e.g. vaults or escrow contracts, pull KT from
+//! the owner with `transfer_from`.
+//!
+//! The owner pays the storage of every allowance, which is returned once it's revoked
+//! or spent.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::{LookupMap, UnorderedMap};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{assert_one_yocto, env, near_bindgen, require, AccountId, Balance, IntoStorageKey};
+use schemars::JsonSchema;
+
+use crate::events::KtEvent;
+use crate::{Contract, ContractExt};
+
+/// Maximum number of spenders per owner.
+const MAX_SPENDERS: u64 = 32;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct Allowance {
+    pub amount: U128,
+    /// Time the allowance can't be spent from, in nanoseconds.
+    pub expires_at: Option<U64>,
+    /// NEAR paid by the owner for the storage.
+    pub storage_deposit: U128,
+}
+
+impl Allowance {
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| env::block_timestamp() >= expires_at.0)
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Allowances {
+    /// Owner AccountID -> Allowance per spender.
+    accounts: LookupMap<AccountId, UnorderedMap<AccountId, Allowance>>,
+    prefix: Vec<u8>,
+}
+
+impl Allowances {
+    pub fn new<S>(prefix: S) -> Self
+    where
+        S: IntoStorageKey,
+    {
+        let prefix = prefix.into_storage_key();
+        Self {
+            accounts: LookupMap::new(prefix.clone()),
+            prefix,
+        }
+    }
+
+    pub fn get(&self, owner_id: &AccountId, spender_id: &AccountId) -> Option<Allowance> {
+        self.accounts.get(owner_id)?.get(spender_id)
+    }
+
+    /// Replaces the allowance of the spender, a zero amount removes it.
+    /// Returns the previous allowance.
+    pub fn set(
+        &mut self,
+        owner_id: &AccountId,
+        spender_id: &AccountId,
+        allowance: &Allowance,
+    ) -> Option<Allowance> {
+        let mut allowances = self.accounts.get(owner_id).unwrap_or_else(|| {
+            let owner_hash = env::sha256(owner_id.as_bytes());
+            UnorderedMap::new([self.prefix.as_slice(), b"s", &owner_hash].concat())
+        });
+        let previous = if allowance.amount.0 == 0 {
+            allowances.remove(spender_id)
+        } else {
+            let previous = allowances.insert(spender_id, allowance);
+            require!(
+                allowances.len() <= MAX_SPENDERS,
+                format!("Owner can't have more than {} spenders", MAX_SPENDERS)
+            );
+            previous
+        };
+        if allowances.is_empty() {
+            self.accounts.remove(owner_id);
+        } else {
+            self.accounts.insert(owner_id, &allowances);
+        }
+        previous
+    }
+
+    /// Checks the spender can spend the amount, returns its allowance.
+    pub fn assert_allowance(
+        &self,
+        owner_id: &AccountId,
+        spender_id: &AccountId,
+        amount: Balance,
+    ) -> Allowance {
+        let allowance = self.get(owner_id, spender_id).unwrap_or_else(|| {
+            env::panic_str(format!("Account {} has no allowance", spender_id).as_str())
+        });
+        require!(
+            !allowance.is_expired(),
+            format!("Allowance of {} is expired", spender_id)
+        );
+        require!(amount <= allowance.amount.0, "Amount exceeds the allowance");
+        allowance
+    }
+
+    /// Spends the amount of the allowance, returns the allowance if it's used up.
+    pub fn spend(
+        &mut self,
+        owner_id: &AccountId,
+        spender_id: &AccountId,
+        amount: Balance,
+    ) -> Option<Allowance> {
+        let mut allowance = self.assert_allowance(owner_id, spender_id, amount);
+        allowance.amount = (allowance.amount.0 - amount).into();
+        self.set(owner_id, spender_id, &allowance)
+            .filter(|_| allowance.amount.0 == 0)
+    }
+}
+
+impl Contract {
+    /// Spends the allowance, returning its storage deposit to the owner once it's used up.
+    pub(crate) fn internal_spend_allowance(
+        &mut self,
+        owner_id: &AccountId,
+        spender_id: &AccountId,
+        amount: Balance,
+    ) {
+        if let Some(allowance) = self.allowances.spend(owner_id, spender_id, amount) {
+            self.internal_refund_storage(owner_id, allowance.storage_deposit.0);
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Allows the spender to transfer up to the amount of the caller's KT until
+    /// `expires_at`, replacing its previous allowance. A zero amount revokes it.
+    /// The attached deposit pays the storage of a new allowance, the rest is refunded.
+    #[payable]
+    pub fn approve(&mut self, spender_id: AccountId, amount: U128, expires_at: Option<U64>) {
+        let initial_usage = env::storage_usage();
+        let owner_id = env::predecessor_account_id();
+        require!(owner_id != spender_id, "Owner can't approve itself");
+        let mut allowance = Allowance {
+            amount,
+            expires_at,
+            storage_deposit: 0.into(),
+        };
+        let previous = self.allowances.set(&owner_id, &spender_id, &allowance);
+        let storage_deposit = previous.map_or(0, |previous| previous.storage_deposit.0);
+        if amount.0 == 0 {
+            assert_one_yocto();
+            self.internal_refund_storage(&owner_id, storage_deposit);
+        } else {
+            let cost = self.internal_charge_storage(initial_usage);
+            allowance.storage_deposit = (storage_deposit + cost).into();
+            self.allowances.set(&owner_id, &spender_id, &allowance);
+        }
+        KtEvent::Approval {
+            owner_id: &owner_id,
+            spender_id: &spender_id,
+            amount: &amount,
+            expires_at: expires_at.as_ref(),
+        }
+        .emit();
+    }
+
+    pub fn allowance(&self, owner_id: AccountId, spender_id: AccountId) -> Option<Allowance> {
+        self.allowances.get(&owner_id, &spender_id)
+    }
+
+    /// Transfers KT of the owner within the allowance of the caller.
+    #[payable]
+    pub fn transfer_from(
+        &mut self,
+        owner_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+    ) {
+        assert_one_yocto();
+        let spender_id = env::predecessor_account_id();
+        self.compliance.assert_not_frozen(&spender_id);
+        self.internal_spend_allowance(&owner_id, &spender_id, amount.0);
+        self.internal_before_transfer(&owner_id, &receiver_id, amount.0);
+        self.token
+            .internal_transfer(&owner_id, &receiver_id, amount.0, memo);
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_contract_standards::fungible_token::core::FungibleTokenCore;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, ONE_YOCTO};
+
+    use crate::test_utils;
+    use crate::Contract;
+
+    const STORAGE_DEPOSIT: u128 = 10u128.pow(23);
+
+    fn setup_contract(context: &mut VMContextBuilder) -> Contract {
+        let mut contract = test_utils::setup_contract_with_kt(context);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(STORAGE_DEPOSIT)
+            .build());
+        contract.approve(accounts(2), 300.into(), Some(10.into()));
+        testing_env!(context.attached_deposit(ONE_YOCTO).build());
+        contract
+    }
+
+    #[test]
+    fn test_transfer_from() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.transfer_from(accounts(1), accounts(5), 100.into(), None);
+        assert_eq!(contract.ft_balance_of(accounts(5)).0, 100);
+        let allowance = contract.allowance(accounts(1), accounts(2)).unwrap();
+        assert_eq!(allowance.amount.0, 200);
+
+        contract.transfer_from(accounts(1), accounts(5), 200.into(), None);
+        assert!(contract.allowance(accounts(1), accounts(2)).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Allowance of charlie is expired")]
+    fn test_transfer_from_expired() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .block_timestamp(10)
+            .build());
+        contract.transfer_from(accounts(1), accounts(5), 100.into(), None);
+    }
+
+    #[test]
+    fn test_approve_storage_deposit() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        let allowance = contract.allowance(accounts(1), accounts(2)).unwrap();
+        assert!(allowance.storage_deposit.0 > 0);
+
+        // Replacing the allowance keeps its deposit.
+        contract.approve(accounts(2), 100.into(), Some(10.into()));
+        let replaced = contract.allowance(accounts(1), accounts(2)).unwrap();
+        assert_eq!(replaced.storage_deposit, allowance.storage_deposit);
+
+        contract.approve(accounts(2), 0.into(), None);
+        assert!(contract.allowance(accounts(1), accounts(2)).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Owner can't have more than 32 spenders")]
+    fn test_approve_too_many_spenders() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        testing_env!(context.attached_deposit(STORAGE_DEPOSIT).build());
+        for i in 0..32 {
+            let spender_id = format!("spender{}.near", i).parse().unwrap();
+            contract.approve(spender_id, 1.into(), None);
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupSet;
+use near_sdk::{env, near_bindgen, require, AccountId, IntoStorageKey};
+
+use crate::{Contract, ContractExt};
+
+/// Accounts allowed to buy KT during a gated phase, transfers and sells are not gated.
+/// Also gates the receivers of `ft_transfer_call` during the early launch.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Allowlist {
+    accounts: LookupSet<AccountId>,
+    enabled: bool,
+    /// Set once the allowlist is disabled for good.
+    retired: bool,
+}
+
+impl Allowlist {
+    pub fn new<S>(prefix: S) -> Self
+    where
+        S: IntoStorageKey,
+    {
+        Self {
+            accounts: LookupSet::new(prefix),
+            enabled: false,
+            retired: false,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        require!(!self.retired, "The allowlist is permanently disabled");
+        self.enabled = enabled;
+    }
+
+    pub fn retire(&mut self) {
+        self.enabled = false;
+        self.retired = true;
+    }
+
+    pub fn contains(&self, account_id: &AccountId) -> bool {
+        self.accounts.contains(account_id)
+    }
+
+    pub fn insert(&mut self, account_id: &AccountId) {
+        self.accounts.insert(account_id);
+    }
+
+    pub fn remove(&mut self, account_id: &AccountId) {
+        self.accounts.remove(account_id);
+    }
+
+    pub fn is_allowed(&self, account_id: &AccountId) -> bool {
+        !self.enabled || self.contains(account_id)
+    }
+
+    pub fn assert_allowed(&self, account_id: &AccountId) {
+        if !self.is_allowed(account_id) {
+            env::panic_str(format!("Account {} is not allowed to buy", account_id).as_str())
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn set_allowlist_enabled(&mut self, enabled: bool) {
+        self.assert_owner();
+        self.allowlist.set_enabled(enabled);
+    }
+
+    /// Disables the allowlist, it can't be enabled again afterwards.
+    pub fn disable_allowlist_permanently(&mut self) {
+        self.assert_owner();
+        self.allowlist.retire();
+    }
+
+    pub fn add_to_allowlist(&mut self, account_ids: Vec<AccountId>) {
+        self.assert_owner();
+        for account_id in account_ids.iter() {
+            self.allowlist.insert(account_id);
+        }
+    }
+
+    pub fn remove_from_allowlist(&mut self, account_ids: Vec<AccountId>) {
+        self.assert_owner();
+        for account_id in account_ids.iter() {
+            self.allowlist.remove(account_id);
+        }
+    }
+
+    pub fn is_allowlist_enabled(&self) -> bool {
+        self.allowlist.is_enabled()
+    }
+
+    pub fn is_allowlisted(&self, account_id: AccountId) -> bool {
+        self.allowlist.contains(&account_id)
+    }
+
+    /// Restricts `ft_transfer_call` to the allowed receiver contracts.
+    pub fn set_receiver_allowlist_enabled(&mut self, enabled: bool) {
+        self.assert_owner();
+        self.receiver_allowlist.set_enabled(enabled);
+    }
+
+    /// Disables the receiver allowlist, it can't be enabled again afterwards.
+    pub fn disable_receiver_allowlist_permanently(&mut self) {
+        self.assert_owner();
+        self.receiver_allowlist.retire();
+    }
+
+    pub fn add_allowed_receivers(&mut self, account_ids: Vec<AccountId>) {
+        self.assert_owner();
+        for account_id in account_ids.iter() {
+            self.receiver_allowlist.insert(account_id);
+        }
+    }
+
+    pub fn remove_allowed_receivers(&mut self, account_ids: Vec<AccountId>) {
+        self.assert_owner();
+        for account_id in account_ids.iter() {
+            self.receiver_allowlist.remove(account_id);
+        }
+    }
+
+    pub fn is_receiver_allowlist_enabled(&self) -> bool {
+        self.receiver_allowlist.is_enabled()
+    }
+
+    pub fn is_receiver_allowed(&self, account_id: AccountId) -> bool {
+        self.receiver_allowlist.is_allowed(&account_id)
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::accounts;
+
+    use crate::allowlist::Allowlist;
+    use crate::StorageKey;
+
+    #[test]
+    fn test_allowlist() {
+        let mut allowlist = Allowlist::new(StorageKey::Allowlist);
+        allowlist.assert_allowed(&accounts(1));
+
+        allowlist.set_enabled(true);
+        allowlist.insert(&accounts(1));
+        allowlist.assert_allowed(&accounts(1));
+        assert!(!allowlist.contains(&accounts(2)));
+
+        allowlist.retire();
+        allowlist.assert_allowed(&accounts(2));
+    }
+
+    #[test]
+    #[should_panic(expected = "Account charlie is not allowed to buy")]
+    fn test_allowlist_not_allowed() {
+        let mut allowlist = Allowlist::new(StorageKey::Allowlist);
+        allowlist.set_enabled(true);
+        allowlist.insert(&accounts(1));
+        allowlist.assert_allowed(&accounts(2));
+    }
+
+    #[test]
+    #[should_panic(expected = "The allowlist is permanently disabled")]
+    fn test_allowlist_retired() {
+        let mut allowlist = Allowlist::new(StorageKey::Allowlist);
+        allowlist.retire();
+        allowlist.set_enabled(true);
+    }
+}
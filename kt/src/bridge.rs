@@ -0,0 +1,306 @@
+//! Burn-and-mint bridging of KT to other chains. Bridge contracts burn the KT leaving
+//! for another chain and mint the KT coming back, within their per-minter limits.
+//! Bridged mints aren't backed by treasury deposits, so they're recorded apart from buys.
+
+use near_contract_standards::fungible_token::events::{FtBurn, FtMint};
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::UnorderedMap;
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen, require, AccountId, Balance, IntoStorageKey};
+use schemars::JsonSchema;
+
+use crate::events::KtEvent;
+use crate::limits::{add_volume, in_window, total, VolumeBucket};
+use crate::roles::Role;
+use crate::{Contract, ContractExt};
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct BridgeMinter {
+    /// Maximum KT minted above the KT burned by the minter.
+    cap: Balance,
+    /// Maximum KT minted within the rolling 24 hours, unlimited if not set.
+    max_daily_mint: Option<Balance>,
+    minted: Balance,
+    burned: Balance,
+    /// Mint buckets within the rolling window.
+    volume: Vec<VolumeBucket>,
+}
+
+impl BridgeMinter {
+    fn minted_today(&self) -> Balance {
+        total(&in_window(self.volume.clone())).0
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct BridgeMinterView {
+    pub cap: U128,
+    pub max_daily_mint: Option<U128>,
+    /// Total KT minted for the transfers from other chains.
+    pub minted: U128,
+    /// Total KT burned for the transfers to other chains.
+    pub burned: U128,
+    /// KT minted within the rolling 24 hours.
+    pub minted_today: U128,
+}
+
+impl From<&BridgeMinter> for BridgeMinterView {
+    fn from(minter: &BridgeMinter) -> Self {
+        Self {
+            cap: minter.cap.into(),
+            max_daily_mint: minter.max_daily_mint.map(Into::into),
+            minted: minter.minted.into(),
+            burned: minter.burned.into(),
+            minted_today: minter.minted_today().into(),
+        }
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct BridgeMinters {
+    /// AccountID -> Minter limits and totals, kept after the minter is removed.
+    minters: UnorderedMap<AccountId, BridgeMinter>,
+}
+
+impl BridgeMinters {
+    pub fn new<S>(prefix: S) -> Self
+    where
+        S: IntoStorageKey,
+    {
+        Self {
+            minters: UnorderedMap::new(prefix),
+        }
+    }
+
+    pub fn set_limits(
+        &mut self,
+        minter_id: &AccountId,
+        cap: Balance,
+        max_daily_mint: Option<Balance>,
+    ) {
+        let minter = match self.minters.get(minter_id) {
+            Some(minter) => BridgeMinter {
+                cap,
+                max_daily_mint,
+                ..minter
+            },
+            None => BridgeMinter {
+                cap,
+                max_daily_mint,
+                minted: 0,
+                burned: 0,
+                volume: Vec::new(),
+            },
+        };
+        self.minters.insert(minter_id, &minter);
+    }
+
+    fn assert_minter(&self, minter_id: &AccountId) -> BridgeMinter {
+        self.minters.get(minter_id).unwrap_or_else(|| {
+            env::panic_str(format!("Account {} is not a bridge minter", minter_id).as_str())
+        })
+    }
+
+    pub fn record_mint(&mut self, minter_id: &AccountId, amount: Balance) {
+        let mut minter = self.assert_minter(minter_id);
+        let minted = minter
+            .minted
+            .checked_add(amount)
+            .unwrap_or_else(|| env::panic_str("Bridged amount overflow"));
+        require!(
+            minted.saturating_sub(minter.burned) <= minter.cap,
+            format!("Bridge mint cap of {} is exceeded", minter.cap)
+        );
+        if let Some(max_daily_mint) = minter.max_daily_mint {
+            require!(
+                minter.minted_today().saturating_add(amount) <= max_daily_mint,
+                format!("Daily bridge mint limit of {} is exceeded", max_daily_mint)
+            );
+        }
+        minter.minted = minted;
+        minter.volume = in_window(minter.volume);
+        add_volume(&mut minter.volume, amount, 0);
+        self.minters.insert(minter_id, &minter);
+    }
+
+    pub fn record_burn(&mut self, minter_id: &AccountId, amount: Balance) {
+        let mut minter = self.assert_minter(minter_id);
+        minter.burned = minter
+            .burned
+            .checked_add(amount)
+            .unwrap_or_else(|| env::panic_str("Bridged amount overflow"));
+        self.minters.insert(minter_id, &minter);
+    }
+
+    pub fn get(&self, minter_id: &AccountId) -> Option<BridgeMinterView> {
+        self.minters.get(minter_id).as_ref().map(Into::into)
+    }
+
+    pub fn to_vec(&self) -> Vec<(AccountId, BridgeMinterView)> {
+        self.minters
+            .iter()
+            .map(|(minter_id, minter)| (minter_id, (&minter).into()))
+            .collect()
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Sets the limits of the bridge minter and grants it the Bridge role.
+    pub fn set_bridge_minter(
+        &mut self,
+        minter_id: AccountId,
+        cap: U128,
+        max_daily_mint: Option<U128>,
+    ) {
+        self.assert_owner();
+        self.bridge_minters
+            .set_limits(&minter_id, cap.0, max_daily_mint.map(Into::into));
+        self.roles.grant_role(&minter_id, Role::Bridge);
+    }
+
+    /// Revokes the Bridge role, the bridged totals of the minter are kept.
+    pub fn remove_bridge_minter(&mut self, minter_id: AccountId) {
+        self.assert_owner();
+        self.roles.revoke_role(&minter_id, Role::Bridge);
+    }
+
+    pub fn get_bridge_minter(&self, minter_id: AccountId) -> Option<BridgeMinterView> {
+        self.bridge_minters.get(&minter_id)
+    }
+
+    pub fn get_bridge_minters(&self) -> Vec<(AccountId, BridgeMinterView)> {
+        self.bridge_minters.to_vec()
+    }
+
+    /// Mints the KT transferred from another chain. `price` is the cost basis
+    /// carried over from the source chain, with 18 decimals.
+    pub fn bridge_mint(
+        &mut self,
+        account_id: AccountId,
+        amount: U128,
+        price: U128,
+        source_chain: String,
+    ) {
+        let minter_id = env::predecessor_account_id();
+        self.roles.assert_role(&minter_id, Role::Bridge);
+        require!(amount.0 > 0, "The amount should be a positive number");
+        self.compliance.assert_not_frozen(&account_id);
+        self.assert_supply_cap(amount.0);
+        self.bridge_minters.record_mint(&minter_id, amount.0);
+        self.stats.record_bridge_mint(amount.0);
+        let fee = self.storage_fee_of(&account_id);
+        require!(
+            amount.0 > fee,
+            format!("Bridged amount doesn't cover the storage fee of {}", fee)
+        );
+        self.internal_record_acquired(&account_id, amount.0);
+        self.token.internal_deposit(&account_id, amount.0, price.0);
+
+        FtMint {
+            owner_id: &account_id,
+            amount: &amount,
+            memo: Some("bridge"),
+        }
+        .emit();
+        self.internal_burn_storage_fee(&account_id, &account_id, fee);
+        KtEvent::BridgeMinted {
+            minter_id: &minter_id,
+            account_id: &account_id,
+            amount: &amount,
+            source_chain: &source_chain,
+        }
+        .emit();
+    }
+
+    /// Burns the KT of the minter which is transferred to another chain.
+    pub fn bridge_burn(&mut self, amount: U128, destination_chain: String, recipient: String) {
+        let minter_id = env::predecessor_account_id();
+        self.roles.assert_role(&minter_id, Role::Bridge);
+        require!(amount.0 > 0, "The amount should be a positive number");
+        self.bridge_minters.record_burn(&minter_id, amount.0);
+        self.stats.record_bridge_burn(amount.0);
+        let price = self.token.cost_basis_of(&minter_id);
+        self.token.internal_withdraw(&minter_id, amount.0, price);
+
+        FtBurn {
+            owner_id: &minter_id,
+            amount: &amount,
+            memo: Some("bridge"),
+        }
+        .emit();
+        KtEvent::BridgeBurned {
+            minter_id: &minter_id,
+            amount: &amount,
+            destination_chain: &destination_chain,
+            recipient: &recipient,
+        }
+        .emit();
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_contract_standards::fungible_token::core::FungibleTokenCore;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    use crate::Contract;
+
+    const PRICE: u128 = 1_000_000_000_000;
+
+    fn setup_contract(context: &mut VMContextBuilder) -> Contract {
+        context
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.set_bridge_minter(accounts(5), 100.into(), Some(150.into()));
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract
+    }
+
+    #[test]
+    fn test_bridge_mint_and_burn() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        contract.bridge_mint(accounts(1), 100.into(), PRICE.into(), "ethereum".into());
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 100);
+
+        contract.token.internal_deposit(&accounts(5), 50, PRICE);
+        contract.bridge_burn(50.into(), "ethereum".into(), "0xabc".into());
+        assert_eq!(contract.ft_total_supply().0, 100);
+        // Burned KT frees the cap
+        contract.bridge_mint(accounts(1), 50.into(), PRICE.into(), "ethereum".into());
+
+        let minter = contract.get_bridge_minter(accounts(5)).unwrap();
+        assert_eq!(minter.minted.0, 150);
+        assert_eq!(minter.burned.0, 50);
+        assert_eq!(minter.minted_today.0, 150);
+
+        let supply = contract.get_supply();
+        assert_eq!(supply.local_supply.0, 150);
+        assert_eq!(supply.bridged_in.0, 100);
+        assert_eq!(supply.backed_supply.0, 50);
+    }
+
+    #[test]
+    #[should_panic(expected = "Bridge mint cap of 100 is exceeded")]
+    fn test_bridge_mint_cap() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        contract.bridge_mint(accounts(1), 101.into(), PRICE.into(), "ethereum".into());
+    }
+
+    #[test]
+    #[should_panic(expected = "Account bob is not a Bridge")]
+    fn test_bridge_mint_not_minter() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.bridge_mint(accounts(1), 1.into(), PRICE.into(), "ethereum".into());
+    }
+}
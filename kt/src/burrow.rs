@@ -0,0 +1,274 @@
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{
+    env, ext_contract, near_bindgen, AccountId, Balance, Promise, PromiseOrValue, PromiseResult,
+    ONE_YOCTO,
+};
+use schemars::JsonSchema;
+
+use crate::gas::GasConfig;
+use crate::price::convert_decimals;
+use crate::strategy::Strategy;
+use crate::treasury::AssetId;
+use crate::{ext_ft_transfer, Contract, ContractExt};
+
+// From https://github.com/burrowfdn/burrowland/blob/main/contracts/contract/src/actions.rs
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AssetAmount {
+    pub token_id: AccountId,
+    /// Amount in the Burrow inner decimals.
+    pub amount: Option<U128>,
+    pub max_amount: Option<U128>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+pub enum Action {
+    Withdraw(AssetAmount),
+}
+
+// From https://github.com/burrowfdn/burrowland/blob/main/contracts/contract/src/account_view.rs
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AssetView {
+    pub token_id: AccountId,
+    /// Supplied balance with the accrued interest, in the Burrow inner decimals.
+    pub balance: U128,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AccountDetailedView {
+    pub account_id: AccountId,
+    pub supplied: Vec<AssetView>,
+}
+
+impl AccountDetailedView {
+    /// Supplied balance of the asset, in the asset decimals.
+    fn supplied_balance(&self, asset_id: &AssetId, extra_decimals: u8) -> Balance {
+        self.supplied
+            .iter()
+            .find(|asset| asset.token_id == *asset_id)
+            .and_then(|asset| convert_decimals(asset.balance.0, extra_decimals, 0))
+            .unwrap_or(0)
+    }
+}
+
+/// Burrow lending interface.
+/// NOTE: the KT contract must be registered on Burrow with `storage_deposit`.
+#[ext_contract(ext_burrow)]
+#[allow(dead_code)]
+pub trait BurrowContract {
+    fn execute(&mut self, actions: Vec<Action>);
+    fn get_account(&self, account_id: AccountId) -> Option<AccountDetailedView>;
+}
+
+/// Supplies treasury funds to Burrow. Supplied funds are not used as collateral,
+/// the interest accrues into the supplied balance and is harvested by withdrawing it.
+pub struct Burrow<'a> {
+    pub account_id: &'a AccountId,
+    /// Burrow stores balances with the extra decimals of the asset config.
+    pub extra_decimals: u8,
+}
+
+impl Burrow<'_> {
+    fn execute_withdraw(&self, gas: &GasConfig, asset_id: &AssetId, amount: Balance) -> Promise {
+        let amount = convert_decimals(amount, 0, self.extra_decimals)
+            .unwrap_or_else(|| env::panic_str("Withdrawal amount overflow"));
+        ext_burrow::ext(self.account_id.clone())
+            .with_static_gas(gas.strategy_withdraw)
+            .with_attached_deposit(ONE_YOCTO)
+            .execute(vec![Action::Withdraw(AssetAmount {
+                token_id: asset_id.clone(),
+                amount: Some(amount.into()),
+                max_amount: None,
+            })])
+    }
+
+    fn get_account(&self, gas: &GasConfig) -> Promise {
+        ext_burrow::ext(self.account_id.clone())
+            .with_static_gas(gas.burrow_view)
+            .get_account(env::current_account_id())
+    }
+}
+
+impl Strategy for Burrow<'_> {
+    fn deposit(&self, gas: &GasConfig, asset_id: &AssetId, amount: Balance) -> Promise {
+        // An empty message supplies the transferred funds
+        ext_ft_transfer::ext(asset_id.clone())
+            .with_static_gas(gas.strategy_deposit)
+            .with_attached_deposit(ONE_YOCTO)
+            .ft_transfer_call(self.account_id.clone(), amount.into(), None, String::new())
+    }
+
+    /// NOTE: Burrow doesn't wait for the transfer, a failed transfer is supplied back
+    /// and shows up in the next report.
+    fn withdraw(&self, gas: &GasConfig, asset_id: &AssetId, amount: Balance) -> Promise {
+        self.execute_withdraw(gas, asset_id, amount).then(
+            ext_burrow_adapter::ext(env::current_account_id())
+                .with_static_gas(gas.resolve_burrow_withdraw)
+                .resolve_burrow_withdraw(amount.into()),
+        )
+    }
+
+    fn harvest(&self, gas: &GasConfig, asset_id: &AssetId) -> Promise {
+        self.get_account(gas).then(
+            ext_burrow_adapter::ext(env::current_account_id())
+                .with_static_gas(gas.burrow_harvest())
+                .burrow_harvest(
+                    asset_id.clone(),
+                    self.account_id.clone(),
+                    self.extra_decimals,
+                ),
+        )
+    }
+
+    fn report(&self, gas: &GasConfig, asset_id: &AssetId) -> Promise {
+        self.get_account(gas).then(
+            ext_burrow_adapter::ext(env::current_account_id())
+                .with_static_gas(gas.burrow_report)
+                .burrow_report(asset_id.clone(), self.extra_decimals),
+        )
+    }
+}
+
+#[ext_contract(ext_burrow_adapter)]
+#[allow(dead_code)]
+pub trait BurrowAdapter {
+    fn burrow_report(
+        &self,
+        asset_id: AssetId,
+        extra_decimals: u8,
+        #[callback_unwrap] account: Option<AccountDetailedView>,
+    ) -> U128;
+    fn burrow_harvest(
+        &mut self,
+        asset_id: AssetId,
+        burrow_id: AccountId,
+        extra_decimals: u8,
+        #[callback_unwrap] account: Option<AccountDetailedView>,
+    ) -> PromiseOrValue<U128>;
+    fn resolve_burrow_withdraw(&mut self, amount: U128) -> U128;
+}
+
+#[near_bindgen]
+impl BurrowAdapter for Contract {
+    #[private]
+    fn burrow_report(
+        &self,
+        asset_id: AssetId,
+        extra_decimals: u8,
+        #[callback_unwrap] account: Option<AccountDetailedView>,
+    ) -> U128 {
+        account
+            .map_or(0, |account| {
+                account.supplied_balance(&asset_id, extra_decimals)
+            })
+            .into()
+    }
+
+    /// Withdraws the supplied balance above the deployed principal.
+    #[private]
+    fn burrow_harvest(
+        &mut self,
+        asset_id: AssetId,
+        burrow_id: AccountId,
+        extra_decimals: u8,
+        #[callback_unwrap] account: Option<AccountDetailedView>,
+    ) -> PromiseOrValue<U128> {
+        let supplied = account.map_or(0, |account| {
+            account.supplied_balance(&asset_id, extra_decimals)
+        });
+        let deployed = self.treasury.assert_asset(&asset_id).deployed;
+        let amount = supplied.saturating_sub(deployed);
+        if amount == 0 {
+            return PromiseOrValue::Value(U128(0));
+        }
+
+        let burrow = Burrow {
+            account_id: &burrow_id,
+            extra_decimals,
+        };
+        burrow
+            .execute_withdraw(&self.gas, &asset_id, amount)
+            .then(
+                ext_burrow_adapter::ext(env::current_account_id())
+                    .with_static_gas(self.gas.resolve_burrow_withdraw)
+                    .resolve_burrow_withdraw(amount.into()),
+            )
+            .into()
+    }
+
+    /// Returns the withdrawn amount.
+    #[private]
+    fn resolve_burrow_withdraw(&mut self, amount: U128) -> U128 {
+        match env::promise_result(0) {
+            PromiseResult::NotReady => env::abort(),
+            PromiseResult::Successful(_) => amount,
+            PromiseResult::Failed => U128(0),
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    use crate::burrow::{AccountDetailedView, AssetView, BurrowAdapter};
+    use crate::strategy::StrategyKind;
+    use crate::Contract;
+
+    fn account_view(balance: u128) -> Option<AccountDetailedView> {
+        Some(AccountDetailedView {
+            account_id: accounts(0),
+            supplied: vec![AssetView {
+                token_id: accounts(1),
+                balance: balance.into(),
+            }],
+        })
+    }
+
+    fn setup_contract() -> Contract {
+        let mut context = VMContextBuilder::new();
+        context
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(1), 6);
+        contract.set_asset_strategy(
+            accounts(1),
+            Some(StrategyKind::Burrow {
+                account_id: accounts(5),
+                extra_decimals: 12,
+            }),
+            10_000,
+        );
+        contract.treasury.internal_deposit(&accounts(1), 1_000_000);
+        contract.strategy_deposit(accounts(1), 1_000_000.into());
+        contract
+    }
+
+    #[test]
+    fn test_burrow_report() {
+        let contract = setup_contract();
+        let value =
+            contract.burrow_report(accounts(1), 12, account_view(1_050_000 * 10u128.pow(12)));
+        assert_eq!(value.0, 1_050_000);
+        assert_eq!(contract.burrow_report(accounts(2), 12, None).0, 0);
+    }
+
+    #[test]
+    fn test_burrow_harvest_without_yield() {
+        let mut contract = setup_contract();
+        let harvested = contract.burrow_harvest(
+            accounts(1),
+            accounts(5),
+            12,
+            account_view(999_999 * 10u128.pow(12)),
+        );
+        assert!(matches!(harvested, near_sdk::PromiseOrValue::Value(amount) if amount.0 == 0));
+    }
+}
@@ -0,0 +1,459 @@
+//! Buys with tokens outside of the treasury: the deposit is swapped on the DEX into a
+//! supported asset first, then the swapped amount buys KT. Every step rolls back on failure,
+//! returning the deposit to the sender through the `ft_transfer_call` refund. Tokens which
+//! can't be withdrawn back from the DEX are recorded as DEX claims of the sender, and refunds
+//! which can't be transferred as asset claims.
+
+use std::collections::HashMap;
+
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{
+    assert_one_yocto, env, ext_contract, log, near_bindgen, require, AccountId, Promise,
+    PromiseOrValue, PromiseResult, ONE_YOCTO,
+};
+
+use schemars::JsonSchema;
+
+use crate::claims::ext_claims_resolver;
+use crate::dex::{ext_ref_exchange, SwapAction};
+use crate::price::{convert_decimals, ExpectedPrice};
+use crate::treasury::AssetId;
+use crate::{
+    ext_ft_transfer, ext_self, promise_result_u128, BuyOptions, Contract, ContractExt, KT_DECIMALS,
+};
+
+/// Buyer and buy parameters carried through the swap callbacks.
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BuyViaOrder {
+    pub account_id: AccountId,
+    pub expected: Option<ExpectedPrice>,
+    pub referrer_id: Option<AccountId>,
+}
+
+impl Contract {
+    /// Deposits the token to the DEX to swap it along the route, returns the unused amount
+    /// of the token.
+    pub(crate) fn start_buy_via(
+        &mut self,
+        token_id: AccountId,
+        amount: U128,
+        route: Vec<SwapAction>,
+        order: BuyViaOrder,
+    ) -> Promise {
+        require!(
+            env::prepaid_gas() > self.gas.buy_via(),
+            "More gas is required"
+        );
+        let dex_id = self
+            .dex_id
+            .clone()
+            .unwrap_or_else(|| env::panic_str("DEX is not configured"));
+        self.assert_buys_enabled();
+        self.compliance.assert_not_frozen(&order.account_id);
+        require!(!route.is_empty(), "Swap route is empty");
+        require!(
+            route[0].token_in == token_id,
+            "Swap route doesn't start with the transferred token"
+        );
+        require!(
+            route
+                .windows(2)
+                .all(|actions| actions[0].token_out == actions[1].token_in),
+            "Swap route is broken"
+        );
+        let last = &route[route.len() - 1];
+        require!(
+            last.min_amount_out.0 > 0,
+            "Swap route has no minimum amount out"
+        );
+        self.treasury.assert_can_buy(&last.token_out);
+
+        ext_ft_transfer::ext(token_id.clone())
+            .with_static_gas(self.gas.dex_deposit)
+            .with_attached_deposit(ONE_YOCTO)
+            .ft_transfer_call(dex_id, amount, None, String::new())
+            .then(
+                ext_buy_via_resolver::ext(env::current_account_id())
+                    .with_static_gas(self.gas.resolve_buy_via_deposit())
+                    .resolve_buy_via_deposit(token_id, amount, route, order),
+            )
+    }
+
+    /// Records the token amount left on the DEX account of the contract for the account.
+    fn internal_add_dex_claim(
+        &mut self,
+        account_id: &AccountId,
+        token_id: &AccountId,
+        amount: U128,
+    ) {
+        log!(
+            "DEX withdrawal of {} {} for @{} failed, it can be claimed with claim_dex_token",
+            amount.0,
+            token_id,
+            account_id
+        );
+        self.dex_claims.internal_add(account_id, token_id, amount.0);
+    }
+
+    fn dex_withdraw_to_contract(&self, token_id: AccountId, amount: U128) -> Promise {
+        ext_ref_exchange::ext(self.dex_id.clone().unwrap())
+            .with_static_gas(self.gas.dex_withdraw)
+            .with_attached_deposit(ONE_YOCTO)
+            .withdraw(token_id, amount, None)
+    }
+}
+
+#[ext_contract(ext_buy_via_resolver)]
+#[allow(dead_code)]
+pub trait BuyViaResolver {
+    fn resolve_buy_via_deposit(
+        &mut self,
+        token_id: AccountId,
+        amount: U128,
+        route: Vec<SwapAction>,
+        order: BuyViaOrder,
+    ) -> PromiseOrValue<U128>;
+    fn resolve_buy_via_swap(
+        &mut self,
+        token_id: AccountId,
+        amount: U128,
+        unused: U128,
+        asset_id: AssetId,
+        order: BuyViaOrder,
+    ) -> PromiseOrValue<U128>;
+    fn resolve_buy_via_rollback(
+        &mut self,
+        account_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+        unused: U128,
+    ) -> U128;
+    fn resolve_buy_via_withdraw(
+        &mut self,
+        asset_id: AssetId,
+        asset_amount: U128,
+        unused: U128,
+        order: BuyViaOrder,
+    ) -> PromiseOrValue<U128>;
+    fn resolve_buy_via(
+        &mut self,
+        account_id: AccountId,
+        asset_id: AssetId,
+        asset_amount: U128,
+        unused: U128,
+    ) -> U128;
+    fn resolve_claim_dex_token(&mut self, account_id: AccountId, token_id: AccountId, amount: U128);
+}
+
+#[near_bindgen]
+impl BuyViaResolver for Contract {
+    /// Swaps the deposited amount along the route.
+    #[private]
+    fn resolve_buy_via_deposit(
+        &mut self,
+        token_id: AccountId,
+        amount: U128,
+        route: Vec<SwapAction>,
+        order: BuyViaOrder,
+    ) -> PromiseOrValue<U128> {
+        // `ft_transfer_call` returns the used amount, the rest is refunded by the token
+        let used = promise_result_u128().unwrap_or(0).min(amount.0);
+        if used == 0 {
+            log!("DEX deposit of {} {} failed", amount.0, token_id);
+            return PromiseOrValue::Value(amount);
+        }
+        let unused = U128(amount.0 - used);
+        let mut route = route;
+        route[0].amount_in = Some(used.into());
+        let asset_id = route[route.len() - 1].token_out.clone();
+
+        ext_ref_exchange::ext(self.dex_id.clone().unwrap())
+            .with_static_gas(self.gas.dex_swap)
+            .with_attached_deposit(ONE_YOCTO)
+            .swap(route, None)
+            .then(
+                ext_buy_via_resolver::ext(env::current_account_id())
+                    .with_static_gas(self.gas.resolve_buy_via_swap())
+                    .resolve_buy_via_swap(token_id, used.into(), unused, asset_id, order),
+            )
+            .into()
+    }
+
+    /// Withdraws the swapped asset, or the deposit back if the swap failed.
+    #[private]
+    fn resolve_buy_via_swap(
+        &mut self,
+        token_id: AccountId,
+        amount: U128,
+        unused: U128,
+        asset_id: AssetId,
+        order: BuyViaOrder,
+    ) -> PromiseOrValue<U128> {
+        let callback = ext_buy_via_resolver::ext(env::current_account_id());
+        match promise_result_u128() {
+            Some(asset_amount) => self
+                .dex_withdraw_to_contract(asset_id.clone(), asset_amount.into())
+                .then(
+                    callback
+                        .with_static_gas(self.gas.resolve_buy_via_withdraw())
+                        .resolve_buy_via_withdraw(asset_id, asset_amount.into(), unused, order),
+                )
+                .into(),
+            None => {
+                log!("DEX swap of {} {} failed", amount.0, token_id);
+                self.dex_withdraw_to_contract(token_id.clone(), amount)
+                    .then(
+                        callback
+                            .with_static_gas(self.gas.resolve_buy_via)
+                            .resolve_buy_via_rollback(order.account_id, token_id, amount, unused),
+                    )
+                    .into()
+            }
+        }
+    }
+
+    /// Returns the whole deposit as unused once it's withdrawn back from the DEX.
+    #[private]
+    fn resolve_buy_via_rollback(
+        &mut self,
+        account_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+        unused: U128,
+    ) -> U128 {
+        match env::promise_result(0) {
+            PromiseResult::NotReady => env::abort(),
+            PromiseResult::Successful(_) => U128(amount.0 + unused.0),
+            PromiseResult::Failed => {
+                self.internal_add_dex_claim(&account_id, &token_id, amount);
+                unused
+            }
+        }
+    }
+
+    /// Buys KT for the withdrawn asset amount.
+    #[private]
+    fn resolve_buy_via_withdraw(
+        &mut self,
+        asset_id: AssetId,
+        asset_amount: U128,
+        unused: U128,
+        order: BuyViaOrder,
+    ) -> PromiseOrValue<U128> {
+        let BuyViaOrder {
+            account_id,
+            expected,
+            referrer_id,
+        } = order;
+        if !matches!(env::promise_result(0), PromiseResult::Successful(_)) {
+            self.internal_add_dex_claim(&account_id, &asset_id, asset_amount);
+            return PromiseOrValue::Value(unused);
+        }
+        let asset = self.treasury.assert_asset(&asset_id);
+        let peg_amount = convert_decimals(asset_amount.0, asset.decimals, KT_DECIMALS);
+        let get_price = self.get_price(&asset_id, peg_amount);
+        let get_price = match self.kyc_check(&account_id) {
+            Some(kyc_check) => get_price.and(kyc_check),
+            None => get_price,
+        };
+        get_price
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(self.gas.buy_with_price)
+                    .buy_with_price(
+                        account_id.clone(),
+                        asset_id.clone(),
+                        asset_amount,
+                        expected,
+                        BuyOptions {
+                            referrer_id,
+                            ..Default::default()
+                        },
+                    ),
+            )
+            .then(
+                ext_buy_via_resolver::ext(env::current_account_id())
+                    .with_static_gas(
+                        self.gas.resolve_buy_via + self.gas.transfer + self.gas.resolve_claim_asset,
+                    )
+                    .resolve_buy_via(account_id, asset_id, asset_amount, unused),
+            )
+            .into()
+    }
+
+    /// Sends the asset amount left from the buy back to the account, everything on failure.
+    /// A failed refund is recorded as an asset claim. Returns the unused amount of the
+    /// transferred token.
+    #[private]
+    fn resolve_buy_via(
+        &mut self,
+        account_id: AccountId,
+        asset_id: AssetId,
+        asset_amount: U128,
+        unused: U128,
+    ) -> U128 {
+        let refund =
+            promise_result_u128().map_or(asset_amount.0, |unused| unused.min(asset_amount.0));
+        if refund > 0 {
+            self.internal_start_payout(&asset_id, refund);
+            log!(
+                "Refunding {} {} to @{} after the buy",
+                refund,
+                asset_id,
+                account_id
+            );
+            ext_ft_transfer::ext(asset_id.clone())
+                .with_static_gas(self.gas.transfer)
+                .with_attached_deposit(ONE_YOCTO)
+                .ft_transfer(account_id.clone(), refund.into(), None)
+                .then(
+                    ext_claims_resolver::ext(env::current_account_id())
+                        .with_static_gas(self.gas.resolve_claim_asset)
+                        .resolve_claim_asset(account_id, asset_id, refund.into()),
+                );
+        }
+        unused
+    }
+
+    /// Sends the token withdrawn from the DEX to the account, or keeps the DEX claim
+    /// if the withdrawal failed.
+    #[private]
+    fn resolve_claim_dex_token(
+        &mut self,
+        account_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+    ) {
+        match env::promise_result(0) {
+            PromiseResult::NotReady => env::abort(),
+            PromiseResult::Successful(_) => {
+                self.internal_start_payout(&token_id, amount.0);
+                ext_ft_transfer::ext(token_id.clone())
+                    .with_static_gas(self.gas.transfer)
+                    .with_attached_deposit(ONE_YOCTO)
+                    .ft_transfer(account_id.clone(), amount, None)
+                    .then(
+                        ext_claims_resolver::ext(env::current_account_id())
+                            .with_static_gas(self.gas.resolve_claim_asset)
+                            .resolve_claim_asset(account_id, token_id, amount),
+                    );
+            }
+            PromiseResult::Failed => self.internal_add_dex_claim(&account_id, &token_id, amount),
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Withdraws the token the caller is owed from the DEX and transfers it to the caller.
+    #[payable]
+    pub fn claim_dex_token(&mut self, token_id: AccountId) -> Promise {
+        assert_one_yocto();
+        require!(
+            env::prepaid_gas() > self.gas.claim_dex_token(),
+            "More gas is required"
+        );
+        let account_id = env::predecessor_account_id();
+        let amount = self.dex_claims.internal_take(&account_id, &token_id);
+        require!(amount > 0, "Nothing to claim");
+
+        self.dex_withdraw_to_contract(token_id.clone(), amount.into())
+            .then(
+                ext_buy_via_resolver::ext(env::current_account_id())
+                    .with_static_gas(self.gas.resolve_claim_dex_token())
+                    .resolve_claim_dex_token(account_id, token_id, amount.into()),
+            )
+    }
+
+    pub fn get_dex_claims(&self, account_id: AccountId) -> HashMap<AccountId, U128> {
+        self.dex_claims
+            .claims_of(&account_id)
+            .into_iter()
+            .map(|(token_id, amount)| (token_id, amount.into()))
+            .collect()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, Gas, PromiseOrValue, PromiseResult, RuntimeFeesConfig, VMConfig};
+
+    use crate::buy_via::{BuyViaOrder, BuyViaResolver};
+    use crate::dex::SwapAction;
+    use crate::test_utils;
+    use crate::Contract;
+
+    fn setup_contract(context: &mut VMContextBuilder) -> Contract {
+        context.prepaid_gas(Gas(300_000_000_000_000));
+        let mut contract = test_utils::setup_contract(context);
+        contract.set_dex(Some(accounts(5)));
+        contract
+    }
+
+    fn action(token_in: usize, token_out: usize) -> SwapAction {
+        SwapAction {
+            pool_id: 0,
+            token_in: accounts(token_in),
+            amount_in: None,
+            token_out: accounts(token_out),
+            min_amount_out: 1.into(),
+        }
+    }
+
+    fn order() -> BuyViaOrder {
+        BuyViaOrder {
+            account_id: accounts(1),
+            expected: None,
+            referrer_id: None,
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Swap route is broken")]
+    fn test_buy_via_broken_route() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        let route = vec![action(2, 4), action(0, 3)];
+        contract.start_buy_via(accounts(2), 1_000.into(), route, order());
+    }
+
+    #[test]
+    fn test_buy_via_deposit_failed() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        testing_env!(
+            context.build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        let unused = contract.resolve_buy_via_deposit(
+            accounts(2),
+            1_000.into(),
+            vec![action(2, 3)],
+            order(),
+        );
+        assert!(matches!(unused, PromiseOrValue::Value(amount) if amount.0 == 1_000));
+    }
+
+    #[test]
+    fn test_buy_via_rollback_failed() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        testing_env!(
+            context.build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        let unused =
+            contract.resolve_buy_via_rollback(accounts(1), accounts(2), 900.into(), 100.into());
+        assert_eq!(unused.0, 100);
+        assert_eq!(contract.get_dex_claims(accounts(1))[&accounts(2)].0, 900);
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{
+    assert_one_yocto, env, ext_contract, near_bindgen, require, AccountId, Balance, IntoStorageKey,
+    Promise, PromiseResult, ONE_YOCTO,
+};
+use schemars::JsonSchema;
+
+use crate::events::KtEvent;
+use crate::treasury::AssetId;
+use crate::{ext_ft_transfer, Contract, ContractExt};
+
+/// How a failed sell payout is refunded.
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, JsonSchema,
+)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub enum SellRefund {
+    /// The sold KT is minted back.
+    Mint,
+    /// The asset amount is recorded to be claimed with `claim_asset`,
+    /// e.g. after the account registers on the asset contract.
+    Claim,
+}
+
+/// Asset amounts owed to accounts, held outside of the treasury balance.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct AssetClaims {
+    /// AccountID -> Claimable amount per asset.
+    accounts: LookupMap<AccountId, HashMap<AssetId, Balance>>,
+    /// AssetID -> Claimable amount of all the accounts.
+    totals: LookupMap<AssetId, Balance>,
+}
+
+impl AssetClaims {
+    pub fn new<S>(prefix: S) -> Self
+    where
+        S: IntoStorageKey,
+    {
+        let prefix = prefix.into_storage_key();
+        Self {
+            accounts: LookupMap::new(prefix.clone()),
+            totals: LookupMap::new([prefix, b"t".to_vec()].concat()),
+        }
+    }
+
+    pub fn total_of(&self, asset_id: &AssetId) -> Balance {
+        self.totals.get(asset_id).unwrap_or_default()
+    }
+
+    pub fn claims_of(&self, account_id: &AccountId) -> HashMap<AssetId, Balance> {
+        self.accounts.get(account_id).unwrap_or_default()
+    }
+
+    pub fn internal_add(&mut self, account_id: &AccountId, asset_id: &AssetId, amount: Balance) {
+        let mut claims = self.claims_of(account_id);
+        let claim = claims.entry(asset_id.clone()).or_default();
+        *claim = claim
+            .checked_add(amount)
+            .unwrap_or_else(|| env::panic_str("Claim amount overflow"));
+        self.accounts.insert(account_id, &claims);
+        let total = self.total_of(asset_id);
+        self.totals.insert(asset_id, &total.saturating_add(amount));
+    }
+
+    /// Removes the claim of the asset and returns its amount.
+    pub fn internal_take(&mut self, account_id: &AccountId, asset_id: &AssetId) -> Balance {
+        let mut claims = self.claims_of(account_id);
+        let amount = claims.remove(asset_id).unwrap_or(0);
+        if amount > 0 {
+            let total = self.total_of(asset_id);
+            self.totals.insert(asset_id, &total.saturating_sub(amount));
+        }
+        if claims.is_empty() {
+            self.accounts.remove(account_id);
+        } else {
+            self.accounts.insert(account_id, &claims);
+        }
+        amount
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn set_sell_refund(&mut self, sell_refund: SellRefund) {
+        self.assert_owner();
+        self.sell_refund = sell_refund;
+    }
+
+    pub fn get_sell_refund(&self) -> SellRefund {
+        self.sell_refund
+    }
+
+    /// Transfers the claimable amount of the asset to the caller.
+    #[payable]
+    pub fn claim_asset(&mut self, asset_id: AssetId) -> Promise {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let amount = self.claims.internal_take(&account_id, &asset_id);
+        require!(amount > 0, "Nothing to claim");
+        self.internal_start_payout(&asset_id, amount);
+
+        ext_ft_transfer::ext(asset_id.clone())
+            .with_static_gas(self.gas.transfer)
+            .with_attached_deposit(ONE_YOCTO)
+            .ft_transfer(account_id.clone(), amount.into(), None)
+            .then(
+                ext_claims_resolver::ext(env::current_account_id())
+                    .with_static_gas(self.gas.resolve_claim_asset)
+                    .resolve_claim_asset(account_id, asset_id, amount.into()),
+            )
+    }
+
+    pub fn get_claims(&self, account_id: AccountId) -> HashMap<AssetId, U128> {
+        self.claims
+            .claims_of(&account_id)
+            .into_iter()
+            .map(|(asset_id, amount)| (asset_id, amount.into()))
+            .collect()
+    }
+}
+
+#[ext_contract(ext_claims_resolver)]
+#[allow(dead_code)]
+pub trait ClaimsResolver {
+    fn resolve_claim_asset(&mut self, account_id: AccountId, asset_id: AssetId, amount: U128);
+}
+
+#[near_bindgen]
+impl ClaimsResolver for Contract {
+    #[private]
+    fn resolve_claim_asset(&mut self, account_id: AccountId, asset_id: AssetId, amount: U128) {
+        self.internal_finish_payout(&asset_id, amount.0);
+        match env::promise_result(0) {
+            PromiseResult::NotReady => env::abort(),
+            PromiseResult::Successful(_) => KtEvent::AssetClaimed {
+                account_id: &account_id,
+                asset_id: &asset_id,
+                amount: &amount,
+            }
+            .emit(),
+            PromiseResult::Failed => {
+                self.claims
+                    .internal_add(&account_id, &asset_id, amount.into());
+            }
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, ONE_YOCTO};
+
+    use crate::claims::AssetClaims;
+    use crate::{Contract, StorageKey};
+
+    #[test]
+    fn test_asset_claims() {
+        let mut claims = AssetClaims::new(StorageKey::Claims);
+        claims.internal_add(&accounts(1), &accounts(2), 100);
+        claims.internal_add(&accounts(1), &accounts(2), 50);
+        claims.internal_add(&accounts(1), &accounts(3), 10);
+        claims.internal_add(&accounts(2), &accounts(2), 5);
+        assert_eq!(claims.claims_of(&accounts(1)).len(), 2);
+        assert_eq!(claims.total_of(&accounts(2)), 155);
+
+        assert_eq!(claims.internal_take(&accounts(1), &accounts(2)), 150);
+        assert_eq!(claims.total_of(&accounts(2)), 5);
+        assert_eq!(claims.internal_take(&accounts(1), &accounts(2)), 0);
+        assert_eq!(claims.internal_take(&accounts(1), &accounts(3)), 10);
+        assert!(claims.claims_of(&accounts(1)).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Nothing to claim")]
+    fn test_claim_asset_without_claim() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.claim_asset(accounts(2));
+    }
+}
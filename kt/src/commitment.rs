@@ -0,0 +1,172 @@
+//! Commit-reveal buys.
+//!
+//! A large buy can be committed first as `sha256("{asset_id}:{amount}:{salt}")` and then
+//! revealed within the window by transferring the asset with the `Reveal` message.
+//! The buy executes at the oracle price at the reveal, so the order can't be sandwiched
+//! by anyone observing the commit.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::json_types::{Base64VecU8, U128, U64};
+use near_sdk::serde::Serialize;
+use near_sdk::{env, near_bindgen, require, AccountId, BlockHeight, IntoStorageKey};
+use schemars::JsonSchema;
+
+use crate::events::KtEvent;
+use crate::treasury::AssetId;
+use crate::{Contract, ContractExt};
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct Commitment {
+    pub hash: Base64VecU8,
+    pub block_height: U64,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Commitments {
+    commitments: LookupMap<AccountId, Commitment>,
+    /// Blocks after the commit within which it has to be revealed, 0 disables commits.
+    window: BlockHeight,
+}
+
+pub fn commitment_hash(asset_id: &AssetId, amount: U128, salt: &str) -> Vec<u8> {
+    env::sha256(format!("{}:{}:{}", asset_id, amount.0, salt).as_bytes())
+}
+
+impl Commitments {
+    pub fn new<S>(prefix: S) -> Self
+    where
+        S: IntoStorageKey,
+    {
+        Self {
+            commitments: LookupMap::new(prefix),
+            window: 0,
+        }
+    }
+
+    pub fn get(&self, account_id: &AccountId) -> Option<Commitment> {
+        self.commitments.get(account_id)
+    }
+
+    /// Replaces the account's commitment, returns the last block to reveal it.
+    pub fn commit(&mut self, account_id: &AccountId, hash: Base64VecU8) -> BlockHeight {
+        require!(self.window > 0, "Commit-reveal buys are disabled");
+        require!(hash.0.len() == 32, "Invalid commitment hash");
+        let block_height = env::block_height();
+        self.commitments.insert(
+            account_id,
+            &Commitment {
+                hash,
+                block_height: block_height.into(),
+            },
+        );
+        block_height + self.window
+    }
+
+    /// Consumes the account's commitment if it matches the revealed buy.
+    pub fn reveal(&mut self, account_id: &AccountId, asset_id: &AssetId, amount: U128, salt: &str) {
+        let commitment = self
+            .commitments
+            .remove(account_id)
+            .unwrap_or_else(|| env::panic_str("No commitment to reveal"));
+        let block_height = env::block_height();
+        require!(
+            block_height > commitment.block_height.0,
+            "The commitment can't be revealed in the same block"
+        );
+        require!(
+            block_height <= commitment.block_height.0 + self.window,
+            "The commitment has expired"
+        );
+        require!(
+            commitment.hash.0 == commitment_hash(asset_id, amount, salt),
+            "The reveal doesn't match the commitment"
+        );
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn set_commit_window(&mut self, blocks: U64) {
+        self.assert_owner();
+        self.commitments.window = blocks.into();
+    }
+
+    pub fn get_commit_window(&self) -> U64 {
+        self.commitments.window.into()
+    }
+
+    /// Commits to a buy to be revealed within the window.
+    pub fn commit_buy(&mut self, hash: Base64VecU8) -> U64 {
+        let account_id = env::predecessor_account_id();
+        self.compliance.assert_not_frozen(&account_id);
+        let expires_at = self.commitments.commit(&account_id, hash);
+        KtEvent::BuyCommitted {
+            account_id: &account_id,
+            expires_at: &expires_at.into(),
+        }
+        .emit();
+        expires_at.into()
+    }
+
+    pub fn get_commitment(&self, account_id: AccountId) -> Option<Commitment> {
+        self.commitments.get(&account_id)
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    use super::commitment_hash;
+    use crate::Contract;
+
+    fn setup_contract(context: &mut VMContextBuilder) -> Contract {
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.set_commit_window(10.into());
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_index(100)
+            .build());
+        let hash = commitment_hash(&accounts(3), 1_000_000.into(), "salt");
+        assert_eq!(contract.commit_buy(hash.into()).0, 110);
+        contract
+    }
+
+    #[test]
+    fn test_reveal() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        testing_env!(context.block_index(110).build());
+        contract
+            .commitments
+            .reveal(&accounts(1), &accounts(3), 1_000_000.into(), "salt");
+        assert!(contract.get_commitment(accounts(1)).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "The reveal doesn't match the commitment")]
+    fn test_reveal_mismatch() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        testing_env!(context.block_index(101).build());
+        contract
+            .commitments
+            .reveal(&accounts(1), &accounts(3), 2_000_000.into(), "salt");
+    }
+
+    #[test]
+    #[should_panic(expected = "The commitment has expired")]
+    fn test_reveal_expired() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        testing_env!(context.block_index(111).build());
+        contract
+            .commitments
+            .reveal(&accounts(1), &accounts(3), 1_000_000.into(), "salt");
+    }
+}
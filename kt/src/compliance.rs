@@ -0,0 +1,179 @@
+//! Account freezing for compliance.
+//!
+//! Accounts holding the `Compliance` role (or the owner) may freeze an account, which blocks
+//! its transfers, buys and sells. The balance of a frozen account may then be seized and
+//! burned, the procedure being: freeze the account, then call `seize_and_burn` with the
+//! reason. Every step emits an event.
+
+use near_contract_standards::fungible_token::events::FtBurn;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupSet;
+use near_sdk::json_types::U128;
+use near_sdk::{assert_one_yocto, env, near_bindgen, require, AccountId, IntoStorageKey};
+
+use crate::events::KtEvent;
+use crate::roles::Role;
+use crate::{Contract, ContractExt};
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Compliance {
+    frozen: LookupSet<AccountId>,
+}
+
+impl Compliance {
+    pub fn new<S>(prefix: S) -> Self
+    where
+        S: IntoStorageKey,
+    {
+        Self {
+            frozen: LookupSet::new(prefix),
+        }
+    }
+
+    pub fn is_frozen(&self, account_id: &AccountId) -> bool {
+        self.frozen.contains(account_id)
+    }
+
+    pub fn assert_not_frozen(&self, account_id: &AccountId) {
+        if self.is_frozen(account_id) {
+            env::panic_str(format!("Account {} is frozen", account_id).as_str())
+        }
+    }
+
+    pub fn freeze(&mut self, account_id: &AccountId) {
+        require!(self.frozen.insert(account_id), "Account is already frozen");
+    }
+
+    pub fn unfreeze(&mut self, account_id: &AccountId) {
+        require!(self.frozen.remove(account_id), "Account is not frozen");
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn freeze_account(&mut self, account_id: AccountId, reason: String) {
+        self.assert_owner_or_role(Role::Compliance);
+        self.compliance.freeze(&account_id);
+        KtEvent::AccountFrozen {
+            account_id: &account_id,
+            compliance_id: &env::predecessor_account_id(),
+            reason: &reason,
+        }
+        .emit();
+    }
+
+    pub fn unfreeze_account(&mut self, account_id: AccountId, reason: String) {
+        self.assert_owner_or_role(Role::Compliance);
+        self.compliance.unfreeze(&account_id);
+        KtEvent::AccountUnfrozen {
+            account_id: &account_id,
+            compliance_id: &env::predecessor_account_id(),
+            reason: &reason,
+        }
+        .emit();
+    }
+
+    /// Burns KT of the frozen account, the treasury assets backing it stay in the treasury.
+    #[payable]
+    pub fn seize_and_burn(&mut self, account_id: AccountId, amount: U128, reason: String) {
+        assert_one_yocto();
+        self.assert_owner_or_role(Role::Compliance);
+        require!(
+            self.compliance.is_frozen(&account_id),
+            "Only frozen accounts can be seized"
+        );
+        let price = self.token.cost_basis_of(&account_id);
+        self.token.internal_withdraw(&account_id, amount.0, price);
+
+        FtBurn {
+            owner_id: &account_id,
+            amount: &amount,
+            memo: Some("seized"),
+        }
+        .emit();
+        KtEvent::BalanceSeized {
+            account_id: &account_id,
+            compliance_id: &env::predecessor_account_id(),
+            amount: &amount,
+            reason: &reason,
+        }
+        .emit();
+    }
+
+    pub fn is_frozen(&self, account_id: AccountId) -> bool {
+        self.compliance.is_frozen(&account_id)
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_contract_standards::fungible_token::core::FungibleTokenCore;
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
+    use near_sdk::{testing_env, ONE_YOCTO};
+
+    use crate::oracle::ExchangePrice;
+    use crate::roles::Role;
+    use crate::test_utils;
+    use crate::{BuyOptions, Contract};
+
+    fn setup_contract(context: &mut VMContextBuilder) -> Contract {
+        let mut contract = test_utils::setup_contract_with_kt(context);
+        contract.grant_role(accounts(2), Role::Compliance);
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.freeze_account(accounts(1), "sanctions".to_string());
+        contract
+    }
+
+    #[test]
+    fn test_seize_and_burn() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        assert!(contract.is_frozen(accounts(1)));
+
+        testing_env!(context.attached_deposit(ONE_YOCTO).build());
+        contract.seize_and_burn(
+            accounts(1),
+            400_000_000_000_000_000.into(),
+            "court order".to_string(),
+        );
+        assert_eq!(
+            contract.ft_balance_of(accounts(1)).0,
+            600_000_000_000_000_000
+        );
+        assert_eq!(contract.ft_total_supply().0, 600_000_000_000_000_000);
+        assert!(get_logs()
+            .last()
+            .unwrap()
+            .contains(r#""event":"balance_seized""#));
+
+        contract.unfreeze_account(accounts(1), "resolved".to_string());
+        assert!(!contract.is_frozen(accounts(1)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Account bob is frozen")]
+    fn test_frozen_transfer() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        contract.ft_transfer(accounts(2), 1.into(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Account bob is frozen")]
+    fn test_frozen_buy() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        contract.internal_buy(
+            &accounts(1),
+            &accounts(3),
+            1_000_000,
+            6,
+            ExchangePrice::new(10000, 10),
+            &BuyOptions::default(),
+        );
+    }
+}
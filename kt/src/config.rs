@@ -0,0 +1,317 @@
+//! Contract-wide settings read and updated together. The values stay stored in
+//! the modules using them, the individual setters keep working.
+
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Deserializer, Serialize};
+use near_sdk::{near_bindgen, AccountId};
+use schemars::JsonSchema;
+
+use crate::claims::SellRefund;
+use crate::fees::FeeSplit;
+use crate::holding::HoldingFeeTier;
+use crate::incentives::KeeperIncentives;
+use crate::limits::Limits;
+use crate::{Contract, ContractExt};
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct Config {
+    pub oracle_id: AccountId,
+    /// Buy fee in basis points.
+    pub buy_fee: u16,
+    /// Share of the buy fee credited to referrers, in basis points.
+    pub referral_share: u16,
+    pub fee_split: FeeSplit,
+    pub holding_fee: Vec<HoldingFeeTier>,
+    pub sell_only: bool,
+    pub sell_refund: SellRefund,
+    /// Nanoseconds after a buy before the account can sell.
+    pub sell_cooldown: U64,
+    pub supply_cap: Option<U128>,
+    pub global_volume_limits: Limits,
+    pub default_volume_limits: Limits,
+    pub rebalance_tolerance: u16,
+    pub rebalance_incentive: u16,
+    pub keeper_incentives: KeeperIncentives,
+    pub dust_threshold: U128,
+    pub price_history_size: u16,
+    pub max_sell_share: u16,
+    pub account_storage_fee: U128,
+    /// Nanoseconds after which a pending operation can be recovered.
+    pub operation_timeout: U64,
+}
+
+/// Changes to the `Config`, the fields which aren't set are kept.
+#[derive(Serialize, Deserialize, JsonSchema, Default)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+#[serde(default)]
+pub struct ConfigPatch {
+    pub oracle_id: Option<AccountId>,
+    pub buy_fee: Option<u16>,
+    pub referral_share: Option<u16>,
+    pub fee_split: Option<FeeSplit>,
+    pub holding_fee: Option<Vec<HoldingFeeTier>>,
+    pub sell_only: Option<bool>,
+    pub sell_refund: Option<SellRefund>,
+    pub sell_cooldown: Option<U64>,
+    /// `null` removes the cap.
+    #[serde(deserialize_with = "deserialize_some")]
+    pub supply_cap: Option<Option<U128>>,
+    pub global_volume_limits: Option<Limits>,
+    pub default_volume_limits: Option<Limits>,
+    pub rebalance_tolerance: Option<u16>,
+    pub rebalance_incentive: Option<u16>,
+    pub keeper_incentives: Option<KeeperIncentives>,
+    pub dust_threshold: Option<U128>,
+    pub price_history_size: Option<u16>,
+    pub max_sell_share: Option<u16>,
+    pub account_storage_fee: Option<U128>,
+    pub operation_timeout: Option<U64>,
+}
+
+/// Tells a `null` value apart from a missing field.
+fn deserialize_some<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+impl Contract {
+    pub(crate) fn internal_update_config(&mut self, patch: ConfigPatch) {
+        if let Some(oracle_id) = patch.oracle_id {
+            self.internal_set_oracle(oracle_id);
+        }
+        if patch.buy_fee.is_some() || patch.referral_share.is_some() {
+            self.internal_set_buy_fee(
+                patch.buy_fee.unwrap_or_else(|| self.fees.buy_fee()),
+                patch
+                    .referral_share
+                    .unwrap_or_else(|| self.fees.referral_share()),
+            );
+        }
+        if let Some(split) = patch.fee_split {
+            self.internal_set_fee_split(split);
+        }
+        if let Some(tiers) = patch.holding_fee {
+            self.internal_set_holding_fee(tiers);
+        }
+        if let Some(enabled) = patch.sell_only {
+            self.internal_set_sell_only(enabled);
+        }
+        if let Some(sell_refund) = patch.sell_refund {
+            self.sell_refund = sell_refund;
+        }
+        if let Some(duration) = patch.sell_cooldown {
+            self.sell_cooldown.set_duration(duration.0);
+        }
+        if let Some(cap) = patch.supply_cap {
+            self.supply_cap = cap.map(Into::into);
+        }
+        if let Some(limits) = patch.global_volume_limits {
+            self.volume_limits.set_global_limits(limits);
+        }
+        if let Some(limits) = patch.default_volume_limits {
+            self.volume_limits.set_default_limits(limits);
+        }
+        if let Some(tolerance) = patch.rebalance_tolerance {
+            self.treasury.set_rebalance_tolerance(tolerance);
+        }
+        if let Some(incentive) = patch.rebalance_incentive {
+            self.treasury.set_rebalance_incentive(incentive);
+        }
+        if let Some(incentives) = patch.keeper_incentives {
+            self.internal_set_keeper_incentives(incentives);
+        }
+        if let Some(threshold) = patch.dust_threshold {
+            self.internal_set_dust_threshold(threshold.0);
+        }
+        if let Some(size) = patch.price_history_size {
+            self.price_history.set_size(size);
+        }
+        if let Some(share) = patch.max_sell_share {
+            self.internal_set_max_sell_share(share);
+        }
+        if let Some(fee) = patch.account_storage_fee {
+            self.account_storage_fee = fee.0;
+        }
+        if let Some(timeout) = patch.operation_timeout {
+            self.operations.set_timeout(timeout.0);
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn get_config(&self) -> Config {
+        Config {
+            oracle_id: self.oracle_id.clone(),
+            buy_fee: self.fees.buy_fee(),
+            referral_share: self.fees.referral_share(),
+            fee_split: self.fees.split().clone(),
+            holding_fee: self.holding_fee.tiers().to_vec(),
+            sell_only: self.sell_only,
+            sell_refund: self.sell_refund,
+            sell_cooldown: self.sell_cooldown.duration().into(),
+            supply_cap: self.supply_cap.map(Into::into),
+            global_volume_limits: self.volume_limits.global_limits(),
+            default_volume_limits: self.volume_limits.default_limits(),
+            rebalance_tolerance: self.treasury.rebalance_tolerance(),
+            rebalance_incentive: self.treasury.rebalance_incentive(),
+            keeper_incentives: self.keeper_incentives.clone(),
+            dust_threshold: self.dust_threshold.into(),
+            price_history_size: self.price_history.size(),
+            max_sell_share: self.max_sell_share,
+            account_storage_fee: self.account_storage_fee.into(),
+            operation_timeout: self.operations.timeout().into(),
+        }
+    }
+
+    /// Applies the set fields of the patch, all or none of them.
+    pub fn update_config(&mut self, patch: ConfigPatch) {
+        self.assert_owner();
+        self.internal_update_config(patch);
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_contract_standards::fungible_token::metadata::{
+        FungibleTokenMetadata, FT_METADATA_SPEC,
+    };
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{serde_json, testing_env, PromiseResult, RuntimeFeesConfig, VMConfig};
+
+    use crate::admin::{AdminAction, AdminResolver};
+    use crate::config::ConfigPatch;
+    use crate::Contract;
+
+    #[test]
+    fn test_new_with_assets_and_config() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(2_500)
+            .build());
+        let assets = vec![
+            (accounts(2), Some(6), 1_000.into()),
+            (accounts(3), None, 1_000.into()),
+        ];
+        let mut contract = Contract::new(
+            accounts(0),
+            accounts(4),
+            Some(assets.clone()),
+            Some(ConfigPatch {
+                buy_fee: Some(25),
+                sell_only: Some(true),
+                ..Default::default()
+            }),
+        );
+        // The assets are added once all of them are listed
+        assert!(contract.treasury.get(&accounts(2)).is_none());
+        let results = [6, 18]
+            .into_iter()
+            .flat_map(|decimals| {
+                let metadata = FungibleTokenMetadata {
+                    spec: FT_METADATA_SPEC.to_string(),
+                    name: "Token".to_string(),
+                    symbol: "TKN".to_string(),
+                    icon: None,
+                    reference: None,
+                    reference_hash: None,
+                    decimals,
+                };
+                [
+                    PromiseResult::Successful(serde_json::to_vec(&metadata).unwrap()),
+                    PromiseResult::Successful(b"{}".to_vec()),
+                ]
+            })
+            .collect();
+        testing_env!(
+            context.attached_deposit(0).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            results,
+        );
+        let actions = assets
+            .into_iter()
+            .map(
+                |(asset_id, decimals, storage_deposit)| AdminAction::AddAsset {
+                    asset_id,
+                    decimals,
+                    price_decimals: None,
+                    storage_deposit,
+                },
+            )
+            .collect();
+        contract.resolve_admin_batch(actions);
+        assert_eq!(contract.treasury.assert_asset(&accounts(2)).decimals, 6);
+        assert_eq!(contract.treasury.assert_asset(&accounts(3)).decimals, 18);
+        let config = contract.get_config();
+        assert_eq!(config.buy_fee, 25);
+        assert!(config.sell_only);
+    }
+
+    #[test]
+    #[should_panic(expected = "Attached deposit doesn't cover the storage deposits")]
+    fn test_new_with_assets_storage_deposit() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1_000)
+            .build());
+        Contract::new(
+            accounts(0),
+            accounts(4),
+            Some(vec![
+                (accounts(2), Some(6), 1_000.into()),
+                (accounts(3), None, 1_000.into()),
+            ]),
+            None,
+        );
+    }
+
+    #[test]
+    fn test_update_config() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.set_supply_cap(Some(1_000.into()));
+
+        let patch: ConfigPatch = near_sdk::serde_json::from_str(
+            r#"{"buy_fee": 30, "sell_only": true, "supply_cap": null}"#,
+        )
+        .unwrap();
+        contract.update_config(patch);
+        let config = contract.get_config();
+        assert_eq!(config.buy_fee, 30);
+        assert!(config.sell_only);
+        assert!(config.supply_cap.is_none());
+        assert_eq!(config.oracle_id, accounts(4));
+
+        // Missing fields are kept
+        contract.update_config(ConfigPatch {
+            dust_threshold: Some(10.into()),
+            ..Default::default()
+        });
+        let config = contract.get_config();
+        assert_eq!(config.buy_fee, 30);
+        assert_eq!(config.dust_threshold.0, 10);
+    }
+
+    #[test]
+    #[should_panic(expected = "Dust threshold exceeds 10000000000000000")]
+    fn test_update_config_dust_threshold_cap() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.update_config(ConfigPatch {
+            dust_threshold: Some(10_000_000_000_000_001.into()),
+            ..Default::default()
+        });
+    }
+}
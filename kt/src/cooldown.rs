@@ -0,0 +1,202 @@
+//! Cooldown between buying and selling KT, so bots can't round-trip through lagging
+//! oracle prices. Transferred KT passes the cooldown of the sender on to the receiver,
+//! weighted by the received amount.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::json_types::U64;
+use near_sdk::{env, near_bindgen, AccountId, Balance, IntoStorageKey};
+
+use crate::{Contract, ContractExt};
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct SellCooldown {
+    /// Nanoseconds after the last buy before the account can sell, disabled if zero.
+    duration: u64,
+    /// AccountID -> Timestamp of the last buy.
+    last_buys: LookupMap<AccountId, u64>,
+}
+
+impl SellCooldown {
+    pub fn new<S>(prefix: S) -> Self
+    where
+        S: IntoStorageKey,
+    {
+        Self {
+            duration: 0,
+            last_buys: LookupMap::new(prefix),
+        }
+    }
+
+    pub fn duration(&self) -> u64 {
+        self.duration
+    }
+
+    pub fn set_duration(&mut self, duration: u64) {
+        self.duration = duration;
+    }
+
+    /// Time the account can sell again, `None` if it's not in the cooldown.
+    pub fn unlocks_at(&self, account_id: &AccountId) -> Option<u64> {
+        if self.duration == 0 {
+            return None;
+        }
+        let unlocks_at = self
+            .last_buys
+            .get(account_id)?
+            .saturating_add(self.duration);
+        (env::block_timestamp() < unlocks_at).then_some(unlocks_at)
+    }
+
+    pub fn record_buy(&mut self, account_id: &AccountId) {
+        if self.duration > 0 {
+            self.last_buys.insert(account_id, &env::block_timestamp());
+        }
+    }
+
+    /// Moves the last buy of the receiver `balance` towards the one of the sender,
+    /// weighted by the received `amount`, so dust transfers can't lock the receiver.
+    pub fn inherit(
+        &mut self,
+        sender_id: &AccountId,
+        receiver_id: &AccountId,
+        balance: Balance,
+        amount: Balance,
+    ) {
+        if self.unlocks_at(sender_id).is_none() || amount == 0 {
+            return;
+        }
+        let last_buy = self.last_buys.get(sender_id).unwrap_or_default();
+        // Untracked balances count as bought long ago
+        let receiver_buy = self.last_buys.get(receiver_id).unwrap_or_default();
+        if receiver_buy >= last_buy {
+            return;
+        }
+        let total = balance.saturating_add(amount);
+        let diff = u128::from(last_buy - receiver_buy);
+        let shift = match diff.checked_mul(amount) {
+            Some(value) => value / total,
+            None => diff * (amount >> 64) / (total >> 64),
+        } as u64;
+        self.last_buys.insert(receiver_id, &(receiver_buy + shift));
+    }
+
+    pub fn assert_elapsed(&self, account_id: &AccountId) {
+        if let Some(unlocks_at) = self.unlocks_at(account_id) {
+            env::panic_str(
+                format!("Sells are locked until {} after the last buy", unlocks_at).as_str(),
+            )
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Sets the nanoseconds after a buy before the account can sell, zero disables it.
+    pub fn set_sell_cooldown(&mut self, duration: U64) {
+        self.assert_owner();
+        self.sell_cooldown.set_duration(duration.0);
+    }
+
+    pub fn get_sell_cooldown(&self) -> U64 {
+        self.sell_cooldown.duration().into()
+    }
+
+    /// Time the account can sell again, `None` if it's not in the cooldown.
+    pub fn get_sell_unlocks_at(&self, account_id: AccountId) -> Option<U64> {
+        self.sell_cooldown.unlocks_at(&account_id).map(Into::into)
+    }
+}
+
+impl Contract {
+    /// Passes the sell cooldown and the acquisition time of the sent KT on to the receiver,
+    /// before the receiver is credited.
+    pub(crate) fn internal_pass_on_transferred(
+        &mut self,
+        sender_id: &AccountId,
+        receiver_id: &AccountId,
+        amount: Balance,
+    ) {
+        let balance = self.token.internal_unwrap_balance_of(receiver_id).amount;
+        self.sell_cooldown
+            .inherit(sender_id, receiver_id, balance, amount);
+        self.internal_transfer_acquired(sender_id, receiver_id, amount);
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_contract_standards::fungible_token::core::FungibleTokenCore;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, ONE_YOCTO};
+
+    use crate::oracle::ExchangePrice;
+    use crate::test_utils;
+    use crate::Contract;
+
+    const COOLDOWN: u64 = 300_000_000_000;
+
+    fn setup_contract(context: &mut VMContextBuilder) -> Contract {
+        context.block_timestamp(1_000);
+        let mut contract = test_utils::setup_contract(context);
+        contract.set_sell_cooldown(COOLDOWN.into());
+        test_utils::buy_kt(&mut contract, &accounts(1));
+        contract
+    }
+
+    #[test]
+    #[should_panic(expected = "Sells are locked until 300000001000 after the last buy")]
+    fn test_sell_in_cooldown() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        let price = ExchangePrice::new(10000, 10);
+        contract.internal_sell(&accounts(1), &accounts(3), 1_000, 6, price, None);
+    }
+
+    #[test]
+    fn test_sell_after_cooldown() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        contract.ft_transfer(accounts(2), 1_000.into(), None);
+        assert_eq!(
+            contract.get_sell_unlocks_at(accounts(2)),
+            Some((COOLDOWN + 1_000).into())
+        );
+
+        testing_env!(context.block_timestamp(COOLDOWN + 1_000).build());
+        let price = ExchangePrice::new(10000, 10);
+        contract.internal_sell(
+            &accounts(1),
+            &accounts(3),
+            1_000_000_000_000,
+            6,
+            price,
+            None,
+        );
+        assert!(contract.get_sell_unlocks_at(accounts(2)).is_none());
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 1_000);
+    }
+
+    #[test]
+    fn test_cooldown_weighted_on_transfer() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        contract
+            .token
+            .internal_deposit(&accounts(2), 999_000, 1_000_000);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        contract.ft_transfer(accounts(2), 1_000.into(), None);
+        // The received 0.1% moves the last buy a thousandth of the way
+        assert_eq!(
+            contract.get_sell_unlocks_at(accounts(2)),
+            Some((COOLDOWN + 1).into())
+        );
+    }
+}
@@ -0,0 +1,351 @@
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{
+    env, ext_contract, log, near_bindgen, require, AccountId, Balance, Promise, PromiseOrValue,
+    ONE_YOCTO,
+};
+use schemars::JsonSchema;
+
+use crate::events::KtEvent;
+use crate::price::exchange_asset_to_asset;
+use crate::roles::Role;
+use crate::treasury::AssetId;
+use crate::{ext_ft_transfer, promise_result_u128, Contract, ContractExt, BASIS_POINTS};
+
+/// Maximum shortfall of a DEX swap from the cached oracle prices, in basis points.
+const MAX_DEX_SLIPPAGE: u16 = 200;
+
+// From https://github.com/ref-finance/ref-contracts/blob/main/ref-exchange/src/action.rs
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapAction {
+    pub pool_id: u64,
+    pub token_in: AccountId,
+    pub amount_in: Option<U128>,
+    pub token_out: AccountId,
+    pub min_amount_out: U128,
+}
+
+/// Ref Finance exchange interface.
+/// NOTE: the KT contract must be registered on the exchange, including the traded tokens.
+#[ext_contract(ext_ref_exchange)]
+#[allow(dead_code)]
+pub trait RefExchange {
+    fn swap(&mut self, actions: Vec<SwapAction>, referral_id: Option<AccountId>) -> U128;
+    /// Returns the withdrawn amount, zero if the token transfer failed and the amount
+    /// is kept on the account.
+    fn withdraw(&mut self, token_id: AccountId, amount: U128, unregister: Option<bool>) -> U128;
+}
+
+impl Contract {
+    fn assert_dex(&self) -> AccountId {
+        self.dex_id
+            .clone()
+            .unwrap_or_else(|| env::panic_str("DEX is not configured"))
+    }
+
+    fn dex_withdraw_promise(&self, asset_id: AssetId, amount: U128) -> Promise {
+        ext_ref_exchange::ext(self.assert_dex())
+            .with_static_gas(self.gas.dex_withdraw)
+            .with_attached_deposit(ONE_YOCTO)
+            .withdraw(asset_id.clone(), amount, None)
+            .then(
+                ext_dex_resolver::ext(env::current_account_id())
+                    .with_static_gas(self.gas.resolve_dex_withdraw)
+                    .resolve_dex_withdraw(asset_id, amount),
+            )
+    }
+
+    /// Lowest amount of `asset_to` accepted for the amount of `asset_from`, valued at
+    /// the cached oracle prices less the maximum slippage.
+    fn dex_min_out(&self, asset_from: &AssetId, asset_to: &AssetId, amount: Balance) -> Balance {
+        let from = self.treasury.assert_asset(asset_from);
+        let to = self.treasury.assert_asset(asset_to);
+        let (Some(price_from), Some(price_to)) = (from.price, to.price) else {
+            env::panic_str("The asset price is not cached")
+        };
+        let bps = Balance::from(BASIS_POINTS);
+        exchange_asset_to_asset(
+            amount,
+            from.decimals,
+            price_from.price,
+            to.decimals,
+            price_to.price,
+        )
+        .and_then(|amount_out| amount_out.checked_mul(bps - Balance::from(MAX_DEX_SLIPPAGE)))
+        .map(|amount_out| amount_out / bps)
+        .unwrap_or_else(|| env::panic_str("Exchange amount overflow"))
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn set_dex(&mut self, dex_id: Option<AccountId>) {
+        self.assert_owner();
+        self.dex_id = dex_id;
+    }
+
+    pub fn get_dex(&self) -> Option<AccountId> {
+        self.dex_id.clone()
+    }
+
+    /// Swaps treasury assets through the DEX pool.
+    /// Funds are deposited to the DEX, swapped and withdrawn back to the treasury,
+    /// on failure the deposited funds are withdrawn back instead. `min_out` can't be below
+    /// the oracle value of the amount less the maximum slippage.
+    pub fn rebalance_via_dex(
+        &mut self,
+        asset_from: AssetId,
+        asset_to: AssetId,
+        amount: U128,
+        min_out: U128,
+        pool_id: u64,
+    ) -> Promise {
+        self.assert_owner_or_role(Role::Keeper);
+        require!(
+            env::prepaid_gas() > self.gas.rebalance_via_dex(),
+            "More gas is required"
+        );
+        let dex_id = self.assert_dex();
+        require!(
+            asset_from != asset_to,
+            "Rebalance assets should be different"
+        );
+        let floor = self.dex_min_out(&asset_from, &asset_to, amount.0);
+        require!(
+            min_out.0 >= floor,
+            format!("Minimum amount out is below the oracle floor of {}", floor)
+        );
+
+        // Funds in flight are not part of the treasury until they are withdrawn back
+        self.treasury.internal_withdraw(&asset_from, amount.into());
+
+        ext_ft_transfer::ext(asset_from.clone())
+            .with_static_gas(self.gas.dex_deposit)
+            .with_attached_deposit(ONE_YOCTO)
+            .ft_transfer_call(dex_id, amount, None, String::new())
+            .then(
+                ext_dex_resolver::ext(env::current_account_id())
+                    .with_static_gas(self.gas.resolve_dex_deposit())
+                    .resolve_dex_deposit(asset_from, asset_to, amount, min_out, pool_id),
+            )
+    }
+
+    /// Withdraws funds left on the DEX account, e.g. after a failed withdrawal.
+    pub fn dex_withdraw(&mut self, asset_id: AssetId, amount: U128) -> Promise {
+        self.assert_owner_or_role(Role::Keeper);
+        self.treasury.assert_asset(&asset_id);
+        self.dex_withdraw_promise(asset_id, amount)
+    }
+}
+
+#[ext_contract(ext_dex_resolver)]
+#[allow(dead_code)]
+pub trait DexResolver {
+    fn resolve_dex_deposit(
+        &mut self,
+        asset_from: AssetId,
+        asset_to: AssetId,
+        amount: U128,
+        min_out: U128,
+        pool_id: u64,
+    ) -> PromiseOrValue<U128>;
+    fn resolve_dex_swap(
+        &mut self,
+        asset_from: AssetId,
+        asset_to: AssetId,
+        amount: U128,
+    ) -> PromiseOrValue<U128>;
+    fn resolve_dex_withdraw(&mut self, asset_id: AssetId, amount: U128) -> U128;
+}
+
+#[near_bindgen]
+impl DexResolver for Contract {
+    #[private]
+    fn resolve_dex_deposit(
+        &mut self,
+        asset_from: AssetId,
+        asset_to: AssetId,
+        amount: U128,
+        min_out: U128,
+        pool_id: u64,
+    ) -> PromiseOrValue<U128> {
+        // `ft_transfer_call` returns the used amount, the rest is refunded by the asset
+        let used_amount = promise_result_u128().unwrap_or(0).min(amount.0);
+        let refund_amount = amount.0 - used_amount;
+        if refund_amount > 0 {
+            self.treasury.internal_deposit(&asset_from, refund_amount);
+        }
+        if used_amount == 0 {
+            log!("DEX deposit of {} {} failed", amount.0, asset_from);
+            return PromiseOrValue::Value(U128(0));
+        }
+
+        let action = SwapAction {
+            pool_id,
+            token_in: asset_from.clone(),
+            amount_in: Some(used_amount.into()),
+            token_out: asset_to.clone(),
+            min_amount_out: min_out,
+        };
+        ext_ref_exchange::ext(self.assert_dex())
+            .with_static_gas(self.gas.dex_swap)
+            .with_attached_deposit(ONE_YOCTO)
+            .swap(vec![action], None)
+            .then(
+                ext_dex_resolver::ext(env::current_account_id())
+                    .with_static_gas(self.gas.resolve_dex_swap())
+                    .resolve_dex_swap(asset_from, asset_to, used_amount.into()),
+            )
+            .into()
+    }
+
+    #[private]
+    fn resolve_dex_swap(
+        &mut self,
+        asset_from: AssetId,
+        asset_to: AssetId,
+        amount: U128,
+    ) -> PromiseOrValue<U128> {
+        match promise_result_u128() {
+            Some(amount_out) => {
+                KtEvent::DexSwap {
+                    asset_in: &asset_from,
+                    amount_in: &amount,
+                    asset_out: &asset_to,
+                    amount_out: &amount_out.into(),
+                }
+                .emit();
+                self.dex_withdraw_promise(asset_to, amount_out.into())
+                    .into()
+            }
+            None => {
+                // Rollback: the deposit is still on the DEX account
+                log!("DEX swap of {} {} failed", amount.0, asset_from);
+                self.dex_withdraw_promise(asset_from, amount).into()
+            }
+        }
+    }
+
+    /// Returns the amount deposited back to the treasury, only the amount the DEX
+    /// reports as withdrawn is credited.
+    #[private]
+    fn resolve_dex_withdraw(&mut self, asset_id: AssetId, amount: U128) -> U128 {
+        let withdrawn = promise_result_u128().map_or(0, |withdrawn| withdrawn.min(amount.0));
+        if withdrawn > 0 {
+            self.treasury.internal_deposit(&asset_id, withdrawn);
+        }
+        if withdrawn < amount.0 {
+            // The DEX keeps the funds on the account, so they can be withdrawn later
+            log!(
+                "DEX withdrawal of {} {} failed",
+                amount.0 - withdrawn,
+                asset_id
+            );
+        }
+        withdrawn.into()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, Gas, PromiseResult, RuntimeFeesConfig, VMConfig};
+
+    use crate::dex::DexResolver;
+    use crate::oracle::ExchangePrice;
+    use crate::roles::Role;
+    use crate::Contract;
+
+    fn setup_contract(context: &mut VMContextBuilder) -> Contract {
+        context
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(0))
+            .prepaid_gas(Gas(300_000_000_000_000));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(1), 6);
+        contract.internal_add_asset(&accounts(2), 6);
+        contract.treasury.internal_deposit(&accounts(1), 1_000_000);
+        let price = ExchangePrice::new(10000, 10);
+        contract.treasury.set_asset_price(&accounts(1), price);
+        contract.treasury.set_asset_price(&accounts(2), price);
+        contract
+    }
+
+    #[test]
+    fn test_rebalance_via_dex() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        contract.set_dex(Some(accounts(5)));
+        contract.grant_role(accounts(3), Role::Keeper);
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.rebalance_via_dex(accounts(1), accounts(2), 400_000.into(), 392_000.into(), 0);
+        assert_eq!(
+            contract.treasury.assert_asset(&accounts(1)).balance,
+            600_000
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Minimum amount out is below")]
+    fn test_rebalance_via_dex_oracle_floor() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        contract.set_dex(Some(accounts(5)));
+        // 400_000 at the oracle prices less 2% slippage
+        contract.rebalance_via_dex(accounts(1), accounts(2), 400_000.into(), 391_999.into(), 0);
+    }
+
+    #[test]
+    fn test_resolve_dex_withdraw() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+
+        testing_env!(
+            context.build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(b"\"0\"".to_vec())],
+        );
+        assert_eq!(
+            contract.resolve_dex_withdraw(accounts(2), 1_000.into()).0,
+            0
+        );
+        assert_eq!(contract.treasury.assert_asset(&accounts(2)).balance, 0);
+
+        testing_env!(
+            context.build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(b"\"1000\"".to_vec())],
+        );
+        assert_eq!(
+            contract.resolve_dex_withdraw(accounts(2), 1_000.into()).0,
+            1_000
+        );
+        assert_eq!(contract.treasury.assert_asset(&accounts(2)).balance, 1_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "DEX is not configured")]
+    fn test_rebalance_via_dex_not_configured() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        contract.rebalance_via_dex(accounts(1), accounts(2), 400_000.into(), 392_000.into(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Account danny is not a Keeper")]
+    fn test_rebalance_via_dex_not_keeper() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        contract.set_dex(Some(accounts(5)));
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.rebalance_via_dex(accounts(1), accounts(2), 400_000.into(), 392_000.into(), 0);
+    }
+}
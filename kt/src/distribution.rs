@@ -0,0 +1,227 @@
+use near_contract_standards::fungible_token::events::FtMint;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::json_types::U128;
+use near_sdk::{env, near_bindgen, require, AccountId, Balance, IntoStorageKey};
+
+use crate::events::KtEvent;
+use crate::{Contract, ContractExt};
+
+/// Precision of the accumulated reward per KT.
+const REWARD_PRECISION: u128 = 1_000_000_000_000;
+
+/// `amount * numerator / REWARD_PRECISION` without overflowing on large balances.
+fn mul_div_precision(amount: Balance, numerator: u128) -> Balance {
+    match amount.checked_mul(numerator) {
+        Some(value) => value / REWARD_PRECISION,
+        None => (amount / REWARD_PRECISION).saturating_mul(numerator),
+    }
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Default)]
+pub struct AccountRewards {
+    /// Reward per KT at the last checkpoint.
+    paid_per_token: u128,
+    /// Rewards accrued until the last checkpoint.
+    accrued: Balance,
+}
+
+/// Claimable rewards ledger, distributed pro-rata to the KT balances.
+/// Every balance change must be preceded by a checkpoint of the old balance.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Rewards {
+    /// AccountID -> Account rewards.
+    accounts: LookupMap<AccountId, AccountRewards>,
+    /// Accumulated reward per KT, scaled by the reward precision.
+    reward_per_token: u128,
+    /// Distributed rewards which are not claimed yet.
+    unclaimed: Balance,
+}
+
+impl Rewards {
+    pub fn new<S>(prefix: S) -> Self
+    where
+        S: IntoStorageKey,
+    {
+        Self {
+            accounts: LookupMap::new(prefix),
+            reward_per_token: 0,
+            unclaimed: 0,
+        }
+    }
+
+    pub fn unclaimed(&self) -> Balance {
+        self.unclaimed
+    }
+
+    /// Rewards accrued by the account, including the ones since the last checkpoint.
+    pub fn accrued(&self, account_id: &AccountId, balance: Balance) -> Balance {
+        let rewards = self.accounts.get(account_id).unwrap_or_default();
+        let pending = mul_div_precision(balance, self.reward_per_token - rewards.paid_per_token);
+        rewards.accrued.saturating_add(pending)
+    }
+
+    /// Accrues the rewards of the balance held until now.
+    pub fn checkpoint(&mut self, account_id: &AccountId, balance: Balance) {
+        let rewards = self.accounts.get(account_id).unwrap_or_default();
+        if rewards.paid_per_token == self.reward_per_token {
+            return;
+        }
+        let rewards = AccountRewards {
+            accrued: self.accrued(account_id, balance),
+            paid_per_token: self.reward_per_token,
+        };
+        self.accounts.insert(account_id, &rewards);
+    }
+
+    pub fn distribute(&mut self, amount: Balance, total_supply: Balance) {
+        require!(amount > 0, "The amount should be a positive number");
+        require!(total_supply > 0, "There are no holders to distribute to");
+        let increase = amount
+            .checked_mul(REWARD_PRECISION)
+            .map(|value| value / total_supply)
+            .unwrap_or_else(|| env::panic_str("Rewards amount overflow"));
+        require!(increase > 0, "Rewards amount is too small");
+        self.reward_per_token = self
+            .reward_per_token
+            .checked_add(increase)
+            .unwrap_or_else(|| env::panic_str("Rewards amount overflow"));
+        self.unclaimed = self.unclaimed.saturating_add(amount);
+    }
+
+    /// Moves the accrued rewards between checkpointed accounts.
+    pub fn move_accrued(&mut self, from_id: &AccountId, to_id: &AccountId) {
+        let mut from = self.accounts.get(from_id).unwrap_or_default();
+        let mut to = self.accounts.get(to_id).unwrap_or_default();
+        to.accrued = to.accrued.saturating_add(from.accrued);
+        from.accrued = 0;
+        self.accounts.insert(from_id, &from);
+        self.accounts.insert(to_id, &to);
+    }
+
+    /// Forfeits the accrued rewards of the removed account, returns their amount.
+    pub fn remove(&mut self, account_id: &AccountId, balance: Balance) -> Balance {
+        let amount = self.accrued(account_id, balance);
+        self.accounts.remove(account_id);
+        self.unclaimed = self.unclaimed.saturating_sub(amount);
+        amount
+    }
+
+    /// Resets the accrued rewards of the account and returns their amount.
+    pub fn claim(&mut self, account_id: &AccountId, balance: Balance) -> Balance {
+        let amount = self.accrued(account_id, balance);
+        self.accounts.insert(
+            account_id,
+            &AccountRewards {
+                paid_per_token: self.reward_per_token,
+                accrued: 0,
+            },
+        );
+        // Rounding can make the accrued sum slightly differ from the distributed amount
+        self.unclaimed = self.unclaimed.saturating_sub(amount);
+        amount
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Distributes KT pro-rata to the holders, minted when claimed.
+    /// The amount should be backed by the treasury surplus, e.g. harvested yield or fees.
+    pub fn distribute_rewards(&mut self, amount: U128) {
+        self.assert_owner();
+        self.token.internal_distribute_rewards(amount.into());
+        KtEvent::RewardsDistributed { amount: &amount }.emit();
+    }
+
+    /// Mints the accrued rewards to the caller.
+    pub fn claim_rewards(&mut self) -> U128 {
+        let account_id = env::predecessor_account_id();
+        let amount = self.token.internal_claim_rewards(&account_id);
+        require!(amount > 0, "No rewards to claim");
+
+        // Rewards have no cost basis
+        self.token.internal_deposit(&account_id, amount, 0);
+        FtMint {
+            owner_id: &account_id,
+            amount: &amount.into(),
+            memo: Some("rewards"),
+        }
+        .emit();
+
+        amount.into()
+    }
+
+    pub fn get_accrued_rewards(&self, account_id: AccountId) -> U128 {
+        self.token.accrued_rewards(&account_id).into()
+    }
+
+    pub fn get_unclaimed_rewards(&self) -> U128 {
+        self.token.unclaimed_rewards().into()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_contract_standards::fungible_token::core::FungibleTokenCore;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, IntoStorageKey, ONE_YOCTO};
+
+    use crate::distribution::Rewards;
+    use crate::{Contract, StorageKey};
+
+    #[test]
+    fn test_rewards() {
+        // Stored under the same key as the rewards of the token
+        let prefix = StorageKey::FungibleToken.into_storage_key();
+        let mut rewards = Rewards::new([prefix, b"r".to_vec()].concat());
+        rewards.distribute(300, 1_000);
+        assert_eq!(rewards.accrued(&accounts(1), 250), 75);
+        assert_eq!(rewards.accrued(&accounts(2), 750), 225);
+
+        // The balance of bob doubles after the checkpoint
+        rewards.checkpoint(&accounts(1), 250);
+        rewards.distribute(300, 1_250);
+        assert_eq!(rewards.accrued(&accounts(1), 500), 75 + 120);
+
+        assert_eq!(rewards.claim(&accounts(1), 500), 195);
+        assert_eq!(rewards.accrued(&accounts(1), 500), 0);
+        assert_eq!(rewards.unclaimed(), 600 - 195);
+    }
+
+    #[test]
+    fn test_claim_rewards() {
+        let mut context = VMContextBuilder::new();
+        context
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.token.internal_deposit(&accounts(1), 1_000, 0);
+        contract.token.internal_deposit(&accounts(2), 3_000, 0);
+        // The contract's own KT, e.g. stakes, doesn't dilute the holders
+        contract.token.internal_deposit(&accounts(0), 4_000, 0);
+        contract.distribute_rewards(400.into());
+
+        // Transfers don't move the accrued rewards
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        contract.ft_transfer(accounts(2), 1_000.into(), None);
+        assert_eq!(contract.get_accrued_rewards(accounts(1)).0, 100);
+        assert_eq!(contract.get_accrued_rewards(accounts(2)).0, 300);
+
+        assert_eq!(contract.claim_rewards().0, 100);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 100);
+        assert_eq!(contract.get_unclaimed_rewards().0, 300);
+    }
+
+    #[test]
+    #[should_panic(expected = "No rewards to claim")]
+    fn test_claim_no_rewards() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.claim_rewards();
+    }
+}
@@ -0,0 +1,168 @@
+//! Sweeping of KT balances too small to be sold, so the account map doesn't bloat
+//! with unusable crumbs. The pending rewards are minted before the sweep, and the share
+//! of the treasury assets backing the burned KT moves to the insurance fund.
+
+use near_contract_standards::fungible_token::core::FungibleTokenCore;
+use near_contract_standards::fungible_token::events::{FtBurn, FtMint};
+use near_sdk::json_types::U128;
+use near_sdk::{env, near_bindgen, require, AccountId, Balance};
+
+use crate::events::KtEvent;
+use crate::operations::OperationKind;
+use crate::treasury::InsuranceSource;
+use crate::{Contract, ContractExt};
+
+/// Maximum dust threshold, 0.01 KT.
+const MAX_DUST_THRESHOLD: Balance = 10_000_000_000_000_000;
+
+impl Contract {
+    /// Unregisters the account if its balance with the pending rewards is positive and
+    /// below the dust threshold, returns the burned amount.
+    fn internal_sweep_dust(&mut self, account_id: &AccountId) -> Balance {
+        let balance = self.token.internal_unwrap_balance_of(account_id).amount;
+        let rewards = self.token.accrued_rewards(account_id);
+        if balance + rewards == 0
+            || balance + rewards >= self.dust_threshold
+            || *account_id == env::current_account_id()
+            || self.compliance.is_frozen(account_id)
+            || self.operations.has_pending(account_id, OperationKind::Sell)
+        {
+            return 0;
+        }
+        let rewards = self.token.internal_claim_rewards(account_id);
+        if rewards > 0 {
+            self.token.internal_deposit(account_id, rewards, 0);
+            FtMint {
+                owner_id: account_id,
+                amount: &rewards.into(),
+                memo: Some("rewards"),
+            }
+            .emit();
+        }
+        let supply = self.token.ft_total_supply().0;
+        let burned = self
+            .token
+            .internal_unregister(account_id, true)
+            .unwrap_or_default();
+        self.internal_insure_backing(burned, supply);
+
+        FtBurn {
+            owner_id: account_id,
+            amount: &burned.into(),
+            memo: Some("dust"),
+        }
+        .emit();
+        KtEvent::AccountUnregistered {
+            account_id,
+            burned: &burned.into(),
+        }
+        .emit();
+        burned
+    }
+
+    /// Moves the share of every asset backing the KT amount out of the supply
+    /// to the insurance fund.
+    pub(crate) fn internal_insure_backing(&mut self, amount: Balance, supply: Balance) {
+        if amount == 0 {
+            return;
+        }
+        for (asset_id, asset) in self.treasury.supported_assets() {
+            let share = match asset.balance.checked_mul(amount) {
+                Some(value) => value / supply,
+                None => asset.balance / supply * amount,
+            };
+            self.internal_fund_insurance(&asset_id, share, InsuranceSource::Dust);
+        }
+    }
+
+    pub(crate) fn internal_set_dust_threshold(&mut self, threshold: Balance) {
+        require!(
+            threshold <= MAX_DUST_THRESHOLD,
+            format!("Dust threshold exceeds {}", MAX_DUST_THRESHOLD)
+        );
+        self.dust_threshold = threshold;
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Sets the KT balance below which the accounts can be swept, zero disables sweeping.
+    pub fn set_dust_threshold(&mut self, threshold: U128) {
+        self.assert_owner();
+        self.internal_set_dust_threshold(threshold.0);
+    }
+
+    pub fn get_dust_threshold(&self) -> U128 {
+        self.dust_threshold.into()
+    }
+
+    /// Burns the dust balances of the accounts and frees their storage, the accounts
+    /// which are frozen or have a pending sell are skipped. Returns the burned amount.
+    pub fn sweep_dust(&mut self, account_ids: Vec<AccountId>) -> U128 {
+        account_ids
+            .iter()
+            .map(|account_id| self.internal_sweep_dust(account_id))
+            .fold(0, Balance::saturating_add)
+            .into()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_contract_standards::fungible_token::core::FungibleTokenCore;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    use crate::Contract;
+
+    #[test]
+    #[should_panic(expected = "Dust threshold exceeds 10000000000000000")]
+    fn test_dust_threshold_cap() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.set_dust_threshold(10_000_000_000_000_001.into());
+    }
+
+    #[test]
+    fn test_sweep_dust_insures_backing() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(3), 6);
+        contract.treasury.internal_deposit(&accounts(3), 1_000);
+        contract.token.internal_deposit(&accounts(1), 10, 1);
+        contract.token.internal_deposit(&accounts(2), 90, 1);
+        contract.distribute_rewards(10.into());
+        contract.set_dust_threshold(100.into());
+
+        // The pending reward of 1 is minted and swept with the balance.
+        assert_eq!(contract.sweep_dust(vec![accounts(1)]).0, 11);
+        let asset = contract.treasury.assert_asset(&accounts(3));
+        // 11 of the supply of 101 KT
+        assert_eq!(asset.insurance, 108);
+        assert_eq!(asset.balance, 892);
+    }
+
+    #[test]
+    fn test_sweep_dust() {
+        let mut context = VMContextBuilder::new();
+        context
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.token.internal_deposit(&accounts(1), 99, 1);
+        contract.token.internal_deposit(&accounts(2), 100, 1);
+        contract.token.internal_deposit(&accounts(3), 10, 1);
+        contract.compliance.freeze(&accounts(3));
+
+        assert_eq!(contract.sweep_dust(vec![accounts(1)]).0, 0);
+        contract.set_dust_threshold(100.into());
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        let swept = contract.sweep_dust(vec![accounts(1), accounts(2), accounts(3), accounts(5)]);
+        assert_eq!(swept.0, 99);
+        assert_eq!(contract.ft_total_supply().0, 110);
+        assert_eq!(contract.get_holder_count().0, 2);
+    }
+}
@@ -0,0 +1,314 @@
+//! Escrowed KT transfers, e.g. for OTC deals.
+//!
+//! The sender locks KT for the receiver, which gets it once the sender confirms the deal.
+//! The receiver can refund the escrow anytime, and anyone can refund it once expired.
+//! The optional resolver of the escrow arbitrates disputes by releasing or refunding it.
+//! The sender pays the storage of the escrow, which is returned once it's settled.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{assert_one_yocto, env, near_bindgen, require, AccountId};
+use schemars::JsonSchema;
+
+use crate::events::{KtEvent, RefundOperation, RefundReason};
+use crate::records::{Parties, RecordId, Records};
+use crate::{Contract, ContractExt};
+
+pub type EscrowId = RecordId;
+pub type Escrows = Records<Escrow>;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct Escrow {
+    pub sender_id: AccountId,
+    pub receiver_id: AccountId,
+    pub amount: U128,
+    /// Cost basis of the escrowed KT.
+    pub cost_price: U128,
+    pub expires_at: U64,
+    pub resolver_id: Option<AccountId>,
+    /// NEAR paid by the sender for the storage.
+    pub storage_deposit: U128,
+}
+
+impl Escrow {
+    pub fn is_expired(&self) -> bool {
+        env::block_timestamp() >= self.expires_at.0
+    }
+
+    pub fn is_resolver(&self, account_id: &AccountId) -> bool {
+        self.resolver_id.as_ref() == Some(account_id)
+    }
+}
+
+impl Parties for Escrow {
+    fn parties(&self) -> [&AccountId; 2] {
+        [&self.sender_id, &self.receiver_id]
+    }
+}
+
+impl Escrows {
+    pub fn assert_escrow(&self, id: EscrowId) -> Escrow {
+        self.get(id)
+            .unwrap_or_else(|| env::panic_str(format!("Escrow {} is not found", id).as_str()))
+    }
+}
+
+impl Contract {
+    fn internal_release_escrow(&mut self, id: EscrowId, escrow: &Escrow) {
+        self.internal_before_transfer(&escrow.sender_id, &escrow.receiver_id, escrow.amount.0);
+        self.escrows.remove(id);
+        self.internal_refund_storage(&escrow.sender_id, escrow.storage_deposit.0);
+        self.token.internal_transfer_at(
+            &env::current_account_id(),
+            &escrow.receiver_id,
+            escrow.amount.0,
+            escrow.cost_price.0,
+            Some("escrow release".to_string()),
+        );
+        KtEvent::EscrowReleased {
+            id,
+            receiver_id: &escrow.receiver_id,
+            amount: &escrow.amount,
+        }
+        .emit();
+    }
+
+    fn internal_refund_escrow(&mut self, id: EscrowId, escrow: &Escrow) {
+        self.escrows.remove(id);
+        self.internal_refund_storage(&escrow.sender_id, escrow.storage_deposit.0);
+        self.token.internal_transfer_at(
+            &env::current_account_id(),
+            &escrow.sender_id,
+            escrow.amount.0,
+            escrow.cost_price.0,
+            Some("escrow refund".to_string()),
+        );
+        KtEvent::KtRefund {
+            operation: RefundOperation::Escrow,
+            reason: if escrow.is_expired() {
+                RefundReason::Expired
+            } else {
+                RefundReason::Cancelled
+            },
+            account_id: &escrow.sender_id,
+            asset_id: None,
+            amount: Some(&escrow.amount),
+            asset_amount: None,
+        }
+        .emit();
+        KtEvent::EscrowRefunded {
+            id,
+            sender_id: &escrow.sender_id,
+        }
+        .emit();
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Locks KT of the caller for the receiver until `expires_at`. The attached deposit
+    /// pays the storage of the escrow, the rest is refunded.
+    #[payable]
+    pub fn create_escrow(
+        &mut self,
+        receiver_id: AccountId,
+        amount: U128,
+        expires_at: U64,
+        resolver_id: Option<AccountId>,
+    ) -> U64 {
+        let initial_usage = env::storage_usage();
+        require!(amount.0 > 0, "Escrow amount should be positive");
+        require!(
+            expires_at.0 > env::block_timestamp(),
+            "Escrow expiration is in the past"
+        );
+        let sender_id = env::predecessor_account_id();
+        require!(sender_id != receiver_id, "Escrow receiver is the sender");
+        self.compliance.assert_not_frozen(&sender_id);
+        self.compliance.assert_not_frozen(&receiver_id);
+        self.operations.assert_no_pending_sell(&sender_id);
+        self.internal_register_for(&sender_id, &receiver_id);
+        let cost_price = self.token.cost_basis_of(&sender_id);
+        self.token.internal_transfer_at(
+            &sender_id,
+            &env::current_account_id(),
+            amount.into(),
+            cost_price,
+            Some("escrow".to_string()),
+        );
+        let mut escrow = Escrow {
+            sender_id,
+            receiver_id,
+            amount,
+            cost_price: cost_price.into(),
+            expires_at,
+            resolver_id,
+            storage_deposit: 0.into(),
+        };
+        let id = self.escrows.push(&escrow);
+        escrow.storage_deposit = self.internal_charge_storage(initial_usage).into();
+        self.escrows.update(id, &escrow);
+        KtEvent::EscrowCreated {
+            id,
+            sender_id: &escrow.sender_id,
+            receiver_id: &escrow.receiver_id,
+            amount: &escrow.amount,
+            expires_at: &escrow.expires_at,
+            resolver_id: escrow.resolver_id.as_ref(),
+        }
+        .emit();
+        id.into()
+    }
+
+    /// Confirms the deal and releases the KT to the receiver, callable by the sender.
+    #[payable]
+    pub fn release_escrow(&mut self, id: U64) {
+        assert_one_yocto();
+        let escrow = self.escrows.assert_escrow(id.0);
+        require!(
+            escrow.sender_id == env::predecessor_account_id(),
+            "Only the sender can release the escrow"
+        );
+        self.internal_release_escrow(id.0, &escrow);
+    }
+
+    /// Returns the KT to the sender, callable by the receiver or by anyone once expired.
+    #[payable]
+    pub fn refund_escrow(&mut self, id: U64) {
+        assert_one_yocto();
+        let escrow = self.escrows.assert_escrow(id.0);
+        require!(
+            escrow.is_expired() || escrow.receiver_id == env::predecessor_account_id(),
+            "Escrow is not expired"
+        );
+        self.internal_refund_escrow(id.0, &escrow);
+    }
+
+    /// Settles a disputed escrow by releasing it to the receiver or refunding the sender.
+    #[payable]
+    pub fn resolve_escrow(&mut self, id: U64, release: bool) {
+        assert_one_yocto();
+        let escrow = self.escrows.assert_escrow(id.0);
+        require!(
+            escrow.is_resolver(&env::predecessor_account_id()),
+            "Only the resolver can resolve the escrow"
+        );
+        if release {
+            self.internal_release_escrow(id.0, &escrow);
+        } else {
+            self.internal_refund_escrow(id.0, &escrow);
+        }
+    }
+
+    pub fn get_escrow(&self, id: U64) -> Option<Escrow> {
+        self.escrows.get(id.0)
+    }
+
+    /// Escrows the account sends or receives, in the creation order.
+    pub fn get_escrows(
+        &self,
+        account_id: AccountId,
+        from_index: Option<U64>,
+        limit: Option<U64>,
+    ) -> Vec<(U64, Escrow)> {
+        self.escrows
+            .records_of(
+                &account_id,
+                from_index.map_or(0, |index| index.0),
+                limit.map_or(u64::MAX, |limit| limit.0),
+            )
+            .into_iter()
+            .map(|(id, escrow)| (id.into(), escrow))
+            .collect()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_contract_standards::fungible_token::core::FungibleTokenCore;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, ONE_YOCTO};
+
+    use crate::test_utils;
+    use crate::Contract;
+
+    const STORAGE_DEPOSIT: u128 = 10u128.pow(23);
+
+    fn setup_contract(context: &mut VMContextBuilder) -> Contract {
+        let mut contract = test_utils::setup_contract_with_kt(context);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(STORAGE_DEPOSIT)
+            .build());
+        let id = contract.create_escrow(accounts(2), 300.into(), 10.into(), Some(accounts(5)));
+        assert_eq!(id.0, 0);
+        assert!(contract.get_escrow(id).unwrap().storage_deposit.0 > 0);
+        testing_env!(context.attached_deposit(ONE_YOCTO).build());
+        contract
+    }
+
+    #[test]
+    fn test_release_escrow() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        contract.release_escrow(0.into());
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 300);
+        assert!(contract.get_escrow(0.into()).is_none());
+        assert!(contract.get_escrows(accounts(2), None, None).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Attached deposit doesn't cover the storage cost")]
+    fn test_create_escrow_without_storage_deposit() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        contract.create_escrow(accounts(2), 100.into(), 10.into(), None);
+    }
+
+    #[test]
+    fn test_get_escrows() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        testing_env!(context.attached_deposit(STORAGE_DEPOSIT).build());
+        contract.create_escrow(accounts(3), 100.into(), 10.into(), None);
+
+        let ids = |account_id, from_index: u64| -> Vec<u64> {
+            contract
+                .get_escrows(account_id, Some(from_index.into()), Some(1.into()))
+                .into_iter()
+                .map(|(id, _)| id.0)
+                .collect()
+        };
+        assert_eq!(ids(accounts(1), 0), vec![0]);
+        assert_eq!(ids(accounts(1), 1), vec![1]);
+        assert_eq!(ids(accounts(2), 0), vec![0]);
+        assert_eq!(ids(accounts(3), 0), vec![1]);
+    }
+
+    #[test]
+    fn test_resolve_escrow() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        let balance = contract.ft_balance_of(accounts(1)).0;
+
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.resolve_escrow(0.into(), false);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, balance + 300);
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Escrow is not expired")]
+    fn test_refund_escrow_not_expired() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(9)
+            .build());
+        contract.refund_escrow(0.into());
+    }
+}
@@ -0,0 +1,590 @@
+//! Standard for KT contract events.
+//!
+//! These events will be picked up by the NEAR indexer, alongside the nep141 events
+//! emitted for mints, burns and transfers.
+//!
+//! This follows the events format (nep-297):
+//! <https://github.com/near/NEPs/blob/master/specs/Standards/EventsFormat.md>
+
+use std::collections::BTreeMap;
+
+use near_sdk::json_types::{I128, U128, U64};
+use near_sdk::serde::Serialize;
+use near_sdk::{env, serde_json, AccountId, PublicKey};
+
+use crate::fees::{CollectedFees, FeeSplit};
+use crate::holding::HoldingFeeTier;
+use crate::limits::VolumeKind;
+use crate::operations::{OperationKind, OperationStage};
+use crate::orders::OrderSide;
+use crate::treasury::{AssetId, AssetStatus, InsuranceSource};
+
+const KT_EVENT_STANDARD: &str = "ktoken";
+const KT_EVENT_VERSION: &str = "1.0.0";
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct KtEventLog<'a> {
+    standard: &'static str,
+    version: &'static str,
+    #[serde(flatten)]
+    event: &'a KtEvent<'a>,
+}
+
+/// Operation whose funds are returned by a `kt_refund` event.
+#[derive(Serialize, Clone, Copy)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub enum RefundOperation {
+    Buy,
+    Sell,
+    TransferCall,
+    LimitOrder,
+    Escrow,
+    Stream,
+    ScheduledBuy,
+    NearBuy,
+    Rebalance,
+    Swap,
+}
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub enum RefundReason {
+    /// The transfer to the account or the receiver failed.
+    PayoutFailed,
+    /// The `ft_transfer_call` receiver or the buy didn't use the amount.
+    Unused,
+    /// The buy failed, e.g. the price moved past the expected one.
+    BuyFailed,
+    Cancelled,
+    Expired,
+    /// The rest is below the minimum buy.
+    BelowMinimum,
+    /// Wrapping the attached NEAR failed.
+    WrapFailed,
+}
+
+/// Data to log for a KT event. To log this event, call [`.emit()`](KtEvent::emit).
+#[must_use]
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(tag = "event", content = "data")]
+#[serde(rename_all = "snake_case")]
+pub enum KtEvent<'a> {
+    OwnerChanged {
+        old_owner_id: &'a AccountId,
+        new_owner_id: &'a AccountId,
+    },
+    OracleChanged {
+        old_oracle_id: &'a AccountId,
+        new_oracle_id: &'a AccountId,
+    },
+    FallbackOracleChanged {
+        asset_id: &'a AssetId,
+        oracle_id: Option<&'a AccountId>,
+    },
+    TransferFeeChanged {
+        asset_id: &'a AssetId,
+        transfer_fee: u16,
+    },
+    SpreadChanged {
+        asset_id: &'a AssetId,
+        spread: u16,
+    },
+    /// The primary oracle had no valid price, the fallback one is asked.
+    OracleFallback {
+        asset_id: &'a AssetId,
+        oracle_id: &'a AccountId,
+    },
+    /// The oracle had no valid price, the asset is priced at the peg.
+    PegPriceUsed {
+        asset_id: &'a AssetId,
+        amount: &'a U128,
+    },
+    PriceSignerAdded {
+        public_key: &'a PublicKey,
+    },
+    PriceSignerRemoved {
+        public_key: &'a PublicKey,
+    },
+    AssetAdded {
+        asset_id: &'a AssetId,
+        decimals: u8,
+    },
+    AssetStatusChanged {
+        asset_id: &'a AssetId,
+        status: &'a AssetStatus,
+    },
+    TreasuryDeposit {
+        asset_id: &'a AssetId,
+        amount: &'a U128,
+    },
+    TreasuryWithdraw {
+        asset_id: &'a AssetId,
+        amount: &'a U128,
+    },
+    EmergencyWithdraw {
+        account_id: &'a AccountId,
+        asset_id: &'a AssetId,
+        amount: &'a U128,
+        receiver_id: &'a AccountId,
+    },
+    AccountFrozen {
+        account_id: &'a AccountId,
+        compliance_id: &'a AccountId,
+        reason: &'a str,
+    },
+    AccountUnfrozen {
+        account_id: &'a AccountId,
+        compliance_id: &'a AccountId,
+        reason: &'a str,
+    },
+    BalanceSeized {
+        account_id: &'a AccountId,
+        compliance_id: &'a AccountId,
+        amount: &'a U128,
+        reason: &'a str,
+    },
+    FeesChanged {
+        buy_fee: u16,
+        referral_share: u16,
+    },
+    FeeSplitChanged {
+        split: &'a FeeSplit,
+    },
+    HoldingFeeChanged {
+        tiers: &'a [HoldingFeeTier],
+    },
+    FeesCollected {
+        asset_id: &'a AssetId,
+        treasury: &'a U128,
+        insurance: &'a U128,
+        operator: &'a U128,
+        referral: &'a U128,
+    },
+    /// Fee shares moved back to the treasury balance after a refunded operation.
+    FeesReverted {
+        asset_id: &'a AssetId,
+        treasury: &'a U128,
+        insurance: &'a U128,
+        operator: &'a U128,
+    },
+    /// Asset balance held for KT compared to the bookkeeping, `drift` is negative
+    /// for a shortfall.
+    AssetReconciled {
+        asset_id: &'a AssetId,
+        expected: &'a U128,
+        actual: &'a U128,
+        drift: &'a I128,
+        resolved: bool,
+    },
+    TokenRescued {
+        token_id: &'a AccountId,
+        amount: &'a U128,
+        receiver_id: &'a AccountId,
+    },
+    AssetSkimmed {
+        asset_id: &'a AssetId,
+        amount: &'a U128,
+    },
+    Approval {
+        owner_id: &'a AccountId,
+        spender_id: &'a AccountId,
+        amount: &'a U128,
+        expires_at: Option<&'a U64>,
+    },
+    /// Fees collected so far, emitted once a day at most.
+    FeeReport {
+        assets: &'a BTreeMap<AssetId, CollectedFees>,
+        kt_value: &'a U128,
+    },
+    FeesWithdrawn {
+        account_id: &'a AccountId,
+        asset_id: &'a AssetId,
+        amount: &'a U128,
+        receiver_id: &'a AccountId,
+    },
+    ReferralCredited {
+        referrer_id: &'a AccountId,
+        account_id: &'a AccountId,
+        asset_id: &'a AssetId,
+        amount: &'a U128,
+    },
+    InsuranceFunded {
+        asset_id: &'a AssetId,
+        amount: &'a U128,
+        source: InsuranceSource,
+    },
+    InsuranceDeployed {
+        account_id: &'a AccountId,
+        asset_id: &'a AssetId,
+        amount: &'a U128,
+        /// Insurance fund left after the deployment.
+        insurance: &'a U128,
+        /// Treasury balance after the deployment.
+        balance: &'a U128,
+    },
+    OrderPlaced {
+        id: u64,
+        account_id: &'a AccountId,
+        side: OrderSide,
+        asset_id: &'a AssetId,
+        amount: &'a U128,
+        limit_price: &'a U128,
+    },
+    OrderExecuted {
+        id: u64,
+        account_id: &'a AccountId,
+        keeper_id: &'a AccountId,
+        price: &'a U128,
+        bounty: &'a U128,
+    },
+    OrderCancelled {
+        id: u64,
+        account_id: &'a AccountId,
+    },
+    OrderExpired {
+        id: u64,
+        account_id: &'a AccountId,
+    },
+    EscrowCreated {
+        id: u64,
+        sender_id: &'a AccountId,
+        receiver_id: &'a AccountId,
+        amount: &'a U128,
+        expires_at: &'a U64,
+        resolver_id: Option<&'a AccountId>,
+    },
+    EscrowReleased {
+        id: u64,
+        receiver_id: &'a AccountId,
+        amount: &'a U128,
+    },
+    EscrowRefunded {
+        id: u64,
+        sender_id: &'a AccountId,
+    },
+    StreamCreated {
+        id: u64,
+        sender_id: &'a AccountId,
+        receiver_id: &'a AccountId,
+        amount: &'a U128,
+        rate: &'a U128,
+        end_at: &'a U64,
+    },
+    StreamWithdrawn {
+        id: u64,
+        receiver_id: &'a AccountId,
+        amount: &'a U128,
+    },
+    StreamCancelled {
+        id: u64,
+        account_id: &'a AccountId,
+    },
+    Subscribed {
+        id: u64,
+        account_id: &'a AccountId,
+        merchant_id: &'a AccountId,
+        amount: &'a U128,
+        period: &'a U64,
+    },
+    SubscriptionCharged {
+        id: u64,
+        account_id: &'a AccountId,
+        amount: &'a U128,
+    },
+    SubscriptionCancelled {
+        id: u64,
+        account_id: &'a AccountId,
+    },
+    StorageFeeCharged {
+        account_id: &'a AccountId,
+        amount: &'a U128,
+    },
+    BuyScheduled {
+        id: u64,
+        account_id: &'a AccountId,
+        asset_id: &'a AssetId,
+        amount: &'a U128,
+        amount_per_buy: &'a U128,
+        interval: &'a U64,
+    },
+    ScheduledBuyExecuted {
+        id: u64,
+        account_id: &'a AccountId,
+        amount: &'a U128,
+        remaining: &'a U128,
+    },
+    ScheduledBuyCancelled {
+        id: u64,
+        account_id: &'a AccountId,
+    },
+    KeeperRewarded {
+        account_id: &'a AccountId,
+        asset_id: &'a AssetId,
+        /// Asset amount credited as a claim.
+        amount: &'a U128,
+    },
+    SellOnlyChanged {
+        enabled: bool,
+    },
+    AccountMigrationProposed {
+        account_id: &'a AccountId,
+        new_account_id: &'a AccountId,
+    },
+    AccountMigrated {
+        old_account_id: &'a AccountId,
+        account_id: &'a AccountId,
+        amount: &'a U128,
+        price: &'a U128,
+    },
+    AccountUnregistered {
+        account_id: &'a AccountId,
+        burned: &'a U128,
+    },
+    BridgeMinted {
+        minter_id: &'a AccountId,
+        account_id: &'a AccountId,
+        amount: &'a U128,
+        source_chain: &'a str,
+    },
+    BridgeBurned {
+        minter_id: &'a AccountId,
+        amount: &'a U128,
+        destination_chain: &'a str,
+        recipient: &'a str,
+    },
+    PriceDecimalsChanged {
+        asset_id: &'a AssetId,
+        price_decimals: u8,
+    },
+    MaxPriceAgeChanged {
+        asset_id: &'a AssetId,
+        max_price_age_ns: Option<&'a U64>,
+    },
+    BuyCommitted {
+        account_id: &'a AccountId,
+        expires_at: &'a U64,
+    },
+    Staked {
+        account_id: &'a AccountId,
+        amount: &'a U128,
+        unlock_at: &'a U64,
+    },
+    Unstaked {
+        account_id: &'a AccountId,
+        amount: &'a U128,
+        cooldown_end: &'a U64,
+    },
+    /// Treasury fees in the asset distributed to the stakers as KT.
+    StakingRewardsDistributed {
+        asset_id: &'a AssetId,
+        amount: &'a U128,
+        kt_amount: &'a U128,
+    },
+    RelayFeeCharged {
+        account_id: &'a AccountId,
+        relayer_id: &'a AccountId,
+        amount: &'a U128,
+    },
+    /// Funds returned after a failed or closed operation. `amount` is the KT amount
+    /// and `asset_amount` the amount of the asset, NEAR for a failed wrap.
+    KtRefund {
+        operation: RefundOperation,
+        reason: RefundReason,
+        account_id: &'a AccountId,
+        asset_id: Option<&'a AssetId>,
+        amount: Option<&'a U128>,
+        asset_amount: Option<&'a U128>,
+    },
+    SellRefunded {
+        account_id: &'a AccountId,
+        asset_id: &'a AssetId,
+        amount: &'a U128,
+        asset_amount: &'a U128,
+    },
+    AssetRemoved {
+        asset_id: &'a AssetId,
+    },
+    AssetMigrated {
+        old_asset_id: &'a AssetId,
+        new_asset_id: &'a AssetId,
+        balance: &'a U128,
+    },
+    Rebalance {
+        account_id: &'a AccountId,
+        asset_in: &'a AssetId,
+        amount_in: &'a U128,
+        asset_out: &'a AssetId,
+        amount_out: &'a U128,
+    },
+    Swap {
+        account_id: &'a AccountId,
+        asset_in: &'a AssetId,
+        amount_in: &'a U128,
+        asset_out: &'a AssetId,
+        amount_out: &'a U128,
+    },
+    DexSwap {
+        asset_in: &'a AssetId,
+        amount_in: &'a U128,
+        asset_out: &'a AssetId,
+        amount_out: &'a U128,
+    },
+    StrategyHarvest {
+        asset_id: &'a AssetId,
+        amount: &'a U128,
+    },
+    RewardsDistributed {
+        amount: &'a U128,
+    },
+    RedemptionQueued {
+        id: u64,
+        account_id: &'a AccountId,
+        asset_id: &'a AssetId,
+        amount: &'a U128,
+    },
+    RedemptionClaimed {
+        id: u64,
+        account_id: &'a AccountId,
+        asset_id: &'a AssetId,
+        amount: &'a U128,
+    },
+    AssetClaimAdded {
+        account_id: &'a AccountId,
+        asset_id: &'a AssetId,
+        amount: &'a U128,
+    },
+    AssetClaimed {
+        account_id: &'a AccountId,
+        asset_id: &'a AssetId,
+        amount: &'a U128,
+    },
+    CircuitBreakerTripped {
+        kind: VolumeKind,
+        volume: &'a U128,
+        limit: &'a U128,
+    },
+    OperationRecovered {
+        id: &'a U64,
+        account_id: &'a AccountId,
+        kind: OperationKind,
+        stage: OperationStage,
+    },
+    Donation {
+        account_id: &'a AccountId,
+        asset_id: &'a AssetId,
+        amount: &'a U128,
+    },
+    KtBuy {
+        account_id: &'a AccountId,
+        asset_id: &'a AssetId,
+        /// Minted KT amount.
+        amount: &'a U128,
+        /// Asset amount paid, including the fee.
+        asset_amount: &'a U128,
+        /// Execution price, with 18 decimals.
+        price: &'a U128,
+        fee: &'a U128,
+        oracle_timestamp: Option<&'a U64>,
+        /// Client memo of the buy.
+        memo: Option<&'a str>,
+    },
+    KtSell {
+        account_id: &'a AccountId,
+        asset_id: &'a AssetId,
+        /// Burned KT amount.
+        amount: &'a U128,
+        /// Asset amount paid out, after the fee.
+        asset_amount: &'a U128,
+        /// Execution price, with 18 decimals.
+        price: &'a U128,
+        fee: &'a U128,
+        oracle_timestamp: Option<&'a U64>,
+        /// Client memo of the sell.
+        memo: Option<&'a str>,
+        /// Weighted mean purchase price of the seller, with 18 decimals.
+        cost_basis: &'a U128,
+        /// Realized profit or loss, `(price - cost_basis) * amount` with 18 decimals.
+        pnl: &'a I128,
+    },
+}
+
+impl KtEvent<'_> {
+    fn to_json_event_string(&self) -> String {
+        let log = KtEventLog {
+            standard: KT_EVENT_STANDARD,
+            version: KT_EVENT_VERSION,
+            event: self,
+        };
+        // Events cannot fail to serialize so fine to panic on error
+        let json = serde_json::to_string(&log).unwrap_or_else(|_| env::abort());
+        format!("EVENT_JSON:{}", json)
+    }
+
+    /// Logs the event to the host.
+    pub fn emit(self) {
+        env::log_str(&self.to_json_event_string());
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, get_logs};
+
+    use near_sdk::json_types::{U128, U64};
+
+    use super::{KtEvent, RefundOperation, RefundReason};
+
+    #[test]
+    fn test_asset_removed() {
+        KtEvent::AssetRemoved {
+            asset_id: &accounts(1),
+        }
+        .emit();
+        assert_eq!(
+            get_logs()[0],
+            r#"EVENT_JSON:{"standard":"ktoken","version":"1.0.0","event":"asset_removed","data":{"asset_id":"bob"}}"#
+        );
+    }
+
+    #[test]
+    fn test_kt_buy() {
+        KtEvent::KtBuy {
+            account_id: &accounts(1),
+            asset_id: &accounts(3),
+            amount: &U128(997_000),
+            asset_amount: &U128(1_000),
+            price: &U128(1_000_000),
+            fee: &U128(3),
+            oracle_timestamp: Some(&U64(42)),
+            memo: Some("order-1"),
+        }
+        .emit();
+        assert_eq!(
+            get_logs()[0],
+            r#"EVENT_JSON:{"standard":"ktoken","version":"1.0.0","event":"kt_buy","data":{"account_id":"bob","asset_id":"danny","amount":"997000","asset_amount":"1000","price":"1000000","fee":"3","oracle_timestamp":"42","memo":"order-1"}}"#
+        );
+    }
+
+    #[test]
+    fn test_kt_refund() {
+        KtEvent::KtRefund {
+            operation: RefundOperation::Sell,
+            reason: RefundReason::PayoutFailed,
+            account_id: &accounts(1),
+            asset_id: Some(&accounts(3)),
+            amount: Some(&U128(1_000)),
+            asset_amount: None,
+        }
+        .emit();
+        assert_eq!(
+            get_logs()[0],
+            r#"EVENT_JSON:{"standard":"ktoken","version":"1.0.0","event":"kt_refund","data":{"operation":"sell","reason":"payout_failed","account_id":"bob","asset_id":"danny","amount":"1000","asset_amount":null}}"#
+        );
+    }
+}
@@ -0,0 +1,738 @@
+use std::collections::{BTreeMap, HashMap};
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::{LookupMap, UnorderedMap};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{
+    assert_one_yocto, env, ext_contract, near_bindgen, require, AccountId, Balance, IntoStorageKey,
+    Promise, PromiseResult, ONE_YOCTO,
+};
+use schemars::JsonSchema;
+
+use crate::events::KtEvent;
+use crate::oracle::ExchangePrice;
+use crate::price::exchange_asset_to_kt;
+use crate::roles::Role;
+use crate::treasury::{AssetId, InsuranceSource};
+use crate::{ext_ft_transfer, Contract, ContractExt, BASIS_POINTS};
+
+/// How the collected fees are split, the shares are in basis points and sum up to 100%.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct FeeSplit {
+    /// Kept in the treasury balance.
+    pub treasury: u16,
+    /// Added to the treasury insurance fund.
+    pub insurance: u16,
+    /// Credited to `operator_id`.
+    pub operator: u16,
+    pub operator_id: Option<AccountId>,
+}
+
+impl Default for FeeSplit {
+    fn default() -> Self {
+        Self {
+            treasury: BASIS_POINTS,
+            insurance: 0,
+            operator: 0,
+            operator_id: None,
+        }
+    }
+}
+
+impl FeeSplit {
+    pub fn assert_valid(&self) {
+        require!(
+            u32::from(self.treasury) + u32::from(self.insurance) + u32::from(self.operator)
+                == u32::from(BASIS_POINTS),
+            "Fee split shares don't sum up to 100%"
+        );
+        require!(
+            self.operator == 0 || self.operator_id.is_some(),
+            "Operator account is not set"
+        );
+    }
+
+    /// Splits the fee into (treasury, insurance, operator) amounts,
+    /// the treasury takes the rounding remainder.
+    pub fn split(&self, fee: Balance) -> (Balance, Balance, Balance) {
+        let share = |bps: u16| match fee.checked_mul(u128::from(bps)) {
+            Some(value) => value / u128::from(BASIS_POINTS),
+            None => fee / u128::from(BASIS_POINTS) * u128::from(bps),
+        };
+        let insurance = share(self.insurance);
+        let operator = share(self.operator);
+        (fee - insurance - operator, insurance, operator)
+    }
+}
+
+/// Fee in basis points of the amount, rounded up.
+pub(crate) fn fee_of(amount: Balance, fee: u16) -> Balance {
+    let fee = u128::from(fee);
+    let bps = u128::from(BASIS_POINTS);
+    match amount.checked_mul(fee) {
+        Some(value) => value.div_ceil(bps),
+        None => amount / bps * fee,
+    }
+}
+
+/// Minimum time between the `fee_report` events, in nanoseconds.
+const FEE_REPORT_PERIOD: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+/// Fees collected in an asset by their destination.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct CollectedFees {
+    pub treasury: U128,
+    pub insurance: U128,
+    pub operator: U128,
+    pub referral: U128,
+    /// Value of the fees in KT, at the prices they were collected at.
+    pub kt_value: U128,
+}
+
+impl Default for CollectedFees {
+    fn default() -> Self {
+        Self {
+            treasury: 0.into(),
+            insurance: 0.into(),
+            operator: 0.into(),
+            referral: 0.into(),
+            kt_value: 0.into(),
+        }
+    }
+}
+
+/// Fees collected per asset, kept apart from the treasury balances.
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct FeeReport {
+    pub assets: BTreeMap<AssetId, CollectedFees>,
+    /// Value of the fees of all the assets in KT.
+    pub kt_value: U128,
+    /// Time of the last `fee_report` event.
+    pub reported_at: Option<U64>,
+}
+
+/// Treasury share of the fees collected in an asset, which can be withdrawn.
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct FeeBalance {
+    pub accrued: U128,
+    pub withdrawn: U128,
+    pub withdrawable: U128,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Fees {
+    /// Fee taken from the bought asset amount, in basis points.
+    buy_fee: u16,
+    /// Share of the buy fee credited to the referrer, in basis points.
+    referral_share: u16,
+    split: FeeSplit,
+    /// AssetID -> Collected fees.
+    collected: UnorderedMap<AssetId, CollectedFees>,
+    /// AccountID -> Total referral earnings per asset.
+    referral_earnings: LookupMap<AccountId, HashMap<AssetId, Balance>>,
+    /// AssetID -> Treasury fees withdrawn.
+    withdrawn: LookupMap<AssetId, Balance>,
+    /// Time of the last `fee_report` event.
+    reported_at: Option<u64>,
+}
+
+impl Fees {
+    pub fn new<S>(prefix: S) -> Self
+    where
+        S: IntoStorageKey,
+    {
+        let prefix = prefix.into_storage_key();
+        Self {
+            buy_fee: 0,
+            referral_share: 0,
+            split: FeeSplit::default(),
+            collected: UnorderedMap::new([prefix.clone(), b"c".to_vec()].concat()),
+            referral_earnings: LookupMap::new([prefix.clone(), b"r".to_vec()].concat()),
+            withdrawn: LookupMap::new([prefix, b"w".to_vec()].concat()),
+            reported_at: None,
+        }
+    }
+
+    pub fn buy_fee(&self) -> u16 {
+        self.buy_fee
+    }
+
+    pub fn set_buy_fee(&mut self, fee: u16) {
+        require!(fee <= BASIS_POINTS, "Fee is out of bounds");
+        self.buy_fee = fee;
+    }
+
+    pub fn referral_share(&self) -> u16 {
+        self.referral_share
+    }
+
+    pub fn set_referral_share(&mut self, share: u16) {
+        require!(share <= BASIS_POINTS, "Referral share is out of bounds");
+        self.referral_share = share;
+    }
+
+    pub fn split(&self) -> &FeeSplit {
+        &self.split
+    }
+
+    pub fn set_split(&mut self, split: FeeSplit) {
+        split.assert_valid();
+        self.split = split;
+    }
+
+    /// Buy fee of the asset amount, rounded up.
+    pub fn buy_fee_of(&self, amount: Balance) -> Balance {
+        fee_of(amount, self.buy_fee)
+    }
+
+    /// Referrer share of the fee, rounded down.
+    pub fn referral_fee_of(&self, fee: Balance) -> Balance {
+        let share = u128::from(self.referral_share);
+        match fee.checked_mul(share) {
+            Some(value) => value / u128::from(BASIS_POINTS),
+            None => fee / u128::from(BASIS_POINTS) * share,
+        }
+    }
+
+    pub fn collected(&self) -> Vec<(AssetId, CollectedFees)> {
+        self.collected.to_vec()
+    }
+
+    pub fn record_collected(
+        &mut self,
+        asset_id: &AssetId,
+        (treasury, insurance, operator, referral): (Balance, Balance, Balance, Balance),
+        kt_value: Balance,
+    ) {
+        let mut collected = self.collected.get(asset_id).unwrap_or_default();
+        collected.treasury = collected.treasury.0.saturating_add(treasury).into();
+        collected.insurance = collected.insurance.0.saturating_add(insurance).into();
+        collected.operator = collected.operator.0.saturating_add(operator).into();
+        collected.referral = collected.referral.0.saturating_add(referral).into();
+        collected.kt_value = collected.kt_value.0.saturating_add(kt_value).into();
+        self.collected.insert(asset_id, &collected);
+        self.report_if_due();
+    }
+
+    pub fn record_reverted(
+        &mut self,
+        asset_id: &AssetId,
+        (treasury, insurance, operator): (Balance, Balance, Balance),
+        kt_value: Balance,
+    ) {
+        let mut collected = self.collected.get(asset_id).unwrap_or_default();
+        collected.treasury = collected.treasury.0.saturating_sub(treasury).into();
+        collected.insurance = collected.insurance.0.saturating_sub(insurance).into();
+        collected.operator = collected.operator.0.saturating_sub(operator).into();
+        collected.kt_value = collected.kt_value.0.saturating_sub(kt_value).into();
+        self.collected.insert(asset_id, &collected);
+    }
+
+    pub fn report(&self) -> FeeReport {
+        let assets: BTreeMap<_, _> = self.collected.iter().collect();
+        let kt_value = assets.values().fold(0, |total: Balance, fees| {
+            total.saturating_add(fees.kt_value.0)
+        });
+        FeeReport {
+            assets,
+            kt_value: kt_value.into(),
+            reported_at: self.reported_at.map(Into::into),
+        }
+    }
+
+    /// Emits the `fee_report` event if the last one is older than the report period.
+    fn report_if_due(&mut self) {
+        let now = env::block_timestamp();
+        if self
+            .reported_at
+            .is_some_and(|reported_at| now.saturating_sub(reported_at) < FEE_REPORT_PERIOD)
+        {
+            return;
+        }
+        self.reported_at = Some(now);
+        let report = self.report();
+        KtEvent::FeeReport {
+            assets: &report.assets,
+            kt_value: &report.kt_value,
+        }
+        .emit();
+    }
+
+    pub fn balance_of(&self, asset_id: &AssetId) -> FeeBalance {
+        let accrued = self
+            .collected
+            .get(asset_id)
+            .map_or(0, |collected| collected.treasury.0);
+        let withdrawn = self.withdrawn.get(asset_id).unwrap_or_default();
+        FeeBalance {
+            accrued: accrued.into(),
+            withdrawn: withdrawn.into(),
+            withdrawable: accrued.saturating_sub(withdrawn).into(),
+        }
+    }
+
+    pub fn record_withdrawn(&mut self, asset_id: &AssetId, amount: Balance) {
+        require!(
+            amount <= self.balance_of(asset_id).withdrawable.0,
+            "Amount exceeds the withdrawable fees"
+        );
+        let withdrawn = self.withdrawn.get(asset_id).unwrap_or_default();
+        self.withdrawn.insert(asset_id, &(withdrawn + amount));
+    }
+
+    /// Reverts the withdrawal of a failed payout.
+    pub fn revert_withdrawn(&mut self, asset_id: &AssetId, amount: Balance) {
+        let withdrawn = self.withdrawn.get(asset_id).unwrap_or_default();
+        self.withdrawn
+            .insert(asset_id, &withdrawn.saturating_sub(amount));
+    }
+
+    pub fn referral_earnings_of(&self, account_id: &AccountId) -> HashMap<AssetId, Balance> {
+        self.referral_earnings.get(account_id).unwrap_or_default()
+    }
+
+    pub fn record_referral(&mut self, account_id: &AccountId, asset_id: &AssetId, amount: Balance) {
+        let mut earnings = self.referral_earnings_of(account_id);
+        let earned = earnings.entry(asset_id.clone()).or_default();
+        *earned = earned.saturating_add(amount);
+        self.referral_earnings.insert(account_id, &earnings);
+    }
+}
+
+impl Contract {
+    /// Moves the fee share out of the treasury balance to the account asset claims.
+    pub(crate) fn internal_credit_fee(
+        &mut self,
+        account_id: &AccountId,
+        asset_id: &AssetId,
+        amount: Balance,
+    ) {
+        if amount > 0 {
+            self.treasury.internal_withdraw(asset_id, amount);
+            self.claims.internal_add(account_id, asset_id, amount);
+        }
+    }
+
+    /// Credits the referrer share of the buy fee, then splits the rest
+    /// between the treasury, the insurance and the operator.
+    pub(crate) fn internal_collect_buy_fee(
+        &mut self,
+        account_id: &AccountId,
+        asset_id: &AssetId,
+        fee: Balance,
+        price: ExchangePrice,
+        referrer_id: Option<&AccountId>,
+    ) {
+        if fee == 0 {
+            return;
+        }
+        let referral_fee = match referrer_id {
+            Some(referrer_id) if referrer_id != account_id => {
+                let referral_fee = self.fees.referral_fee_of(fee);
+                if referral_fee > 0 {
+                    self.internal_credit_fee(referrer_id, asset_id, referral_fee);
+                    self.fees
+                        .record_referral(referrer_id, asset_id, referral_fee);
+                    KtEvent::ReferralCredited {
+                        referrer_id,
+                        account_id,
+                        asset_id,
+                        amount: &referral_fee.into(),
+                    }
+                    .emit();
+                }
+                referral_fee
+            }
+            _ => 0,
+        };
+
+        self.internal_split_fee(asset_id, fee - referral_fee, referral_fee, price);
+    }
+
+    /// Splits the fee kept in the treasury between the treasury, the insurance
+    /// and the operator, and records it with the referral fee already credited.
+    /// The price values the fees in KT.
+    pub(crate) fn internal_split_fee(
+        &mut self,
+        asset_id: &AssetId,
+        fee: Balance,
+        referral_fee: Balance,
+        price: ExchangePrice,
+    ) {
+        if fee == 0 && referral_fee == 0 {
+            return;
+        }
+        let split = self.fees.split().clone();
+        let (treasury, insurance, operator) = split.split(fee);
+        self.internal_fund_insurance(asset_id, insurance, InsuranceSource::Fee);
+        if let Some(operator_id) = split.operator_id.as_ref() {
+            self.internal_credit_fee(operator_id, asset_id, operator);
+        }
+        let decimals = self.treasury.assert_asset(asset_id).decimals;
+        let kt_value =
+            exchange_asset_to_kt(fee + referral_fee, decimals, price).unwrap_or_default();
+        self.fees.record_collected(
+            asset_id,
+            (treasury, insurance, operator, referral_fee),
+            kt_value,
+        );
+        KtEvent::FeesCollected {
+            asset_id,
+            treasury: &treasury.into(),
+            insurance: &insurance.into(),
+            operator: &operator.into(),
+            referral: &referral_fee.into(),
+        }
+        .emit();
+    }
+
+    /// Reverts the split of a fee which is refunded with its operation: the insurance
+    /// and the operator shares move back to the treasury balance, as far as they weren't
+    /// deployed or claimed meanwhile. The fees are valued at the cached asset price.
+    pub(crate) fn internal_revert_fee(&mut self, asset_id: &AssetId, fee: Balance) {
+        if fee == 0 {
+            return;
+        }
+        let split = self.fees.split().clone();
+        let (treasury, insurance, operator) = split.split(fee);
+        let asset = self.treasury.assert_asset(asset_id);
+        let insurance = insurance.min(asset.insurance);
+        self.treasury.internal_deploy_insurance(asset_id, insurance);
+        let operator = match split.operator_id.as_ref() {
+            Some(operator_id) => {
+                let claimed = self.claims.internal_take(operator_id, asset_id);
+                let taken = operator.min(claimed);
+                if claimed > taken {
+                    self.claims
+                        .internal_add(operator_id, asset_id, claimed - taken);
+                }
+                if taken > 0 {
+                    self.treasury.internal_deposit(asset_id, taken);
+                }
+                taken
+            }
+            None => 0,
+        };
+        let kt_value = asset
+            .price
+            .and_then(|cached| {
+                exchange_asset_to_kt(
+                    treasury + insurance + operator,
+                    asset.decimals,
+                    cached.price,
+                )
+            })
+            .unwrap_or_default();
+        self.fees
+            .record_reverted(asset_id, (treasury, insurance, operator), kt_value);
+        KtEvent::FeesReverted {
+            asset_id,
+            treasury: &treasury.into(),
+            insurance: &insurance.into(),
+            operator: &operator.into(),
+        }
+        .emit();
+    }
+
+    pub(crate) fn internal_set_buy_fee(&mut self, fee: u16, referral_share: u16) {
+        self.fees.set_buy_fee(fee);
+        self.fees.set_referral_share(referral_share);
+        KtEvent::FeesChanged {
+            buy_fee: fee,
+            referral_share,
+        }
+        .emit();
+    }
+
+    pub(crate) fn internal_set_fee_split(&mut self, split: FeeSplit) {
+        self.fees.set_split(split);
+        KtEvent::FeeSplitChanged {
+            split: self.fees.split(),
+        }
+        .emit();
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Sets the buy fee and the share of it credited to referrers, in basis points.
+    pub fn set_buy_fee(&mut self, fee: u16, referral_share: u16) {
+        self.assert_owner();
+        self.internal_set_buy_fee(fee, referral_share);
+    }
+
+    pub fn get_buy_fee(&self) -> (u16, u16) {
+        (self.fees.buy_fee(), self.fees.referral_share())
+    }
+
+    /// Sets how the collected fees are split, the operator share is credited
+    /// to be paid out with `claim_asset`.
+    pub fn set_fee_split(&mut self, split: FeeSplit) {
+        self.assert_owner();
+        self.internal_set_fee_split(split);
+    }
+
+    pub fn get_fee_split(&self) -> FeeSplit {
+        self.fees.split().clone()
+    }
+
+    pub fn get_collected_fees(&self) -> HashMap<AssetId, CollectedFees> {
+        self.fees.collected().into_iter().collect()
+    }
+
+    /// Pays out the treasury share of the collected fees, the balance backing KT
+    /// isn't touched. Allowed to the owner and treasurers.
+    #[payable]
+    pub fn withdraw_fees(
+        &mut self,
+        asset_id: AssetId,
+        amount: U128,
+        receiver_id: AccountId,
+    ) -> Promise {
+        assert_one_yocto();
+        self.assert_owner_or_role(Role::Treasurer);
+        require!(amount.0 > 0, "Amount should be positive");
+        let asset = self.treasury.assert_asset(&asset_id);
+        require!(
+            amount.0 <= self.available_balance(&asset_id, asset.balance),
+            "Amount exceeds the available treasury balance"
+        );
+        self.fees.record_withdrawn(&asset_id, amount.0);
+        self.treasury.internal_withdraw(&asset_id, amount.0);
+        self.internal_start_payout(&asset_id, amount.0);
+
+        KtEvent::FeesWithdrawn {
+            account_id: &env::predecessor_account_id(),
+            asset_id: &asset_id,
+            amount: &amount,
+            receiver_id: &receiver_id,
+        }
+        .emit();
+
+        ext_ft_transfer::ext(asset_id.clone())
+            .with_static_gas(self.gas.transfer)
+            .with_attached_deposit(ONE_YOCTO)
+            .ft_transfer(receiver_id, amount, Some("fees".to_string()))
+            .then(
+                ext_fees_resolver::ext(env::current_account_id())
+                    .with_static_gas(self.gas.resolve_withdraw_fees)
+                    .resolve_withdraw_fees(asset_id, amount),
+            )
+    }
+
+    pub fn get_fee_report(&self) -> FeeReport {
+        self.fees.report()
+    }
+
+    /// Fees accrued to the treasury and withdrawn, per asset.
+    pub fn get_fee_balances(&self) -> HashMap<AssetId, FeeBalance> {
+        self.fees
+            .collected()
+            .into_iter()
+            .map(|(asset_id, _)| {
+                let balance = self.fees.balance_of(&asset_id);
+                (asset_id, balance)
+            })
+            .collect()
+    }
+
+    /// Total referral earnings of the account, which are paid out with `claim_asset`.
+    pub fn get_referral_earnings(&self, account_id: AccountId) -> HashMap<AssetId, U128> {
+        self.fees
+            .referral_earnings_of(&account_id)
+            .into_iter()
+            .map(|(asset_id, amount)| (asset_id, amount.into()))
+            .collect()
+    }
+}
+
+#[ext_contract(ext_fees_resolver)]
+#[allow(dead_code)]
+pub trait FeesResolver {
+    fn resolve_withdraw_fees(&mut self, asset_id: AssetId, amount: U128);
+}
+
+#[near_bindgen]
+impl FeesResolver for Contract {
+    #[private]
+    fn resolve_withdraw_fees(&mut self, asset_id: AssetId, amount: U128) {
+        self.internal_finish_payout(&asset_id, amount.0);
+        match env::promise_result(0) {
+            PromiseResult::NotReady => env::abort(),
+            PromiseResult::Successful(_) => {}
+            PromiseResult::Failed => {
+                self.treasury.internal_deposit(&asset_id, amount.0);
+                self.fees.revert_withdrawn(&asset_id, amount.0);
+            }
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
+    use near_sdk::{testing_env, PromiseResult, RuntimeFeesConfig, VMConfig, ONE_YOCTO};
+
+    use crate::fees::{FeeSplit, Fees, FeesResolver};
+    use crate::oracle::ExchangePrice;
+    use crate::roles::Role;
+    use crate::test_utils;
+    use crate::{BuyOptions, Contract, StorageKey};
+
+    #[test]
+    fn test_buy_fee() {
+        let mut fees = Fees::new(StorageKey::Fees);
+        assert_eq!(fees.buy_fee_of(1_000_000), 0);
+
+        fees.set_buy_fee(30);
+        fees.set_referral_share(2_000);
+        assert_eq!(fees.buy_fee_of(1_000_000), 3_000);
+        assert_eq!(fees.buy_fee_of(1_001), 4);
+        assert_eq!(fees.buy_fee_of(u128::MAX), u128::MAX / 10_000 * 30);
+        assert_eq!(fees.referral_fee_of(3_000), 600);
+        assert_eq!(fees.referral_fee_of(u128::MAX), u128::MAX / 10_000 * 2_000);
+
+        fees.record_referral(&accounts(1), &accounts(3), 600);
+        fees.record_referral(&accounts(1), &accounts(3), 600);
+        assert_eq!(fees.referral_earnings_of(&accounts(1))[&accounts(3)], 1_200);
+    }
+
+    #[test]
+    fn test_fee_split() {
+        let split = FeeSplit {
+            treasury: 5_000,
+            insurance: 3_000,
+            operator: 2_000,
+            operator_id: Some(accounts(2)),
+        };
+        split.assert_valid();
+        assert_eq!(split.split(1_000), (500, 300, 200));
+        assert_eq!(split.split(9), (6, 2, 1));
+    }
+
+    #[test]
+    #[should_panic(expected = "Fee split shares don't sum up to 100%")]
+    fn test_fee_split_invalid() {
+        let mut fees = Fees::new(StorageKey::Fees);
+        fees.set_split(FeeSplit {
+            treasury: 5_000,
+            insurance: 0,
+            operator: 2_000,
+            operator_id: Some(accounts(2)),
+        });
+    }
+
+    fn setup_contract(context: &mut VMContextBuilder) -> Contract {
+        let mut contract = test_utils::setup_contract(context);
+        contract.set_buy_fee(100, 0);
+        test_utils::buy_kt(&mut contract, &accounts(1));
+        contract.grant_role(accounts(2), Role::Treasurer);
+        contract
+    }
+
+    #[test]
+    fn test_withdraw_fees() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        let balance = &contract.get_fee_balances()[&accounts(3)];
+        assert_eq!(balance.accrued.0, 10_000);
+        assert_eq!(balance.withdrawable.0, 10_000);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        contract.withdraw_fees(accounts(3), 4_000.into(), accounts(2));
+        assert_eq!(
+            contract.treasury.assert_asset(&accounts(3)).balance,
+            996_000
+        );
+        let balance = &contract.get_fee_balances()[&accounts(3)];
+        assert_eq!(balance.withdrawn.0, 4_000);
+        assert_eq!(balance.withdrawable.0, 6_000);
+
+        // The failed payout is returned to the fees
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        contract.resolve_withdraw_fees(accounts(3), 4_000.into());
+        assert_eq!(
+            contract.treasury.assert_asset(&accounts(3)).balance,
+            1_000_000
+        );
+        assert_eq!(contract.get_fee_balances()[&accounts(3)].withdrawn.0, 0);
+    }
+
+    #[test]
+    fn test_fee_report() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        let report = contract.get_fee_report();
+        assert_eq!(report.assets[&accounts(3)].treasury.0, 10_000);
+        assert_eq!(report.kt_value.0, 10_000_000_000_000_000);
+        assert!(get_logs()
+            .iter()
+            .any(|log| log.contains(r#""event":"fee_report""#)));
+
+        // Reported once a day at most
+        let price = ExchangePrice::new(10000, 10);
+        testing_env!(context.block_timestamp(1_000).build());
+        contract.internal_buy(
+            &accounts(1),
+            &accounts(3),
+            1_000_000,
+            6,
+            price,
+            &BuyOptions::default(),
+        );
+        assert!(!get_logs()
+            .iter()
+            .any(|log| log.contains(r#""event":"fee_report""#)));
+        assert_eq!(contract.get_fee_report().kt_value.0, 20_000_000_000_000_000);
+
+        testing_env!(context.block_timestamp(86_400_000_001_000).build());
+        contract.internal_buy(
+            &accounts(1),
+            &accounts(3),
+            1_000_000,
+            6,
+            price,
+            &BuyOptions::default(),
+        );
+        assert!(get_logs()
+            .iter()
+            .any(|log| log.contains(r#""event":"fee_report""#)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Amount exceeds the withdrawable fees")]
+    fn test_withdraw_fees_backing() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        contract.withdraw_fees(accounts(3), 10_001.into(), accounts(2));
+    }
+
+    #[test]
+    #[should_panic(expected = "Fee is out of bounds")]
+    fn test_buy_fee_out_of_bounds() {
+        let mut fees = Fees::new(StorageKey::Fees);
+        fees.set_buy_fee(10_001);
+    }
+}
@@ -4,33 +4,48 @@ use near_contract_standards::fungible_token::metadata::{
     FungibleTokenMetadata, FungibleTokenMetadataProvider,
 };
 use near_contract_standards::fungible_token::receiver::{ext_ft_receiver, FungibleTokenReceiver};
+use near_contract_standards::storage_management::{
+    StorageBalance, StorageBalanceBounds, StorageManagement,
+};
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::LookupMap;
+use near_sdk::collections::{LookupMap, UnorderedSet};
 use near_sdk::env::{self, log_str};
-use near_sdk::json_types::U128;
+use near_sdk::json_types::{U128, U64};
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::{
     assert_one_yocto, ext_contract, near_bindgen, require, AccountId, Balance, IntoStorageKey,
     PromiseOrValue, PromiseResult,
 };
+use schemars::JsonSchema;
 
-use crate::oracle::ext_oracle;
-use crate::price::ExpectedPrice;
-use crate::treasury::AssetStatus;
-use crate::{
-    ext_self, Contract, ContractExt, GAS_FOR_BUY_WITH_PRICE, GAS_FOR_GET_EXCHANGE_PRICE,
-    GAS_FOR_ON_TRANSFER, GAS_FOR_RESOLVE_TRANSFER, GAS_FOR_TRANSFER_CALL,
-};
+use crate::buy_via::BuyViaOrder;
+use crate::dex::SwapAction;
+use crate::distribution::Rewards;
+use crate::events::{KtEvent, RefundOperation, RefundReason};
+use crate::gas::GasConfig;
+use crate::operations::{ext_operation_resolver, OperationKind, OperationStage};
+use crate::price::{convert_decimals, ExpectedPrice};
+use crate::signed_price::SignedPrice;
+use crate::treasury::AssetId;
+use crate::{assert_valid_memo, ext_self, BuyOptions, Contract, ContractExt, KT_DECIMALS};
 
 type Price = u128;
 
-#[derive(BorshSerialize, BorshDeserialize, Default)]
+/// Storage bounds of an account record with the longest account ID, including its
+/// holder entries and its storage deposit, in bytes.
+const ACCOUNT_STORAGE_USAGE: u64 = 500;
+
+#[derive(BorshSerialize, BorshDeserialize, Default, Clone, Copy)]
 pub struct AccountBalance {
-    amount: Balance,
-    price: Price, // Weighted mean
+    pub(crate) amount: Balance,
+    pub(crate) price: Price, // Weighted mean
 }
 
 impl AccountBalance {
+    pub fn new(amount: Balance, price: Price) -> Self {
+        Self { amount, price }
+    }
+
     pub fn checked_add(&self, amount: Balance, price: Price) -> Option<Self> {
         //  balance + amount
         let balance = self.amount.checked_add(amount)?;
@@ -63,6 +78,9 @@ impl AccountBalance {
     pub fn checked_sub(&self, amount: Balance, price: Price) -> Option<Self> {
         //  balance - amount
         let balance = self.amount.checked_sub(amount)?;
+        if balance == 0 {
+            return Some(Self::default());
+        }
 
         // Weighted arithmetic mean
         // https://en.wikipedia.org/wiki/Algorithms_for_calculating_variance
@@ -90,13 +108,52 @@ impl AccountBalance {
     }
 }
 
+/// Versioned account record, older versions are upgraded on access.
+/// New versions are added as variants and converted in `From<VAccountBalance>`.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub enum VAccountBalance {
+    Current(AccountBalance),
+}
+
+impl From<VAccountBalance> for AccountBalance {
+    fn from(balance: VAccountBalance) -> Self {
+        match balance {
+            VAccountBalance::Current(balance) => balance,
+        }
+    }
+}
+
+impl From<AccountBalance> for VAccountBalance {
+    fn from(balance: AccountBalance) -> Self {
+        Self::Current(balance)
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct BalanceDetail {
+    pub amount: U128,
+    /// Weighted mean purchase price, with 18 decimals.
+    pub price: U128,
+}
+
 /// Implementation of a FungibleToken NEP-141 standard.
 #[derive(BorshDeserialize, BorshSerialize)]
 pub struct FungibleToken {
     /// AccountID -> Account balance.
-    accounts: LookupMap<AccountId, AccountBalance>,
+    accounts: LookupMap<AccountId, VAccountBalance>,
+    /// AccountID -> Untagged account balance stored by the first release, moved to
+    /// `accounts` on the first write.
+    legacy_accounts: LookupMap<AccountId, AccountBalance>,
     /// Total supply of the all token.
     total_supply: Balance,
+    /// Rewards distributed to the token holders.
+    rewards: Rewards,
+    /// Accounts with a positive balance.
+    holders: UnorderedSet<AccountId>,
+    /// Number of the account records.
+    account_count: u64,
 }
 
 impl FungibleToken {
@@ -104,20 +161,187 @@ impl FungibleToken {
     where
         S: IntoStorageKey,
     {
+        let prefix = prefix.into_storage_key();
         Self {
-            accounts: LookupMap::new(prefix),
+            accounts: LookupMap::new([prefix.clone(), b"a".to_vec()].concat()),
+            legacy_accounts: LookupMap::new(prefix.clone()),
             total_supply: 0,
+            rewards: Rewards::new([prefix.clone(), b"r".to_vec()].concat()),
+            holders: UnorderedSet::new([prefix, b"h".to_vec()].concat()),
+            account_count: 0,
+        }
+    }
+
+    /// Token with the accounts and the supply stored by the first release. The holders
+    /// and the account count only cover the moved records, see `internal_migrate_account`.
+    pub(crate) fn from_legacy<S>(
+        prefix: S,
+        legacy_accounts: LookupMap<AccountId, AccountBalance>,
+        total_supply: Balance,
+    ) -> Self
+    where
+        S: IntoStorageKey,
+    {
+        Self {
+            legacy_accounts,
+            total_supply,
+            ..Self::new(prefix)
+        }
+    }
+
+    fn get_balance(&self, account_id: &AccountId) -> Option<AccountBalance> {
+        match self.accounts.get(account_id) {
+            Some(balance) => Some(balance.into()),
+            None => self.legacy_accounts.get(account_id),
+        }
+    }
+
+    /// Balance earning the holder rewards, the KT held by the contract itself, e.g. stakes,
+    /// doesn't earn any.
+    fn rewards_balance_of(&self, account_id: &AccountId) -> Balance {
+        if *account_id == env::current_account_id() {
+            return 0;
         }
+        self.internal_unwrap_balance_of(account_id).amount
+    }
+
+    /// Stores the new account balance, accruing the rewards of the old one.
+    /// A legacy record is moved to the current ones.
+    fn internal_set_balance(&mut self, account_id: &AccountId, balance: &AccountBalance) {
+        let old_balance = self.internal_unwrap_balance_of(account_id);
+        self.rewards
+            .checkpoint(account_id, self.rewards_balance_of(account_id));
+        let moved = self.legacy_accounts.remove(account_id).is_some();
+        if self
+            .accounts
+            .insert(account_id, &(*balance).into())
+            .is_none()
+        {
+            self.account_count += 1;
+        }
+        if balance.amount == 0 {
+            self.holders.remove(account_id);
+        } else if old_balance.amount == 0 || moved {
+            self.holders.insert(account_id);
+        }
+    }
+
+    /// Moves the legacy record of the account to the current ones, counting it and adding
+    /// it to the holders. Returns whether there was a legacy record.
+    pub(crate) fn internal_migrate_account(&mut self, account_id: &AccountId) -> bool {
+        let balance = match self.legacy_accounts.remove(account_id) {
+            Some(balance) => balance,
+            None => return false,
+        };
+        self.accounts.insert(account_id, &balance.into());
+        self.account_count += 1;
+        if balance.amount > 0 {
+            self.holders.insert(account_id);
+        }
+        true
+    }
+
+    /// Holders at the indexes, read directly instead of iterating from the first one.
+    /// Removing a holder moves the last one to its index.
+    pub fn holders(&self, from_index: u64, limit: u64) -> Vec<AccountId> {
+        let holders = self.holders.as_vector();
+        let to_index = from_index.saturating_add(limit).min(holders.len());
+        (from_index..to_index)
+            .filter_map(|index| holders.get(index))
+            .collect()
+    }
+
+    pub fn holder_count(&self) -> u64 {
+        self.holders.len()
+    }
+
+    pub fn account_count(&self) -> u64 {
+        self.account_count
+    }
+
+    pub fn is_registered(&self, account_id: &AccountId) -> bool {
+        self.accounts.contains_key(account_id) || self.legacy_accounts.contains_key(account_id)
+    }
+
+    /// Stores an empty record of the account if it isn't registered.
+    pub fn internal_register(&mut self, account_id: &AccountId) {
+        if !self.is_registered(account_id) {
+            self.accounts
+                .insert(account_id, &AccountBalance::default().into());
+            self.account_count += 1;
+        }
+    }
+
+    pub fn internal_distribute_rewards(&mut self, amount: Balance) {
+        let contract_balance = self
+            .internal_unwrap_balance_of(&env::current_account_id())
+            .amount;
+        self.rewards
+            .distribute(amount, self.total_supply - contract_balance);
+    }
+
+    pub fn internal_claim_rewards(&mut self, account_id: &AccountId) -> Balance {
+        let balance = self.rewards_balance_of(account_id);
+        self.rewards.claim(account_id, balance)
+    }
+
+    /// Moves the accrued rewards, checkpointing both balances first.
+    pub fn internal_move_rewards(&mut self, from_id: &AccountId, to_id: &AccountId) {
+        let from = self.rewards_balance_of(from_id);
+        let to = self.rewards_balance_of(to_id);
+        self.rewards.checkpoint(from_id, from);
+        self.rewards.checkpoint(to_id, to);
+        self.rewards.move_accrued(from_id, to_id);
+    }
+
+    pub fn accrued_rewards(&self, account_id: &AccountId) -> Balance {
+        self.rewards
+            .accrued(account_id, self.rewards_balance_of(account_id))
+    }
+
+    pub fn unclaimed_rewards(&self) -> Balance {
+        self.rewards.unclaimed()
+    }
+
+    /// Weighted mean purchase price of the account balance.
+    pub fn cost_basis_of(&self, account_id: &AccountId) -> Price {
+        self.internal_unwrap_balance_of(account_id).price
     }
 
     pub fn internal_unwrap_balance_of(&self, account_id: &AccountId) -> AccountBalance {
-        self.accounts.get(account_id).unwrap_or_default()
+        self.get_balance(account_id).unwrap_or_default()
+    }
+
+    /// Removes the account record. With `force`, the remaining balance is burned and
+    /// the accrued rewards are forfeited. Returns the burned amount, `None` if the account
+    /// isn't registered.
+    pub fn internal_unregister(&mut self, account_id: &AccountId, force: bool) -> Option<Balance> {
+        let balance = self.get_balance(account_id)?;
+        let rewards_balance = self.rewards_balance_of(account_id);
+        if !force {
+            require!(
+                balance.amount == 0,
+                "Can't unregister the account with a positive balance without force"
+            );
+            require!(
+                self.rewards.accrued(account_id, rewards_balance) == 0,
+                "Can't unregister the account with unclaimed rewards without force"
+            );
+        }
+        self.rewards.remove(account_id, rewards_balance);
+        self.legacy_accounts.remove(account_id);
+        self.holders.remove(account_id);
+        if self.accounts.remove(account_id).is_some() {
+            self.account_count -= 1;
+        }
+        self.total_supply -= balance.amount;
+        Some(balance.amount)
     }
 
     pub fn internal_deposit(&mut self, account_id: &AccountId, amount: Balance, price: Price) {
         let balance = self.internal_unwrap_balance_of(account_id);
         if let Some(new_balance) = balance.checked_add(amount, price) {
-            self.accounts.insert(account_id, &new_balance);
+            self.internal_set_balance(account_id, &new_balance);
             self.total_supply = self
                 .total_supply
                 .checked_add(amount)
@@ -130,7 +354,7 @@ impl FungibleToken {
     pub fn internal_withdraw(&mut self, account_id: &AccountId, amount: Balance, price: Price) {
         let balance = self.internal_unwrap_balance_of(account_id);
         if let Some(new_balance) = balance.checked_sub(amount, price) {
-            self.accounts.insert(account_id, &new_balance);
+            self.internal_set_balance(account_id, &new_balance);
             self.total_supply = self
                 .total_supply
                 .checked_sub(amount)
@@ -140,19 +364,34 @@ impl FungibleToken {
         }
     }
 
+    /// Transfers at the sender's weighted mean price, so the cost basis moves with the tokens.
+    /// Returns the transfer price.
     pub fn internal_transfer(
         &mut self,
         sender_id: &AccountId,
         receiver_id: &AccountId,
         amount: Balance,
-        price: Price,
         memo: Option<String>,
-    ) {
+    ) -> Price {
         require!(
             sender_id != receiver_id,
             "Sender and receiver should be different"
         );
         require!(amount > 0, "The amount should be a positive number");
+        let price = self.internal_unwrap_balance_of(sender_id).price;
+        self.internal_transfer_at(sender_id, receiver_id, amount, price, memo);
+        price
+    }
+
+    /// Transfers at the given price, e.g. to return staked KT at its original cost basis.
+    pub fn internal_transfer_at(
+        &mut self,
+        sender_id: &AccountId,
+        receiver_id: &AccountId,
+        amount: Balance,
+        price: Price,
+        memo: Option<String>,
+    ) {
         self.internal_withdraw(sender_id, amount, price);
         self.internal_deposit(receiver_id, amount, price);
         FtTransfer {
@@ -170,8 +409,7 @@ impl FungibleTokenCore for FungibleToken {
         assert_one_yocto();
         let sender_id = env::predecessor_account_id();
         let amount: Balance = amount.into();
-        let price = 0; // FIXME: Get price from Oracle.
-        self.internal_transfer(&sender_id, &receiver_id, amount, price, memo);
+        self.internal_transfer(&sender_id, &receiver_id, amount, memo);
     }
 
     fn ft_transfer_call(
@@ -180,38 +418,47 @@ impl FungibleTokenCore for FungibleToken {
         amount: U128,
         memo: Option<String>,
         msg: String,
+    ) -> PromiseOrValue<U128> {
+        self.internal_transfer_call(receiver_id, amount, memo, msg, &GasConfig::default())
+    }
+
+    fn ft_total_supply(&self) -> U128 {
+        self.total_supply.into()
+    }
+
+    fn ft_balance_of(&self, account_id: AccountId) -> U128 {
+        self.internal_unwrap_balance_of(&account_id).amount.into()
+    }
+}
+
+impl FungibleToken {
+    pub fn internal_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: String,
+        gas: &GasConfig,
     ) -> PromiseOrValue<U128> {
         assert_one_yocto();
-        require!(
-            env::prepaid_gas() > GAS_FOR_TRANSFER_CALL,
-            "More gas is required"
-        );
+        let receiver_gas = gas.receiver_budget();
         let sender_id = env::predecessor_account_id();
         let amount: Balance = amount.into();
-        let price = 0; // FIXME: Get price from Oracle.
-        self.internal_transfer(&sender_id, &receiver_id, amount, price, memo);
+        let price = self.internal_transfer(&sender_id, &receiver_id, amount, memo);
         // Initiating receiver's call and the callback
         ext_ft_receiver::ext(receiver_id.clone())
-            .with_static_gas(env::prepaid_gas() - GAS_FOR_TRANSFER_CALL)
+            .with_static_gas(receiver_gas)
+            .with_unused_gas_weight(1)
             .ft_on_transfer(sender_id.clone(), amount.into(), msg)
             .then(
                 ext_ft_resolver::ext(env::current_account_id())
-                    .with_static_gas(GAS_FOR_RESOLVE_TRANSFER)
+                    .with_static_gas(gas.resolve_transfer)
+                    .with_unused_gas_weight(0)
                     .ft_resolve_transfer(sender_id, receiver_id, amount.into(), price.into()),
             )
             .into()
     }
 
-    fn ft_total_supply(&self) -> U128 {
-        self.total_supply.into()
-    }
-
-    fn ft_balance_of(&self, account_id: AccountId) -> U128 {
-        self.internal_unwrap_balance_of(&account_id).amount.into()
-    }
-}
-
-impl FungibleToken {
     /// Internal method that returns the amount of burned tokens in a corner case when the sender
     /// has deleted (unregistered) their account while the `ft_transfer_call` was still in flight.
     /// Returns (Used token amount, Burned token amount)
@@ -243,14 +490,12 @@ impl FungibleToken {
             if receiver_balance.amount > 0 {
                 let refund_amount = std::cmp::min(receiver_balance.amount, unused_amount);
                 if let Some(new_balance) = receiver_balance.checked_sub(refund_amount, price) {
-                    self.accounts.insert(&receiver_id, &new_balance);
+                    self.internal_set_balance(&receiver_id, &new_balance);
                 }
 
-                if let Some(sender_balance) = self.accounts.get(sender_id) {
-                    if let Some(new_balance) =
-                        sender_balance.checked_add(sender_balance.amount + refund_amount, price)
-                    {
-                        self.accounts.insert(sender_id, &new_balance);
+                if let Some(sender_balance) = self.get_balance(sender_id) {
+                    if let Some(new_balance) = sender_balance.checked_add(refund_amount, price) {
+                        self.internal_set_balance(sender_id, &new_balance);
                     }
 
                     FtTransfer {
@@ -260,6 +505,15 @@ impl FungibleToken {
                         memo: Some("refund"),
                     }
                     .emit();
+                    KtEvent::KtRefund {
+                        operation: RefundOperation::TransferCall,
+                        reason: RefundReason::Unused,
+                        account_id: sender_id,
+                        asset_id: None,
+                        amount: Some(&U128(refund_amount)),
+                        asset_amount: None,
+                    }
+                    .emit();
                     return (amount - refund_amount, 0);
                 } else {
                     // NOTE: this will only happen if we unregister accouns, e.g. when balance is 0.
@@ -280,10 +534,42 @@ impl FungibleToken {
     }
 }
 
+impl Contract {
+    /// Checks the transfer is allowed, charges the relay fee and passes
+    /// the sell cooldown and the acquisition time on to the receiver.
+    /// The relay fee is charged to the caller, i.e. the spender of `transfer_from`.
+    pub(crate) fn internal_before_transfer(
+        &mut self,
+        sender_id: &AccountId,
+        receiver_id: &AccountId,
+        amount: Balance,
+    ) {
+        self.operations.assert_no_pending_sell(sender_id);
+        self.compliance.assert_not_frozen(sender_id);
+        self.compliance.assert_not_frozen(receiver_id);
+        self.charge_relay_fee(&env::predecessor_account_id());
+        self.internal_burn_storage_fee(sender_id, receiver_id, self.storage_fee_of(receiver_id));
+        self.internal_pass_on_transferred(sender_id, receiver_id, amount);
+    }
+
+    pub(crate) fn internal_storage_balance_of(
+        &self,
+        account_id: &AccountId,
+    ) -> Option<StorageBalance> {
+        self.token
+            .is_registered(account_id)
+            .then(|| StorageBalance {
+                total: self.storage_deposits.get(account_id).unwrap_or(0).into(),
+                available: 0.into(),
+            })
+    }
+}
+
 #[near_bindgen]
 impl FungibleTokenCore for Contract {
     #[payable]
     fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>) {
+        self.internal_before_transfer(&env::predecessor_account_id(), &receiver_id, amount.0);
         self.token.ft_transfer(receiver_id, amount, memo)
     }
     #[payable]
@@ -294,7 +580,19 @@ impl FungibleTokenCore for Contract {
         memo: Option<String>,
         msg: String,
     ) -> PromiseOrValue<U128> {
-        self.token.ft_transfer_call(receiver_id, amount, memo, msg)
+        self.internal_before_transfer(&env::predecessor_account_id(), &receiver_id, amount.0);
+        if !self.receiver_allowlist.is_allowed(&receiver_id) {
+            env::panic_str(
+                format!(
+                    "Receiver {} is not allowed for ft_transfer_call",
+                    receiver_id
+                )
+                .as_str(),
+            )
+        }
+        let msg = self.ft_data_to_msg(&env::predecessor_account_id(), &receiver_id, msg);
+        self.token
+            .internal_transfer_call(receiver_id, amount, memo, msg, &self.gas)
     }
     fn ft_total_supply(&self) -> U128 {
         self.token.ft_total_supply()
@@ -304,7 +602,136 @@ impl FungibleTokenCore for Contract {
     }
 }
 
+#[near_bindgen]
+impl Contract {
+    /// Balance of the account along with its cost basis.
+    pub fn ft_balance_detail(&self, account_id: AccountId) -> BalanceDetail {
+        let balance = self.token.internal_unwrap_balance_of(&account_id);
+        BalanceDetail {
+            amount: balance.amount.into(),
+            price: balance.price.into(),
+        }
+    }
+
+    /// Holders of a positive balance. After the upgrade from the first release, the accounts
+    /// which weren't written to since are missing until moved with `migrate_accounts`.
+    pub fn get_holders(&self, from_index: Option<U64>, limit: Option<U64>) -> Vec<AccountId> {
+        self.token.holders(
+            from_index.map_or(0, |index| index.0),
+            limit.map_or(u64::MAX, |limit| limit.0),
+        )
+    }
+
+    pub fn get_holder_count(&self) -> U64 {
+        self.token.holder_count().into()
+    }
+}
+
+/// Accounts are registered either by the KT storage fee of the first credit, or ahead of
+/// it with a NEAR `storage_deposit` which waives the fee and is refunded on unregister.
+#[near_bindgen]
+impl StorageManagement for Contract {
+    /// Registers the account, the attached deposit above the storage cost of its record
+    /// is refunded, all of it if the account is already registered.
+    #[payable]
+    fn storage_deposit(
+        &mut self,
+        account_id: Option<AccountId>,
+        registration_only: Option<bool>,
+    ) -> StorageBalance {
+        // The balance bounds are equal, so every deposit is registration only
+        let _ = registration_only;
+        let account_id = account_id.unwrap_or_else(env::predecessor_account_id);
+        if self.token.is_registered(&account_id) {
+            log_str("The account is already registered, refunding the deposit");
+            self.internal_refund_storage(&env::predecessor_account_id(), env::attached_deposit());
+        } else {
+            let initial_usage = env::storage_usage();
+            self.token.internal_register(&account_id);
+            self.storage_deposits.insert(&account_id, &0);
+            let cost = self.internal_charge_storage(initial_usage);
+            self.storage_deposits.insert(&account_id, &cost);
+        }
+        self.internal_storage_balance_of(&account_id).unwrap()
+    }
+
+    /// Nothing is withdrawable as the whole deposit pays for the account record.
+    #[payable]
+    fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let balance = self
+            .internal_storage_balance_of(&account_id)
+            .unwrap_or_else(|| {
+                env::panic_str(format!("The account {} is not registered", &account_id).as_str())
+            });
+        require!(
+            matches!(amount, None | Some(U128(0))),
+            "The amount is greater than the available storage balance"
+        );
+        balance
+    }
+
+    /// Removes the account of the caller to free its storage. With `force` the remaining
+    /// balance, which with the pending rewards must be below the dust threshold, is burned
+    /// and its backing moves to the insurance fund. The NEAR storage deposit is refunded,
+    /// the KT storage fee of an account registered by a credit is kept as a protocol fee.
+    /// Returns `false` if the account isn't registered.
+    #[payable]
+    fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        self.compliance.assert_not_frozen(&account_id);
+        self.operations.assert_no_pending_sell(&account_id);
+        let force = force.unwrap_or(false);
+        if force {
+            let balance = self.token.internal_unwrap_balance_of(&account_id).amount;
+            require!(
+                balance + self.token.accrued_rewards(&account_id) < self.dust_threshold,
+                "Can't force unregister the account with a balance above the dust threshold"
+            );
+        }
+        let supply = self.token.ft_total_supply().0;
+        let burned = match self.token.internal_unregister(&account_id, force) {
+            Some(burned) => burned,
+            None => return false,
+        };
+        self.internal_insure_backing(burned, supply);
+        if let Some(deposit) = self.storage_deposits.remove(&account_id) {
+            self.internal_refund_storage(&account_id, deposit);
+        }
+
+        if burned > 0 {
+            FtBurn {
+                owner_id: &account_id,
+                amount: &burned.into(),
+                memo: Some("unregister"),
+            }
+            .emit();
+        }
+        KtEvent::AccountUnregistered {
+            account_id: &account_id,
+            burned: &burned.into(),
+        }
+        .emit();
+        true
+    }
+
+    fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        let deposit = Balance::from(ACCOUNT_STORAGE_USAGE) * env::storage_byte_cost();
+        StorageBalanceBounds {
+            min: deposit.into(),
+            max: Some(deposit.into()),
+        }
+    }
+
+    fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
+        self.internal_storage_balance_of(&account_id)
+    }
+}
+
 #[ext_contract(ext_ft_resolver)]
+#[allow(dead_code)]
 trait FungibleTokenResolver {
     fn ft_resolve_transfer(
         &mut self,
@@ -342,13 +769,80 @@ impl FungibleTokenMetadataProvider for Contract {
     }
 }
 
-// TODO: impl ft_data_to_msg for Contract
+/// Arguments of the `Buy` message, the legacy ones are just the expected price.
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde", untagged)]
+enum BuyMessage {
+    Legacy(Option<(U128, u8, U128)>),
+    Options {
+        #[serde(default)]
+        expected: Option<(U128, u8, U128)>,
+        #[serde(default)]
+        referrer_id: Option<AccountId>,
+        /// Client order id echoed in the buy events.
+        #[serde(default)]
+        memo: Option<String>,
+    },
+}
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, JsonSchema)]
 #[serde(crate = "near_sdk::serde")]
 enum OnTransferMessage {
-    Buy(Option<(U128, u8, U128)>),
-    // TODO: Rebalance
+    /// Buys KT for the deposit, the referrer is credited a share of the buy fee.
+    Buy(BuyMessage),
+    /// Swaps a token outside of the treasury into a supported asset on the DEX along
+    /// the route, then buys KT for the swapped amount.
+    BuyVia {
+        route: Vec<SwapAction>,
+        #[serde(default)]
+        expected: Option<(U128, u8, U128)>,
+        #[serde(default)]
+        referrer_id: Option<AccountId>,
+    },
+    /// Buys KT for the deposit at the signed price, without the oracle call.
+    BuySigned {
+        price: SignedPrice,
+        #[serde(default)]
+        expected: Option<(U128, u8, U128)>,
+        #[serde(default)]
+        referrer_id: Option<AccountId>,
+    },
+    /// Buys the exact KT amount, the rest of the deposit is returned as unused.
+    BuyExact {
+        amount: U128,
+        expected: Option<(U128, u8, U128)>,
+    },
+    /// Splits the deposit into buys for the receivers by their asset amounts,
+    /// the unallocated rest is returned as unused.
+    BuyBatch {
+        receivers: Vec<(AccountId, U128)>,
+        expected: Option<(U128, u8, U128)>,
+    },
+    /// Reveals the committed buy of the deposit and buys KT at the current price.
+    Reveal {
+        salt: String,
+        #[serde(default)]
+        referrer_id: Option<AccountId>,
+    },
+    /// Holds the deposit in a buy order until the asset price reaches the limit.
+    LimitBuy {
+        limit_price: U128,
+        expires_at: Option<U64>,
+    },
+    /// Holds the deposit to buy KT with `amount_per_buy` once every `interval` nanoseconds.
+    ScheduleBuy { amount_per_buy: U128, interval: U64 },
+    /// Deposits the asset to the treasury without minting KT.
+    Donate,
+    /// Swaps the deposited asset for another treasury asset, restricted to keepers.
+    Rebalance {
+        asset_id: AssetId,
+        min_amount: Option<U128>,
+    },
+    /// Swaps the deposited asset for another treasury asset at the oracle prices.
+    Swap {
+        asset_id: AssetId,
+        min_amount: Option<U128>,
+    },
 }
 
 impl TryFrom<&str> for OnTransferMessage {
@@ -368,42 +862,467 @@ impl FungibleTokenReceiver for Contract {
         msg: String,
     ) -> PromiseOrValue<U128> {
         require!(
-            env::prepaid_gas() > GAS_FOR_ON_TRANSFER,
+            env::prepaid_gas() > self.gas.on_transfer(),
             "More gas is required"
         );
 
         let contract_id = env::current_account_id();
         let asset_id = env::predecessor_account_id();
 
-        let msg = OnTransferMessage::try_from(msg.as_str())
-            .unwrap_or_else(|_| env::panic_str(format!("Invalid message: {}", msg).as_ref()));
+        // Fee-on-transfer assets deliver less than the stated amount
+        let stated_amount = amount;
+        let charged = self
+            .treasury
+            .get(&asset_id)
+            .filter(|asset| asset.transfer_fee > 0);
+        let amount = charged
+            .as_ref()
+            .map_or(amount, |asset| asset.received_amount(amount.0).into());
+        // A panic makes the token refund the stated amount, partly out of the treasury,
+        // so rejected fee-on-transfer deposits are handed back as received instead
+        let msg = match OnTransferMessage::try_from(msg.as_str()) {
+            Ok(msg) => msg,
+            Err(_) if charged.is_some() => return PromiseOrValue::Value(amount),
+            Err(_) => env::panic_str(format!("Invalid message: {}", msg).as_ref()),
+        };
 
-        match msg {
-            OnTransferMessage::Buy(expected) => {
+        let (expected, kt_amount, receivers, referrer_id, salt, memo) = match msg {
+            OnTransferMessage::Buy(BuyMessage::Legacy(expected)) => {
+                (expected, None, None, None, None, None)
+            }
+            OnTransferMessage::Buy(BuyMessage::Options {
+                expected,
+                referrer_id,
+                memo,
+            }) => (expected, None, None, referrer_id, None, memo),
+            OnTransferMessage::BuyExact {
+                amount: kt_amount,
+                expected,
+            } => (expected, Some(kt_amount), None, None, None, None),
+            OnTransferMessage::Reveal { salt, referrer_id } => {
+                (None, None, None, referrer_id, Some(salt), None)
+            }
+            OnTransferMessage::BuyBatch {
+                receivers,
+                expected,
+            } => (expected, None, Some(receivers), None, None, None),
+            OnTransferMessage::LimitBuy {
+                limit_price,
+                expires_at,
+            } => {
+                self.internal_place_buy_order(
+                    &sender_id,
+                    &asset_id,
+                    amount,
+                    limit_price,
+                    expires_at,
+                );
+                return PromiseOrValue::Value(U128::from(0));
+            }
+            OnTransferMessage::ScheduleBuy {
+                amount_per_buy,
+                interval,
+            } => {
+                self.internal_schedule_buy(&sender_id, &asset_id, amount, amount_per_buy, interval);
+                return PromiseOrValue::Value(U128::from(0));
+            }
+            OnTransferMessage::BuySigned {
+                price,
+                expected,
+                referrer_id,
+            } => {
                 let expected = expected.map(|(multiplier, decimals, slippage)| {
                     ExpectedPrice::new(multiplier, decimals, slippage)
                 });
-
-                self.treasury
-                    .assert_asset_status(&asset_id, AssetStatus::Enabled);
-
-                ext_oracle::ext(self.oracle_id.clone())
-                    .with_static_gas(GAS_FOR_GET_EXCHANGE_PRICE)
-                    .get_exchange_price(asset_id.clone())
-                    .then(
-                        ext_self::ext(contract_id)
-                            .with_static_gas(GAS_FOR_BUY_WITH_PRICE)
-                            .buy_with_price(sender_id, asset_id, amount, expected),
-                    )
+                return PromiseOrValue::Value(self.internal_buy_signed(
+                    sender_id,
+                    asset_id,
+                    amount,
+                    price,
+                    expected,
+                    referrer_id,
+                ));
+            }
+            OnTransferMessage::BuyVia {
+                route,
+                expected,
+                referrer_id,
+            } => {
+                let expected = expected.map(|(multiplier, decimals, slippage)| {
+                    ExpectedPrice::new(multiplier, decimals, slippage)
+                });
+                let order = BuyViaOrder {
+                    account_id: sender_id,
+                    expected,
+                    referrer_id,
+                };
+                return self.start_buy_via(asset_id, amount, route, order).into();
+            }
+            OnTransferMessage::Donate => {
+                self.internal_donate(&sender_id, &asset_id, amount.into());
+                return PromiseOrValue::Value(U128::from(0));
+            }
+            OnTransferMessage::Rebalance {
+                asset_id: asset_out,
+                min_amount,
+            } => {
+                return self
+                    .start_rebalance(sender_id, asset_id, amount, asset_out, min_amount)
+                    .into()
+            }
+            OnTransferMessage::Swap {
+                asset_id: asset_out,
+                min_amount,
+            } => {
+                return self
+                    .start_swap(sender_id, asset_id, amount, asset_out, min_amount)
                     .into()
             }
+        };
+        let expected = expected.map(|(multiplier, decimals, slippage)| {
+            ExpectedPrice::new(multiplier, decimals, slippage)
+        });
+
+        if charged.is_some_and(|asset| self.sell_only || !asset.status.can_buy()) {
+            return PromiseOrValue::Value(amount);
+        }
+        assert_valid_memo(memo.as_deref());
+        self.assert_buys_enabled();
+        let asset = self.treasury.assert_can_buy(&asset_id);
+        // Dust isn't worth the oracle call and may mint no KT at all
+        if amount.0 < asset.min_buy {
+            return PromiseOrValue::Value(amount);
         }
+        if let Some(receivers) = &receivers {
+            require!(!receivers.is_empty(), "No batch receivers");
+            let total = receivers
+                .iter()
+                .try_fold(0, |total: Balance, (_, amount)| total.checked_add(amount.0))
+                .unwrap_or_else(|| env::panic_str("Batch amount overflow"));
+            require!(
+                total <= amount.0,
+                "Batch amounts exceed the transferred amount"
+            );
+        }
+        let kyc_check = self.kyc_check(&sender_id);
+        let buy_gas = self.gas.buy_budget(
+            self.price_gas(&asset_id),
+            receivers.as_ref().map_or(0, Vec::len),
+            kyc_check.is_some(),
+        );
+        if let Some(salt) = salt {
+            self.commitments
+                .reveal(&sender_id, &asset_id, stated_amount, &salt);
+        }
+        let operation_id = self.operations.start(
+            &sender_id,
+            OperationKind::Buy,
+            Some(asset_id.clone()),
+            amount,
+            OperationStage::Pricing,
+        );
+
+        let peg_amount = convert_decimals(amount.0, asset.decimals, KT_DECIMALS);
+        let get_price = self.get_price(&asset_id, peg_amount);
+        let get_price = match kyc_check {
+            Some(kyc_check) => get_price.and(kyc_check),
+            None => get_price,
+        };
+        let buy = match receivers {
+            Some(receivers) => ext_self::ext(contract_id.clone())
+                .with_static_gas(buy_gas)
+                .with_unused_gas_weight(1)
+                .buy_batch_with_price(
+                    sender_id,
+                    asset_id,
+                    amount,
+                    receivers,
+                    expected,
+                    operation_id.into(),
+                ),
+            None => ext_self::ext(contract_id.clone())
+                .with_static_gas(buy_gas)
+                .with_unused_gas_weight(1)
+                .buy_with_price(
+                    sender_id,
+                    asset_id,
+                    amount,
+                    expected,
+                    BuyOptions {
+                        kt_amount,
+                        referrer_id,
+                        memo,
+                        operation_id: Some(operation_id.into()),
+                    },
+                ),
+        };
+        get_price
+            .then(buy)
+            .then(
+                ext_operation_resolver::ext(contract_id)
+                    .with_static_gas(self.gas.finish_operation)
+                    .with_unused_gas_weight(0)
+                    .finish_buy(operation_id.into(), amount),
+            )
+            .into()
     }
 }
 
 #[cfg(all(test, not(target_arch = "wasm32")))]
 mod tests {
-    use crate::ft::AccountBalance;
+    use near_contract_standards::fungible_token::core::FungibleTokenCore;
+    use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+    use near_contract_standards::storage_management::StorageManagement;
+    use near_sdk::borsh::{BorshDeserialize, BorshSerialize};
+    use near_sdk::collections::LookupMap;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, AccountId, Gas, PromiseOrValue, ONE_YOCTO};
+
+    use crate::ft::{
+        AccountBalance, BuyMessage, FungibleToken, OnTransferMessage, VAccountBalance,
+    };
+    use crate::{Contract, StorageKey};
+
+    #[test]
+    fn test_account_balance_versions() {
+        let balance = AccountBalance::new(100, 1_000_000);
+        let legacy = balance.try_to_vec().unwrap();
+        let current = VAccountBalance::from(balance).try_to_vec().unwrap();
+        // The current records are the legacy ones tagged with the variant index
+        assert_eq!(current[0], 0);
+        assert_eq!(current[1..], legacy);
+
+        let balance = AccountBalance::from(VAccountBalance::try_from_slice(&current).unwrap());
+        assert_eq!(balance.amount, 100);
+        assert_eq!(balance.price, 1_000_000);
+    }
+
+    #[test]
+    fn test_legacy_account() {
+        testing_env!(VMContextBuilder::new().build());
+        let mut legacy_accounts = LookupMap::new(StorageKey::FungibleToken);
+        legacy_accounts.insert(&accounts(1), &AccountBalance::new(100, 1_000_000));
+        let mut token = FungibleToken::from_legacy(StorageKey::FungibleToken, legacy_accounts, 100);
+        assert!(token.is_registered(&accounts(1)));
+        assert_eq!(token.internal_unwrap_balance_of(&accounts(1)).amount, 100);
+        assert_eq!(token.account_count(), 0);
+
+        // The first write moves the record
+        token.internal_deposit(&accounts(1), 50, 1_000_000);
+        assert!(token.legacy_accounts.get(&accounts(1)).is_none());
+        assert_eq!(token.internal_unwrap_balance_of(&accounts(1)).amount, 150);
+        assert_eq!(token.account_count(), 1);
+        assert_eq!(token.holders(0, 10), vec![accounts(1)]);
+    }
+
+    #[test]
+    fn test_transfer_price() {
+        let mut token = FungibleToken::new(StorageKey::FungibleToken);
+        token.internal_deposit(&accounts(1), 100, 1_000_000);
+        token.internal_deposit(&accounts(2), 100, 2_000_000);
+
+        let price = token.internal_transfer(&accounts(1), &accounts(2), 50, None);
+        assert_eq!(price, 1_000_000);
+        assert_eq!(
+            token.internal_unwrap_balance_of(&accounts(1)).price,
+            1_000_000
+        );
+        // (100 * 2 + 50 * 1) / 150 = 1.666
+        let balance = token.internal_unwrap_balance_of(&accounts(2));
+        assert_eq!(balance.amount, 150);
+        assert_eq!(balance.price, 1_666_667);
+    }
+
+    #[test]
+    fn test_holders() {
+        let mut token = FungibleToken::new(StorageKey::FungibleToken);
+        token.internal_deposit(&accounts(1), 100, 1);
+        token.internal_deposit(&accounts(2), 100, 1);
+        token.internal_deposit(&accounts(2), 100, 1);
+        assert_eq!(token.holder_count(), 2);
+        assert_eq!(token.holders(1, 10), vec![accounts(2)]);
+        assert_eq!(token.holders(0, 1), vec![accounts(1)]);
+        assert!(token.holders(2, u64::MAX).is_empty());
+
+        token.internal_transfer(&accounts(1), &accounts(3), 100, None);
+        assert_eq!(token.holders(0, 10), vec![accounts(2), accounts(3)]);
+    }
+
+    #[test]
+    fn test_buy_message_formats() {
+        let legacy = OnTransferMessage::try_from(r#"{"Buy":["100",8,"1"]}"#).unwrap();
+        assert!(matches!(
+            legacy,
+            OnTransferMessage::Buy(BuyMessage::Legacy(Some((multiplier, 8, _)))) if multiplier.0 == 100
+        ));
+        let legacy = OnTransferMessage::try_from(r#"{"Buy":null}"#).unwrap();
+        assert!(matches!(
+            legacy,
+            OnTransferMessage::Buy(BuyMessage::Legacy(None))
+        ));
+        let options = OnTransferMessage::try_from(r#"{"Buy":{"referrer_id":"bob"}}"#).unwrap();
+        assert!(matches!(
+            options,
+            OnTransferMessage::Buy(BuyMessage::Options { referrer_id: Some(referrer_id), .. })
+                if referrer_id == accounts(1)
+        ));
+    }
+
+    #[test]
+    fn test_on_transfer_rejects_fee_asset_as_received() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(3), 6);
+        contract.treasury.set_transfer_fee(&accounts(3), 30);
+        contract.sell_only = true;
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        for msg in [r#"{"Buy":{}}"#, "invalid"] {
+            let unused = contract.ft_on_transfer(accounts(1), 1_000_000.into(), msg.to_string());
+            assert!(matches!(unused, PromiseOrValue::Value(unused) if unused.0 == 997_000));
+        }
+    }
+
+    #[test]
+    fn test_ft_balance_detail() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract
+            .token
+            .internal_deposit(&accounts(1), 100, 1_000_000);
+
+        testing_env!(context.is_view(true).build());
+        let detail = contract.ft_balance_detail(accounts(1));
+        assert_eq!(detail.amount.0, 100);
+        assert_eq!(detail.price.0, 1_000_000);
+    }
+
+    #[test]
+    fn test_storage_unregister() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.set_dust_threshold(101.into());
+        contract
+            .token
+            .internal_deposit(&accounts(1), 100, 1_000_000);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        assert!(contract.storage_unregister(Some(true)));
+        assert_eq!(contract.ft_total_supply().0, 0);
+        assert_eq!(contract.get_holder_count().0, 0);
+        assert!(!contract.storage_unregister(None));
+    }
+
+    #[test]
+    fn test_storage_deposit() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.set_account_storage_fee(10.into());
+        let bounds = contract.storage_balance_bounds();
+        let account_id: AccountId = "a".repeat(64).parse().unwrap();
+        assert!(contract.storage_balance_of(account_id.clone()).is_none());
+
+        // The deposit covers the record of the longest account ID
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(bounds.min.0)
+            .build());
+        let balance = contract.storage_deposit(Some(account_id.clone()), None);
+        assert!(balance.total.0 > 0 && balance.total.0 <= bounds.min.0);
+        assert_eq!(balance.available.0, 0);
+        assert_eq!(contract.storage_fee_of(&account_id), 0);
+
+        // Depositing again changes nothing
+        let again = contract.storage_deposit(Some(account_id.clone()), None);
+        assert_eq!(again.total, balance.total);
+
+        testing_env!(context
+            .predecessor_account_id(account_id.clone())
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        assert!(contract.storage_unregister(None));
+        assert!(contract.storage_balance_of(account_id.clone()).is_none());
+        assert!(contract.storage_deposits.get(&account_id).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Attached deposit doesn't cover the storage cost")]
+    fn test_storage_deposit_insufficient() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.storage_deposit(None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Can't unregister the account with a positive balance without force")]
+    fn test_storage_unregister_positive_balance() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract
+            .token
+            .internal_deposit(&accounts(1), 100, 1_000_000);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        contract.storage_unregister(None);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Can't force unregister the account with a balance above the dust threshold"
+    )]
+    fn test_storage_unregister_above_dust() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.set_dust_threshold(100.into());
+        contract
+            .token
+            .internal_deposit(&accounts(1), 100, 1_000_000);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        contract.storage_unregister(Some(true));
+    }
+
+    #[test]
+    #[should_panic(expected = "Receiver charlie is not allowed for ft_transfer_call")]
+    fn test_transfer_call_receiver_not_allowed() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract
+            .token
+            .internal_deposit(&accounts(1), 100, 1_000_000);
+        contract.set_receiver_allowlist_enabled(true);
+        contract.add_allowed_receivers(vec![accounts(3)]);
+        assert!(contract.is_receiver_allowed(accounts(3)));
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(ONE_YOCTO)
+            .prepaid_gas(Gas(300_000_000_000_000))
+            .build());
+        contract.ft_transfer_call(accounts(2), 10.into(), None, String::new());
+    }
 
     #[test]
     fn test_account_balance() {
@@ -0,0 +1,340 @@
+//! Gas allocations of the cross-contract calls, tunable by the owner after protocol
+//! upgrades change the costs. Chained allocations are derived from the base ones.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen, require, Gas};
+use schemars::JsonSchema;
+
+use crate::{Contract, ContractExt};
+
+/// Maximum gas attachable to a transaction.
+const MAX_PREPAID_GAS: Gas = Gas(300_000_000_000_000);
+/// Least gas passed to an `ft_transfer_call` receiver.
+const MIN_GAS_FOR_RECEIVER: Gas = Gas(5_000_000_000_000);
+/// Gas kept for the rest of the current call after the budget is taken.
+const GAS_RESERVE: Gas = Gas(2_000_000_000_000);
+
+/// Static gas of a call which gets all the unused prepaid gas through its weight,
+/// the other calls of the receipt must have no weight. Fails before any state change
+/// if the unused gas doesn't cover `reserved` and `minimum`.
+pub fn gas_budget(reserved: Gas, minimum: Gas) -> Gas {
+    let remaining = env::prepaid_gas() - env::used_gas();
+    let required = reserved + GAS_RESERVE + minimum;
+    if remaining < required {
+        env::panic_str(
+            format!(
+                "More gas is required, attach at least {} TGas more",
+                (required.0 - remaining.0).div_ceil(Gas::ONE_TERA.0)
+            )
+            .as_str(),
+        )
+    }
+    minimum
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct GasConfig {
+    pub buy_with_price: Gas,
+    pub batch_buy: Gas,
+    pub resolve_sell: Gas,
+    pub cache_price: Gas,
+    pub resolve_claim_redemption: Gas,
+    pub resolve_claim_asset: Gas,
+    pub finish_operation: Gas,
+    pub ft_metadata: Gas,
+    pub storage_deposit: Gas,
+    pub resolve_add_asset: Gas,
+    pub resolve_emergency_withdraw: Gas,
+    pub resolve_withdraw_fees: Gas,
+    pub resolve_reconcile: Gas,
+    pub resolve_rebalance: Gas,
+    pub resolve_swap: Gas,
+    pub resolve_buy_via: Gas,
+    // DEX
+    pub dex_deposit: Gas,
+    pub dex_swap: Gas,
+    pub dex_withdraw: Gas,
+    pub resolve_dex_withdraw: Gas,
+    // Strategy
+    pub strategy_deposit: Gas,
+    pub strategy_withdraw: Gas,
+    pub strategy_harvest: Gas,
+    pub strategy_report: Gas,
+    pub resolve_strategy: Gas,
+    pub burrow_view: Gas,
+    pub burrow_report: Gas,
+    pub resolve_burrow_withdraw: Gas,
+    // FT
+    pub transfer: Gas,
+    pub resolve_transfer: Gas,
+    pub ft_balance_of: Gas,
+    // Oracle
+    pub get_exchange_price: Gas,
+    pub resolve_price: Gas,
+    // KYC verifier
+    pub kyc_check: Gas,
+    pub resolve_kyc_check: Gas,
+    // wNEAR
+    pub near_deposit: Gas,
+    pub near_withdraw: Gas,
+    pub resolve_near_refund: Gas,
+}
+
+impl Default for GasConfig {
+    fn default() -> Self {
+        Self {
+            buy_with_price: Gas(25_000_000_000_000),
+            batch_buy: Gas(5_000_000_000_000),
+            resolve_sell: Gas(25_000_000_000_000),
+            cache_price: Gas(5_000_000_000_000),
+            resolve_claim_redemption: Gas(5_000_000_000_000),
+            resolve_claim_asset: Gas(5_000_000_000_000),
+            finish_operation: Gas(5_000_000_000_000),
+            ft_metadata: Gas(10_000_000_000_000),
+            storage_deposit: Gas(10_000_000_000_000),
+            resolve_add_asset: Gas(10_000_000_000_000),
+            resolve_emergency_withdraw: Gas(5_000_000_000_000),
+            resolve_withdraw_fees: Gas(5_000_000_000_000),
+            resolve_reconcile: Gas(5_000_000_000_000),
+            resolve_rebalance: Gas(10_000_000_000_000),
+            resolve_swap: Gas(10_000_000_000_000),
+            resolve_buy_via: Gas(10_000_000_000_000),
+            dex_deposit: Gas(40_000_000_000_000),
+            dex_swap: Gas(20_000_000_000_000),
+            dex_withdraw: Gas(30_000_000_000_000),
+            resolve_dex_withdraw: Gas(10_000_000_000_000),
+            strategy_deposit: Gas(40_000_000_000_000),
+            strategy_withdraw: Gas(30_000_000_000_000),
+            strategy_harvest: Gas(30_000_000_000_000),
+            strategy_report: Gas(10_000_000_000_000),
+            resolve_strategy: Gas(10_000_000_000_000),
+            burrow_view: Gas(10_000_000_000_000),
+            burrow_report: Gas(5_000_000_000_000),
+            resolve_burrow_withdraw: Gas(5_000_000_000_000),
+            transfer: Gas(450_000_000_000),
+            resolve_transfer: Gas(5_000_000_000_000),
+            ft_balance_of: Gas(5_000_000_000_000),
+            get_exchange_price: Gas(25_000_000_000_000),
+            resolve_price: Gas(5_000_000_000_000),
+            kyc_check: Gas(10_000_000_000_000),
+            resolve_kyc_check: Gas(5_000_000_000_000),
+            near_deposit: Gas(5_000_000_000_000),
+            near_withdraw: Gas(5_000_000_000_000),
+            resolve_near_refund: Gas(5_000_000_000_000),
+        }
+    }
+}
+
+impl GasConfig {
+    pub fn sell_with_price(&self) -> Gas {
+        Gas(2_000_000_000_000) + self.transfer + self.resolve_sell
+    }
+
+    pub fn rebalance_with_prices(&self) -> Gas {
+        Gas(10_000_000_000_000) + self.transfer + self.resolve_rebalance
+    }
+
+    pub fn rebalance(&self) -> Gas {
+        self.get_exchange_price * 2 + self.rebalance_with_prices()
+    }
+
+    pub fn swap_with_prices(&self) -> Gas {
+        Gas(10_000_000_000_000) + self.transfer + self.resolve_swap
+    }
+
+    pub fn swap(&self) -> Gas {
+        self.get_exchange_price * 2 + self.swap_with_prices()
+    }
+
+    pub fn resolve_dex_swap(&self) -> Gas {
+        Gas(10_000_000_000_000) + self.dex_withdraw + self.resolve_dex_withdraw
+    }
+
+    pub fn resolve_dex_deposit(&self) -> Gas {
+        Gas(10_000_000_000_000) + self.dex_swap + self.resolve_dex_swap()
+    }
+
+    pub fn rebalance_via_dex(&self) -> Gas {
+        Gas(10_000_000_000_000) + self.dex_deposit + self.resolve_dex_deposit()
+    }
+
+    pub fn resolve_buy_via_withdraw(&self) -> Gas {
+        self.resolve_buy_via * 2
+            + self.get_exchange_price
+            + self.kyc_check
+            + self.buy_with_price
+            + self.transfer
+            + self.resolve_claim_asset
+    }
+
+    pub fn resolve_claim_dex_token(&self) -> Gas {
+        self.resolve_buy_via + self.transfer + self.resolve_claim_asset
+    }
+
+    pub fn claim_dex_token(&self) -> Gas {
+        self.dex_withdraw + self.resolve_claim_dex_token()
+    }
+
+    pub fn resolve_buy_via_swap(&self) -> Gas {
+        self.resolve_buy_via + self.dex_withdraw + self.resolve_buy_via_withdraw()
+    }
+
+    pub fn resolve_buy_via_deposit(&self) -> Gas {
+        self.resolve_buy_via + self.dex_swap + self.resolve_buy_via_swap()
+    }
+
+    pub fn buy_via(&self) -> Gas {
+        self.dex_deposit + self.resolve_buy_via_deposit()
+    }
+
+    pub fn burrow_harvest(&self) -> Gas {
+        Gas(10_000_000_000_000) + self.strategy_withdraw + self.resolve_burrow_withdraw
+    }
+
+    pub fn transfer_call(&self) -> Gas {
+        Gas(25_000_000_000_000) + self.resolve_transfer
+    }
+
+    /// Static gas of the receiver call of `ft_transfer_call`, which gets the unused gas.
+    pub fn receiver_budget(&self) -> Gas {
+        gas_budget(self.resolve_transfer, MIN_GAS_FOR_RECEIVER)
+    }
+
+    /// Gas of the price check retrying the fallback oracle.
+    pub fn fallback_price(&self) -> Gas {
+        self.resolve_price + self.get_exchange_price
+    }
+
+    /// Static gas of the buy callback, which gets the unused gas,
+    /// the oracle, KYC and operation calls get their allocations.
+    pub fn buy_budget(&self, price_gas: Gas, batch_size: usize, kyc_check: bool) -> Gas {
+        let mut reserved = price_gas + self.finish_operation;
+        if kyc_check {
+            reserved += self.kyc_check;
+        }
+        gas_budget(
+            reserved,
+            self.buy_with_price + self.batch_buy * batch_size as u64,
+        )
+    }
+
+    pub fn on_transfer(&self) -> Gas {
+        Gas(2_000_000_000_000)
+            + self.get_exchange_price
+            + self.buy_with_price
+            + self.finish_operation
+    }
+
+    pub fn resolve_near_buy(&self) -> Gas {
+        Gas(5_000_000_000_000) + self.near_withdraw + self.resolve_near_refund
+    }
+
+    pub fn on_near_wrapped(&self) -> Gas {
+        Gas(10_000_000_000_000)
+            + self.get_exchange_price
+            + self.kyc_check
+            + self.buy_with_price
+            + self.resolve_near_buy()
+    }
+
+    pub fn buy_with_near(&self) -> Gas {
+        self.near_deposit + self.on_near_wrapped()
+    }
+
+    pub fn sell_for_near_with_price(&self) -> Gas {
+        Gas(10_000_000_000_000) + self.near_withdraw + self.resolve_sell
+    }
+
+    pub fn execute_order_with_price(&self) -> Gas {
+        Gas(10_000_000_000_000) + self.sell_with_price()
+    }
+
+    /// Every call chain has to fit in a single transaction.
+    pub fn assert_valid(&self) {
+        let chains = [
+            self.transfer_call() + self.on_transfer(),
+            self.transfer_call() + self.buy_via(),
+            self.get_exchange_price
+                + self.fallback_price()
+                + self.sell_with_price() * 2
+                + self.finish_operation
+                + self.kyc_check,
+            self.rebalance(),
+            self.swap(),
+            self.rebalance_via_dex(),
+            self.buy_with_near(),
+            self.get_exchange_price
+                + self.fallback_price()
+                + self.sell_for_near_with_price()
+                + self.finish_operation
+                + self.kyc_check,
+            self.burrow_harvest(),
+        ];
+        require!(
+            chains.iter().all(|gas| *gas <= MAX_PREPAID_GAS),
+            "Gas config exceeds the maximum prepaid gas"
+        );
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn set_gas_config(&mut self, config: GasConfig) {
+        self.assert_owner();
+        config.assert_valid();
+        self.gas = config;
+    }
+
+    pub fn get_gas_config(&self) -> GasConfig {
+        self.gas.clone()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, Gas};
+
+    use super::{gas_budget, GasConfig};
+    use crate::Contract;
+
+    #[test]
+    fn test_default_gas_config() {
+        GasConfig::default().assert_valid();
+    }
+
+    #[test]
+    fn test_buy_budget() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.prepaid_gas(Gas(300_000_000_000_000)).build());
+        let gas = GasConfig::default();
+        // The unused gas goes to the buy through its weight
+        assert_eq!(
+            gas.buy_budget(gas.get_exchange_price, 0, false),
+            gas.buy_with_price
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "More gas is required, attach at least")]
+    fn test_gas_budget_exceeded() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.prepaid_gas(Gas(20_000_000_000_000)).build());
+        gas_budget(Gas(15_000_000_000_000), Gas(10_000_000_000_000));
+    }
+
+    #[test]
+    #[should_panic(expected = "Gas config exceeds the maximum prepaid gas")]
+    fn test_set_gas_config_exceeded() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.set_gas_config(GasConfig {
+            buy_with_price: Gas(250_000_000_000_000),
+            ..GasConfig::default()
+        });
+    }
+}
@@ -0,0 +1,314 @@
+//! Redemption fee decaying with how long the sold KT was held, to discourage hot-money
+//! flows that stress the treasury. The holding time starts at the mean acquisition time
+//! of the account balance, weighted by the bought and received amounts.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::json_types::U64;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen, require, AccountId, Balance, IntoStorageKey};
+use schemars::JsonSchema;
+
+use crate::events::KtEvent;
+use crate::oracle::ExchangePrice;
+use crate::treasury::AssetId;
+use crate::{Contract, ContractExt, BASIS_POINTS};
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct HoldingFeeTier {
+    /// Nanoseconds the KT was held for less than.
+    pub held_under: U64,
+    /// Fee taken from the sold asset amount, in basis points.
+    pub fee: u16,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct HoldingFee {
+    /// Tiers by increasing holding time with decreasing fees, disabled if empty.
+    tiers: Vec<HoldingFeeTier>,
+    /// AccountID -> Weighted mean acquisition time of the balance.
+    acquired_at: LookupMap<AccountId, u64>,
+}
+
+impl HoldingFee {
+    pub fn new<S>(prefix: S) -> Self
+    where
+        S: IntoStorageKey,
+    {
+        Self {
+            tiers: Vec::new(),
+            acquired_at: LookupMap::new(prefix),
+        }
+    }
+
+    pub fn tiers(&self) -> &[HoldingFeeTier] {
+        &self.tiers
+    }
+
+    pub fn set_tiers(&mut self, tiers: Vec<HoldingFeeTier>) {
+        for (i, tier) in tiers.iter().enumerate() {
+            require!(tier.fee <= BASIS_POINTS, "Fee is out of bounds");
+            if let Some(next) = tiers.get(i + 1) {
+                require!(
+                    tier.held_under.0 < next.held_under.0 && tier.fee >= next.fee,
+                    "Holding fee tiers should decrease with the holding time"
+                );
+            }
+        }
+        self.tiers = tiers;
+    }
+
+    /// Holding time of the account balance, `None` if the acquisitions aren't tracked.
+    pub fn held_for(&self, account_id: &AccountId) -> Option<u64> {
+        let acquired_at = self.acquired_at.get(account_id)?;
+        Some(env::block_timestamp().saturating_sub(acquired_at))
+    }
+
+    /// Fee in basis points for selling the account balance now.
+    pub fn fee_bps(&self, account_id: &AccountId) -> u16 {
+        if self.tiers.is_empty() {
+            return 0;
+        }
+        let Some(held_for) = self.held_for(account_id) else {
+            return 0;
+        };
+        self.tiers
+            .iter()
+            .find(|tier| held_for < tier.held_under.0)
+            .map_or(0, |tier| tier.fee)
+    }
+
+    /// Moves the acquisition time of the account `balance` towards the time
+    /// the `amount` was acquired at.
+    pub fn record_deposit(
+        &mut self,
+        account_id: &AccountId,
+        balance: Balance,
+        amount: Balance,
+        acquired_at: u64,
+    ) {
+        if self.tiers.is_empty() || amount == 0 {
+            return;
+        }
+        // Untracked balances count as held since the start
+        let old = self.acquired_at.get(account_id).unwrap_or_default();
+        let total = balance.saturating_add(amount);
+        let diff = u128::from(old.abs_diff(acquired_at));
+        let shift = match diff.checked_mul(amount) {
+            Some(value) => value / total,
+            None => diff * (amount >> 64) / (total >> 64),
+        } as u64;
+        let mean = if acquired_at > old {
+            old + shift
+        } else {
+            old - shift
+        };
+        self.acquired_at.insert(account_id, &mean);
+    }
+
+    /// Time the KT of the account was acquired at, for passing it on with transfers.
+    pub fn acquired_at(&self, account_id: &AccountId) -> u64 {
+        self.acquired_at.get(account_id).unwrap_or_default()
+    }
+}
+
+impl Contract {
+    /// Records the KT amount bought or received now by the account.
+    pub(crate) fn internal_record_acquired(&mut self, account_id: &AccountId, amount: Balance) {
+        let balance = self.token.internal_unwrap_balance_of(account_id).amount;
+        self.holding_fee
+            .record_deposit(account_id, balance, amount, env::block_timestamp());
+    }
+
+    /// Transferred KT keeps the acquisition time of the sender.
+    pub(crate) fn internal_transfer_acquired(
+        &mut self,
+        sender_id: &AccountId,
+        receiver_id: &AccountId,
+        amount: Balance,
+    ) {
+        let balance = self.token.internal_unwrap_balance_of(receiver_id).amount;
+        let acquired_at = self.holding_fee.acquired_at(sender_id);
+        self.holding_fee
+            .record_deposit(receiver_id, balance, amount, acquired_at);
+    }
+
+    /// Holding fee of the sold asset amount, rounded up.
+    pub(crate) fn holding_fee_of(&self, account_id: &AccountId, asset_amount: Balance) -> Balance {
+        crate::fees::fee_of(asset_amount, self.holding_fee.fee_bps(account_id))
+    }
+
+    /// Keeps the holding fee of the sold asset amount in the treasury,
+    /// split as the other collected fees.
+    pub(crate) fn internal_collect_holding_fee(
+        &mut self,
+        asset_id: &AssetId,
+        fee: Balance,
+        price: ExchangePrice,
+    ) {
+        self.internal_split_fee(asset_id, fee, 0, price);
+    }
+
+    pub(crate) fn internal_set_holding_fee(&mut self, tiers: Vec<HoldingFeeTier>) {
+        self.holding_fee.set_tiers(tiers);
+        KtEvent::HoldingFeeChanged {
+            tiers: self.holding_fee.tiers(),
+        }
+        .emit();
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Sets the sell fee tiers by the holding time of the KT, empty tiers disable it.
+    pub fn set_holding_fee(&mut self, tiers: Vec<HoldingFeeTier>) {
+        self.assert_owner();
+        self.internal_set_holding_fee(tiers);
+    }
+
+    pub fn get_holding_fee(&self) -> Vec<HoldingFeeTier> {
+        self.holding_fee.tiers().to_vec()
+    }
+
+    /// Sell fee of the account balance now, in basis points.
+    pub fn get_holding_fee_of(&self, account_id: AccountId) -> u16 {
+        self.holding_fee.fee_bps(&account_id)
+    }
+
+    /// Nanoseconds the account balance was held for, `None` if it isn't tracked.
+    pub fn get_held_for(&self, account_id: AccountId) -> Option<U64> {
+        self.holding_fee.held_for(&account_id).map(Into::into)
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_contract_standards::fungible_token::core::FungibleTokenCore;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, PromiseResult, RuntimeFeesConfig, VMConfig, ONE_YOCTO};
+
+    use crate::holding::HoldingFeeTier;
+    use crate::operations::OperationLeg;
+    use crate::oracle::ExchangePrice;
+    use crate::test_utils;
+    use crate::{BuyOptions, Contract, ContractResolver};
+
+    const DAY: u64 = 86_400_000_000_000;
+
+    fn setup_contract(context: &mut VMContextBuilder) -> Contract {
+        context.block_timestamp(DAY);
+        let mut contract = test_utils::setup_contract(context);
+        contract.set_holding_fee(vec![
+            HoldingFeeTier {
+                held_under: DAY.into(),
+                fee: 50,
+            },
+            HoldingFeeTier {
+                held_under: (7 * DAY).into(),
+                fee: 10,
+            },
+        ]);
+        test_utils::buy_kt(&mut contract, &accounts(1));
+        contract
+    }
+
+    #[test]
+    fn test_holding_fee_decays() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        let price = ExchangePrice::new(10000, 10);
+        let kt = 100_000_000_000_000_000;
+
+        let asset_amount = contract.internal_sell(&accounts(1), &accounts(3), kt, 6, price, None);
+        assert_eq!(asset_amount.0, 99_500);
+
+        testing_env!(context.block_timestamp(3 * DAY).build());
+        assert_eq!(contract.get_holding_fee_of(accounts(1)), 10);
+        let asset_amount = contract.internal_sell(&accounts(1), &accounts(3), kt, 6, price, None);
+        assert_eq!(asset_amount.0, 99_900);
+
+        testing_env!(context.block_timestamp(8 * DAY).build());
+        let asset_amount = contract.internal_sell(&accounts(1), &accounts(3), kt, 6, price, None);
+        assert_eq!(asset_amount.0, 100_000);
+        assert_eq!(
+            contract.treasury.assert_asset(&accounts(3)).balance,
+            700_600
+        );
+    }
+
+    #[test]
+    fn test_holding_time_weighted_on_transfer() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        let price = ExchangePrice::new(10000, 10);
+
+        testing_env!(context.block_timestamp(5 * DAY).build());
+        contract.internal_buy(
+            &accounts(2),
+            &accounts(3),
+            3_000_000,
+            6,
+            price,
+            &BuyOptions::default(),
+        );
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        contract.ft_transfer(accounts(2), contract.ft_balance_of(accounts(1)), None);
+        // 3/4 of the balance is held for 0 days, 1/4 for 4 days
+        assert_eq!(contract.get_held_for(accounts(2)).unwrap().0, DAY);
+        assert_eq!(contract.get_holding_fee_of(accounts(2)), 10);
+    }
+
+    #[test]
+    fn test_holding_time_on_escrow_release() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(10u128.pow(23))
+            .build());
+        let amount = contract.ft_balance_of(accounts(1));
+        let id = contract.create_escrow(accounts(2), amount, (2 * DAY).into(), None);
+        testing_env!(context.attached_deposit(ONE_YOCTO).build());
+        contract.release_escrow(id);
+        assert_eq!(contract.get_held_for(accounts(2)).unwrap().0, 0);
+        assert_eq!(contract.get_holding_fee_of(accounts(2)), 50);
+    }
+
+    #[test]
+    fn test_refund_sell_reverts_holding_fee() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        let price = ExchangePrice::new(10000, 10);
+        let kt = 100_000_000_000_000_000;
+
+        let (asset_id, amount, asset_amount, _, fee) =
+            contract.internal_sell_leg(&accounts(1), &accounts(3), kt, 6, price, None);
+        assert_eq!(fee, 500);
+        let leg = OperationLeg {
+            asset_id,
+            amount: amount.into(),
+            asset_amount,
+            price: price.to_decimals().into(),
+            fee: fee.into(),
+        };
+        testing_env!(
+            context.build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        contract.resolve_sell(accounts(1), leg, None);
+        assert_eq!(
+            contract.treasury.assert_asset(&accounts(3)).balance,
+            1_000_000
+        );
+        assert_eq!(contract.get_collected_fees()[&accounts(3)].treasury.0, 0);
+    }
+}
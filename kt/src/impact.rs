@@ -0,0 +1,164 @@
+//! Guard against single sells draining a large share of an asset balance, which
+//! would strand the other redeemers of the asset until the next rebalance.
+
+use near_sdk::{near_bindgen, require, Balance};
+
+use crate::treasury::AssetId;
+use crate::{Contract, ContractExt, BASIS_POINTS};
+
+impl Contract {
+    /// Largest asset amount a single sell may pay out of the available balance.
+    pub(crate) fn sell_limit(&self, available: Balance) -> Balance {
+        if self.max_sell_share >= BASIS_POINTS {
+            return available;
+        }
+        let share = u128::from(self.max_sell_share);
+        let bps = u128::from(BASIS_POINTS);
+        match available.checked_mul(share) {
+            Some(value) => value / bps,
+            None => available / bps * share,
+        }
+    }
+
+    pub(crate) fn assert_sell_impact(
+        &self,
+        asset_id: &AssetId,
+        asset_amount: Balance,
+        available: Balance,
+    ) {
+        require!(
+            asset_amount <= self.sell_limit(available),
+            format!(
+                "Sell exceeds {} basis points of the {} balance",
+                self.max_sell_share, asset_id
+            )
+        );
+    }
+
+    pub(crate) fn internal_set_max_sell_share(&mut self, share: u16) {
+        require!(
+            share > 0 && share <= BASIS_POINTS,
+            "Sell share is out of bounds"
+        );
+        self.max_sell_share = share;
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Sets the maximum share of the available asset balance paid out by a single sell,
+    /// in basis points. 100% disables the guard.
+    pub fn set_max_sell_share(&mut self, share: u16) {
+        self.assert_owner();
+        self.internal_set_max_sell_share(share);
+    }
+
+    pub fn get_max_sell_share(&self) -> u16 {
+        self.max_sell_share
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    use crate::oracle::ExchangePrice;
+    use crate::{BuyOptions, Contract};
+
+    fn setup_contract() -> Contract {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(3), 6);
+        let price = ExchangePrice::new(10000, 10);
+        contract.internal_buy(
+            &accounts(1),
+            &accounts(3),
+            4_000_000,
+            6,
+            price,
+            &BuyOptions::default(),
+        );
+        contract.set_max_sell_share(2_500);
+        contract
+    }
+
+    #[test]
+    fn test_sell_impact() {
+        let mut contract = setup_contract();
+        let price = ExchangePrice::new(10000, 10);
+        contract.internal_sell(
+            &accounts(1),
+            &accounts(3),
+            1_000_000_000_000_000_000,
+            6,
+            price,
+            None,
+        );
+        assert_eq!(
+            contract.treasury.assert_asset(&accounts(3)).balance,
+            3_000_000
+        );
+    }
+
+    #[test]
+    fn test_sell_with_queue_impact() {
+        let mut contract = setup_contract();
+        let price = ExchangePrice::new(10000, 10);
+        let legs = contract.internal_sell_with_queue(
+            &accounts(1),
+            &accounts(3),
+            2_000_000_000_000_000_000,
+            price,
+            None,
+        );
+        assert_eq!(legs.len(), 1);
+        assert_eq!(legs[0].2 .0, 1_000_000);
+        let queue = contract.get_redemptions(accounts(1), None, None);
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue[0].1.amount.0, 1_000_000);
+    }
+
+    #[test]
+    fn test_sell_with_fallback_impact() {
+        let mut contract = setup_contract();
+        let price = ExchangePrice::new(10000, 10);
+        contract.internal_add_asset(&accounts(5), 6);
+        contract.treasury.set_asset_price(&accounts(5), price);
+        contract.internal_buy(
+            &accounts(2),
+            &accounts(5),
+            8_000_000,
+            6,
+            price,
+            &BuyOptions::default(),
+        );
+        let legs = contract.internal_sell_with_fallback(
+            &accounts(1),
+            &accounts(3),
+            2_500_000_000_000_000_000,
+            price,
+            (accounts(5), price),
+            None,
+        );
+        assert_eq!(legs.len(), 2);
+        assert_eq!((legs[0].0.clone(), legs[0].2 .0), (accounts(3), 1_000_000));
+        assert_eq!((legs[1].0.clone(), legs[1].2 .0), (accounts(5), 1_500_000));
+    }
+
+    #[test]
+    #[should_panic(expected = "Sell exceeds 2500 basis points of the danny balance")]
+    fn test_sell_impact_exceeded() {
+        let mut contract = setup_contract();
+        let price = ExchangePrice::new(10000, 10);
+        contract.internal_sell(
+            &accounts(1),
+            &accounts(3),
+            1_000_001_000_000_000_000,
+            6,
+            price,
+            None,
+        );
+    }
+}
@@ -0,0 +1,202 @@
+//! Incentives for permissionless maintenance.
+//!
+//! Refreshing a cached price older than the refresh interval pays the refresh reward
+//! to the caller out of the collected fees, at most once per asset and interval, so
+//! prices stay fresh without relying on the team's keys. With open rebalancing anyone
+//! can rebalance the treasury for the rebalance incentive.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen, require, AccountId};
+use schemars::JsonSchema;
+
+use crate::events::KtEvent;
+use crate::oracle::ExchangePrice;
+use crate::price::exchange_kt_to_asset;
+use crate::treasury::AssetId;
+use crate::{Contract, ContractExt};
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct KeeperIncentives {
+    /// KT value of the reward for refreshing a stale price, paid in the asset out of its
+    /// collected fees. 0 disables the reward.
+    pub refresh_reward: U128,
+    /// Age in nanoseconds after which a cached price is stale.
+    pub refresh_interval: U64,
+    /// Lets anyone rebalance, not only keepers.
+    pub open_rebalance: bool,
+}
+
+impl Default for KeeperIncentives {
+    fn default() -> Self {
+        Self {
+            refresh_reward: 0.into(),
+            refresh_interval: 0.into(),
+            open_rebalance: false,
+        }
+    }
+}
+
+impl KeeperIncentives {
+    pub fn assert_valid(&self) {
+        require!(
+            self.refresh_reward.0 == 0 || self.refresh_interval.0 > 0,
+            "Refresh reward requires a refresh interval"
+        );
+    }
+}
+
+impl Contract {
+    /// Whether the cached price of the asset is missing or older than the refresh interval.
+    pub(crate) fn is_price_stale(&self, asset_id: &AssetId) -> bool {
+        let interval = self.keeper_incentives.refresh_interval.0;
+        self.treasury
+            .assert_asset(asset_id)
+            .price
+            .is_none_or(|cached| {
+                env::block_timestamp().saturating_sub(cached.timestamp.0) >= interval
+            })
+    }
+
+    /// Credits the refresh reward to the keeper as an asset claim, taken from the collected
+    /// treasury fees of the asset. Paid once per asset and refresh interval, nothing is
+    /// paid when the fees don't cover it.
+    pub(crate) fn reward_price_refresh(
+        &mut self,
+        keeper_id: &AccountId,
+        asset_id: &AssetId,
+        price: ExchangePrice,
+    ) {
+        let KeeperIncentives {
+            refresh_reward,
+            refresh_interval,
+            ..
+        } = self.keeper_incentives;
+        if refresh_reward.0 == 0 || refresh_interval.0 == 0 {
+            return;
+        }
+        let now = env::block_timestamp();
+        if self
+            .refresh_rewards
+            .get(asset_id)
+            .is_some_and(|rewarded_at| now.saturating_sub(rewarded_at) < refresh_interval.0)
+        {
+            return;
+        }
+        let asset = self.treasury.assert_asset(asset_id);
+        let amount = exchange_kt_to_asset(refresh_reward.0, asset.decimals, price)
+            .unwrap_or_default()
+            .min(self.fees.balance_of(asset_id).withdrawable.0)
+            .min(self.available_balance(asset_id, asset.balance));
+        if amount == 0 {
+            return;
+        }
+        self.refresh_rewards.insert(asset_id, &now);
+        self.fees.record_withdrawn(asset_id, amount);
+        self.internal_credit_fee(keeper_id, asset_id, amount);
+        KtEvent::KeeperRewarded {
+            account_id: keeper_id,
+            asset_id,
+            amount: &amount.into(),
+        }
+        .emit();
+    }
+
+    pub(crate) fn internal_set_keeper_incentives(&mut self, incentives: KeeperIncentives) {
+        incentives.assert_valid();
+        self.keeper_incentives = incentives;
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn set_keeper_incentives(&mut self, incentives: KeeperIncentives) {
+        self.assert_owner();
+        self.internal_set_keeper_incentives(incentives);
+    }
+
+    pub fn get_keeper_incentives(&self) -> KeeperIncentives {
+        self.keeper_incentives.clone()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_contract_standards::fungible_token::core::FungibleTokenCore;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    use super::KeeperIncentives;
+    use crate::oracle::{ExchangePrice, Price, PriceData};
+    use crate::{BuyOptions, Contract, ContractResolver};
+
+    const REWARD: u128 = 100_000_000_000_000_000;
+
+    #[test]
+    fn test_refresh_reward() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(0))
+            .build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(3), 6);
+        contract.set_keeper_incentives(KeeperIncentives {
+            refresh_reward: REWARD.into(),
+            refresh_interval: 1_000.into(),
+            open_rebalance: false,
+        });
+        let data = || PriceData {
+            expiration: u64::MAX.into(),
+            ..PriceData::new(false, Some(Price::new(10000, 16)))
+        };
+
+        // Nothing is paid without collected fees
+        contract.cache_price(accounts(3), Some(accounts(1)), data());
+        assert!(contract.get_claims(accounts(1)).is_empty());
+
+        contract.set_buy_fee(100, 0);
+        contract.internal_buy(
+            &accounts(2),
+            &accounts(3),
+            100_000_000,
+            6,
+            ExchangePrice::new(10000, 10),
+            &BuyOptions::default(),
+        );
+        testing_env!(context.block_timestamp(1_000).build());
+        contract.cache_price(accounts(3), Some(accounts(1)), data());
+        assert_eq!(contract.get_claims(accounts(1))[&accounts(3)].0, 100_000);
+        assert_eq!(contract.ft_total_supply().0, 99_000_000_000_000_000_000);
+
+        // Rewarded once per interval
+        contract.cache_price(accounts(3), Some(accounts(1)), data());
+        assert_eq!(contract.get_claims(accounts(1))[&accounts(3)].0, 100_000);
+        testing_env!(context.block_timestamp(2_000).build());
+        contract.cache_price(accounts(3), Some(accounts(1)), data());
+        assert_eq!(contract.get_claims(accounts(1))[&accounts(3)].0, 200_000);
+
+        testing_env!(context.block_timestamp(1_000).build());
+        contract
+            .treasury
+            .set_asset_price(&accounts(3), ExchangePrice::new(10000, 10));
+        assert!(!contract.is_price_stale(&accounts(3)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Refresh reward requires a refresh interval")]
+    fn test_refresh_reward_without_interval() {
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(accounts(0))
+            .build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.set_keeper_incentives(KeeperIncentives {
+            refresh_reward: REWARD.into(),
+            refresh_interval: 0.into(),
+            open_rebalance: false,
+        });
+    }
+}
@@ -0,0 +1,211 @@
+//! Optional KYC gate backed by an external verifier, e.g. the i-am-human SBT registry.
+//!
+//! Buys and sells of accounts without a cached verification query the verifier alongside
+//! the oracle, the callback then rejects unverified accounts.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::json_types::U64;
+use near_sdk::{
+    env, ext_contract, near_bindgen, serde_json, AccountId, IntoStorageKey, Promise, PromiseResult,
+};
+
+use crate::{Contract, ContractExt};
+
+/// Soulbound tokens proving the account is verified: (issuer, token ids).
+pub type HumanSbts = Vec<(AccountId, Vec<u64>)>;
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Kyc {
+    verifier_id: Option<AccountId>,
+    /// Time a positive verification is cached for, in nanoseconds.
+    ttl: u64,
+    /// AccountID -> Timestamp of the last positive verification.
+    verified: LookupMap<AccountId, u64>,
+}
+
+impl Kyc {
+    pub fn new<S>(prefix: S) -> Self
+    where
+        S: IntoStorageKey,
+    {
+        Self {
+            verifier_id: None,
+            ttl: 0,
+            verified: LookupMap::new(prefix),
+        }
+    }
+
+    pub fn verifier_id(&self) -> Option<AccountId> {
+        self.verifier_id.clone()
+    }
+
+    pub fn set_verifier(&mut self, verifier_id: Option<AccountId>, ttl: u64) {
+        self.verifier_id = verifier_id;
+        self.ttl = ttl;
+    }
+
+    /// Whether the account doesn't need a verifier call, which is always the case
+    /// with the gate disabled.
+    pub fn is_verified(&self, account_id: &AccountId) -> bool {
+        if self.verifier_id.is_none() {
+            return true;
+        }
+        matches!(
+            self.verified.get(account_id),
+            Some(verified_at) if verified_at.saturating_add(self.ttl) > env::block_timestamp()
+        )
+    }
+
+    pub fn assert_verified(&self, account_id: &AccountId) {
+        if !self.is_verified(account_id) {
+            env::panic_str(format!("Account {} is not verified", account_id).as_str())
+        }
+    }
+
+    pub fn record_verified(&mut self, account_id: &AccountId) {
+        self.verified.insert(account_id, &env::block_timestamp());
+    }
+}
+
+impl Contract {
+    /// Verifier call for the account, `None` if it's verified already.
+    pub(crate) fn kyc_check(&self, account_id: &AccountId) -> Option<Promise> {
+        if self.kyc.is_verified(account_id) {
+            return None;
+        }
+        let verifier_id = self.kyc.verifier_id()?;
+        Some(
+            ext_kyc_verifier::ext(verifier_id)
+                .with_static_gas(self.gas.kyc_check)
+                .with_unused_gas_weight(0)
+                .is_human(account_id.clone()),
+        )
+    }
+
+    /// Checks the verifier response at the promise result index, if any,
+    /// and caches a positive one.
+    pub(crate) fn resolve_kyc(&mut self, account_id: &AccountId, index: u64) {
+        if env::promise_results_count() <= index {
+            return;
+        }
+        let verified = match env::promise_result(index) {
+            PromiseResult::NotReady => env::abort(),
+            PromiseResult::Successful(value) => {
+                matches!(serde_json::from_slice::<HumanSbts>(&value), Ok(sbts) if !sbts.is_empty())
+            }
+            PromiseResult::Failed => false,
+        };
+        if !verified {
+            env::panic_str(format!("Account {} is not verified", account_id).as_str())
+        }
+        self.kyc.record_verified(account_id);
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Enables the KYC gate with the verifier, `None` disables it.
+    /// Positive verifications are cached for `ttl` nanoseconds.
+    pub fn set_kyc_verifier(&mut self, verifier_id: Option<AccountId>, ttl: U64) {
+        self.assert_owner();
+        self.kyc.set_verifier(verifier_id, ttl.0);
+    }
+
+    pub fn get_kyc_verifier(&self) -> Option<AccountId> {
+        self.kyc.verifier_id()
+    }
+
+    pub fn is_kyc_verified(&self, account_id: AccountId) -> bool {
+        self.kyc.is_verified(&account_id)
+    }
+
+    /// Refreshes the cached verification of the account, e.g. before a basket sell
+    /// which doesn't query the verifier.
+    pub fn verify_account(&mut self, account_id: AccountId) -> Promise {
+        let check = self
+            .kyc_check(&account_id)
+            .unwrap_or_else(|| env::panic_str("Account is already verified"));
+        check.then(
+            ext_kyc_resolver::ext(env::current_account_id())
+                .with_static_gas(self.gas.resolve_kyc_check)
+                .resolve_verify_account(account_id),
+        )
+    }
+}
+
+#[ext_contract(ext_kyc_verifier)]
+#[allow(dead_code)]
+pub trait KycVerifier {
+    fn is_human(&self, account: AccountId) -> HumanSbts;
+}
+
+#[ext_contract(ext_kyc_resolver)]
+#[allow(dead_code)]
+pub trait KycResolver {
+    fn resolve_verify_account(&mut self, account_id: AccountId);
+}
+
+#[near_bindgen]
+impl KycResolver for Contract {
+    #[private]
+    fn resolve_verify_account(&mut self, account_id: AccountId) {
+        self.resolve_kyc(&account_id, 0);
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{serde_json, testing_env, PromiseResult, RuntimeFeesConfig, VMConfig};
+
+    use crate::kyc::{HumanSbts, Kyc};
+    use crate::{Contract, StorageKey};
+
+    fn setup_verifier_result(sbts: HumanSbts) {
+        testing_env!(
+            VMContextBuilder::new().build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![
+                PromiseResult::Successful(vec![]),
+                PromiseResult::Successful(serde_json::to_vec(&sbts).unwrap())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_verification_ttl() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.block_timestamp(1_000).build());
+        let mut kyc = Kyc::new(StorageKey::Kyc);
+        assert!(kyc.is_verified(&accounts(1)));
+
+        kyc.set_verifier(Some(accounts(3)), 500);
+        assert!(!kyc.is_verified(&accounts(1)));
+        kyc.record_verified(&accounts(1));
+        assert!(kyc.is_verified(&accounts(1)));
+
+        testing_env!(context.block_timestamp(1_500).build());
+        assert!(!kyc.is_verified(&accounts(1)));
+    }
+
+    #[test]
+    fn test_resolve_kyc() {
+        setup_verifier_result(vec![(accounts(3), vec![1])]);
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.kyc.set_verifier(Some(accounts(3)), 500);
+        contract.resolve_kyc(&accounts(1), 1);
+        assert!(contract.kyc.is_verified(&accounts(1)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Account bob is not verified")]
+    fn test_resolve_kyc_not_verified() {
+        setup_verifier_result(vec![]);
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.kyc.set_verifier(Some(accounts(3)), 500);
+        contract.resolve_kyc(&accounts(1), 1);
+    }
+}
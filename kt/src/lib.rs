@@ -1,43 +1,148 @@
+mod account_migration;
+mod admin;
+mod allowances;
+mod allowlist;
+mod bridge;
+mod burrow;
+mod buy_via;
+mod claims;
+mod commitment;
+mod compliance;
+mod config;
+mod cooldown;
+mod dex;
+mod distribution;
+mod dust;
+mod escrow;
+mod events;
+mod fees;
 mod ft;
+mod gas;
+mod holding;
+mod impact;
+mod incentives;
+mod kyc;
+mod limits;
+mod mode;
+mod operations;
 mod oracle;
+mod orders;
 mod owner;
+mod peg;
 mod price;
+mod price_history;
+mod quote;
+mod rebalance;
+mod reconcile;
+mod records;
+mod redemption;
+mod relay;
+mod roles;
+mod schedule;
+mod signed_price;
+mod spread;
+mod staking;
+mod stats;
+mod storage;
+mod strategy;
+mod streams;
+mod subscriptions;
+mod swap;
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod test_utils;
+mod transfer_data;
 mod treasury;
+mod upgrade;
+mod wnear;
 
 use near_contract_standards::fungible_token::events::{FtBurn, FtMint};
 use near_contract_standards::fungible_token::metadata::{FungibleTokenMetadata, FT_METADATA_SPEC};
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::LazyOption;
-use near_sdk::json_types::U128;
+use near_sdk::collections::{LazyOption, LookupMap, LookupSet};
+use near_sdk::json_types::{I128, U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::{
     assert_one_yocto, env, ext_contract, log, near_bindgen, require, AccountId, Balance,
-    BorshStorageKey, Gas, PanicOnDefault, Promise, PromiseResult, ONE_YOCTO,
+    BorshStorageKey, Gas, PanicOnDefault, Promise, PromiseOrValue, PromiseResult, ONE_YOCTO,
 };
+use schemars::JsonSchema;
 
+use crate::account_migration::*;
+use crate::admin::AdminAction;
+use crate::allowances::*;
+use crate::allowlist::*;
+use crate::bridge::*;
+use crate::claims::*;
+use crate::commitment::*;
+use crate::compliance::*;
+use crate::config::ConfigPatch;
+use crate::cooldown::*;
+use crate::escrow::Escrows;
+use crate::events::{KtEvent, RefundOperation, RefundReason};
+use crate::fees::*;
 use crate::ft::*;
+use crate::gas::*;
+use crate::holding::*;
+use crate::incentives::*;
+use crate::kyc::*;
+use crate::limits::*;
+use crate::operations::*;
 use crate::oracle::*;
+use crate::orders::*;
+use crate::peg::PegFallback;
 use crate::price::*;
+use crate::price_history::*;
+use crate::reconcile::AssetDrift;
+use crate::redemption::*;
+use crate::relay::*;
+use crate::roles::*;
+use crate::schedule::*;
+use crate::signed_price::SignedPrices;
+use crate::staking::*;
+use crate::stats::*;
+use crate::streams::Streams;
+use crate::subscriptions::Subscriptions;
 use crate::treasury::*;
 
 const DATA_IMAGE_SVG_NEAR_ICON: &str = "data:image/svg+xml,%3Csvg xmlns='http://www.w3.org/2000/svg' viewBox='0 0 288 288'%3E%3Cg id='l' data-name='l'%3E%3Cpath d='M187.58,79.81l-30.1,44.69a3.2,3.2,0,0,0,4.75,4.2L191.86,103a1.2,1.2,0,0,1,2,.91v80.46a1.2,1.2,0,0,1-2.12.77L102.18,77.93A15.35,15.35,0,0,0,90.47,72.5H87.34A15.34,15.34,0,0,0,72,87.84V201.16A15.34,15.34,0,0,0,87.34,216.5h0a15.35,15.35,0,0,0,13.08-7.31l30.1-44.69a3.2,3.2,0,0,0-4.75-4.2L96.14,186a1.2,1.2,0,0,1-2-.91V104.61a1.2,1.2,0,0,1,2.12-.77l89.55,107.23a15.35,15.35,0,0,0,11.71,5.43h3.13A15.34,15.34,0,0,0,216,201.16V87.84A15.34,15.34,0,0,0,200.66,72.5h0A15.35,15.35,0,0,0,187.58,79.81Z'/%3E%3C/g%3E%3C/svg%3E";
 
 const KT_DECIMALS: u8 = 18;
 const MAX_U128_DECIMALS: u8 = 37;
+const BASIS_POINTS: u16 = 10_000;
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const MAX_MEMO_LENGTH: usize = 128;
+
+/// Sold KT paid out in the asset: (asset, KT amount, asset amount, price, holding fee).
+type SellLeg = (AssetId, Balance, U128, ExchangePrice, Balance);
+
+/// Optional parameters of a buy, carried to the execution callback.
+#[derive(Serialize, Deserialize, JsonSchema, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BuyOptions {
+    /// Buys exactly this KT amount, returning the rest of the asset as unused.
+    pub kt_amount: Option<U128>,
+    /// Credited a share of the buy fee.
+    pub referrer_id: Option<AccountId>,
+    /// Client order id echoed in the buy events and logs.
+    pub memo: Option<String>,
+    /// Pending operation of the buy, moved to settling by the callback.
+    pub operation_id: Option<U64>,
+}
 
-// Gas
-// TODO: estimate gas cost via workspace tests
-const GAS_FOR_BUY_WITH_PRICE: Gas = Gas(25_000_000_000_000);
-const GAS_FOR_RESOLVE_SELL: Gas = Gas(25_000_000_000_000);
-const GAS_FOR_SELL_WITH_PRICE: Gas =
-    Gas(2_000_000_000_000 + GAS_FOR_TRANSFER.0 + GAS_FOR_RESOLVE_SELL.0);
-// FT
-const GAS_FOR_TRANSFER: Gas = Gas(450_000_000_000);
-const GAS_FOR_RESOLVE_TRANSFER: Gas = Gas(5_000_000_000_000);
-const GAS_FOR_TRANSFER_CALL: Gas = Gas(25_000_000_000_000 + GAS_FOR_RESOLVE_TRANSFER.0);
-const GAS_FOR_ON_TRANSFER: Gas =
-    Gas(2_000_000_000_000 + GAS_FOR_GET_EXCHANGE_PRICE.0 + GAS_FOR_BUY_WITH_PRICE.0);
-// Oracle
-const GAS_FOR_GET_EXCHANGE_PRICE: Gas = Gas(25_000_000_000_000);
+/// Optional parameters of a sell, carried to the execution callback.
+#[derive(Serialize, Deserialize, JsonSchema, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SellOptions {
+    pub shortfall: Option<Shortfall>,
+    /// Paid out the asset instead of the seller.
+    pub receiver_id: Option<AccountId>,
+    /// Client order id echoed in the sell events.
+    pub memo: Option<String>,
+    /// Pending operation of the sell, moved to settling by the callback.
+    pub operation_id: Option<U64>,
+    /// Asset sold for the shortfall, its price is the last promise result.
+    pub fallback_id: Option<AssetId>,
+}
 
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
@@ -47,6 +152,66 @@ pub struct Contract {
     token: FungibleToken,
     metadata: LazyOption<FungibleTokenMetadata>,
     treasury: Treasury,
+    roles: Roles,
+    dex_id: Option<AccountId>,
+    redemptions: RedemptionQueue,
+    sell_refund: SellRefund,
+    claims: AssetClaims,
+    operations: Operations,
+    volume_limits: VolumeLimits,
+    stats: Stats,
+    allowlist: Allowlist,
+    /// Contracts allowed to receive `ft_transfer_call`.
+    receiver_allowlist: Allowlist,
+    /// Receivers of `ft_transfer_call` which get the transfer data in the message.
+    data_receivers: LookupSet<AccountId>,
+    compliance: Compliance,
+    kyc: Kyc,
+    fees: Fees,
+    staking: Staking,
+    relayers: Relayers,
+    commitments: Commitments,
+    orders: LimitOrders,
+    scheduled_buys: ScheduledBuys,
+    keeper_incentives: KeeperIncentives,
+    gas: GasConfig,
+    account_migrations: AccountMigrations,
+    /// Buys are disabled while sells and transfers continue.
+    sell_only: bool,
+    /// Maximum KT total supply reachable by minting.
+    supply_cap: Option<Balance>,
+    /// Wrapped NEAR asset used for the buys and sells in native NEAR.
+    wnear_id: Option<AssetId>,
+    bridge_minters: BridgeMinters,
+    sell_cooldown: SellCooldown,
+    holding_fee: HoldingFee,
+    /// KT balance below which the accounts can be swept by anyone.
+    dust_threshold: Balance,
+    price_history: PriceHistory,
+    /// Maximum share of the available asset balance paid out by a single sell.
+    max_sell_share: u16,
+    /// AssetID -> Drift found by the last reconciliation.
+    asset_drifts: LookupMap<AssetId, AssetDrift>,
+    allowances: Allowances,
+    escrows: Escrows,
+    streams: Streams,
+    subscriptions: Subscriptions,
+    signed_prices: SignedPrices,
+    peg_fallback: Option<PegFallback>,
+    /// KT deducted from the first mint to an account for its storage.
+    account_storage_fee: Balance,
+    /// AccountID -> NEAR paid with `storage_deposit` for the account record.
+    storage_deposits: LookupMap<AccountId, Balance>,
+    /// Fee of the asset swaps in basis points, `None` if the swaps are disabled.
+    swap_fee: Option<u16>,
+    /// AssetID -> Time of the last rewarded price refresh.
+    refresh_rewards: LookupMap<AssetId, u64>,
+    /// Tokens left on the DEX account of the contract by the failed DEX buys.
+    dex_claims: AssetClaims,
+    /// AssetID -> Asset amount of the payouts waiting for their transfer result.
+    payouts_in_flight: LookupMap<AssetId, Balance>,
+    /// AssetID -> (Time of the cached oracle price, KT amount priced at the peg since).
+    peg_used: LookupMap<AssetId, (u64, Balance)>,
 }
 
 #[derive(BorshSerialize, BorshStorageKey)]
@@ -54,32 +219,155 @@ enum StorageKey {
     FungibleToken,
     Metadata,
     Treasury,
+    Roles,
+    Redemptions,
+    Claims,
+    Operations,
+    VolumeLimits,
+    Stats,
+    Allowlist,
+    ReceiverAllowlist,
+    DataReceivers,
+    Compliance,
+    Kyc,
+    Fees,
+    Staking,
+    Relayers,
+    Commitments,
+    Orders,
+    ScheduledBuys,
+    AccountMigrations,
+    BridgeMinters,
+    SellCooldown,
+    HoldingFee,
+    PriceHistory,
+    AssetDrifts,
+    Allowances,
+    Escrows,
+    Streams,
+    Subscriptions,
+    SignedPrices,
+    RefreshRewards,
+    DexClaims,
+    PayoutsInFlight,
+    PegUsed,
+    StorageDeposits,
 }
 
 #[near_bindgen]
 impl Contract {
-    /// Initializes the contract owned by the given `owner_id`
+    /// Initializes the contract owned by the given `owner_id`, optionally with the
+    /// assets and the config set. The assets are given with their decimals and storage
+    /// deposit, and are listed like with `admin_batch`: they appear asynchronously once
+    /// all of them are listed, and none is added if any listing fails. The attached
+    /// deposit above their storage deposits is refunded.
     #[init]
-    pub fn new(owner_id: AccountId, oracle_id: AccountId) -> Self {
+    #[payable]
+    pub fn new(
+        owner_id: AccountId,
+        oracle_id: AccountId,
+        assets: Option<Vec<(AssetId, Option<u8>, U128)>>,
+        config: Option<ConfigPatch>,
+    ) -> Self {
         require!(!env::state_exists(), "Already initialized");
 
+        let metadata = LazyOption::new(
+            StorageKey::Metadata,
+            Some(&FungibleTokenMetadata {
+                spec: FT_METADATA_SPEC.to_string(),
+                name: "K fungible token".to_string(),
+                symbol: "KTK".to_string(),
+                icon: Some(DATA_IMAGE_SVG_NEAR_ICON.to_string()),
+                reference: None,
+                reference_hash: None,
+                decimals: KT_DECIMALS,
+            }),
+        );
+        let mut contract = Self::with_state(
+            owner_id,
+            oracle_id,
+            FungibleToken::new(StorageKey::FungibleToken),
+            metadata,
+            Treasury::new(StorageKey::Treasury),
+        );
+        let actions = assets
+            .unwrap_or_default()
+            .into_iter()
+            .map(
+                |(asset_id, decimals, storage_deposit)| AdminAction::AddAsset {
+                    asset_id,
+                    decimals,
+                    price_decimals: None,
+                    storage_deposit,
+                },
+            )
+            .collect();
+        contract.internal_start_admin_batch(actions);
+        if let Some(config) = config {
+            contract.internal_update_config(config);
+        }
+        contract
+    }
+
+    /// State with the token and the treasury records, everything else is set to defaults.
+    fn with_state(
+        owner_id: AccountId,
+        oracle_id: AccountId,
+        token: FungibleToken,
+        metadata: LazyOption<FungibleTokenMetadata>,
+        treasury: Treasury,
+    ) -> Self {
         Self {
             owner_id,
             oracle_id,
-            token: FungibleToken::new(StorageKey::FungibleToken),
-            metadata: LazyOption::new(
-                StorageKey::Metadata,
-                Some(&FungibleTokenMetadata {
-                    spec: FT_METADATA_SPEC.to_string(),
-                    name: "K fungible token".to_string(),
-                    symbol: "KTK".to_string(),
-                    icon: Some(DATA_IMAGE_SVG_NEAR_ICON.to_string()),
-                    reference: None,
-                    reference_hash: None,
-                    decimals: KT_DECIMALS,
-                }),
-            ),
-            treasury: Treasury::new(StorageKey::Treasury),
+            token,
+            metadata,
+            treasury,
+            roles: Roles::new(StorageKey::Roles),
+            dex_id: None,
+            redemptions: RedemptionQueue::new(StorageKey::Redemptions),
+            sell_refund: SellRefund::Mint,
+            claims: AssetClaims::new(StorageKey::Claims),
+            operations: Operations::new(StorageKey::Operations),
+            volume_limits: VolumeLimits::new(StorageKey::VolumeLimits),
+            stats: Stats::new(StorageKey::Stats),
+            allowlist: Allowlist::new(StorageKey::Allowlist),
+            receiver_allowlist: Allowlist::new(StorageKey::ReceiverAllowlist),
+            data_receivers: LookupSet::new(StorageKey::DataReceivers),
+            compliance: Compliance::new(StorageKey::Compliance),
+            kyc: Kyc::new(StorageKey::Kyc),
+            fees: Fees::new(StorageKey::Fees),
+            staking: Staking::new(StorageKey::Staking),
+            relayers: Relayers::new(StorageKey::Relayers),
+            commitments: Commitments::new(StorageKey::Commitments),
+            orders: LimitOrders::new(StorageKey::Orders),
+            scheduled_buys: ScheduledBuys::new(StorageKey::ScheduledBuys),
+            keeper_incentives: KeeperIncentives::default(),
+            gas: GasConfig::default(),
+            account_migrations: AccountMigrations::new(StorageKey::AccountMigrations),
+            sell_only: false,
+            supply_cap: None,
+            wnear_id: None,
+            bridge_minters: BridgeMinters::new(StorageKey::BridgeMinters),
+            sell_cooldown: SellCooldown::new(StorageKey::SellCooldown),
+            holding_fee: HoldingFee::new(StorageKey::HoldingFee),
+            dust_threshold: 0,
+            price_history: PriceHistory::new(StorageKey::PriceHistory),
+            max_sell_share: BASIS_POINTS,
+            asset_drifts: LookupMap::new(StorageKey::AssetDrifts),
+            allowances: Allowances::new(StorageKey::Allowances),
+            escrows: Escrows::new(StorageKey::Escrows),
+            streams: Streams::new(StorageKey::Streams),
+            subscriptions: Subscriptions::new(StorageKey::Subscriptions),
+            signed_prices: SignedPrices::new(StorageKey::SignedPrices),
+            peg_fallback: None,
+            account_storage_fee: 0,
+            storage_deposits: LookupMap::new(StorageKey::StorageDeposits),
+            swap_fee: None,
+            refresh_rewards: LookupMap::new(StorageKey::RefreshRewards),
+            dex_claims: AssetClaims::new(StorageKey::DexClaims),
+            payouts_in_flight: LookupMap::new(StorageKey::PayoutsInFlight),
+            peg_used: LookupMap::new(StorageKey::PegUsed),
         }
     }
 
@@ -87,6 +375,7 @@ impl Contract {
         log!("Account @{} burned {}", account_id, amount);
     }
 
+    /// Buys KT for the asset amount, returns the asset amount used and the KT minted.
     pub(crate) fn internal_buy(
         &mut self,
         account_id: &AccountId,
@@ -94,24 +383,300 @@ impl Contract {
         asset_amount: Balance,
         asset_decimals: u8,
         price: ExchangePrice,
-    ) {
-        self.treasury.internal_deposit(asset_id, asset_amount);
+        options: &BuyOptions,
+    ) -> (Balance, Balance) {
+        let fee = self.fees.buy_fee_of(asset_amount);
+        let buy_price = self.buy_price(asset_id, price);
+        let kt_amount = exchange_asset_to_kt(asset_amount - fee, asset_decimals, buy_price)
+            .unwrap_or_else(|| env::panic_str("Exchange amount overflow"));
+        let memo = options.memo.as_deref();
+        let minted =
+            self.internal_mint_for(account_id, asset_id, asset_amount, kt_amount, price, memo);
+        let referrer_id = options.referrer_id.as_ref();
+        self.internal_collect_buy_fee(account_id, asset_id, fee, price, referrer_id);
+        self.emit_kt_buy(
+            account_id,
+            asset_id,
+            minted,
+            (asset_amount, fee),
+            buy_price,
+            memo,
+        );
+
+        let cost = exchange_kt_to_asset_cost(kt_amount, asset_decimals, buy_price)
+            .unwrap_or_else(|| env::panic_str("Exchange amount overflow"));
+        let surplus = (asset_amount - fee).saturating_sub(cost);
+        self.internal_fund_insurance(asset_id, surplus, InsuranceSource::Rounding);
+        (asset_amount, minted)
+    }
+
+    /// Buys as much KT as the caps, limits and the allowlist let the account, returns
+    /// the asset amount used and the KT minted. The rest of the asset amount is left unused.
+    pub(crate) fn internal_buy_allowed(
+        &mut self,
+        account_id: &AccountId,
+        asset_id: &AssetId,
+        asset_amount: Balance,
+        asset_decimals: u8,
+        price: ExchangePrice,
+        options: &BuyOptions,
+    ) -> (Balance, Balance) {
+        if !self.allowlist.is_allowed(account_id) {
+            return (0, 0);
+        }
+        let asset = self.treasury.assert_asset(asset_id);
+        let asset_amount = asset
+            .buy_room()
+            .map_or(asset_amount, |room| asset_amount.min(room));
+        if asset_amount == 0 || asset_amount < asset.min_buy {
+            return (0, 0);
+        }
+        if let Some(room) = self.mint_room(account_id) {
+            let fee = self.fees.buy_fee_of(asset_amount);
+            let buy_price = self.buy_price(asset_id, price);
+            let kt_amount = exchange_asset_to_kt(asset_amount - fee, asset_decimals, buy_price)
+                .unwrap_or_else(|| env::panic_str("Exchange amount overflow"))
+                .saturating_sub(self.storage_fee_of(account_id));
+            if kt_amount > room {
+                if room == 0 {
+                    return (0, 0);
+                }
+                let used = self.internal_buy_exact(
+                    account_id,
+                    asset_id,
+                    room,
+                    asset_amount,
+                    asset_decimals,
+                    price,
+                    options,
+                );
+                return (used, room);
+            }
+        }
+        self.internal_buy(
+            account_id,
+            asset_id,
+            asset_amount,
+            asset_decimals,
+            price,
+            options,
+        )
+    }
 
-        let kt_amount = exchange_asset_to_kt(asset_amount, asset_decimals, price)
+    /// Buys the exact KT amount for at most `max_asset_amount`, returns the asset amount used.
+    /// The buy fee and the storage fee of a new account are added on top of the KT cost.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn internal_buy_exact(
+        &mut self,
+        account_id: &AccountId,
+        asset_id: &AssetId,
+        kt_amount: Balance,
+        max_asset_amount: Balance,
+        asset_decimals: u8,
+        price: ExchangePrice,
+        options: &BuyOptions,
+    ) -> Balance {
+        let kt_amount = kt_amount
+            .checked_add(self.storage_fee_of(account_id))
+            .unwrap_or_else(|| env::panic_str("Exchange amount overflow"));
+        let buy_price = self.buy_price(asset_id, price);
+        let cost = exchange_kt_to_asset_cost(kt_amount, asset_decimals, buy_price)
+            .unwrap_or_else(|| env::panic_str("Exchange amount overflow"));
+        let fee = self.fees.buy_fee_of(cost);
+        let asset_amount = cost
+            .checked_add(fee)
             .unwrap_or_else(|| env::panic_str("Exchange amount overflow"));
+        require!(
+            asset_amount <= max_asset_amount,
+            format!(
+                "Transferred amount doesn't cover the cost of {}",
+                asset_amount
+            )
+        );
+        let memo = options.memo.as_deref();
+        let minted =
+            self.internal_mint_for(account_id, asset_id, asset_amount, kt_amount, price, memo);
+        let referrer_id = options.referrer_id.as_ref();
+        self.internal_collect_buy_fee(account_id, asset_id, fee, price, referrer_id);
+        self.emit_kt_buy(
+            account_id,
+            asset_id,
+            minted,
+            (asset_amount, fee),
+            buy_price,
+            memo,
+        );
+        asset_amount
+    }
+
+    /// Mints the KT bought for the asset amount, returns the KT minted after the storage fee.
+    fn internal_mint_for(
+        &mut self,
+        account_id: &AccountId,
+        asset_id: &AssetId,
+        asset_amount: Balance,
+        kt_amount: Balance,
+        price: ExchangePrice,
+        memo: Option<&str>,
+    ) -> Balance {
+        self.assert_buys_enabled();
+        self.compliance.assert_not_frozen(account_id);
+        self.allowlist.assert_allowed(account_id);
+        let asset = self.treasury.assert_asset(asset_id);
+        asset.assert_buy_amount(asset_amount);
+        asset.assert_cap(asset_amount);
+        self.treasury.internal_deposit(asset_id, asset_amount);
+
+        require!(kt_amount > 0, "Buy amount is too small");
+        let kt_amount = self.internal_charge_storage_fee(account_id, asset_id, kt_amount, price);
+        self.assert_supply_cap(kt_amount);
+        self.volume_limits.record_mint(account_id, kt_amount);
+        self.stats.record_buy(asset_id, asset_amount, kt_amount);
+        self.price_history.record(asset_id, price);
+        self.sell_cooldown.record_buy(account_id);
+        self.internal_record_acquired(account_id, kt_amount);
 
-        // TODO: withdraw buying fees
         self.token
             .internal_deposit(account_id, kt_amount, price.to_decimals());
 
         FtMint {
             owner_id: account_id,
             amount: &U128::from(kt_amount),
-            memo: None,
+            memo,
+        }
+        .emit();
+        kt_amount
+    }
+
+    /// Adds the asset to the treasury backing without minting KT.
+    pub(crate) fn internal_donate(
+        &mut self,
+        account_id: &AccountId,
+        asset_id: &AssetId,
+        amount: Balance,
+    ) {
+        let asset = self.treasury.assert_asset(asset_id);
+        require!(
+            asset.status != AssetStatus::Deprecated,
+            format!("Asset {} is deprecated", asset_id)
+        );
+        self.treasury.internal_deposit(asset_id, amount);
+
+        KtEvent::Donation {
+            account_id,
+            asset_id,
+            amount: &amount.into(),
+        }
+        .emit();
+    }
+
+    /// Emits the buy execution, `amounts` are the asset amount used and the fee.
+    pub(crate) fn emit_kt_buy(
+        &self,
+        account_id: &AccountId,
+        asset_id: &AssetId,
+        kt_amount: Balance,
+        amounts: (Balance, Balance),
+        price: ExchangePrice,
+        memo: Option<&str>,
+    ) {
+        let (asset_amount, fee) = amounts;
+        KtEvent::KtBuy {
+            account_id,
+            asset_id,
+            amount: &kt_amount.into(),
+            asset_amount: &asset_amount.into(),
+            price: &price.to_decimals().into(),
+            fee: &fee.into(),
+            oracle_timestamp: price.timestamp.as_ref(),
+            memo,
+        }
+        .emit();
+    }
+
+    /// Emits the sell execution with the realized profit or loss of selling KT at the price
+    /// against the cost basis the seller had before the sell. `amounts` are the asset amount
+    /// paid out and the fee.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn emit_kt_sell(
+        &self,
+        account_id: &AccountId,
+        asset_id: &AssetId,
+        kt_amount: Balance,
+        amounts: (Balance, Balance),
+        price: ExchangePrice,
+        cost_basis: u128,
+        memo: Option<&str>,
+    ) {
+        let (asset_amount, fee) = amounts;
+        let oracle_timestamp = price.timestamp;
+        let price = price.to_decimals();
+        let one = 10u128.pow(u32::from(KT_DECIMALS));
+        let diff = price.abs_diff(cost_basis);
+        let pnl = match diff.checked_mul(kt_amount) {
+            Some(value) => value / one,
+            None => diff / one * kt_amount,
+        };
+        let pnl = i128::try_from(pnl).unwrap_or(i128::MAX);
+        KtEvent::KtSell {
+            account_id,
+            asset_id,
+            amount: &kt_amount.into(),
+            asset_amount: &asset_amount.into(),
+            price: &price.into(),
+            fee: &fee.into(),
+            oracle_timestamp: oracle_timestamp.as_ref(),
+            memo,
+            cost_basis: &cost_basis.into(),
+            pnl: &I128::from(if price < cost_basis { -pnl } else { pnl }),
+        }
+        .emit();
+    }
+
+    /// Sells the KT required to pay out exactly the asset amount after the holding fee.
+    /// The rounding surplus is kept in the treasury.
+    pub(crate) fn internal_sell_exact(
+        &mut self,
+        account_id: &AccountId,
+        asset_id: &AssetId,
+        asset_amount: Balance,
+        asset_decimals: u8,
+        price: ExchangePrice,
+        memo: Option<&str>,
+    ) -> SellLeg {
+        let fee = self.holding_fee.fee_bps(account_id);
+        require!(fee < BASIS_POINTS, "Holding fee takes the whole amount");
+        let bps = u128::from(BASIS_POINTS);
+        let mut gross = asset_amount
+            .checked_mul(bps)
+            .unwrap_or_else(|| env::panic_str("Exchange amount overflow"))
+            / (bps - u128::from(fee));
+        while gross - fee_of(gross, fee) < asset_amount {
+            gross += 1;
+        }
+        let sell_price = self.sell_price(asset_id, price);
+        let kt_amount = exchange_asset_to_kt_cost(gross, asset_decimals, sell_price)
+            .unwrap_or_else(|| env::panic_str("Exchange amount overflow"));
+        let balance = self.token.internal_unwrap_balance_of(account_id).amount;
+        require!(
+            kt_amount <= balance,
+            format!(
+                "Account balance doesn't cover the {} KT required",
+                kt_amount
+            )
+        );
+
+        let (asset_id, _, paid, _, fee) =
+            self.internal_sell_leg(account_id, asset_id, kt_amount, asset_decimals, price, memo);
+        if paid.0 > asset_amount {
+            self.treasury
+                .internal_deposit(&asset_id, paid.0 - asset_amount);
         }
-        .emit()
+        (asset_id, kt_amount, asset_amount.into(), price, fee)
     }
 
+    /// Sells the KT amount for the asset, returns the asset amount paid out.
+    #[cfg(test)]
     pub(crate) fn internal_sell(
         &mut self,
         account_id: &AccountId,
@@ -119,50 +684,638 @@ impl Contract {
         kt_amount: Balance,
         asset_decimals: u8,
         price: ExchangePrice,
+        memo: Option<&str>,
     ) -> U128 {
+        self.internal_sell_leg(account_id, asset_id, kt_amount, asset_decimals, price, memo)
+            .2
+    }
+
+    /// Sells the KT amount for the asset, the holding fee of the leg stays in the treasury.
+    pub(crate) fn internal_sell_leg(
+        &mut self,
+        account_id: &AccountId,
+        asset_id: &AssetId,
+        kt_amount: Balance,
+        asset_decimals: u8,
+        price: ExchangePrice,
+        memo: Option<&str>,
+    ) -> SellLeg {
         // TODO: withdraw profit fees
+        self.compliance.assert_not_frozen(account_id);
+        self.sell_cooldown.assert_elapsed(account_id);
+        self.volume_limits.record_burn(account_id, kt_amount);
+        let cost_basis = self.token.cost_basis_of(account_id);
         self.token
             .internal_withdraw(account_id, kt_amount, price.to_decimals());
 
         FtBurn {
             owner_id: account_id,
             amount: &U128::from(kt_amount),
-            memo: None,
+            memo,
         }
         .emit();
 
-        let asset_amount = exchange_kt_to_asset(kt_amount, asset_decimals, price)
+        let sell_price = self.sell_price(asset_id, price);
+        let asset_amount = exchange_kt_to_asset(kt_amount, asset_decimals, sell_price)
             .unwrap_or_else(|| env::panic_str("Exchange amount overflow"));
 
-        self.treasury.internal_withdraw(asset_id, asset_amount);
+        let asset = self.treasury.assert_asset(asset_id);
+        asset.assert_sell_amount(asset_amount);
+        let available = self.available_balance(asset_id, asset.balance);
+        require!(
+            asset_amount <= available,
+            "The treasury doesn't have enough balance"
+        );
+        self.assert_sell_impact(asset_id, asset_amount, available);
+        let fee = self.holding_fee_of(account_id, asset_amount);
+        self.treasury
+            .internal_withdraw(asset_id, asset_amount - fee);
+        self.internal_collect_holding_fee(asset_id, fee, price);
+        self.stats.record_sell(asset_id, asset_amount, kt_amount);
+        self.price_history.record(asset_id, price);
+        self.emit_kt_sell(
+            account_id,
+            asset_id,
+            kt_amount,
+            (asset_amount - fee, fee),
+            sell_price,
+            cost_basis,
+            memo,
+        );
+
+        (
+            asset_id.clone(),
+            kt_amount,
+            (asset_amount - fee).into(),
+            price,
+            fee,
+        )
+    }
+
+    /// Sells KT for the sellable assets, proportionally to the value of their balances
+    /// at the given prices. The assets whose share would pay out less than their minimum
+    /// sell are left out, their share goes to the others. Returns the sold legs:
+    /// (asset, KT amount, asset amount, price).
+    pub(crate) fn internal_sell_basket(
+        &mut self,
+        account_id: &AccountId,
+        kt_amount: Balance,
+        prices: Vec<(AssetId, ExchangePrice)>,
+    ) -> Vec<SellLeg> {
+        let mut assets: Vec<_> = prices
+            .into_iter()
+            .filter_map(|(asset_id, price)| {
+                let asset = self.treasury.get(&asset_id)?;
+                if !asset.status.can_sell() {
+                    return None;
+                }
+                let balance = self.available_balance(&asset_id, asset.balance);
+                let value = exchange_asset_to_kt(balance, asset.decimals, price)?;
+                if value == 0 {
+                    return None;
+                }
+                Some((asset_id, asset.decimals, asset.min_sell, price, value))
+            })
+            .collect();
+
+        loop {
+            let total_value = assets
+                .iter()
+                .fold(0, |total: Balance, (_, _, _, _, value)| {
+                    total.saturating_add(*value)
+                });
+            require!(total_value > 0, "No assets are available for selling");
+
+            let mut remaining = kt_amount;
+            let shares: Vec<Balance> = assets
+                .iter()
+                .enumerate()
+                .map(|(i, (_, _, _, _, value))| {
+                    // The last leg takes the rounding remainder
+                    let share = if i == assets.len() - 1 {
+                        remaining
+                    } else {
+                        match kt_amount.checked_mul(*value) {
+                            Some(amount) => amount / total_value,
+                            None => kt_amount / total_value * value,
+                        }
+                    };
+                    remaining -= share;
+                    share
+                })
+                .collect();
+            let below_min: Vec<bool> = assets
+                .iter()
+                .zip(&shares)
+                .map(|((asset_id, decimals, min_sell, price, _), share)| {
+                    let sell_price = self.sell_price(asset_id, *price);
+                    exchange_kt_to_asset(*share, *decimals, sell_price)
+                        .is_some_and(|amount| amount < *min_sell)
+                })
+                .collect();
+            if below_min.contains(&true) {
+                let mut below_min = below_min.into_iter();
+                assets.retain(|_| !below_min.next().unwrap_or_default());
+                continue;
+            }
+
+            let mut legs = Vec::with_capacity(assets.len());
+            for ((asset_id, decimals, _, price, _), leg_amount) in assets.into_iter().zip(shares) {
+                if leg_amount == 0 {
+                    continue;
+                }
+                legs.push(
+                    self.internal_sell_leg(
+                        account_id, &asset_id, leg_amount, decimals, price, None,
+                    ),
+                );
+            }
+            return legs;
+        }
+    }
+
+    /// Price of the asset in the promise result, cached in the treasury.
+    fn price_result(&mut self, index: u64, asset_id: &AssetId) -> ExchangePrice {
+        let data = match env::promise_result(index) {
+            PromiseResult::Successful(value) => {
+                near_sdk::serde_json::from_slice::<PriceData>(&value)
+                    .unwrap_or_else(|_| env::panic_str("Oracle price is invalid"))
+            }
+            _ => env::panic_str("Oracle price is unavailable"),
+        };
+        let price = ExchangePrice::from_price_data(&self.treasury.assert_asset(asset_id), data);
+        self.treasury.set_asset_price(asset_id, price);
+        price
+    }
+
+    /// Most liquid sellable asset other than the given one, at the cached prices.
+    /// Sells falling short of the asset balance sell the rest for it.
+    pub(crate) fn fallback_asset_of(&self, asset_id: &AssetId) -> Option<AssetId> {
+        self.treasury
+            .supported_assets()
+            .into_iter()
+            .filter(|(id, asset)| id != asset_id && asset.status.can_sell())
+            .filter_map(|(id, asset)| {
+                let price = asset.price?.price;
+                let balance = self.sell_limit(self.available_balance(&id, asset.balance));
+                let value = exchange_asset_to_kt(balance, asset.decimals, price)?;
+                Some((id, value))
+            })
+            .max_by_key(|(_, value)| *value)
+            .map(|(id, _)| id)
+    }
+
+    /// Sells the available balance of the asset, up to the sell share limit, and the rest
+    /// of the KT amount for the fallback asset at its fetched price.
+    pub(crate) fn internal_sell_with_fallback(
+        &mut self,
+        account_id: &AccountId,
+        asset_id: &AssetId,
+        kt_amount: Balance,
+        price: ExchangePrice,
+        fallback: (AssetId, ExchangePrice),
+        memo: Option<&str>,
+    ) -> Vec<SellLeg> {
+        let (fallback_id, fallback_price) = fallback;
+        let asset = self.treasury.assert_asset(asset_id);
+        let fallback_decimals = self.treasury.assert_can_sell(&fallback_id).decimals;
+
+        let balance = self.sell_limit(self.available_balance(asset_id, asset.balance));
+        let available = exchange_asset_to_kt(balance, asset.decimals, price)
+            .unwrap_or_else(|| env::panic_str("Exchange amount overflow"))
+            .min(kt_amount);
+        let mut legs = Vec::with_capacity(2);
+        if available > 0 {
+            legs.push(self.internal_sell_leg(
+                account_id,
+                asset_id,
+                available,
+                asset.decimals,
+                price,
+                memo,
+            ));
+        }
+        legs.push(self.internal_sell_leg(
+            account_id,
+            &fallback_id,
+            kt_amount - available,
+            fallback_decimals,
+            fallback_price,
+            memo,
+        ));
+        legs
+    }
+
+    /// Sells the available balance of the asset, up to the sell share limit, and queues
+    /// the rest of the KT amount as a redemption to be claimed later.
+    pub(crate) fn internal_sell_with_queue(
+        &mut self,
+        account_id: &AccountId,
+        asset_id: &AssetId,
+        kt_amount: Balance,
+        price: ExchangePrice,
+        memo: Option<&str>,
+    ) -> Vec<SellLeg> {
+        let asset = self.treasury.assert_asset(asset_id);
+        let balance = self.sell_limit(self.available_balance(asset_id, asset.balance));
+        let available = exchange_asset_to_kt(balance, asset.decimals, price)
+            .unwrap_or_else(|| env::panic_str("Exchange amount overflow"))
+            .min(kt_amount);
+        let mut legs = Vec::with_capacity(1);
+        if available > 0 {
+            legs.push(self.internal_sell_leg(
+                account_id,
+                asset_id,
+                available,
+                asset.decimals,
+                price,
+                memo,
+            ));
+        }
+        self.internal_queue_redemption(
+            account_id,
+            asset_id,
+            kt_amount - available,
+            asset.decimals,
+            price,
+            memo,
+        );
+        legs
+    }
+
+    /// Gives the seller back the sold KT or the asset claim after a failed payout.
+    /// The KT minted back undoes the holding fee of the sell as well.
+    pub(crate) fn internal_refund_sell(&mut self, account_id: &AccountId, leg: &OperationLeg) {
+        let OperationLeg {
+            asset_id,
+            amount,
+            asset_amount,
+            price,
+            fee,
+        } = leg;
+        match self.sell_refund {
+            SellRefund::Mint => {
+                self.treasury.internal_deposit(asset_id, asset_amount.0);
+                self.internal_revert_fee(asset_id, fee.0);
+                self.token.internal_deposit(account_id, amount.0, price.0);
+                self.volume_limits.revert_burn(account_id, amount.0);
+
+                FtMint {
+                    owner_id: account_id,
+                    amount,
+                    memo: Some("refund"),
+                }
+                .emit();
+                KtEvent::SellRefunded {
+                    account_id,
+                    asset_id,
+                    amount,
+                    asset_amount,
+                }
+                .emit();
+                KtEvent::KtRefund {
+                    operation: RefundOperation::Sell,
+                    reason: RefundReason::PayoutFailed,
+                    account_id,
+                    asset_id: Some(asset_id),
+                    amount: Some(amount),
+                    asset_amount: None,
+                }
+                .emit();
+            }
+            SellRefund::Claim => {
+                self.claims
+                    .internal_add(account_id, asset_id, asset_amount.0);
+
+                KtEvent::AssetClaimAdded {
+                    account_id,
+                    asset_id,
+                    amount: asset_amount,
+                }
+                .emit();
+                KtEvent::KtRefund {
+                    operation: RefundOperation::Sell,
+                    reason: RefundReason::PayoutFailed,
+                    account_id,
+                    asset_id: Some(asset_id),
+                    amount: None,
+                    asset_amount: Some(asset_amount),
+                }
+                .emit();
+            }
+        }
+    }
 
-        asset_amount.into()
+    /// Transfers the sold assets to the receiver, every transfer is refunded separately
+    /// to the seller on failure.
+    fn sell_transfers(
+        &mut self,
+        account_id: &AccountId,
+        receiver_id: &AccountId,
+        legs: Vec<SellLeg>,
+        operation_id: Option<OperationId>,
+    ) -> Promise {
+        let legs: Vec<OperationLeg> = legs
+            .into_iter()
+            .map(
+                |(asset_id, kt_amount, asset_amount, price, fee)| OperationLeg {
+                    asset_id,
+                    amount: kt_amount.into(),
+                    asset_amount,
+                    price: price.to_decimals().into(),
+                    fee: fee.into(),
+                },
+            )
+            .collect();
+        for leg in &legs {
+            self.internal_start_payout(&leg.asset_id, leg.asset_amount.0);
+            if let Some(operation_id) = operation_id {
+                self.operations.add_leg(operation_id, leg.clone());
+            }
+        }
+        legs.into_iter()
+            .map(|leg| {
+                ext_ft_transfer::ext(leg.asset_id.clone())
+                    .with_static_gas(self.gas.transfer)
+                    .with_attached_deposit(ONE_YOCTO)
+                    .ft_transfer(receiver_id.clone(), leg.asset_amount, None)
+                    .then(
+                        ext_self::ext(env::current_account_id())
+                            .with_static_gas(self.gas.resolve_sell)
+                            .resolve_sell(account_id.clone(), leg, operation_id.map(U64::from)),
+                    )
+            })
+            .reduce(Promise::and)
+            .unwrap_or_else(|| env::abort())
     }
 
+    /// Sells KT for the asset. With `shortfall`, the amount exceeding the treasury balance
+    /// of the asset is either sold for the most liquid sellable asset or queued.
+    /// The asset is paid out to `receiver_id` if set, refunds still go to the seller.
+    /// The `memo` is echoed in the sell events.
     #[payable]
     pub fn sell(
         &mut self,
         asset_id: AssetId,
         amount: U128,
         expected: Option<ExpectedPrice>,
+        shortfall: Option<Shortfall>,
+        receiver_id: Option<AccountId>,
+        memo: Option<String>,
     ) -> Promise {
         assert_one_yocto();
-        require!(
-            env::prepaid_gas() > GAS_FOR_SELL_WITH_PRICE,
-            "More gas is required"
-        );
-        self.treasury
-            .assert_asset_status(&asset_id, AssetStatus::Enabled);
-
-        ext_oracle::ext(self.oracle_id.clone())
-            .with_static_gas(GAS_FOR_GET_EXCHANGE_PRICE)
-            .get_exchange_price(asset_id.clone())
+        assert_valid_memo(memo.as_deref());
+        let legs = if shortfall == Some(Shortfall::Fallback) {
+            2
+        } else {
+            1
+        };
+        let account_id = env::predecessor_account_id();
+        let (mut get_price, operation_id) =
+            self.internal_start_sell(&account_id, &asset_id, amount, legs);
+        // The fresh fallback price is the last promise result
+        let fallback_id = match shortfall {
+            Some(Shortfall::Fallback) => self.fallback_asset_of(&asset_id),
+            _ => None,
+        };
+        if let Some(fallback_id) = &fallback_id {
+            require!(
+                env::prepaid_gas()
+                    > self.gas.sell_with_price() * legs
+                        + self.gas.finish_operation
+                        + self.price_gas(fallback_id),
+                "More gas is required"
+            );
+            get_price = get_price.and(self.get_price(fallback_id, None));
+        }
+        get_price
             .then(ext_self::ext(env::current_account_id()).sell_with_price(
-                env::predecessor_account_id(),
+                account_id,
                 asset_id,
                 amount,
                 expected,
+                SellOptions {
+                    shortfall,
+                    receiver_id,
+                    memo,
+                    operation_id: Some(operation_id.into()),
+                    fallback_id,
+                },
             ))
+            .then(
+                ext_operation_resolver::ext(env::current_account_id())
+                    .with_static_gas(self.gas.finish_operation)
+                    .finish_operation(operation_id.into()),
+            )
+    }
+
+    /// Sells the whole KT balance of the caller for the asset. The balance is read
+    /// once the price is fetched, so changes made meanwhile are sold too.
+    #[payable]
+    pub fn sell_all(&mut self, asset_id: AssetId, expected: Option<ExpectedPrice>) -> Promise {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let balance = self.token.internal_unwrap_balance_of(&account_id).amount;
+        require!(balance > 0, "Nothing to sell");
+        let (get_price, operation_id) =
+            self.internal_start_sell(&account_id, &asset_id, balance.into(), 1);
+        get_price
+            .then(
+                ext_self::ext(env::current_account_id()).sell_all_with_price(
+                    account_id,
+                    asset_id,
+                    expected,
+                    operation_id.into(),
+                ),
+            )
+            .then(
+                ext_operation_resolver::ext(env::current_account_id())
+                    .with_static_gas(self.gas.finish_operation)
+                    .finish_operation(operation_id.into()),
+            )
+    }
+
+    /// Sells KT of the owner within the allowance of the caller, e.g. a vault managing
+    /// the owner's position. The asset is paid out to `receiver_id` if set, else to the owner.
+    #[payable]
+    pub fn sell_from(
+        &mut self,
+        owner_id: AccountId,
+        asset_id: AssetId,
+        amount: U128,
+        expected: Option<ExpectedPrice>,
+        receiver_id: Option<AccountId>,
+    ) -> Promise {
+        assert_one_yocto();
+        let operator_id = env::predecessor_account_id();
+        self.compliance.assert_not_frozen(&operator_id);
+        self.allowances
+            .assert_allowance(&owner_id, &operator_id, amount.0);
+        let (get_price, operation_id) = self.internal_start_sell(&owner_id, &asset_id, amount, 1);
+        get_price
+            .then(
+                ext_self::ext(env::current_account_id()).sell_from_with_price(
+                    operator_id,
+                    owner_id,
+                    asset_id,
+                    amount,
+                    expected,
+                    SellOptions {
+                        receiver_id,
+                        operation_id: Some(operation_id.into()),
+                        ..Default::default()
+                    },
+                ),
+            )
+            .then(
+                ext_operation_resolver::ext(env::current_account_id())
+                    .with_static_gas(self.gas.finish_operation)
+                    .finish_operation(operation_id.into()),
+            )
+    }
+
+    /// Sells the KT required to pay out exactly the asset amount at the oracle price,
+    /// e.g. to pay an invoice. Fails if the caller's balance doesn't cover it.
+    /// The asset is paid out to `receiver_id` if set.
+    #[payable]
+    pub fn sell_exact(
+        &mut self,
+        asset_id: AssetId,
+        asset_amount: U128,
+        expected: Option<ExpectedPrice>,
+        receiver_id: Option<AccountId>,
+    ) -> Promise {
+        assert_one_yocto();
+        require!(asset_amount.0 > 0, "Amount should be positive");
+        let account_id = env::predecessor_account_id();
+        let balance = self.token.internal_unwrap_balance_of(&account_id).amount;
+        let (get_price, operation_id) =
+            self.internal_start_sell(&account_id, &asset_id, balance.into(), 1);
+        get_price
+            .then(
+                ext_self::ext(env::current_account_id()).sell_exact_with_price(
+                    account_id,
+                    asset_id,
+                    asset_amount,
+                    expected,
+                    receiver_id,
+                    operation_id.into(),
+                ),
+            )
+            .then(
+                ext_operation_resolver::ext(env::current_account_id())
+                    .with_static_gas(self.gas.finish_operation)
+                    .finish_operation(operation_id.into()),
+            )
+    }
+
+    /// Checks the sell can start and records it as pending, returns the promise
+    /// fetching the price along with the KYC check, and the operation ID.
+    fn internal_start_sell(
+        &mut self,
+        account_id: &AccountId,
+        asset_id: &AssetId,
+        amount: U128,
+        legs: u64,
+    ) -> (Promise, OperationId) {
+        require!(
+            env::prepaid_gas()
+                > Gas(self.gas.sell_with_price().0 * legs + self.gas.finish_operation.0),
+            "More gas is required"
+        );
+        self.treasury.assert_can_sell(asset_id);
+        self.compliance.assert_not_frozen(account_id);
+        self.operations.assert_no_pending_sell(account_id);
+        self.charge_relay_fee(account_id);
+        let kyc_check = self.kyc_check(account_id);
+        if kyc_check.is_some() {
+            require!(
+                env::prepaid_gas()
+                    > Gas(self.gas.sell_with_price().0 * legs
+                        + self.gas.finish_operation.0
+                        + self.gas.kyc_check.0),
+                "More gas is required"
+            );
+        }
+        let operation_id = self.operations.start(
+            account_id,
+            OperationKind::Sell,
+            Some(asset_id.clone()),
+            amount,
+            OperationStage::Pricing,
+        );
+
+        let get_price = self.get_price(asset_id, Some(amount.0));
+        let get_price = match kyc_check {
+            Some(kyc_check) => get_price.and(kyc_check),
+            None => get_price,
+        };
+        (get_price, operation_id)
+    }
+
+    /// Sells KT for all sellable treasury assets at once, proportionally to their balances.
+    /// Fetches the prices of all the assets first, along with the KYC check, every transfer
+    /// is refunded separately on failure.
+    #[payable]
+    pub fn sell_basket(&mut self, amount: U128) -> Promise {
+        assert_one_yocto();
+        require!(amount.0 > 0, "Amount should be positive");
+        let account_id = env::predecessor_account_id();
+        self.operations.assert_no_pending_sell(&account_id);
+        let asset_ids: Vec<_> = self
+            .treasury
+            .supported_assets()
+            .into_iter()
+            .filter(|(_, asset)| asset.status.can_sell())
+            .map(|(asset_id, _)| asset_id)
+            .collect();
+        require!(!asset_ids.is_empty(), "No assets are available for selling");
+        let price_gas = asset_ids
+            .iter()
+            .fold(Gas(0), |gas, asset_id| gas + self.price_gas(asset_id));
+        let kyc_check = self.kyc_check(&account_id);
+        let kyc_gas = if kyc_check.is_some() {
+            self.gas.kyc_check
+        } else {
+            Gas(0)
+        };
+        require!(
+            env::prepaid_gas()
+                > price_gas
+                    + self.gas.sell_with_price() * asset_ids.len() as u64
+                    + self.gas.finish_operation
+                    + kyc_gas,
+            "More gas is required"
+        );
+        self.charge_relay_fee(&account_id);
+        let operation_id = self.operations.start(
+            &account_id,
+            OperationKind::Sell,
+            None,
+            amount,
+            OperationStage::Pricing,
+        );
+
+        // The KYC check, if any, follows the prices
+        asset_ids
+            .iter()
+            .map(|asset_id| self.get_price(asset_id, None))
+            .chain(kyc_check)
+            .reduce(Promise::and)
+            .unwrap_or_else(|| env::abort())
+            .then(
+                ext_self::ext(env::current_account_id()).sell_basket_with_prices(
+                    account_id,
+                    amount,
+                    asset_ids,
+                    operation_id.into(),
+                ),
+            )
+            .then(
+                ext_operation_resolver::ext(env::current_account_id())
+                    .with_static_gas(self.gas.finish_operation)
+                    .finish_operation(operation_id.into()),
+            )
     }
 }
 
@@ -174,23 +1327,72 @@ pub trait ContractResolver {
         asset_id: AssetId,
         amount: U128,
         expected: Option<ExpectedPrice>,
+        options: BuyOptions,
         #[callback_unwrap] price: PriceData,
     ) -> U128;
-    fn sell_with_price(
+    #[allow(clippy::too_many_arguments)]
+    fn buy_batch_with_price(
         &mut self,
         account_id: AccountId,
         asset_id: AssetId,
         amount: U128,
+        receivers: Vec<(AccountId, U128)>,
         expected: Option<ExpectedPrice>,
+        operation_id: U64,
         #[callback_unwrap] price: PriceData,
-    ) -> Promise;
-    fn resolve_sell(
+    ) -> U128;
+    fn sell_with_price(
+        &mut self,
+        account_id: AccountId,
+        asset_id: AssetId,
+        amount: U128,
+        expected: Option<ExpectedPrice>,
+        options: SellOptions,
+        #[callback_unwrap] price: PriceData,
+    ) -> PromiseOrValue<()>;
+    fn sell_all_with_price(
+        &mut self,
+        account_id: AccountId,
+        asset_id: AssetId,
+        expected: Option<ExpectedPrice>,
+        operation_id: U64,
+        #[callback_unwrap] price: PriceData,
+    ) -> PromiseOrValue<()>;
+    #[allow(clippy::too_many_arguments)]
+    fn sell_from_with_price(
         &mut self,
+        operator_id: AccountId,
         account_id: AccountId,
+        asset_id: AssetId,
         amount: U128,
+        expected: Option<ExpectedPrice>,
+        options: SellOptions,
+        #[callback_unwrap] price: PriceData,
+    ) -> PromiseOrValue<()>;
+    #[allow(clippy::too_many_arguments)]
+    fn sell_exact_with_price(
+        &mut self,
+        account_id: AccountId,
         asset_id: AssetId,
         asset_amount: U128,
-        price: U128,
+        expected: Option<ExpectedPrice>,
+        receiver_id: Option<AccountId>,
+        operation_id: U64,
+        #[callback_unwrap] price: PriceData,
+    ) -> Promise;
+    fn sell_basket_with_prices(
+        &mut self,
+        account_id: AccountId,
+        amount: U128,
+        asset_ids: Vec<AssetId>,
+        operation_id: U64,
+    ) -> Promise;
+    fn resolve_sell(&mut self, account_id: AccountId, leg: OperationLeg, operation_id: Option<U64>);
+    fn cache_price(
+        &mut self,
+        asset_id: AssetId,
+        keeper_id: Option<AccountId>,
+        #[callback_unwrap] price: PriceData,
     );
 }
 
@@ -203,21 +1405,112 @@ impl ContractResolver for Contract {
         asset_id: AssetId,
         amount: U128,
         expected: Option<ExpectedPrice>,
+        options: BuyOptions,
         #[callback_unwrap] data: PriceData,
     ) -> U128 {
-        let asset = self
-            .treasury
-            .assert_asset_status(&asset_id, AssetStatus::Enabled);
+        let operation_id = options.operation_id;
+        self.resolve_kyc(&account_id, 1);
+        if let Some(operation_id) = operation_id {
+            self.operations
+                .set_stage(operation_id.0, OperationStage::Settling);
+        }
+        let asset = self.treasury.assert_can_buy(&asset_id);
 
+        let pegged = data.pegged;
         let price = ExchangePrice::from_price_data(&asset, data);
 
         if let Some(expected) = expected {
             expected.assert_price(price);
         }
 
-        self.internal_buy(&account_id, &asset_id, amount.into(), asset.decimals, price);
+        if !pegged {
+            self.treasury.set_asset_price(&asset_id, price);
+        }
+        let (used, minted) = match options.kt_amount {
+            Some(kt_amount) => {
+                let asset_amount = self.internal_buy_exact(
+                    &account_id,
+                    &asset_id,
+                    kt_amount.into(),
+                    amount.into(),
+                    asset.decimals,
+                    price,
+                    &options,
+                );
+                (asset_amount, kt_amount.0)
+            }
+            None => self.internal_buy_allowed(
+                &account_id,
+                &asset_id,
+                amount.into(),
+                asset.decimals,
+                price,
+                &options,
+            ),
+        };
+        if let Some(operation_id) = operation_id {
+            self.operations
+                .set_leg_amount(operation_id.0, &asset_id, (amount.0 - used).into());
+        }
+        self.charge_relay_fee(&account_id);
+        log!(
+            "Account @{} bought {} KT for {} {}{}",
+            account_id,
+            minted,
+            used,
+            asset_id,
+            options
+                .memo
+                .as_ref()
+                .map_or_else(String::new, |memo| format!(", memo: {}", memo))
+        );
+        U128::from(amount.0 - used)
+    }
+
+    /// Buys KT for every receiver, returns the unallocated amount.
+    #[private]
+    #[allow(clippy::too_many_arguments)]
+    fn buy_batch_with_price(
+        &mut self,
+        account_id: AccountId,
+        asset_id: AssetId,
+        amount: U128,
+        receivers: Vec<(AccountId, U128)>,
+        expected: Option<ExpectedPrice>,
+        operation_id: U64,
+        #[callback_unwrap] data: PriceData,
+    ) -> U128 {
+        self.resolve_kyc(&account_id, 1);
+        self.operations
+            .set_stage(operation_id.0, OperationStage::Settling);
+        let asset = self.treasury.assert_can_buy(&asset_id);
+
+        let pegged = data.pegged;
+        let price = ExchangePrice::from_price_data(&asset, data);
+
+        if let Some(expected) = expected {
+            expected.assert_price(price);
+        }
 
-        U128::from(0)
+        if !pegged {
+            self.treasury.set_asset_price(&asset_id, price);
+        }
+        let mut unused = amount.0;
+        for (receiver_id, receiver_amount) in receivers {
+            unused = unused
+                .checked_sub(receiver_amount.0)
+                .unwrap_or_else(|| env::panic_str("Batch amounts exceed the transferred amount"));
+            let (used, _) = self.internal_buy_allowed(
+                &receiver_id,
+                &asset_id,
+                receiver_amount.into(),
+                asset.decimals,
+                price,
+                &BuyOptions::default(),
+            );
+            unused += receiver_amount.0 - used;
+        }
+        U128::from(unused)
     }
 
     #[private]
@@ -227,76 +1520,276 @@ impl ContractResolver for Contract {
         asset_id: AssetId,
         amount: U128,
         expected: Option<ExpectedPrice>,
+        options: SellOptions,
         #[callback_unwrap] data: PriceData,
-    ) -> Promise {
-        let asset = self
-            .treasury
-            .assert_asset_status(&asset_id, AssetStatus::Enabled);
+    ) -> PromiseOrValue<()> {
+        let SellOptions {
+            shortfall,
+            receiver_id,
+            memo,
+            operation_id,
+            fallback_id,
+        } = options;
+        // The fallback price follows the optional KYC result
+        if fallback_id.is_none() || env::promise_results_count() > 2 {
+            self.resolve_kyc(&account_id, 1);
+        }
+        let operation_id = operation_id.map(|operation_id| operation_id.0);
+        if let Some(operation_id) = operation_id {
+            self.operations
+                .set_stage(operation_id, OperationStage::Settling);
+        }
+        let asset = self.treasury.assert_can_sell(&asset_id);
 
+        let pegged = data.pegged;
         let price = ExchangePrice::from_price_data(&asset, data);
 
         if let Some(expected) = expected {
             expected.assert_price(price);
         }
 
-        let asset_amount =
-            self.internal_sell(&account_id, &asset_id, amount.into(), asset.decimals, price);
+        if !pegged {
+            self.treasury.set_asset_price(&asset_id, price);
+        }
 
-        let price = price.to_decimals().into();
+        let asset_amount = exchange_kt_to_asset(amount.into(), asset.decimals, price)
+            .unwrap_or_else(|| env::panic_str("Exchange amount overflow"));
+        let is_short =
+            asset_amount > self.sell_limit(self.available_balance(&asset_id, asset.balance));
+        let legs = match shortfall {
+            Some(Shortfall::Fallback) if is_short => {
+                let fallback_id = fallback_id
+                    .unwrap_or_else(|| env::panic_str("The treasury doesn't have enough balance"));
+                let index = env::promise_results_count() - 1;
+                let fallback_price = self.price_result(index, &fallback_id);
+                self.internal_sell_with_fallback(
+                    &account_id,
+                    &asset_id,
+                    amount.into(),
+                    price,
+                    (fallback_id, fallback_price),
+                    memo.as_deref(),
+                )
+            }
+            Some(Shortfall::Queue) if is_short => self.internal_sell_with_queue(
+                &account_id,
+                &asset_id,
+                amount.into(),
+                price,
+                memo.as_deref(),
+            ),
+            _ => vec![self.internal_sell_leg(
+                &account_id,
+                &asset_id,
+                amount.into(),
+                asset.decimals,
+                price,
+                memo.as_deref(),
+            )],
+        };
 
-        ext_ft_transfer::ext(asset_id.clone())
-            .with_static_gas(GAS_FOR_TRANSFER)
-            .with_attached_deposit(ONE_YOCTO)
-            .ft_transfer(account_id.clone(), asset_amount, None)
-            .then(
-                ext_self::ext(env::current_account_id())
-                    .with_static_gas(GAS_FOR_RESOLVE_SELL)
-                    .resolve_sell(account_id, amount, asset_id, asset_amount, price),
-            )
+        if legs.is_empty() {
+            return PromiseOrValue::Value(());
+        }
+        let receiver_id = receiver_id.unwrap_or_else(|| account_id.clone());
+        self.sell_transfers(&account_id, &receiver_id, legs, operation_id)
+            .into()
     }
 
+    /// Sells the balance of the account at the time the price is received.
     #[private]
-    fn resolve_sell(
+    fn sell_all_with_price(
         &mut self,
         account_id: AccountId,
+        asset_id: AssetId,
+        expected: Option<ExpectedPrice>,
+        operation_id: U64,
+        #[callback_unwrap] data: PriceData,
+    ) -> PromiseOrValue<()> {
+        let balance = self.token.internal_unwrap_balance_of(&account_id).amount;
+        require!(balance > 0, "Nothing to sell");
+        self.sell_with_price(
+            account_id,
+            asset_id,
+            balance.into(),
+            expected,
+            SellOptions {
+                operation_id: Some(operation_id),
+                ..Default::default()
+            },
+            data,
+        )
+    }
+
+    /// Spends the allowance of the operator once the price is received,
+    /// so it's kept if the sell fails.
+    #[private]
+    #[allow(clippy::too_many_arguments)]
+    fn sell_from_with_price(
+        &mut self,
+        operator_id: AccountId,
+        account_id: AccountId,
+        asset_id: AssetId,
         amount: U128,
+        expected: Option<ExpectedPrice>,
+        options: SellOptions,
+        #[callback_unwrap] data: PriceData,
+    ) -> PromiseOrValue<()> {
+        self.internal_spend_allowance(&account_id, &operator_id, amount.0);
+        self.sell_with_price(account_id, asset_id, amount, expected, options, data)
+    }
+
+    #[private]
+    #[allow(clippy::too_many_arguments)]
+    fn sell_exact_with_price(
+        &mut self,
+        account_id: AccountId,
         asset_id: AssetId,
         asset_amount: U128,
-        price: U128,
+        expected: Option<ExpectedPrice>,
+        receiver_id: Option<AccountId>,
+        operation_id: U64,
+        #[callback_unwrap] data: PriceData,
+    ) -> Promise {
+        self.resolve_kyc(&account_id, 1);
+        self.operations
+            .set_stage(operation_id.0, OperationStage::Settling);
+        let asset = self.treasury.assert_can_sell(&asset_id);
+
+        let pegged = data.pegged;
+        let price = ExchangePrice::from_price_data(&asset, data);
+
+        if let Some(expected) = expected {
+            expected.assert_price(price);
+        }
+
+        if !pegged {
+            self.treasury.set_asset_price(&asset_id, price);
+        }
+        let leg = self.internal_sell_exact(
+            &account_id,
+            &asset_id,
+            asset_amount.into(),
+            asset.decimals,
+            price,
+            None,
+        );
+        let receiver_id = receiver_id.unwrap_or_else(|| account_id.clone());
+        self.sell_transfers(&account_id, &receiver_id, vec![leg], Some(operation_id.0))
+    }
+
+    /// Sells the basket at the prices of `asset_ids`, received in the same order.
+    #[private]
+    fn sell_basket_with_prices(
+        &mut self,
+        account_id: AccountId,
+        amount: U128,
+        asset_ids: Vec<AssetId>,
+        operation_id: U64,
+    ) -> Promise {
+        self.resolve_kyc(&account_id, asset_ids.len() as u64);
+        self.operations
+            .set_stage(operation_id.0, OperationStage::Settling);
+        let prices = asset_ids
+            .into_iter()
+            .enumerate()
+            .map(|(i, asset_id)| {
+                let price = self.price_result(i as u64, &asset_id);
+                (asset_id, price)
+            })
+            .collect();
+        let legs = self.internal_sell_basket(&account_id, amount.into(), prices);
+        self.sell_transfers(&account_id, &account_id, legs, Some(operation_id.0))
+    }
+
+    /// Refunds the failed payout, unless the operation was recovered meanwhile.
+    #[private]
+    fn resolve_sell(
+        &mut self,
+        account_id: AccountId,
+        leg: OperationLeg,
+        operation_id: Option<U64>,
     ) {
+        if let Some(operation_id) = operation_id {
+            if !self
+                .operations
+                .resolve_leg(operation_id.0, &leg.asset_id, leg.asset_amount)
+            {
+                return;
+            }
+        }
+        self.internal_finish_payout(&leg.asset_id, leg.asset_amount.0);
         match env::promise_result(0) {
             PromiseResult::NotReady => env::abort(),
             PromiseResult::Successful(_) => {}
-            PromiseResult::Failed => {
-                self.treasury
-                    .internal_deposit(&asset_id, asset_amount.into());
-                self.token
-                    .internal_deposit(&account_id, amount.into(), price.into());
+            PromiseResult::Failed => self.internal_refund_sell(&account_id, &leg),
+        }
+    }
 
-                FtMint {
-                    owner_id: &account_id,
-                    amount: &amount,
-                    memo: Some("refund"),
-                }
-                .emit();
-            }
+    #[private]
+    fn cache_price(
+        &mut self,
+        asset_id: AssetId,
+        keeper_id: Option<AccountId>,
+        #[callback_unwrap] data: PriceData,
+    ) {
+        let asset = self.treasury.assert_asset(&asset_id);
+        let price = ExchangePrice::from_price_data(&asset, data);
+        let is_stale = self.is_price_stale(&asset_id);
+        self.treasury.set_asset_price(&asset_id, price);
+        if let (Some(keeper_id), true) = (keeper_id, is_stale) {
+            self.reward_price_refresh(&keeper_id, &asset_id, price);
         }
     }
 }
 
+/// Rejects memos too long to be echoed in the events.
+pub(crate) fn assert_valid_memo(memo: Option<&str>) {
+    if let Some(memo) = memo {
+        require!(memo.len() <= MAX_MEMO_LENGTH, "Memo is too long");
+    }
+}
+
+/// Parses a `U128` promise result, `None` if the promise failed.
+pub(crate) fn promise_result_u128() -> Option<Balance> {
+    match env::promise_result(0) {
+        PromiseResult::NotReady => env::abort(),
+        PromiseResult::Successful(value) => near_sdk::serde_json::from_slice::<U128>(&value)
+            .ok()
+            .map(Into::into),
+        PromiseResult::Failed => None,
+    }
+}
+
 #[ext_contract(ext_ft_transfer)]
 pub trait FungibleTokenTransfer {
     fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
+    fn ft_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: String,
+    ) -> U128;
 }
 
 #[cfg(all(test, not(target_arch = "wasm32")))]
 mod tests {
     use near_contract_standards::fungible_token::core::FungibleTokenCore;
-    use near_sdk::test_utils::{accounts, VMContextBuilder};
-    use near_sdk::{testing_env, AccountId, Balance, ONE_YOCTO};
+    use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
+    use near_sdk::{
+        testing_env, AccountId, Balance, PromiseOrValue, PromiseResult, RuntimeFeesConfig,
+        VMConfig, ONE_YOCTO,
+    };
 
-    use crate::oracle::ExchangePrice;
-    use crate::Contract;
+    use crate::claims::SellRefund;
+    use crate::fees::FeeSplit;
+    use crate::holding::HoldingFeeTier;
+    use crate::operations::OperationLeg;
+    use crate::oracle::{ExchangePrice, Price, PriceData};
+    use crate::price::exchange_asset_to_kt_cost;
+    use crate::{BuyOptions, Contract, ContractResolver, SellOptions, Shortfall};
 
     const AMOUNT: Balance = 3_000_000_000_000_000_000_000_000;
 
@@ -313,7 +1806,7 @@ mod tests {
     fn test_new() {
         let mut context = get_context(accounts(0));
         testing_env!(context.build());
-        let contract = Contract::new(accounts(1), accounts(4));
+        let contract = Contract::new(accounts(1), accounts(4), None, None);
         testing_env!(context.is_view(true).build());
         assert_eq!(contract.owner_id, accounts(1));
         assert_eq!(contract.ft_total_supply().0, 0);
@@ -332,7 +1825,7 @@ mod tests {
     fn test_transfer() {
         let mut context = get_context(accounts(0));
         testing_env!(context.build());
-        let mut contract = Contract::new(accounts(1), accounts(4));
+        let mut contract = Contract::new(accounts(1), accounts(4), None, None);
         contract.token.internal_deposit(&accounts(2), AMOUNT, 1);
 
         testing_env!(context
@@ -356,19 +1849,26 @@ mod tests {
             (accounts(1), accounts(2), accounts(3), accounts(4));
         let mut context = get_context(accounts(0));
         testing_env!(context.build());
-        let mut contract = Contract::new(owner_id.clone(), oracle_id);
+        let mut contract = Contract::new(owner_id.clone(), oracle_id, None, None);
 
         let amount = 1_000_000;
         let decimals = 6;
         testing_env!(context.predecessor_account_id(owner_id).build());
-        contract.add_asset(&asset_id, decimals);
+        contract.internal_add_asset(&asset_id, decimals);
 
         testing_env!(context
             .attached_deposit(ONE_YOCTO)
             .predecessor_account_id(account_id.clone())
             .build());
         let price = ExchangePrice::new(10001, 10);
-        contract.internal_buy(&account_id, &asset_id, amount, decimals, price);
+        contract.internal_buy(
+            &account_id,
+            &asset_id,
+            amount,
+            decimals,
+            price,
+            &BuyOptions::default(),
+        );
         assert_eq!(contract.treasury.supported_assets()[0].1.balance, amount);
         assert_eq!(
             contract.ft_balance_of(account_id).0,
@@ -376,33 +1876,748 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_internal_buy_referral_fee() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(3), 6);
+        contract.set_buy_fee(100, 5_000);
+        contract.set_fee_split(FeeSplit {
+            treasury: 5_000,
+            insurance: 5_000,
+            operator: 0,
+            operator_id: None,
+        });
+
+        let price = ExchangePrice::new(10000, 10);
+        contract.internal_buy(
+            &accounts(2),
+            &accounts(3),
+            1_000_000,
+            6,
+            price,
+            &BuyOptions {
+                referrer_id: Some(accounts(0)),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            contract.ft_balance_of(accounts(2)).0,
+            990_000_000_000_000_000
+        );
+        assert_eq!(
+            contract.treasury.assert_asset(&accounts(3)).balance,
+            992_500
+        );
+        assert_eq!(contract.get_insurance_fund()[&accounts(3)].0, 2_500);
+        assert_eq!(contract.get_claims(accounts(0))[&accounts(3)].0, 5_000);
+        assert_eq!(
+            contract.get_referral_earnings(accounts(0))[&accounts(3)].0,
+            5_000
+        );
+        let collected = &contract.get_collected_fees()[&accounts(3)];
+        assert_eq!(collected.treasury.0, 2_500);
+        assert_eq!(collected.insurance.0, 2_500);
+    }
+
+    #[test]
+    fn test_internal_buy_exact() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(3), 6);
+
+        let price = ExchangePrice::new(10001, 10);
+        let kt_amount = 1_000_000_000_000_000_001;
+        let used = contract.internal_buy_exact(
+            &accounts(2),
+            &accounts(3),
+            kt_amount,
+            2_000_000,
+            6,
+            price,
+            &BuyOptions::default(),
+        );
+        assert_eq!(used, 1_000_101);
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, kt_amount);
+        assert_eq!(contract.treasury.assert_asset(&accounts(3)).balance, used);
+    }
+
+    #[test]
+    fn test_internal_buy_exact_referral_fee() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(3), 6);
+        contract.set_buy_fee(100, 5_000);
+
+        let used = contract.internal_buy_exact(
+            &accounts(2),
+            &accounts(3),
+            1_000_000_000_000_000_000,
+            2_000_000,
+            6,
+            ExchangePrice::new(10000, 10),
+            &BuyOptions {
+                referrer_id: Some(accounts(0)),
+                ..Default::default()
+            },
+        );
+        assert_eq!(used, 1_010_000);
+        assert_eq!(
+            contract.get_referral_earnings(accounts(0))[&accounts(3)].0,
+            5_000
+        );
+    }
+
+    #[test]
+    fn test_buy_with_price_unused() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(3), 6);
+        let data = || PriceData::new(false, Some(Price::new(10000, 16)));
+
+        let unused = contract.buy_with_price(
+            accounts(2),
+            accounts(3),
+            2_000_000.into(),
+            None,
+            BuyOptions {
+                kt_amount: Some(1_000_000_000_000_000_000.into()),
+                ..Default::default()
+            },
+            data(),
+        );
+        assert_eq!(unused.0, 1_000_000);
+
+        let unused = contract.buy_with_price(
+            accounts(2),
+            accounts(3),
+            500_000.into(),
+            None,
+            BuyOptions::default(),
+            data(),
+        );
+        assert_eq!(unused.0, 0);
+        assert!(get_logs()
+            .iter()
+            .any(|log| log == "Account @charlie bought 500000000000000000 KT for 500000 danny"));
+    }
+
+    #[test]
+    fn test_buy_with_price_memo() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(3), 6);
+
+        contract.buy_with_price(
+            accounts(2),
+            accounts(3),
+            500_000.into(),
+            None,
+            BuyOptions {
+                memo: Some("order-1".to_string()),
+                ..Default::default()
+            },
+            PriceData::new(false, Some(Price::new(10000, 16))),
+        );
+        let logs = get_logs();
+        assert!(logs.iter().any(|log| log
+            == "Account @charlie bought 500000000000000000 KT for 500000 danny, memo: order-1"));
+        assert!(logs
+            .iter()
+            .any(|log| log.contains(r#""event":"kt_buy""#) && log.contains(r#""memo":"order-1""#)));
+    }
+
+    #[test]
+    fn test_buy_with_price_partial() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(3), 6);
+        contract.set_asset_limits(
+            &accounts(3),
+            0.into(),
+            Some(2_000_000.into()),
+            0.into(),
+            None,
+        );
+        contract.set_supply_cap(Some(1_500_000_000_000_000_000.into()));
+        let data = || PriceData::new(false, Some(Price::new(10000, 16)));
+
+        // Capped by the maximum buy first, then by the supply cap
+        let unused = contract.buy_with_price(
+            accounts(2),
+            accounts(3),
+            3_000_000.into(),
+            None,
+            BuyOptions::default(),
+            data(),
+        );
+        assert_eq!(unused.0, 1_500_000);
+        assert_eq!(contract.ft_total_supply().0, 1_500_000_000_000_000_000);
+
+        let unused = contract.buy_with_price(
+            accounts(2),
+            accounts(3),
+            1_000_000.into(),
+            None,
+            BuyOptions::default(),
+            data(),
+        );
+        assert_eq!(unused.0, 1_000_000);
+
+        contract.set_supply_cap(None);
+        contract.set_allowlist_enabled(true);
+        let unused = contract.buy_with_price(
+            accounts(2),
+            accounts(3),
+            1_000_000.into(),
+            None,
+            BuyOptions::default(),
+            data(),
+        );
+        assert_eq!(unused.0, 1_000_000);
+    }
+
+    #[test]
+    fn test_sell_all_with_price() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(3), 6);
+        let price = ExchangePrice::new(10000, 10);
+        contract.internal_buy(
+            &accounts(2),
+            &accounts(3),
+            3_000_000,
+            6,
+            price,
+            &BuyOptions::default(),
+        );
+
+        let data = PriceData::new(false, Some(Price::new(10000, 16)));
+        contract.sell_all_with_price(accounts(2), accounts(3), None, 0.into(), data);
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 0);
+        assert_eq!(contract.treasury.assert_asset(&accounts(3)).balance, 0);
+    }
+
+    #[test]
+    fn test_sell_from_with_price() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.attached_deposit(10u128.pow(23)).build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(3), 6);
+        let price = ExchangePrice::new(10000, 10);
+        contract.internal_buy(
+            &accounts(2),
+            &accounts(3),
+            3_000_000,
+            6,
+            price,
+            &BuyOptions::default(),
+        );
+        contract.approve(accounts(5), 2_000_000_000_000_000_000.into(), None);
+
+        let data = PriceData::new(false, Some(Price::new(10000, 16)));
+        contract.sell_from_with_price(
+            accounts(5),
+            accounts(2),
+            accounts(3),
+            1_500_000_000_000_000_000.into(),
+            None,
+            SellOptions::default(),
+            data,
+        );
+        assert_eq!(
+            contract.ft_balance_of(accounts(2)).0,
+            1_500_000_000_000_000_000
+        );
+        let allowance = contract.allowance(accounts(2), accounts(5)).unwrap();
+        assert_eq!(allowance.amount.0, 500_000_000_000_000_000);
+    }
+
+    #[test]
+    fn test_internal_sell_exact() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(3), 6);
+        contract.set_holding_fee(vec![HoldingFeeTier {
+            held_under: u64::MAX.into(),
+            fee: 100,
+        }]);
+        contract.internal_buy(
+            &accounts(2),
+            &accounts(3),
+            3_000_000,
+            6,
+            ExchangePrice::new(10000, 10),
+            &BuyOptions::default(),
+        );
+
+        let price = ExchangePrice::new(10001, 10);
+        let (_, kt_amount, _, _, fee) =
+            contract.internal_sell_exact(&accounts(2), &accounts(3), 1_000_000, 6, price, None);
+        // 1_010_102 asset units before the 1% fee of 10_102
+        assert_eq!(fee, 10_102);
+        assert_eq!(
+            kt_amount,
+            exchange_asset_to_kt_cost(1_010_102, 6, price).unwrap()
+        );
+        assert_eq!(
+            contract.ft_balance_of(accounts(2)).0,
+            3_000_000_000_000_000_000 - kt_amount
+        );
+        assert_eq!(
+            contract.treasury.assert_asset(&accounts(3)).balance,
+            2_000_000
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Transferred amount doesn't cover the cost of 1000101")]
+    fn test_internal_buy_exact_not_covered() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(3), 6);
+
+        let price = ExchangePrice::new(10001, 10);
+        let kt_amount = 1_000_000_000_000_000_001;
+        contract.internal_buy_exact(
+            &accounts(2),
+            &accounts(3),
+            kt_amount,
+            1_000_000,
+            6,
+            price,
+            &BuyOptions::default(),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Total supply cap of 1500000000000000000 is exceeded")]
+    fn test_internal_buy_supply_cap() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(3), 6);
+        contract.set_supply_cap(Some(1_500_000_000_000_000_000.into()));
+
+        let price = ExchangePrice::new(10000, 10);
+        contract.internal_buy(
+            &accounts(2),
+            &accounts(3),
+            1_000_000,
+            6,
+            price,
+            &BuyOptions::default(),
+        );
+        assert_eq!(contract.ft_total_supply().0, 1_000_000_000_000_000_000);
+        contract.internal_buy(
+            &accounts(2),
+            &accounts(3),
+            1_000_000,
+            6,
+            price,
+            &BuyOptions::default(),
+        );
+    }
+
+    #[test]
+    fn test_buy_batch_with_price() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(3), 6);
+
+        let receivers = vec![(accounts(1), 600_000.into()), (accounts(2), 300_000.into())];
+        let data = PriceData::new(false, Some(Price::new(10000, 16)));
+        let unused = contract.buy_batch_with_price(
+            accounts(1),
+            accounts(3),
+            1_000_000.into(),
+            receivers,
+            None,
+            0.into(),
+            data,
+        );
+        assert_eq!(unused.0, 100_000);
+        assert_eq!(
+            contract.treasury.assert_asset(&accounts(3)).balance,
+            900_000
+        );
+        assert_eq!(
+            contract.ft_balance_of(accounts(1)).0,
+            2 * contract.ft_balance_of(accounts(2)).0
+        );
+    }
+
+    #[test]
+    fn test_donate() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(3), 6);
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        let unused = contract.ft_on_transfer(accounts(2), 1_000.into(), r#""Donate""#.to_string());
+        assert!(matches!(unused, PromiseOrValue::Value(amount) if amount.0 == 0));
+        assert_eq!(contract.treasury.assert_asset(&accounts(3)).balance, 1_000);
+        assert_eq!(contract.ft_total_supply().0, 0);
+    }
+
+    #[test]
+    fn test_buy_below_minimum_is_unused() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(3), 6);
+        contract.set_asset_limits(&accounts(3), 1_000.into(), None, 0.into(), None);
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        let unused = contract.ft_on_transfer(accounts(2), 999.into(), r#"{"Buy":{}}"#.to_string());
+        assert!(matches!(unused, PromiseOrValue::Value(amount) if amount.0 == 999));
+        assert!(contract.get_pending_operations(accounts(2)).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Buy amount is too small")]
+    fn test_internal_buy_zero_kt() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(3), 6);
+        let price = ExchangePrice::new(10_000_000_000_000, 6);
+        contract.internal_buy(
+            &accounts(2),
+            &accounts(3),
+            1,
+            6,
+            price,
+            &BuyOptions::default(),
+        );
+    }
+
     #[test]
     fn test_internal_sell() {
         let (owner_id, account_id, asset_id, oracle_id) =
             (accounts(1), accounts(2), accounts(3), accounts(4));
         let mut context = get_context(accounts(0));
         testing_env!(context.build());
-        let mut contract = Contract::new(owner_id.clone(), oracle_id);
+        let mut contract = Contract::new(owner_id.clone(), oracle_id, None, None);
 
         let amount = 1_000_000;
         let decimals = 6;
         testing_env!(context.predecessor_account_id(owner_id).build());
-        contract.add_asset(&asset_id, decimals);
+        contract.internal_add_asset(&asset_id, decimals);
 
         testing_env!(context
             .attached_deposit(ONE_YOCTO)
             .predecessor_account_id(account_id.clone())
             .build());
         let price = ExchangePrice::new(10001, 10);
-        contract.internal_buy(&account_id, &asset_id, amount, decimals, price);
+        contract.internal_buy(
+            &account_id,
+            &asset_id,
+            amount,
+            decimals,
+            price,
+            &BuyOptions::default(),
+        );
         contract.internal_sell(
             &account_id,
             &asset_id,
             999_900_009_999_000_099,
             decimals,
             price,
+            None,
         );
         assert_eq!(contract.treasury.supported_assets()[0].1.balance, 1); // Rounding error
         assert_eq!(contract.ft_balance_of(account_id).0, 0);
     }
+
+    #[test]
+    fn test_internal_sell_pnl() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(3), 6);
+        let price = ExchangePrice::new(10000, 10);
+        contract.internal_buy(
+            &accounts(2),
+            &accounts(3),
+            1_000_000,
+            6,
+            price,
+            &BuyOptions::default(),
+        );
+
+        let price = ExchangePrice::new(12500, 10);
+        contract.internal_sell(
+            &accounts(2),
+            &accounts(3),
+            500_000_000_000_000_000,
+            6,
+            price,
+            None,
+        );
+        let logs = get_logs();
+        assert!(logs.iter().any(|log| log.contains(r#""event":"kt_sell""#)
+            && log.contains(r#""cost_basis":"1000000000000""#)
+            && log.contains(r#""pnl":"125000000000""#)));
+    }
+
+    #[test]
+    fn test_resolve_sell_claim_refund() {
+        let (owner_id, account_id, asset_id) = (accounts(1), accounts(2), accounts(3));
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new(owner_id.clone(), accounts(4), None, None);
+        testing_env!(context.predecessor_account_id(owner_id).build());
+        contract.internal_add_asset(&asset_id, 6);
+        contract.set_sell_refund(SellRefund::Claim);
+
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        let leg = OperationLeg {
+            asset_id: asset_id.clone(),
+            amount: 1_000.into(),
+            asset_amount: 10.into(),
+            price: 1.into(),
+            fee: 0.into(),
+        };
+        contract.resolve_sell(account_id.clone(), leg, None);
+        assert_eq!(contract.ft_balance_of(account_id.clone()).0, 0);
+        assert_eq!(contract.get_claims(account_id)[&asset_id].0, 10);
+        assert_eq!(contract.treasury.assert_asset(&asset_id).balance, 0);
+    }
+
+    #[test]
+    fn test_internal_sell_with_fallback() {
+        let (owner_id, account_id, oracle_id) = (accounts(1), accounts(2), accounts(4));
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new(owner_id.clone(), oracle_id, None, None);
+
+        testing_env!(context.predecessor_account_id(owner_id).build());
+        let price = ExchangePrice::new(10000, 10);
+        for asset_id in [accounts(0), accounts(3), accounts(5)] {
+            contract.internal_add_asset(&asset_id, 6);
+            contract.treasury.set_asset_price(&asset_id, price);
+        }
+        contract.internal_buy(
+            &account_id,
+            &accounts(0),
+            1_000_000,
+            6,
+            price,
+            &BuyOptions::default(),
+        );
+        contract.internal_buy(
+            &account_id,
+            &accounts(3),
+            1_000_000,
+            6,
+            price,
+            &BuyOptions::default(),
+        );
+        contract.internal_buy(
+            &account_id,
+            &accounts(5),
+            2_000_000,
+            6,
+            price,
+            &BuyOptions::default(),
+        );
+
+        assert_eq!(contract.fallback_asset_of(&accounts(3)), Some(accounts(5)));
+        let legs = contract.internal_sell_with_fallback(
+            &account_id,
+            &accounts(3),
+            2_500_000_000_000_000_000,
+            price,
+            (accounts(5), price),
+            None,
+        );
+        assert_eq!(legs.len(), 2);
+        assert_eq!((legs[0].0.clone(), legs[0].2 .0), (accounts(3), 1_000_000));
+        assert_eq!((legs[1].0.clone(), legs[1].2 .0), (accounts(5), 1_500_000));
+    }
+
+    #[test]
+    fn test_sell_with_price_fallback() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        let price = ExchangePrice::new(10000, 10);
+        for asset_id in [accounts(3), accounts(5)] {
+            contract.internal_add_asset(&asset_id, 6);
+            contract.treasury.set_asset_price(&asset_id, price);
+        }
+        contract.internal_buy(
+            &accounts(2),
+            &accounts(3),
+            1_000_000,
+            6,
+            price,
+            &BuyOptions::default(),
+        );
+        contract.internal_buy(
+            &accounts(2),
+            &accounts(5),
+            2_000_000,
+            6,
+            price,
+            &BuyOptions::default(),
+        );
+
+        // The fallback asset is worth twice its cached price now
+        let data = PriceData::new(false, Some(Price::new(10000, 16)));
+        let fallback_data = PriceData::new(false, Some(Price::new(5000, 16)));
+        let results = [&data, &fallback_data]
+            .map(|data| PromiseResult::Successful(near_sdk::serde_json::to_vec(data).unwrap()));
+        testing_env!(
+            context.build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            Vec::from(results),
+        );
+        contract.sell_with_price(
+            accounts(2),
+            accounts(3),
+            2_000_000_000_000_000_000.into(),
+            None,
+            SellOptions {
+                shortfall: Some(Shortfall::Fallback),
+                fallback_id: Some(accounts(5)),
+                memo: Some("order-2".to_string()),
+                ..Default::default()
+            },
+            data,
+        );
+        assert_eq!(contract.treasury.assert_asset(&accounts(3)).balance, 0);
+        assert_eq!(
+            contract.treasury.assert_asset(&accounts(5)).balance,
+            1_500_000
+        );
+        // Both legs echo the memo
+        let sells = get_logs()
+            .into_iter()
+            .filter(|log| log.contains(r#""event":"kt_sell""#))
+            .collect::<Vec<_>>();
+        assert_eq!(sells.len(), 2);
+        assert!(sells.iter().all(|log| log.contains(r#""memo":"order-2""#)));
+    }
+
+    #[test]
+    fn test_internal_sell_basket() {
+        let (owner_id, account_id, oracle_id) = (accounts(1), accounts(2), accounts(4));
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new(owner_id.clone(), oracle_id, None, None);
+
+        testing_env!(context.predecessor_account_id(owner_id).build());
+        let price = ExchangePrice::new(10000, 10);
+        for asset_id in [accounts(3), accounts(5)] {
+            contract.internal_add_asset(&asset_id, 6);
+            contract.treasury.set_asset_price(&asset_id, price);
+        }
+        contract.internal_buy(
+            &account_id,
+            &accounts(3),
+            3_000_000,
+            6,
+            price,
+            &BuyOptions::default(),
+        );
+        contract.internal_buy(
+            &account_id,
+            &accounts(5),
+            1_000_000,
+            6,
+            price,
+            &BuyOptions::default(),
+        );
+
+        let prices = vec![(accounts(3), price), (accounts(5), price)];
+        let legs = contract.internal_sell_basket(&account_id, 2_000_000_000_000_000_000, prices);
+        assert_eq!(legs.len(), 2);
+        assert_eq!(legs[0].2 .0, 1_500_000);
+        assert_eq!(legs[1].2 .0, 500_000);
+        assert_eq!(
+            contract.ft_balance_of(account_id).0,
+            2_000_000_000_000_000_000
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Account charlie is not verified")]
+    fn test_sell_basket_with_prices_not_verified() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.set_kyc_verifier(Some(accounts(5)), 1_000.into());
+        contract.internal_add_asset(&accounts(3), 6);
+
+        // The verifier result follows the asset price
+        let data = PriceData::new(false, Some(Price::new(10000, 16)));
+        testing_env!(
+            context.build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![
+                PromiseResult::Successful(near_sdk::serde_json::to_vec(&data).unwrap()),
+                PromiseResult::Successful(b"[]".to_vec()),
+            ],
+        );
+        contract.sell_basket_with_prices(accounts(2), 1_000.into(), vec![accounts(3)], 0.into());
+    }
+
+    #[test]
+    fn test_internal_sell_basket_below_min_sell() {
+        let (owner_id, account_id, oracle_id) = (accounts(1), accounts(2), accounts(4));
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new(owner_id.clone(), oracle_id, None, None);
+
+        testing_env!(context.predecessor_account_id(owner_id).build());
+        let price = ExchangePrice::new(10000, 10);
+        for asset_id in [accounts(3), accounts(5)] {
+            contract.internal_add_asset(&asset_id, 6);
+        }
+        contract.internal_buy(
+            &account_id,
+            &accounts(3),
+            3_000_000,
+            6,
+            price,
+            &BuyOptions::default(),
+        );
+        contract.internal_buy(
+            &account_id,
+            &accounts(5),
+            1_000_000,
+            6,
+            price,
+            &BuyOptions::default(),
+        );
+        contract.set_asset_limits(&accounts(5), 0.into(), None, 600_000.into(), None);
+
+        let prices = vec![(accounts(3), price), (accounts(5), price)];
+        let legs = contract.internal_sell_basket(&account_id, 2_000_000_000_000_000_000, prices);
+        assert_eq!(legs.len(), 1);
+        assert_eq!((legs[0].0.clone(), legs[0].2 .0), (accounts(3), 2_000_000));
+    }
 }
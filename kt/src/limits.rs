@@ -0,0 +1,435 @@
+use near_contract_standards::fungible_token::core::FungibleTokenCore;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen, require, AccountId, Balance, IntoStorageKey};
+use schemars::JsonSchema;
+
+use crate::events::KtEvent;
+use crate::{Contract, ContractExt};
+
+/// Length of a volume bucket, in nanoseconds.
+const BUCKET_DURATION: u64 = 3_600_000_000_000;
+/// Number of buckets in the rolling window, i.e. 24 hours.
+const WINDOW_BUCKETS: u64 = 24;
+
+fn current_bucket() -> u64 {
+    env::block_timestamp() / BUCKET_DURATION
+}
+
+/// Drops the buckets which are out of the rolling window.
+pub(crate) fn in_window(mut buckets: Vec<VolumeBucket>) -> Vec<VolumeBucket> {
+    let first_bucket = (current_bucket() + 1).saturating_sub(WINDOW_BUCKETS);
+    buckets.retain(|bucket| bucket.bucket >= first_bucket);
+    buckets
+}
+
+/// (minted, burned) KT of the buckets.
+pub(crate) fn total(buckets: &[VolumeBucket]) -> (Balance, Balance) {
+    buckets.iter().fold((0, 0), |(minted, burned), bucket| {
+        (
+            minted.saturating_add(bucket.minted),
+            burned.saturating_add(bucket.burned),
+        )
+    })
+}
+
+pub(crate) fn add_volume(buckets: &mut Vec<VolumeBucket>, minted: Balance, burned: Balance) {
+    let bucket = current_bucket();
+    match buckets.last_mut() {
+        Some(last) if last.bucket == bucket => {
+            last.minted = last.minted.saturating_add(minted);
+            last.burned = last.burned.saturating_add(burned);
+        }
+        _ => buckets.push(VolumeBucket {
+            bucket,
+            minted,
+            burned,
+        }),
+    }
+}
+
+/// Takes the burned amount back from the latest buckets.
+fn remove_burned(buckets: &mut [VolumeBucket], mut amount: Balance) {
+    for bucket in buckets.iter_mut().rev() {
+        let removed = bucket.burned.min(amount);
+        bucket.burned -= removed;
+        amount -= removed;
+        if amount == 0 {
+            break;
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum VolumeKind {
+    Mint,
+    Burn,
+}
+
+/// Maximum KT volume within the rolling window, unlimited if not set.
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, Default, JsonSchema,
+)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug, PartialEq, Eq))]
+pub struct Limits {
+    pub max_mint: Option<U128>,
+    pub max_burn: Option<U128>,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Clone, Copy)]
+pub struct VolumeBucket {
+    bucket: u64,
+    minted: Balance,
+    burned: Balance,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct VolumeView {
+    pub limits: Limits,
+    /// KT minted within the rolling window.
+    pub minted: U128,
+    /// KT burned within the rolling window.
+    pub burned: U128,
+}
+
+/// Per-account and global rolling limits of the minted and burned KT.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct VolumeLimits {
+    /// Circuit breaker thresholds, buys or sells are paused once reached.
+    global_limits: Limits,
+    /// Volume buckets of all accounts within the rolling window.
+    global_volume: Vec<VolumeBucket>,
+    default_limits: Limits,
+    /// AccountID -> Limits overriding the default ones.
+    overrides: LookupMap<AccountId, Limits>,
+    /// AccountID -> Volume buckets within the rolling window.
+    volumes: LookupMap<AccountId, Vec<VolumeBucket>>,
+}
+
+impl VolumeLimits {
+    pub fn new<S>(prefix: S) -> Self
+    where
+        S: IntoStorageKey,
+    {
+        let prefix = prefix.into_storage_key();
+        Self {
+            global_limits: Limits::default(),
+            global_volume: Vec::new(),
+            default_limits: Limits::default(),
+            overrides: LookupMap::new([prefix.clone(), b"o".to_vec()].concat()),
+            volumes: LookupMap::new([prefix, b"v".to_vec()].concat()),
+        }
+    }
+
+    pub fn set_global_limits(&mut self, limits: Limits) {
+        self.global_limits = limits;
+    }
+
+    pub fn global_limits(&self) -> Limits {
+        self.global_limits
+    }
+
+    /// (minted, burned) KT of all accounts within the rolling window.
+    pub fn global_volume(&self) -> (Balance, Balance) {
+        total(&in_window(self.global_volume.clone()))
+    }
+
+    pub fn set_default_limits(&mut self, limits: Limits) {
+        self.default_limits = limits;
+    }
+
+    pub fn default_limits(&self) -> Limits {
+        self.default_limits
+    }
+
+    pub fn set_account_limits(&mut self, account_id: &AccountId, limits: Option<Limits>) {
+        match limits {
+            Some(limits) => self.overrides.insert(account_id, &limits),
+            None => self.overrides.remove(account_id),
+        };
+    }
+
+    pub fn limits_of(&self, account_id: &AccountId) -> Limits {
+        self.overrides
+            .get(account_id)
+            .unwrap_or(self.default_limits)
+    }
+
+    /// (minted, burned) KT of the account within the rolling window.
+    pub fn volume_of(&self, account_id: &AccountId) -> (Balance, Balance) {
+        total(&in_window(self.volumes.get(account_id).unwrap_or_default()))
+    }
+
+    fn internal_record(&mut self, account_id: &AccountId, minted: Balance, burned: Balance) {
+        let mut buckets = in_window(self.volumes.get(account_id).unwrap_or_default());
+        add_volume(&mut buckets, minted, burned);
+        self.volumes.insert(account_id, &buckets);
+
+        let mut global_volume = in_window(std::mem::take(&mut self.global_volume));
+        add_volume(&mut global_volume, minted, burned);
+        self.global_volume = global_volume;
+    }
+
+    /// Panics while the global volume of the kind is at its threshold.
+    fn assert_not_paused(&self, kind: VolumeKind) {
+        let (minted, burned) = self.global_volume();
+        match kind {
+            VolumeKind::Mint => {
+                if matches!(self.global_limits.max_mint, Some(max_mint) if minted >= max_mint.0) {
+                    env::panic_str("Buys are paused by the circuit breaker")
+                }
+            }
+            VolumeKind::Burn => {
+                if matches!(self.global_limits.max_burn, Some(max_burn) if burned >= max_burn.0) {
+                    env::panic_str("Sells are paused by the circuit breaker")
+                }
+            }
+        }
+    }
+
+    /// Emits the event if the recorded amount made the global volume reach its threshold.
+    fn check_breaker(&self, kind: VolumeKind, amount: Balance) {
+        let (minted, burned) = self.global_volume();
+        let (volume, limit) = match kind {
+            VolumeKind::Mint => (minted, self.global_limits.max_mint),
+            VolumeKind::Burn => (burned, self.global_limits.max_burn),
+        };
+        if let Some(limit) = limit {
+            if volume >= limit.0 && volume.saturating_sub(amount) < limit.0 {
+                KtEvent::CircuitBreakerTripped {
+                    kind,
+                    volume: &volume.into(),
+                    limit: &limit,
+                }
+                .emit();
+            }
+        }
+    }
+
+    /// KT the account can still mint within the rolling window, `None` if unlimited.
+    pub fn mint_room(&self, account_id: &AccountId) -> Option<Balance> {
+        let max_mint = self.limits_of(account_id).max_mint?;
+        let (minted, _) = self.volume_of(account_id);
+        Some(max_mint.0.saturating_sub(minted))
+    }
+
+    pub fn record_mint(&mut self, account_id: &AccountId, amount: Balance) {
+        self.assert_not_paused(VolumeKind::Mint);
+        if let Some(max_mint) = self.limits_of(account_id).max_mint {
+            let (minted, _) = self.volume_of(account_id);
+            require!(
+                minted.saturating_add(amount) <= max_mint.0,
+                format!("Daily mint limit of {} is exceeded", max_mint.0)
+            );
+        }
+        self.internal_record(account_id, amount, 0);
+        self.check_breaker(VolumeKind::Mint, amount);
+    }
+
+    pub fn record_burn(&mut self, account_id: &AccountId, amount: Balance) {
+        self.assert_not_paused(VolumeKind::Burn);
+        if let Some(max_burn) = self.limits_of(account_id).max_burn {
+            let (_, burned) = self.volume_of(account_id);
+            require!(
+                burned.saturating_add(amount) <= max_burn.0,
+                format!("Daily burn limit of {} is exceeded", max_burn.0)
+            );
+        }
+        self.internal_record(account_id, 0, amount);
+        self.check_breaker(VolumeKind::Burn, amount);
+    }
+
+    /// Reverts the burn of a sell refunded after its payout failed.
+    pub fn revert_burn(&mut self, account_id: &AccountId, amount: Balance) {
+        let mut buckets = in_window(self.volumes.get(account_id).unwrap_or_default());
+        remove_burned(&mut buckets, amount);
+        self.volumes.insert(account_id, &buckets);
+
+        let mut global_volume = in_window(std::mem::take(&mut self.global_volume));
+        remove_burned(&mut global_volume, amount);
+        self.global_volume = global_volume;
+    }
+}
+
+impl Contract {
+    /// KT which can still be minted to the account under the supply cap and the mint
+    /// limit, `None` if unlimited.
+    pub(crate) fn mint_room(&self, account_id: &AccountId) -> Option<Balance> {
+        let supply_room = self
+            .supply_cap
+            .map(|cap| cap.saturating_sub(self.token.ft_total_supply().0));
+        match (supply_room, self.volume_limits.mint_room(account_id)) {
+            (Some(supply_room), Some(mint_room)) => Some(supply_room.min(mint_room)),
+            (supply_room, mint_room) => supply_room.or(mint_room),
+        }
+    }
+
+    pub(crate) fn assert_supply_cap(&self, amount: Balance) {
+        if let Some(cap) = self.supply_cap {
+            require!(
+                self.token.ft_total_supply().0.saturating_add(amount) <= cap,
+                format!("Total supply cap of {} is exceeded", cap)
+            );
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Sets the maximum KT total supply reachable by buys, `None` removes the cap.
+    pub fn set_supply_cap(&mut self, cap: Option<U128>) {
+        self.assert_owner();
+        self.supply_cap = cap.map(Into::into);
+    }
+
+    pub fn get_supply_cap(&self) -> Option<U128> {
+        self.supply_cap.map(Into::into)
+    }
+
+    /// Sets the rolling 24 hours thresholds of the minted and burned KT of all accounts,
+    /// buys or sells are paused once reached until the volume leaves the window.
+    pub fn set_global_volume_limits(&mut self, limits: Limits) {
+        self.assert_owner();
+        self.volume_limits.set_global_limits(limits);
+    }
+
+    pub fn get_global_volume(&self) -> VolumeView {
+        let (minted, burned) = self.volume_limits.global_volume();
+        VolumeView {
+            limits: self.volume_limits.global_limits(),
+            minted: minted.into(),
+            burned: burned.into(),
+        }
+    }
+
+    /// Sets the default rolling 24 hours limits of the minted and burned KT per account.
+    pub fn set_default_volume_limits(&mut self, limits: Limits) {
+        self.assert_owner();
+        self.volume_limits.set_default_limits(limits);
+    }
+
+    /// Overrides the default volume limits for the account, `None` resets them.
+    pub fn set_account_volume_limits(&mut self, account_id: AccountId, limits: Option<Limits>) {
+        self.assert_owner();
+        self.volume_limits.set_account_limits(&account_id, limits);
+    }
+
+    pub fn get_account_volume(&self, account_id: AccountId) -> VolumeView {
+        let (minted, burned) = self.volume_limits.volume_of(&account_id);
+        VolumeView {
+            limits: self.volume_limits.limits_of(&account_id),
+            minted: minted.into(),
+            burned: burned.into(),
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    use crate::limits::{Limits, VolumeLimits, BUCKET_DURATION, WINDOW_BUCKETS};
+    use crate::StorageKey;
+
+    fn limits(max_mint: u128, max_burn: u128) -> Limits {
+        Limits {
+            max_mint: Some(max_mint.into()),
+            max_burn: Some(max_burn.into()),
+        }
+    }
+
+    #[test]
+    fn test_rolling_window() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.block_timestamp(BUCKET_DURATION * 100).build());
+        let mut volume_limits = VolumeLimits::new(StorageKey::VolumeLimits);
+        volume_limits.set_default_limits(limits(100, 50));
+        volume_limits.record_mint(&accounts(1), 60);
+        volume_limits.record_burn(&accounts(1), 50);
+
+        testing_env!(context.block_timestamp(BUCKET_DURATION * 101).build());
+        volume_limits.record_mint(&accounts(1), 40);
+        assert_eq!(volume_limits.volume_of(&accounts(1)), (100, 50));
+
+        // The first bucket leaves the window
+        testing_env!(context
+            .block_timestamp(BUCKET_DURATION * (100 + WINDOW_BUCKETS))
+            .build());
+        assert_eq!(volume_limits.volume_of(&accounts(1)), (40, 0));
+        volume_limits.record_mint(&accounts(1), 60);
+    }
+
+    #[test]
+    fn test_revert_burn() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.block_timestamp(BUCKET_DURATION * 100).build());
+        let mut volume_limits = VolumeLimits::new(StorageKey::VolumeLimits);
+        volume_limits.set_default_limits(limits(100, 50));
+        volume_limits.record_burn(&accounts(1), 30);
+        testing_env!(context.block_timestamp(BUCKET_DURATION * 101).build());
+        volume_limits.record_burn(&accounts(1), 20);
+
+        // Taken back from the latest buckets first
+        volume_limits.revert_burn(&accounts(1), 25);
+        assert_eq!(volume_limits.volume_of(&accounts(1)), (0, 25));
+        assert_eq!(volume_limits.global_volume(), (0, 25));
+        volume_limits.record_burn(&accounts(1), 25);
+    }
+
+    #[test]
+    #[should_panic(expected = "Daily mint limit of 100 is exceeded")]
+    fn test_mint_limit_exceeded() {
+        let mut volume_limits = VolumeLimits::new(StorageKey::VolumeLimits);
+        volume_limits.set_default_limits(limits(100, 50));
+        volume_limits.record_mint(&accounts(1), 60);
+        volume_limits.record_mint(&accounts(1), 41);
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.block_timestamp(BUCKET_DURATION * 100).build());
+        let mut volume_limits = VolumeLimits::new(StorageKey::VolumeLimits);
+        volume_limits.set_global_limits(limits(100, 50));
+        volume_limits.record_mint(&accounts(1), 60);
+        // The trade reaching the threshold passes and trips the breaker
+        volume_limits.record_mint(&accounts(2), 60);
+        assert!(get_logs()[0].contains("circuit_breaker_tripped"));
+        assert_eq!(volume_limits.global_volume(), (120, 0));
+        volume_limits.record_burn(&accounts(2), 10);
+
+        testing_env!(context
+            .block_timestamp(BUCKET_DURATION * (100 + WINDOW_BUCKETS))
+            .build());
+        volume_limits.record_mint(&accounts(1), 10);
+    }
+
+    #[test]
+    #[should_panic(expected = "Buys are paused by the circuit breaker")]
+    fn test_circuit_breaker_paused() {
+        let mut volume_limits = VolumeLimits::new(StorageKey::VolumeLimits);
+        volume_limits.set_global_limits(limits(100, 50));
+        volume_limits.record_mint(&accounts(1), 100);
+        volume_limits.record_mint(&accounts(2), 1);
+    }
+
+    #[test]
+    fn test_account_override() {
+        let mut volume_limits = VolumeLimits::new(StorageKey::VolumeLimits);
+        volume_limits.set_default_limits(limits(100, 50));
+        volume_limits.set_account_limits(&accounts(1), Some(Limits::default()));
+        volume_limits.record_burn(&accounts(1), 1_000);
+        assert_eq!(volume_limits.limits_of(&accounts(2)), limits(100, 50));
+
+        volume_limits.set_account_limits(&accounts(1), None);
+        assert_eq!(volume_limits.limits_of(&accounts(1)), limits(100, 50));
+    }
+}
@@ -0,0 +1,89 @@
+//! Sell-only mode for an orderly wind-down or asset and oracle incidents.
+//! Buys are disabled while sells, redemptions and transfers stay open, so holders can always exit.
+
+use near_sdk::{env, near_bindgen};
+
+use crate::events::KtEvent;
+use crate::roles::Role;
+use crate::{Contract, ContractExt};
+
+impl Contract {
+    pub(crate) fn assert_buys_enabled(&self) {
+        if self.sell_only {
+            env::panic_str("Buys are disabled in the sell-only mode")
+        }
+    }
+
+    pub(crate) fn internal_set_sell_only(&mut self, enabled: bool) {
+        self.sell_only = enabled;
+        KtEvent::SellOnlyChanged { enabled }.emit();
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn set_sell_only(&mut self, enabled: bool) {
+        self.assert_owner_or_role(Role::Guardian);
+        self.internal_set_sell_only(enabled);
+    }
+
+    pub fn is_sell_only(&self) -> bool {
+        self.sell_only
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_contract_standards::fungible_token::core::FungibleTokenCore;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, ONE_YOCTO};
+
+    use crate::oracle::ExchangePrice;
+    use crate::test_utils;
+    use crate::{BuyOptions, Contract};
+
+    fn setup_contract(context: &mut VMContextBuilder) -> Contract {
+        let mut contract = test_utils::setup_contract_with_kt(context);
+        contract.set_sell_only(true);
+        contract
+    }
+
+    #[test]
+    #[should_panic(expected = "Buys are disabled in the sell-only mode")]
+    fn test_sell_only_buy() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        contract.internal_buy(
+            &accounts(1),
+            &accounts(3),
+            1_000_000,
+            6,
+            ExchangePrice::new(10000, 10),
+            &BuyOptions::default(),
+        );
+    }
+
+    #[test]
+    fn test_sell_only_exits() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        contract.internal_sell(
+            &accounts(1),
+            &accounts(3),
+            400_000_000_000_000_000,
+            6,
+            ExchangePrice::new(10000, 10),
+            None,
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        contract.ft_transfer(accounts(2), 100_000_000_000_000_000.into(), None);
+        assert_eq!(
+            contract.ft_balance_of(accounts(1)).0,
+            500_000_000_000_000_000
+        );
+    }
+}
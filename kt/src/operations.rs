@@ -0,0 +1,573 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::{LookupMap, UnorderedMap};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, ext_contract, log, near_bindgen, require, AccountId, IntoStorageKey};
+use schemars::JsonSchema;
+
+use crate::events::{KtEvent, RefundOperation, RefundReason};
+use crate::promise_result_u128;
+use crate::roles::Role;
+use crate::treasury::AssetId;
+use crate::{Contract, ContractExt, NANOS_PER_SECOND};
+
+pub type OperationId = u64;
+
+/// Minimum time before a pending operation can be recovered, its callbacks resolve
+/// within a few blocks.
+const MIN_OPERATION_TIMEOUT: u64 = 60 * NANOS_PER_SECOND;
+
+#[derive(
+    BorshDeserialize,
+    BorshSerialize,
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    JsonSchema,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum OperationKind {
+    Buy,
+    Sell,
+}
+
+/// Progress of a pending operation, in order.
+#[derive(
+    BorshDeserialize,
+    BorshSerialize,
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    JsonSchema,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum OperationStage {
+    /// Waiting for the attached NEAR to be wrapped.
+    Wrapping,
+    /// Waiting for the oracle price and the KYC check.
+    Pricing,
+    /// Executed, waiting for the payout transfers or the refund of the unused amount.
+    Settling,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct Operation {
+    pub account_id: AccountId,
+    pub kind: OperationKind,
+    /// Asset of the operation, `None` for multi-asset sells.
+    pub asset_id: Option<AssetId>,
+    pub amount: U128,
+    pub created_at: U64,
+    pub stage: OperationStage,
+    /// Funds in transit for the account, given back if the operation is recovered.
+    pub legs: Vec<OperationLeg>,
+}
+
+/// A sell payout sent to the account, or the wrapped NEAR held for a NEAR buy.
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, JsonSchema,
+)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct OperationLeg {
+    pub asset_id: AssetId,
+    /// KT amount sold for the payout, zero for buys.
+    pub amount: U128,
+    pub asset_amount: U128,
+    /// Price of the sell in KT decimals, to mint the KT back.
+    pub price: U128,
+    /// Holding fee of the sell, reverted with the refund.
+    pub fee: U128,
+}
+
+/// Buys and sells which are waiting for their callbacks.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Operations {
+    /// OperationID -> Pending operation.
+    operations: UnorderedMap<OperationId, Operation>,
+    /// AccountID -> Pending operation ids.
+    accounts: LookupMap<AccountId, Vec<OperationId>>,
+    next_id: OperationId,
+    /// Nanoseconds after the start before a pending operation can be recovered.
+    timeout: u64,
+}
+
+impl Operations {
+    pub fn new<S>(prefix: S) -> Self
+    where
+        S: IntoStorageKey,
+    {
+        let prefix = prefix.into_storage_key();
+        Self {
+            operations: UnorderedMap::new([prefix.clone(), b"o".to_vec()].concat()),
+            accounts: LookupMap::new([prefix, b"a".to_vec()].concat()),
+            next_id: 0,
+            timeout: 10 * MIN_OPERATION_TIMEOUT,
+        }
+    }
+
+    pub fn timeout(&self) -> u64 {
+        self.timeout
+    }
+
+    pub fn set_timeout(&mut self, timeout: u64) {
+        require!(
+            timeout >= MIN_OPERATION_TIMEOUT,
+            format!("Timeout is below the minimum of {}", MIN_OPERATION_TIMEOUT)
+        );
+        self.timeout = timeout;
+    }
+
+    pub fn pending_of(&self, account_id: &AccountId) -> Vec<(OperationId, Operation)> {
+        self.accounts
+            .get(account_id)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|id| self.operations.get(&id).map(|operation| (id, operation)))
+            .collect()
+    }
+
+    /// Whether an operation of the asset is pending, multi-asset sells count for every asset.
+    pub fn has_pending_asset(&self, asset_id: &AssetId) -> bool {
+        self.operations.values().any(|operation| {
+            operation
+                .asset_id
+                .as_ref()
+                .is_none_or(|operation_asset_id| operation_asset_id == asset_id)
+        })
+    }
+
+    pub fn has_pending(&self, account_id: &AccountId, kind: OperationKind) -> bool {
+        self.pending_of(account_id)
+            .iter()
+            .any(|(_, operation)| operation.kind == kind)
+    }
+
+    /// Rejects operations conflicting with a pending sell: its KT is burned in a callback,
+    /// so the balance can't change in between.
+    pub fn assert_no_pending_sell(&self, account_id: &AccountId) {
+        if self.has_pending(account_id, OperationKind::Sell) {
+            env::panic_str(format!("Account {} has a pending sell", account_id).as_str())
+        }
+    }
+
+    pub fn start(
+        &mut self,
+        account_id: &AccountId,
+        kind: OperationKind,
+        asset_id: Option<AssetId>,
+        amount: U128,
+        stage: OperationStage,
+    ) -> OperationId {
+        let id = self.next_id;
+        self.next_id += 1;
+        let operation = Operation {
+            account_id: account_id.clone(),
+            kind,
+            asset_id,
+            amount,
+            created_at: env::block_timestamp().into(),
+            stage,
+            legs: Vec::new(),
+        };
+        self.operations.insert(&id, &operation);
+
+        let mut ids = self.accounts.get(account_id).unwrap_or_default();
+        ids.push(id);
+        self.accounts.insert(account_id, &ids);
+        id
+    }
+
+    pub fn set_stage(&mut self, id: OperationId, stage: OperationStage) {
+        if let Some(mut operation) = self.operations.get(&id) {
+            operation.stage = stage;
+            self.operations.insert(&id, &operation);
+        }
+    }
+
+    pub fn add_leg(&mut self, id: OperationId, leg: OperationLeg) {
+        if let Some(mut operation) = self.operations.get(&id) {
+            operation.legs.push(leg);
+            self.operations.insert(&id, &operation);
+        }
+    }
+
+    /// Updates the asset amount held for the account, e.g. to the unused part of a buy.
+    pub fn set_leg_amount(&mut self, id: OperationId, asset_id: &AssetId, asset_amount: U128) {
+        if let Some(mut operation) = self.operations.get(&id) {
+            if let Some(leg) = operation
+                .legs
+                .iter_mut()
+                .find(|leg| &leg.asset_id == asset_id)
+            {
+                leg.asset_amount = asset_amount;
+                self.operations.insert(&id, &operation);
+            }
+        }
+    }
+
+    /// Removes the leg once its transfer is resolved. Returns false if the leg
+    /// isn't pending anymore, i.e. the operation was recovered meanwhile.
+    pub fn resolve_leg(&mut self, id: OperationId, asset_id: &AssetId, asset_amount: U128) -> bool {
+        let Some(mut operation) = self.operations.get(&id) else {
+            return false;
+        };
+        let Some(index) = operation
+            .legs
+            .iter()
+            .position(|leg| &leg.asset_id == asset_id && leg.asset_amount == asset_amount)
+        else {
+            return false;
+        };
+        operation.legs.remove(index);
+        self.operations.insert(&id, &operation);
+        true
+    }
+
+    /// Clears the operation if it's pending for longer than the timeout.
+    pub fn recover(&mut self, id: OperationId) -> Operation {
+        let operation = self
+            .operations
+            .get(&id)
+            .unwrap_or_else(|| env::panic_str("Operation is not pending"));
+        require!(
+            env::block_timestamp() >= operation.created_at.0.saturating_add(self.timeout),
+            "Operation is not timed out yet"
+        );
+        self.finish(id).unwrap()
+    }
+
+    /// Clears the operation unless some of its legs were never resolved, those are
+    /// kept for the recovery.
+    pub fn finish_resolved(&mut self, id: OperationId) {
+        if self
+            .operations
+            .get(&id)
+            .is_some_and(|operation| operation.legs.is_empty())
+        {
+            self.finish(id);
+        }
+    }
+
+    pub fn finish(&mut self, id: OperationId) -> Option<Operation> {
+        let operation = self.operations.remove(&id)?;
+        let mut ids = self.accounts.get(&operation.account_id).unwrap_or_default();
+        ids.retain(|other_id| *other_id != id);
+        if ids.is_empty() {
+            self.accounts.remove(&operation.account_id);
+        } else {
+            self.accounts.insert(&operation.account_id, &ids);
+        }
+        Some(operation)
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Buys and sells of the account waiting for their callbacks, with their stage.
+    pub fn get_pending_operations(&self, account_id: AccountId) -> Vec<(U64, Operation)> {
+        self.operations
+            .pending_of(&account_id)
+            .into_iter()
+            .map(|(id, operation)| (id.into(), operation))
+            .collect()
+    }
+
+    /// Clears an operation whose callbacks never finished, unblocking the account.
+    /// The unresolved sell payouts are refunded like failed ones and the wrapped NEAR
+    /// of a NEAR buy becomes a wNEAR claim. The KT is burned only once the sell executes
+    /// and fungible token buys are refunded by the transfer resolution.
+    pub fn recover_operation(&mut self, id: U64) {
+        self.assert_owner_or_role(Role::Keeper);
+        let operation = self.operations.recover(id.0);
+        for leg in &operation.legs {
+            match operation.kind {
+                OperationKind::Sell => {
+                    self.internal_finish_payout(&leg.asset_id, leg.asset_amount.0);
+                    self.internal_refund_sell(&operation.account_id, leg);
+                }
+                OperationKind::Buy => {
+                    self.claims.internal_add(
+                        &operation.account_id,
+                        &leg.asset_id,
+                        leg.asset_amount.0,
+                    );
+                    KtEvent::AssetClaimAdded {
+                        account_id: &operation.account_id,
+                        asset_id: &leg.asset_id,
+                        amount: &leg.asset_amount,
+                    }
+                    .emit();
+                }
+            }
+        }
+        log!(
+            "Recovered the operation {} of @{}",
+            id.0,
+            operation.account_id
+        );
+        KtEvent::OperationRecovered {
+            id: &id,
+            account_id: &operation.account_id,
+            kind: operation.kind,
+            stage: operation.stage,
+        }
+        .emit();
+    }
+
+    /// Sets the nanoseconds after which a pending operation can be recovered.
+    pub fn set_operation_timeout(&mut self, timeout: U64) {
+        self.assert_owner();
+        self.operations.set_timeout(timeout.0);
+    }
+
+    pub fn get_operation_timeout(&self) -> U64 {
+        self.operations.timeout().into()
+    }
+}
+
+#[ext_contract(ext_operation_resolver)]
+#[allow(dead_code)]
+pub trait OperationResolver {
+    fn finish_operation(&mut self, id: U64);
+    fn finish_buy(&mut self, id: U64, amount: U128) -> U128;
+}
+
+#[near_bindgen]
+impl OperationResolver for Contract {
+    /// Clears the operation once its callbacks are done, whatever the outcome,
+    /// unless a payout was never resolved.
+    #[private]
+    fn finish_operation(&mut self, id: U64) {
+        self.operations.finish_resolved(id.0);
+    }
+
+    /// Clears the buy and passes through the unused amount, everything is unused on failure.
+    #[private]
+    fn finish_buy(&mut self, id: U64, amount: U128) -> U128 {
+        let operation = self.operations.finish(id.0);
+        let (unused, reason) = match promise_result_u128() {
+            Some(unused) => (unused.min(amount.0), RefundReason::Unused),
+            None => (amount.0, RefundReason::BuyFailed),
+        };
+        if let Some(operation) = operation.filter(|_| unused > 0) {
+            KtEvent::KtRefund {
+                operation: RefundOperation::Buy,
+                reason,
+                account_id: &operation.account_id,
+                asset_id: operation.asset_id.as_ref(),
+                amount: None,
+                asset_amount: Some(&unused.into()),
+            }
+            .emit();
+        }
+        unused.into()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_contract_standards::fungible_token::core::FungibleTokenCore;
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
+    use near_sdk::{testing_env, PromiseResult, RuntimeFeesConfig, VMConfig};
+
+    use crate::operations::{
+        OperationKind, OperationLeg, OperationResolver, OperationStage, Operations,
+    };
+    use crate::oracle::{ExchangePrice, Price, PriceData};
+    use crate::{BuyOptions, Contract, ContractResolver, StorageKey};
+
+    const PRICING: OperationStage = OperationStage::Pricing;
+
+    #[test]
+    fn test_operations() {
+        let mut operations = Operations::new(StorageKey::Operations);
+        let buy = operations.start(&accounts(1), OperationKind::Buy, None, 1.into(), PRICING);
+        let sell = operations.start(&accounts(1), OperationKind::Sell, None, 2.into(), PRICING);
+        assert_eq!(operations.pending_of(&accounts(1)).len(), 2);
+        assert!(operations.has_pending(&accounts(1), OperationKind::Sell));
+        assert!(!operations.has_pending(&accounts(2), OperationKind::Buy));
+
+        assert_eq!(operations.finish(sell).unwrap().amount.0, 2);
+        assert!(operations.finish(sell).is_none());
+        assert!(!operations.has_pending(&accounts(1), OperationKind::Sell));
+        operations.finish(buy);
+        assert!(operations.pending_of(&accounts(1)).is_empty());
+    }
+
+    #[test]
+    fn test_buy_advances_own_operation() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(3), 6);
+        let first = contract.operations.start(
+            &accounts(1),
+            OperationKind::Buy,
+            Some(accounts(3)),
+            1_000_000.into(),
+            PRICING,
+        );
+        let second = contract.operations.start(
+            &accounts(1),
+            OperationKind::Buy,
+            Some(accounts(3)),
+            1_000_000.into(),
+            PRICING,
+        );
+
+        contract.buy_with_price(
+            accounts(1),
+            accounts(3),
+            1_000_000.into(),
+            None,
+            BuyOptions {
+                operation_id: Some(second.into()),
+                ..Default::default()
+            },
+            PriceData::new(false, Some(Price::new(10000, 16))),
+        );
+        let stages: Vec<_> = contract
+            .operations
+            .pending_of(&accounts(1))
+            .into_iter()
+            .map(|(id, operation)| (id, operation.stage))
+            .collect();
+        assert_eq!(
+            stages,
+            vec![(first, PRICING), (second, OperationStage::Settling)]
+        );
+    }
+
+    #[test]
+    fn test_finish_buy_refund() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        let id = contract.operations.start(
+            &accounts(1),
+            OperationKind::Buy,
+            Some(accounts(3)),
+            1_000.into(),
+            PRICING,
+        );
+
+        testing_env!(
+            context.build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(b"\"400\"".to_vec())],
+        );
+        assert_eq!(contract.finish_buy(id.into(), 1_000.into()).0, 400);
+        assert!(get_logs()[0].contains(
+            r#""operation":"buy","reason":"unused","account_id":"bob","asset_id":"danny","amount":null,"asset_amount":"400""#
+        ));
+    }
+
+    #[test]
+    fn test_recover_operation() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        let id =
+            contract
+                .operations
+                .start(&accounts(1), OperationKind::Sell, None, 1.into(), PRICING);
+
+        let timeout = contract.get_operation_timeout().0;
+        testing_env!(context.block_timestamp(timeout).build());
+        contract.recover_operation(id.into());
+        assert!(contract.get_pending_operations(accounts(1)).is_empty());
+        contract.operations.assert_no_pending_sell(&accounts(1));
+    }
+
+    #[test]
+    fn test_recover_operation_refunds_legs() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(3), 6);
+        let price = ExchangePrice::new(10000, 10);
+        contract.internal_buy(
+            &accounts(1),
+            &accounts(3),
+            1_000_000,
+            6,
+            price,
+            &BuyOptions::default(),
+        );
+        let amount = contract.ft_balance_of(accounts(1)).0;
+
+        let id = contract.operations.start(
+            &accounts(1),
+            OperationKind::Sell,
+            Some(accounts(3)),
+            amount.into(),
+            OperationStage::Settling,
+        );
+        let leg = contract.internal_sell_leg(&accounts(1), &accounts(3), amount, 6, price, None);
+        let asset_amount = leg.2;
+        let _ = contract.sell_transfers(&accounts(1), &accounts(1), vec![leg], Some(id));
+        contract.finish_operation(id.into());
+        assert_eq!(contract.get_pending_operations(accounts(1)).len(), 1);
+
+        let timeout = contract.get_operation_timeout().0;
+        testing_env!(context.block_timestamp(timeout).build());
+        contract.recover_operation(id.into());
+        assert!(contract.get_pending_operations(accounts(1)).is_empty());
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, amount);
+        assert_eq!(contract.payouts_in_flight.get(&accounts(3)), None);
+
+        // The late callback doesn't refund twice
+        testing_env!(
+            context.build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        let leg = OperationLeg {
+            asset_id: accounts(3),
+            amount: amount.into(),
+            asset_amount,
+            price: price.to_decimals().into(),
+            fee: 0.into(),
+        };
+        contract.resolve_sell(accounts(1), leg, Some(id.into()));
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, amount);
+    }
+
+    #[test]
+    #[should_panic(expected = "Operation is not timed out yet")]
+    fn test_recover_operation_early() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        let id =
+            contract
+                .operations
+                .start(&accounts(1), OperationKind::Buy, None, 1.into(), PRICING);
+        contract.recover_operation(id.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "Account bob has a pending sell")]
+    fn test_assert_no_pending_sell() {
+        let mut operations = Operations::new(StorageKey::Operations);
+        operations.start(&accounts(1), OperationKind::Sell, None, 1.into(), PRICING);
+        operations.assert_no_pending_sell(&accounts(1));
+    }
+}
@@ -1,10 +1,17 @@
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::json_types::{U128, U64};
 use near_sdk::serde::{Deserialize, Serialize};
-use near_sdk::{env, ext_contract, require, Balance};
+use near_sdk::{
+    env, ext_contract, near_bindgen, serde_json, AccountId, Balance, Gas, Promise, PromiseOrValue,
+    PromiseResult,
+};
+use schemars::JsonSchema;
 
+use crate::events::KtEvent;
+use crate::peg::ext_peg_resolver;
 use crate::price::convert_decimals;
 use crate::treasury::{AssetId, AssetInfo};
+use crate::{ext_self, Contract, ContractExt};
 
 const PRICE_DECIMALS: u8 = 18;
 
@@ -14,7 +21,7 @@ type Timestamp = U64;
 // Price USDC { multiplier: 10000, decimals: 10 }
 // 5 USDC = 5 * 10**6 * 10000 / 10**(10 - 6) = 5 * 10**6
 
-#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize)]
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, JsonSchema)]
 #[serde(crate = "near_sdk::serde")]
 pub struct Price {
     pub multiplier: U128,
@@ -31,38 +38,51 @@ impl Price {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, JsonSchema)]
 #[serde(crate = "near_sdk::serde")]
 pub struct PriceData {
+    /// Time the price was reported, if the oracle provides it.
+    #[serde(default)]
+    pub timestamp: Option<Timestamp>,
     pub expiration: Timestamp,
     pub price: Option<Price>,
+    /// The price is the peg fallback, not cached as an oracle price.
+    #[serde(default)]
+    pub pegged: bool,
 }
 
 #[cfg(test)]
 impl PriceData {
     pub fn new(expired: bool, price: Option<Price>) -> Self {
         Self {
+            timestamp: Some(U64::from(0)),
             expiration: match expired {
                 // Note: env::block_timestamp() return 0 on tests
                 true => U64::from(0),
                 false => U64::from(1),
             },
             price,
+            pegged: false,
         }
     }
 }
 
 #[ext_contract(ext_oracle)]
+#[allow(dead_code)]
 pub trait Oracle {
     fn get_exchange_price(&self, asset_id: AssetId) -> PriceData;
 }
 
-#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Copy)]
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Copy, JsonSchema)]
 #[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
 #[serde(crate = "near_sdk::serde")]
 pub struct ExchangePrice {
     pub multiplier: Balance,
     pub decimals: u8,
+    /// Oracle timestamp of the price, not kept in the cached prices.
+    #[borsh_skip]
+    #[serde(skip)]
+    pub timestamp: Option<Timestamp>,
 }
 
 impl ExchangePrice {
@@ -71,33 +91,58 @@ impl ExchangePrice {
         Self {
             multiplier,
             decimals,
+            timestamp: None,
         }
     }
 
     pub fn from_price_data(asset: &AssetInfo, data: PriceData) -> Self {
-        require!(
-            env::block_timestamp() < data.expiration.0,
-            "Oracle price is outdated",
-        );
+        Self::try_from_price_data(asset, &data).unwrap_or_else(|err| env::panic_str(&err))
+    }
+
+    /// Price of the asset in the oracle data, if it's unexpired, recent enough
+    /// for the asset and in the expected decimals.
+    pub fn try_from_price_data(asset: &AssetInfo, data: &PriceData) -> Result<Self, String> {
+        if env::block_timestamp() >= data.expiration.0 {
+            return Err("Oracle price is outdated".to_string());
+        }
+        if let Some(max_price_age) = asset.max_price_age_ns {
+            let timestamp = data
+                .timestamp
+                .ok_or_else(|| "Oracle price timestamp is missing".to_string())?;
+            if env::block_timestamp().saturating_sub(timestamp.0) > max_price_age.0 {
+                return Err("Oracle price is too old".to_string());
+            }
+        }
 
         let price = data
             .price
-            .unwrap_or_else(|| env::panic_str("Oracle price is missing"));
+            .as_ref()
+            .ok_or_else(|| "Oracle price is missing".to_string())?;
+
+        if let Some(price_decimals) = asset.price_decimals {
+            if price.decimals != price_decimals {
+                return Err(format!(
+                    "Oracle price decimals changed from {} to {}",
+                    price_decimals, price.decimals
+                ));
+            }
+        }
 
         // price.decimals - asset.decimals
         let diff = price
             .decimals
             .checked_sub(asset.decimals)
-            .unwrap_or_else(|| env::panic_str("Oracle price wrong decimals"));
+            .ok_or_else(|| "Oracle price wrong decimals".to_string())?;
 
         if price.multiplier.0 == 0 {
-            env::panic_str("Oracle price is zero")
+            return Err("Oracle price is zero".to_string());
         }
 
-        Self {
+        Ok(Self {
             multiplier: price.multiplier.into(),
             decimals: diff,
-        }
+            timestamp: data.timestamp,
+        })
     }
 
     pub fn to_decimals(self) -> u128 {
@@ -107,11 +152,202 @@ impl ExchangePrice {
     }
 }
 
+/// Last exchange price received from the oracle for an asset.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Copy, JsonSchema)]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+#[serde(crate = "near_sdk::serde")]
+pub struct CachedPrice {
+    pub price: ExchangePrice,
+    pub timestamp: Timestamp,
+}
+
+impl CachedPrice {
+    pub fn new(price: ExchangePrice) -> Self {
+        Self {
+            price,
+            timestamp: env::block_timestamp().into(),
+        }
+    }
+}
+
+impl Contract {
+    /// Promise fetching the asset price, retried against the fallback oracle of the asset
+    /// if the primary one fails or has no valid price. Buys and sells of the KT amount
+    /// `peg_amount` fall back to the peg price at last, if enabled.
+    pub(crate) fn get_price(&self, asset_id: &AssetId, peg_amount: Option<Balance>) -> Promise {
+        let mut get_price = ext_oracle::ext(self.oracle_id.clone())
+            .with_static_gas(self.gas.get_exchange_price)
+            .with_unused_gas_weight(0)
+            .get_exchange_price(asset_id.clone());
+        if let Some(oracle_id) = self.fallback_oracle_of(asset_id) {
+            get_price = get_price.then(
+                ext_price_resolver::ext(env::current_account_id())
+                    .with_static_gas(self.gas.fallback_price())
+                    .with_unused_gas_weight(0)
+                    .price_or_fallback(asset_id.clone(), oracle_id),
+            );
+        }
+        match peg_amount {
+            Some(amount) if self.peg_of(asset_id).is_some() => get_price.then(
+                ext_peg_resolver::ext(env::current_account_id())
+                    .with_static_gas(self.gas.resolve_price)
+                    .with_unused_gas_weight(0)
+                    .price_or_peg(asset_id.clone(), amount.into()),
+            ),
+            _ => get_price,
+        }
+    }
+
+    /// Gas of the price promise of the asset.
+    pub(crate) fn price_gas(&self, asset_id: &AssetId) -> Gas {
+        let mut gas = self.gas.get_exchange_price;
+        if self.fallback_oracle_of(asset_id).is_some() {
+            gas += self.gas.fallback_price();
+        }
+        if self.peg_of(asset_id).is_some() {
+            gas += self.gas.resolve_price;
+        }
+        gas
+    }
+
+    fn fallback_oracle_of(&self, asset_id: &AssetId) -> Option<AccountId> {
+        self.treasury.get(asset_id)?.fallback_oracle_id
+    }
+
+    pub(crate) fn internal_set_oracle(&mut self, oracle_id: AccountId) {
+        KtEvent::OracleChanged {
+            old_oracle_id: &self.oracle_id,
+            new_oracle_id: &oracle_id,
+        }
+        .emit();
+        self.oracle_id = oracle_id;
+    }
+}
+
+/// Price data of the oracle call result, if it has a valid price of the asset.
+pub(crate) fn valid_price_result(asset: &AssetInfo) -> Option<PriceData> {
+    let data = match env::promise_result(0) {
+        PromiseResult::Successful(value) => serde_json::from_slice::<PriceData>(&value).ok()?,
+        _ => return None,
+    };
+    ExchangePrice::try_from_price_data(asset, &data)
+        .is_ok()
+        .then_some(data)
+}
+
+#[ext_contract(ext_price_resolver)]
+#[allow(dead_code)]
+pub trait PriceResolver {
+    fn price_or_fallback(
+        &mut self,
+        asset_id: AssetId,
+        oracle_id: AccountId,
+    ) -> PromiseOrValue<PriceData>;
+}
+
+#[near_bindgen]
+impl PriceResolver for Contract {
+    /// Passes the primary price through if it's valid, else asks the fallback oracle.
+    #[private]
+    fn price_or_fallback(
+        &mut self,
+        asset_id: AssetId,
+        oracle_id: AccountId,
+    ) -> PromiseOrValue<PriceData> {
+        match valid_price_result(&self.treasury.assert_asset(&asset_id)) {
+            Some(data) => PromiseOrValue::Value(data),
+            None => {
+                KtEvent::OracleFallback {
+                    asset_id: &asset_id,
+                    oracle_id: &oracle_id,
+                }
+                .emit();
+                ext_oracle::ext(oracle_id)
+                    .with_static_gas(self.gas.get_exchange_price)
+                    .get_exchange_price(asset_id)
+                    .into()
+            }
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Fetches the asset price from the oracle and caches it in the treasury.
+    /// Refreshing a stale price is rewarded with the keeper incentive.
+    pub fn refresh_price(&mut self, asset_id: AssetId) -> Promise {
+        self.treasury.assert_asset(&asset_id);
+
+        self.get_price(&asset_id, None).then(
+            ext_self::ext(env::current_account_id())
+                .with_static_gas(self.gas.cache_price)
+                .cache_price(asset_id, Some(env::predecessor_account_id())),
+        )
+    }
+
+    pub fn set_oracle(&mut self, oracle_id: AccountId) {
+        self.assert_owner();
+        self.internal_set_oracle(oracle_id);
+    }
+
+    pub fn get_oracle(&self) -> AccountId {
+        self.oracle_id.clone()
+    }
+}
+
 #[cfg(all(test, not(target_arch = "wasm32")))]
 mod tests {
-    use crate::{oracle::ExchangePrice, treasury::AssetInfo};
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
+    use near_sdk::{
+        serde_json, testing_env, PromiseOrValue, PromiseResult, RuntimeFeesConfig, VMConfig,
+    };
 
-    use super::{Price, PriceData};
+    use crate::{oracle::ExchangePrice, treasury::AssetInfo, Contract};
+
+    use super::{Price, PriceData, PriceResolver};
+
+    fn price_or_fallback(
+        contract: &mut Contract,
+        result: PromiseResult,
+        price_decimals: u8,
+    ) -> PromiseOrValue<PriceData> {
+        let context = VMContextBuilder::new();
+        testing_env!(
+            context.build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![result]
+        );
+        contract
+            .treasury
+            .set_price_decimals(&accounts(3), price_decimals);
+        contract.price_or_fallback(accounts(3), accounts(5))
+    }
+
+    #[test]
+    fn test_price_or_fallback() {
+        testing_env!(VMContextBuilder::new().build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(3), 6);
+        let data = PriceData::new(false, Some(Price::new(10000, 10)));
+        let value = serde_json::to_vec(&data).unwrap();
+        let result = price_or_fallback(&mut contract, PromiseResult::Successful(value.clone()), 10);
+        assert!(matches!(result, PromiseOrValue::Value(data) if data.price.is_some()));
+
+        // The price decimals aren't the expected ones
+        let result = price_or_fallback(&mut contract, PromiseResult::Successful(value), 12);
+        assert!(matches!(result, PromiseOrValue::Promise(_)));
+
+        let data = PriceData::new(true, Some(Price::new(10000, 10)));
+        let value = serde_json::to_vec(&data).unwrap();
+        let result = price_or_fallback(&mut contract, PromiseResult::Successful(value), 10);
+        assert!(matches!(result, PromiseOrValue::Promise(_)));
+        assert!(get_logs()[0].contains("oracle_fallback"));
+
+        let result = price_or_fallback(&mut contract, PromiseResult::Failed, 10);
+        assert!(matches!(result, PromiseOrValue::Promise(_)));
+    }
 
     #[test]
     fn test_exchange_price() {
@@ -132,6 +368,23 @@ mod tests {
         );
     }
 
+    #[test]
+    #[should_panic(expected = "Oracle price is too old")]
+    fn test_too_old_exchange_price() {
+        testing_env!(VMContextBuilder::new().block_timestamp(100).build());
+        let mut asset = AssetInfo::new(6);
+        asset.max_price_age_ns = Some(10.into());
+        ExchangePrice::from_price_data(
+            &asset,
+            PriceData {
+                timestamp: Some(50.into()),
+                expiration: 1_000.into(),
+                price: Some(Price::new(10001, 10)),
+                pegged: false,
+            },
+        );
+    }
+
     #[test]
     #[should_panic(expected = "Oracle price is missing")]
     fn test_missing_exchange_price() {
@@ -147,6 +400,17 @@ mod tests {
         );
     }
 
+    #[test]
+    #[should_panic(expected = "Oracle price decimals changed from 10 to 12")]
+    fn test_changed_decimals_exchange_price() {
+        let mut asset = AssetInfo::new(6);
+        asset.price_decimals = Some(10);
+        ExchangePrice::from_price_data(
+            &asset,
+            PriceData::new(false, Some(Price::new(1000100, 12))),
+        );
+    }
+
     #[test]
     #[should_panic(expected = "Oracle price is zero")]
     fn test_zero_exchange_price() {
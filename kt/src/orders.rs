@@ -0,0 +1,516 @@
+//! Limit orders.
+//!
+//! A buy order holds the deposited asset and buys KT once the asset price reaches the limit,
+//! a sell order holds the KT and sells it once the asset price falls to the limit. Limit
+//! prices are in the same 18 decimals format as the KT cost basis. Keepers execute the orders at the oracle
+//! price and earn the bounty out of the order amount. Refunds of the asset are paid as claims.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::UnorderedMap;
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{
+    assert_one_yocto, env, ext_contract, near_bindgen, require, AccountId, Balance, IntoStorageKey,
+    Promise, PromiseOrValue,
+};
+use schemars::JsonSchema;
+
+use crate::events::{KtEvent, RefundOperation, RefundReason};
+use crate::oracle::{ExchangePrice, PriceData};
+use crate::roles::Role;
+use crate::treasury::AssetId;
+use crate::{BuyOptions, Contract, ContractExt, BASIS_POINTS};
+
+pub type OrderId = u64;
+
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, JsonSchema,
+)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub enum OrderSide {
+    Buy,
+    Sell,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct LimitOrder {
+    pub account_id: AccountId,
+    pub side: OrderSide,
+    pub asset_id: AssetId,
+    /// Asset amount of a buy, KT amount of a sell.
+    pub amount: U128,
+    pub limit_price: U128,
+    /// Cost basis of the KT held by a sell.
+    pub cost_price: U128,
+    pub expires_at: Option<U64>,
+}
+
+impl LimitOrder {
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| env::block_timestamp() >= expires_at.0)
+    }
+
+    pub fn is_triggered(&self, price: ExchangePrice) -> bool {
+        let price = price.to_decimals();
+        match self.side {
+            OrderSide::Buy => price >= self.limit_price.0,
+            OrderSide::Sell => price <= self.limit_price.0,
+        }
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct LimitOrders {
+    orders: UnorderedMap<OrderId, LimitOrder>,
+    next_id: OrderId,
+    /// Keeper bounty, in basis points of the order amount.
+    bounty: u16,
+}
+
+impl LimitOrders {
+    pub fn new<S>(prefix: S) -> Self
+    where
+        S: IntoStorageKey,
+    {
+        Self {
+            orders: UnorderedMap::new(prefix),
+            next_id: 0,
+            bounty: 0,
+        }
+    }
+
+    pub fn get(&self, id: OrderId) -> Option<LimitOrder> {
+        self.orders.get(&id)
+    }
+
+    pub fn assert_order(&self, id: OrderId) -> LimitOrder {
+        self.get(id)
+            .unwrap_or_else(|| env::panic_str(format!("Order {} is not found", id).as_str()))
+    }
+
+    pub fn push(&mut self, order: &LimitOrder) -> OrderId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.orders.insert(&id, order);
+        id
+    }
+
+    pub fn remove(&mut self, id: OrderId) -> Option<LimitOrder> {
+        self.orders.remove(&id)
+    }
+
+    pub fn bounty_of(&self, amount: Balance) -> Balance {
+        let bounty = u128::from(self.bounty);
+        let bps = u128::from(BASIS_POINTS);
+        match amount.checked_mul(bounty) {
+            Some(value) => value / bps,
+            None => amount / bps * bounty,
+        }
+    }
+}
+
+impl Contract {
+    fn internal_place_order(&mut self, order: LimitOrder) -> OrderId {
+        require!(order.amount.0 > 0, "Order amount should be positive");
+        require!(order.limit_price.0 > 0, "Limit price should be positive");
+        require!(!order.is_expired(), "Order expiration is in the past");
+        let id = self.orders.push(&order);
+
+        KtEvent::OrderPlaced {
+            id,
+            account_id: &order.account_id,
+            side: order.side,
+            asset_id: &order.asset_id,
+            amount: &order.amount,
+            limit_price: &order.limit_price,
+        }
+        .emit();
+
+        id
+    }
+
+    /// Places a buy order for the asset deposited by the account.
+    pub(crate) fn internal_place_buy_order(
+        &mut self,
+        account_id: &AccountId,
+        asset_id: &AssetId,
+        amount: U128,
+        limit_price: U128,
+        expires_at: Option<U64>,
+    ) -> OrderId {
+        self.assert_buys_enabled();
+        self.treasury.assert_can_buy(asset_id);
+        self.compliance.assert_not_frozen(account_id);
+        self.internal_place_order(LimitOrder {
+            account_id: account_id.clone(),
+            side: OrderSide::Buy,
+            asset_id: asset_id.clone(),
+            amount,
+            limit_price,
+            cost_price: 0.into(),
+            expires_at,
+        })
+    }
+
+    /// Returns the held funds of the removed order to its account.
+    fn internal_refund_order(&mut self, order: &LimitOrder, reason: RefundReason) {
+        let (amount, asset_amount) = match order.side {
+            OrderSide::Buy => (None, Some(&order.amount)),
+            OrderSide::Sell => (Some(&order.amount), None),
+        };
+        KtEvent::KtRefund {
+            operation: RefundOperation::LimitOrder,
+            reason,
+            account_id: &order.account_id,
+            asset_id: Some(&order.asset_id),
+            amount,
+            asset_amount,
+        }
+        .emit();
+        match order.side {
+            OrderSide::Buy => {
+                self.claims
+                    .internal_add(&order.account_id, &order.asset_id, order.amount.0);
+                KtEvent::AssetClaimAdded {
+                    account_id: &order.account_id,
+                    asset_id: &order.asset_id,
+                    amount: &order.amount,
+                }
+                .emit();
+            }
+            OrderSide::Sell => self.token.internal_transfer_at(
+                &env::current_account_id(),
+                &order.account_id,
+                order.amount.0,
+                order.cost_price.0,
+                Some("order refund".to_string()),
+            ),
+        }
+    }
+
+    /// Executes the triggered order at the price, paying the keeper the bounty.
+    pub(crate) fn internal_execute_order(
+        &mut self,
+        id: OrderId,
+        keeper_id: &AccountId,
+        price: ExchangePrice,
+    ) -> Option<Promise> {
+        let order = self.orders.assert_order(id);
+        require!(!order.is_expired(), "Order is expired");
+        require!(
+            order.is_triggered(price),
+            "Order limit price is not reached"
+        );
+        self.orders.remove(id);
+        self.treasury.set_asset_price(&order.asset_id, price);
+
+        let asset = self.treasury.assert_asset(&order.asset_id);
+        let bounty = self.orders.bounty_of(order.amount.0);
+        let amount = order.amount.0 - bounty;
+        KtEvent::OrderExecuted {
+            id,
+            account_id: &order.account_id,
+            keeper_id,
+            price: &price.to_decimals().into(),
+            bounty: &bounty.into(),
+        }
+        .emit();
+
+        match order.side {
+            OrderSide::Buy => {
+                if bounty > 0 {
+                    self.claims.internal_add(keeper_id, &order.asset_id, bounty);
+                }
+                self.internal_buy(
+                    &order.account_id,
+                    &order.asset_id,
+                    amount,
+                    asset.decimals,
+                    price,
+                    &BuyOptions::default(),
+                );
+                None
+            }
+            OrderSide::Sell => {
+                self.token.internal_transfer_at(
+                    &env::current_account_id(),
+                    &order.account_id,
+                    order.amount.0,
+                    order.cost_price.0,
+                    None,
+                );
+                if bounty > 0 {
+                    self.token.internal_transfer(
+                        &order.account_id,
+                        keeper_id,
+                        bounty,
+                        Some("order bounty".to_string()),
+                    );
+                }
+                let leg = self.internal_sell_leg(
+                    &order.account_id,
+                    &order.asset_id,
+                    amount,
+                    asset.decimals,
+                    price,
+                    None,
+                );
+                Some(self.sell_transfers(&order.account_id, &order.account_id, vec![leg], None))
+            }
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn set_order_bounty(&mut self, bounty: u16) {
+        self.assert_owner();
+        require!(bounty <= BASIS_POINTS, "Bounty is out of bounds");
+        self.orders.bounty = bounty;
+    }
+
+    pub fn get_order_bounty(&self) -> u16 {
+        self.orders.bounty
+    }
+
+    /// Holds the KT until the asset price falls to the limit.
+    /// Buy orders are placed by transferring the asset with the `LimitBuy` message.
+    #[payable]
+    pub fn place_sell_order(
+        &mut self,
+        asset_id: AssetId,
+        amount: U128,
+        limit_price: U128,
+        expires_at: Option<U64>,
+    ) -> U64 {
+        assert_one_yocto();
+        self.treasury.assert_can_sell(&asset_id);
+        let account_id = env::predecessor_account_id();
+        self.compliance.assert_not_frozen(&account_id);
+        self.operations.assert_no_pending_sell(&account_id);
+        let cost_price = self.token.cost_basis_of(&account_id);
+        self.token.internal_transfer_at(
+            &account_id,
+            &env::current_account_id(),
+            amount.into(),
+            cost_price,
+            Some("sell order".to_string()),
+        );
+        self.internal_place_order(LimitOrder {
+            account_id,
+            side: OrderSide::Sell,
+            asset_id,
+            amount,
+            limit_price,
+            cost_price: cost_price.into(),
+            expires_at,
+        })
+        .into()
+    }
+
+    #[payable]
+    pub fn cancel_order(&mut self, id: U64) {
+        assert_one_yocto();
+        let order = self.orders.assert_order(id.0);
+        require!(
+            order.account_id == env::predecessor_account_id(),
+            "Order belongs to another account"
+        );
+        self.orders.remove(id.0);
+        self.internal_refund_order(&order, RefundReason::Cancelled);
+        KtEvent::OrderCancelled {
+            id: id.0,
+            account_id: &order.account_id,
+        }
+        .emit();
+    }
+
+    /// Closes the expired order and refunds it, callable by anyone.
+    pub fn expire_order(&mut self, id: U64) {
+        let order = self.orders.assert_order(id.0);
+        require!(order.is_expired(), "Order is not expired");
+        self.orders.remove(id.0);
+        self.internal_refund_order(&order, RefundReason::Expired);
+        KtEvent::OrderExpired {
+            id: id.0,
+            account_id: &order.account_id,
+        }
+        .emit();
+    }
+
+    /// Executes the order if the oracle price crossed its limit.
+    pub fn execute_order(&mut self, id: U64) -> Promise {
+        let keeper_id = env::predecessor_account_id();
+        self.assert_owner_or_role(Role::Keeper);
+        let order = self.orders.assert_order(id.0);
+        require!(
+            env::prepaid_gas()
+                > self.price_gas(&order.asset_id) + self.gas.execute_order_with_price(),
+            "More gas is required"
+        );
+
+        self.get_price(&order.asset_id, None).then(
+            ext_order_resolver::ext(env::current_account_id())
+                .with_static_gas(self.gas.execute_order_with_price())
+                .execute_order_with_price(id, keeper_id),
+        )
+    }
+
+    pub fn get_order(&self, id: U64) -> Option<LimitOrder> {
+        self.orders.get(id.0)
+    }
+
+    pub fn get_orders(
+        &self,
+        account_id: AccountId,
+        from_index: Option<U64>,
+        limit: Option<U64>,
+    ) -> Vec<(U64, LimitOrder)> {
+        let from_index = from_index.map_or(0, |index| index.0 as usize);
+        let limit = limit.map_or(usize::MAX, |limit| limit.0 as usize);
+        self.orders
+            .orders
+            .iter()
+            .filter(|(_, order)| order.account_id == account_id)
+            .skip(from_index)
+            .take(limit)
+            .map(|(id, order)| (id.into(), order))
+            .collect()
+    }
+
+    pub fn get_open_orders(
+        &self,
+        from_index: Option<U64>,
+        limit: Option<U64>,
+    ) -> Vec<(U64, LimitOrder)> {
+        let from_index = from_index.map_or(0, |index| index.0 as usize);
+        let limit = limit.map_or(usize::MAX, |limit| limit.0 as usize);
+        self.orders
+            .orders
+            .iter()
+            .skip(from_index)
+            .take(limit)
+            .map(|(id, order)| (id.into(), order))
+            .collect()
+    }
+}
+
+#[ext_contract(ext_order_resolver)]
+#[allow(dead_code)]
+pub trait OrderResolver {
+    fn execute_order_with_price(
+        &mut self,
+        id: U64,
+        keeper_id: AccountId,
+        #[callback_unwrap] price: PriceData,
+    ) -> PromiseOrValue<()>;
+}
+
+#[near_bindgen]
+impl OrderResolver for Contract {
+    #[private]
+    fn execute_order_with_price(
+        &mut self,
+        id: U64,
+        keeper_id: AccountId,
+        #[callback_unwrap] price: PriceData,
+    ) -> PromiseOrValue<()> {
+        let order = self.orders.assert_order(id.0);
+        let asset = self.treasury.assert_asset(&order.asset_id);
+        let price = ExchangePrice::from_price_data(&asset, price);
+        match self.internal_execute_order(id.0, &keeper_id, price) {
+            Some(promise) => promise.into(),
+            None => PromiseOrValue::Value(()),
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_contract_standards::fungible_token::core::FungibleTokenCore;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, ONE_YOCTO};
+
+    use crate::oracle::ExchangePrice;
+    use crate::test_utils;
+    use crate::Contract;
+
+    const ONE_KT: u128 = 1_000_000_000_000_000_000;
+    // Price of ExchangePrice::new(10000, 10) in decimals
+    const LIMIT_PRICE: u128 = 1_000_000_000_000;
+
+    fn setup_contract(context: &mut VMContextBuilder) -> Contract {
+        let mut contract = test_utils::setup_contract(context);
+        contract.set_order_bounty(100);
+        contract
+    }
+
+    #[test]
+    fn test_execute_buy_order() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        let id = contract.internal_place_buy_order(
+            &accounts(1),
+            &accounts(3),
+            1_000_000.into(),
+            LIMIT_PRICE.into(),
+            None,
+        );
+
+        contract.internal_execute_order(id, &accounts(2), ExchangePrice::new(10000, 10));
+        assert!(contract.get_order(id.into()).is_none());
+        assert_eq!(
+            contract.ft_balance_of(accounts(1)).0,
+            990_000_000_000_000_000
+        );
+        assert_eq!(
+            contract.claims.claims_of(&accounts(2))[&accounts(3)],
+            10_000
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Order limit price is not reached")]
+    fn test_execute_buy_order_not_triggered() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        let id = contract.internal_place_buy_order(
+            &accounts(1),
+            &accounts(3),
+            1_000_000.into(),
+            (2 * LIMIT_PRICE).into(),
+            None,
+        );
+        contract.internal_execute_order(id, &accounts(2), ExchangePrice::new(10000, 10));
+    }
+
+    #[test]
+    fn test_cancel_sell_order() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        contract
+            .token
+            .internal_deposit(&accounts(1), ONE_KT, ONE_KT);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        let id = contract.place_sell_order(accounts(3), ONE_KT.into(), LIMIT_PRICE.into(), None);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 0);
+        assert_eq!(contract.get_orders(accounts(1), None, None).len(), 1);
+        assert!(contract
+            .get_orders(accounts(1), Some(1.into()), None)
+            .is_empty());
+        assert!(contract.get_orders(accounts(2), None, None).is_empty());
+
+        contract.cancel_order(id);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, ONE_KT);
+        assert!(contract.get_open_orders(None, None).is_empty());
+    }
+}
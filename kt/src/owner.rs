@@ -1,20 +1,23 @@
 use crate::*;
-use near_contract_standards::upgrade::Ownable;
 
-impl Ownable for Contract {
-    fn get_owner(&self) -> AccountId {
+impl Contract {
+    pub fn get_owner(&self) -> AccountId {
         self.owner_id.clone()
     }
 
-    fn set_owner(&mut self, owner_id: AccountId) {
+    pub fn set_owner(&mut self, owner_id: AccountId) {
         self.assert_owner();
+        KtEvent::OwnerChanged {
+            old_owner_id: &self.owner_id,
+            new_owner_id: &owner_id,
+        }
+        .emit();
         self.owner_id = owner_id;
     }
 }
 #[cfg(all(test, not(target_arch = "wasm32")))]
 mod tests {
-    use near_contract_standards::upgrade::Ownable;
-    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
     use near_sdk::testing_env;
 
     use crate::Contract;
@@ -28,7 +31,7 @@ mod tests {
             .signer_account_id(accounts(1))
             .predecessor_account_id(accounts(1));
         testing_env!(context.build());
-        let contract = Contract::new(accounts(2), accounts(4));
+        let contract = Contract::new(accounts(2), accounts(4), None, None);
 
         testing_env!(context.predecessor_account_id(accounts(1)).build());
         contract.assert_owner();
@@ -42,7 +45,7 @@ mod tests {
             .signer_account_id(accounts(1))
             .predecessor_account_id(accounts(1));
         testing_env!(context.build());
-        let contract = Contract::new(accounts(2), accounts(4));
+        let contract = Contract::new(accounts(2), accounts(4), None, None);
 
         testing_env!(context.predecessor_account_id(accounts(2)).build());
         assert_eq!(contract.get_owner(), accounts(2));
@@ -56,10 +59,11 @@ mod tests {
             .signer_account_id(accounts(1))
             .predecessor_account_id(accounts(1));
         testing_env!(context.build());
-        let mut contract = Contract::new(accounts(2), accounts(4));
+        let mut contract = Contract::new(accounts(2), accounts(4), None, None);
 
         testing_env!(context.predecessor_account_id(accounts(2)).build());
         contract.set_owner(accounts(4));
         assert_eq!(contract.owner_id, accounts(4));
+        assert!(get_logs()[0].contains(r#""event":"owner_changed""#));
     }
 }
@@ -0,0 +1,247 @@
+//! Peg fallback pricing of stablecoins during oracle outages.
+//!
+//! Once enabled by the owner for an asset, its buys and sells execute at 1:1 when the oracle
+//! has no valid price, if the last oracle price is recent and was within the band around 1:1.
+//! Single operations and the total amount until the oracle price refreshes are limited.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, ext_contract, near_bindgen, require, Balance};
+use schemars::JsonSchema;
+
+use crate::events::KtEvent;
+use crate::oracle::{valid_price_result, ExchangePrice, Price, PriceData};
+use crate::treasury::AssetId;
+use crate::{Contract, ContractExt, BASIS_POINTS};
+
+/// Widest band around 1:1 allowed for the peg fallback, in basis points.
+const MAX_PEG_BAND: u16 = 100;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct PegFallback {
+    /// Maximum deviation of the last oracle price from 1:1, in basis points.
+    pub band: u16,
+    /// Maximum KT amount bought or sold at the peg by a single operation.
+    pub max_amount: U128,
+    /// Maximum KT amount bought or sold at the peg per asset until its oracle price
+    /// is refreshed.
+    pub max_total: U128,
+    /// Maximum age of the last oracle price, in nanoseconds.
+    pub max_price_age: U64,
+    /// Assets which can be priced at the peg.
+    pub asset_ids: Vec<AssetId>,
+}
+
+impl Contract {
+    /// Peg fallback of the asset, if it's enabled for it.
+    pub(crate) fn peg_of(&self, asset_id: &AssetId) -> Option<&PegFallback> {
+        self.peg_fallback
+            .as_ref()
+            .filter(|peg| peg.asset_ids.contains(asset_id))
+    }
+
+    /// KT amount priced at the peg since the cached oracle price of the asset.
+    fn peg_used(&self, asset_id: &AssetId, price_timestamp: u64) -> Balance {
+        match self.peg_used.get(asset_id) {
+            Some((timestamp, used)) if timestamp == price_timestamp => used,
+            _ => 0,
+        }
+    }
+
+    /// Price data at 1:1 for the asset, if its last oracle price is recent and within
+    /// the band, and the amount fits the limits.
+    pub(crate) fn peg_price(&self, asset_id: &AssetId, amount: Balance) -> Option<PriceData> {
+        let peg = self.peg_of(asset_id)?;
+        if amount > peg.max_amount.0 {
+            return None;
+        }
+        let asset = self.treasury.get(asset_id)?;
+        let cached = asset.price?;
+        if env::block_timestamp().saturating_sub(cached.timestamp.0) > peg.max_price_age.0 {
+            return None;
+        }
+        let used = self.peg_used(asset_id, cached.timestamp.0);
+        if used.saturating_add(amount) > peg.max_total.0 {
+            return None;
+        }
+        let cached = cached.price;
+        let diff = cached.decimals.checked_sub(asset.decimals)?;
+        let peg_price = ExchangePrice {
+            multiplier: 10u128.checked_pow(u32::from(diff))?,
+            decimals: cached.decimals,
+            timestamp: None,
+        };
+        let peg_value = peg_price.to_decimals();
+        let deviation = peg_value.abs_diff(cached.to_decimals());
+        if deviation > peg_value / u128::from(BASIS_POINTS) * u128::from(peg.band) {
+            return None;
+        }
+        Some(PriceData {
+            timestamp: Some(env::block_timestamp().into()),
+            expiration: (env::block_timestamp() + 1).into(),
+            price: Some(Price {
+                multiplier: peg_price.multiplier.into(),
+                decimals: cached.decimals + asset.decimals,
+            }),
+            pegged: true,
+        })
+    }
+}
+
+#[ext_contract(ext_peg_resolver)]
+#[allow(dead_code)]
+pub trait PegResolver {
+    fn price_or_peg(&mut self, asset_id: AssetId, amount: U128) -> PriceData;
+}
+
+#[near_bindgen]
+impl PegResolver for Contract {
+    /// Passes the oracle price through if it's valid, else prices the asset at the peg.
+    #[private]
+    fn price_or_peg(&mut self, asset_id: AssetId, amount: U128) -> PriceData {
+        match valid_price_result(&self.treasury.assert_asset(&asset_id)) {
+            Some(data) => data,
+            None => {
+                let data = self
+                    .peg_price(&asset_id, amount.0)
+                    .unwrap_or_else(|| env::panic_str("Oracle price is unavailable"));
+                let price_timestamp = self
+                    .treasury
+                    .assert_asset(&asset_id)
+                    .price
+                    .map_or(0, |cached| cached.timestamp.0);
+                let used = self.peg_used(&asset_id, price_timestamp) + amount.0;
+                self.peg_used.insert(&asset_id, &(price_timestamp, used));
+                KtEvent::PegPriceUsed {
+                    asset_id: &asset_id,
+                    amount: &amount,
+                }
+                .emit();
+                data
+            }
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Enables the peg fallback of the listed stablecoins, `None` disables it.
+    pub fn set_peg_fallback(&mut self, peg: Option<PegFallback>) {
+        self.assert_owner();
+        if let Some(peg) = &peg {
+            require!(
+                peg.band > 0 && peg.band <= MAX_PEG_BAND,
+                "Peg band is out of bounds"
+            );
+            require!(
+                peg.max_amount.0 <= peg.max_total.0,
+                "Peg amount exceeds the total limit"
+            );
+            require!(peg.max_price_age.0 > 0, "Peg price age should be positive");
+            for asset_id in &peg.asset_ids {
+                self.treasury.assert_asset(asset_id);
+            }
+        }
+        self.peg_fallback = peg;
+    }
+
+    pub fn get_peg_fallback(&self) -> Option<PegFallback> {
+        self.peg_fallback.clone()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, PromiseResult, RuntimeFeesConfig, VMConfig};
+
+    use super::{PegFallback, PegResolver};
+    use crate::oracle::ExchangePrice;
+    use crate::Contract;
+
+    fn setup_contract(cached_price: ExchangePrice) -> Contract {
+        let mut context = VMContextBuilder::new();
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed]
+        );
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(3), 6);
+        contract
+            .treasury
+            .set_asset_price(&accounts(3), cached_price);
+        contract.set_peg_fallback(Some(PegFallback {
+            band: 50,
+            max_amount: 1_000_000_000_000_000_000_000.into(),
+            max_total: 1_500_000_000_000_000_000_000.into(),
+            max_price_age: 3_600_000_000_000.into(),
+            asset_ids: vec![accounts(3)],
+        }));
+        contract
+    }
+
+    #[test]
+    fn test_price_or_peg() {
+        let mut contract = setup_contract(ExchangePrice::new(10040, 10));
+        let data = contract.price_or_peg(accounts(3), 1_000_000_000_000_000_000.into());
+        assert!(data.pegged);
+        let price = data.price.unwrap();
+        assert_eq!((price.multiplier.0, price.decimals), (10000, 16));
+    }
+
+    #[test]
+    #[should_panic(expected = "Oracle price is unavailable")]
+    fn test_price_or_peg_out_of_band() {
+        let mut contract = setup_contract(ExchangePrice::new(10060, 10));
+        contract.price_or_peg(accounts(3), 1_000_000_000_000_000_000.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "Oracle price is unavailable")]
+    fn test_price_or_peg_not_enabled() {
+        let mut contract = setup_contract(ExchangePrice::new(10000, 10));
+        contract.internal_add_asset(&accounts(5), 6);
+        contract
+            .treasury
+            .set_asset_price(&accounts(5), ExchangePrice::new(10000, 10));
+        contract.price_or_peg(accounts(5), 1_000_000_000_000_000_000.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "Oracle price is unavailable")]
+    fn test_price_or_peg_stale() {
+        let mut contract = setup_contract(ExchangePrice::new(10000, 10));
+        testing_env!(
+            VMContextBuilder::new()
+                .block_timestamp(3_600_000_000_001)
+                .build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed]
+        );
+        contract.price_or_peg(accounts(3), 1_000_000_000_000_000_000.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "Oracle price is unavailable")]
+    fn test_price_or_peg_above_total() {
+        let mut contract = setup_contract(ExchangePrice::new(10000, 10));
+        let amount = 1_000_000_000_000_000_000_000;
+        contract.price_or_peg(accounts(3), amount.into());
+        contract.price_or_peg(accounts(3), amount.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "Oracle price is unavailable")]
+    fn test_price_or_peg_above_limit() {
+        let mut contract = setup_contract(ExchangePrice::new(10000, 10));
+        contract.price_or_peg(accounts(3), 1_000_000_000_000_000_000_001.into());
+    }
+}
@@ -2,11 +2,12 @@ use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::json_types::U128;
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::{require, Balance};
+use schemars::JsonSchema;
 
 use crate::oracle::ExchangePrice;
 use crate::KT_DECIMALS;
 
-#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize)]
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, JsonSchema)]
 #[serde(crate = "near_sdk::serde")]
 pub struct ExpectedPrice {
     multiplier: U128,
@@ -79,13 +80,63 @@ pub fn exchange_kt_to_asset(
     convert_decimals(amount, KT_DECIMALS, asset_decimals)
 }
 
+/// Asset amount required to buy the KT amount, rounded up in favor of the treasury.
+pub fn exchange_kt_to_asset_cost(
+    amount: Balance,
+    asset_decimals: u8,
+    price: ExchangePrice,
+) -> Option<Balance> {
+    let asset_amount = exchange_kt_to_asset(amount, asset_decimals, price)?;
+    if exchange_asset_to_kt(asset_amount, asset_decimals, price)? < amount {
+        asset_amount.checked_add(1)
+    } else {
+        Some(asset_amount)
+    }
+}
+
+/// KT amount paying out at least the asset amount, rounded up in favor of the treasury.
+pub fn exchange_asset_to_kt_cost(
+    asset_amount: Balance,
+    asset_decimals: u8,
+    price: ExchangePrice,
+) -> Option<Balance> {
+    let amount = if asset_decimals > KT_DECIMALS {
+        asset_amount.div_ceil(10u128.checked_pow(u32::from(asset_decimals - KT_DECIMALS))?)
+    } else {
+        convert_decimals(asset_amount, asset_decimals, KT_DECIMALS)?
+    };
+    let diff = price.decimals.checked_sub(asset_decimals)?;
+    if price.multiplier == 0 {
+        return None;
+    }
+    Some(
+        amount
+            .checked_mul(10u128.pow(u32::from(diff)))?
+            .div_ceil(price.multiplier),
+    )
+}
+
+pub fn exchange_asset_to_asset(
+    amount: Balance,
+    decimals_in: u8,
+    price_in: ExchangePrice,
+    decimals_out: u8,
+    price_out: ExchangePrice,
+) -> Option<Balance> {
+    let kt_amount = exchange_asset_to_kt(amount, decimals_in, price_in)?;
+    exchange_kt_to_asset(kt_amount, decimals_out, price_out)
+}
+
 #[cfg(all(test, not(target_arch = "wasm32")))]
 mod tests {
     use near_sdk::json_types::U128;
 
     use crate::{
         oracle::ExchangePrice,
-        price::{convert_decimals, exchange_asset_to_kt, exchange_kt_to_asset},
+        price::{
+            convert_decimals, exchange_asset_to_asset, exchange_asset_to_kt,
+            exchange_asset_to_kt_cost, exchange_kt_to_asset, exchange_kt_to_asset_cost,
+        },
     };
 
     use super::ExpectedPrice;
@@ -218,4 +269,64 @@ mod tests {
         )
         .is_none());
     }
+
+    #[test]
+    fn test_exchange_kt_to_asset_cost() {
+        let price = ExchangePrice::new(10001, 10);
+        assert_eq!(
+            exchange_kt_to_asset_cost(999_900_009_999_000_099, 6, price),
+            Some(1_000_000)
+        );
+        assert_eq!(
+            exchange_kt_to_asset_cost(1_000_000_000_000_000_000, 6, price),
+            Some(1_000_100)
+        );
+        assert_eq!(
+            exchange_kt_to_asset_cost(1_000_000_000_000_000_001, 6, price),
+            Some(1_000_101)
+        );
+        assert_eq!(
+            exchange_kt_to_asset_cost(1_000_000_000_000_000_000, 6, ExchangePrice::new(10000, 10)),
+            Some(1_000_000)
+        );
+    }
+
+    #[test]
+    fn test_exchange_asset_to_kt_cost() {
+        for (asset_amount, decimals, price) in [
+            (1_000_000, 6, ExchangePrice::new(10001, 10)),
+            (1_000_001, 6, ExchangePrice::new(9999, 10)),
+            (10u128.pow(24) + 1, 24, ExchangePrice::new(30_123, 28)),
+        ] {
+            let cost = exchange_asset_to_kt_cost(asset_amount, decimals, price).unwrap();
+            assert!(exchange_kt_to_asset(cost, decimals, price).unwrap() >= asset_amount);
+            assert!(exchange_kt_to_asset(cost - 1, decimals, price).unwrap() < asset_amount);
+        }
+    }
+
+    #[test]
+    fn test_exchange_asset_to_asset() {
+        // USDC -> DAI
+        assert_eq!(
+            exchange_asset_to_asset(
+                1_000_000,
+                6,
+                ExchangePrice::new(10000, 10),
+                18,
+                ExchangePrice::new(10000, 22)
+            ),
+            Some(1_000_000_000_000_000_000)
+        );
+        // DAI -> USDC
+        assert_eq!(
+            exchange_asset_to_asset(
+                1_000_000_000_000_000_000,
+                18,
+                ExchangePrice::new(10000, 22),
+                6,
+                ExchangePrice::new(20000, 10)
+            ),
+            Some(2_000_000)
+        );
+    }
 }
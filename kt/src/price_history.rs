@@ -0,0 +1,161 @@
+//! Ring buffers of the last prices trades were executed at, per asset.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::{near_bindgen, require, IntoStorageKey};
+
+use crate::oracle::{CachedPrice, ExchangePrice};
+use crate::treasury::AssetId;
+use crate::{Contract, ContractExt};
+
+const DEFAULT_PRICE_HISTORY_SIZE: u16 = 24;
+const MAX_PRICE_HISTORY_SIZE: u16 = 256;
+
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+struct PriceRing {
+    /// Index of the oldest price once the ring is full.
+    head: u16,
+    prices: Vec<CachedPrice>,
+}
+
+impl PriceRing {
+    /// Prices from the oldest to the newest.
+    fn to_vec(&self) -> Vec<CachedPrice> {
+        let (newer, older) = self.prices.split_at(usize::from(self.head));
+        older.iter().chain(newer).copied().collect()
+    }
+
+    fn last(&self) -> Option<&CachedPrice> {
+        if self.head == 0 {
+            self.prices.last()
+        } else {
+            self.prices.get(usize::from(self.head) - 1)
+        }
+    }
+
+    fn push(&mut self, price: CachedPrice, size: u16) {
+        if self.prices.len() > usize::from(size) {
+            // The size was reduced, keep the newest prices
+            let mut prices = self.to_vec();
+            prices.drain(..prices.len() - usize::from(size));
+            *self = Self { head: 0, prices };
+        }
+        if self.prices.len() < usize::from(size) {
+            self.prices.push(price);
+        } else {
+            self.prices[usize::from(self.head)] = price;
+            self.head = (self.head + 1) % size;
+        }
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct PriceHistory {
+    /// Number of the prices kept per asset, disabled if zero.
+    size: u16,
+    /// AssetID -> Last executed prices.
+    rings: LookupMap<AssetId, PriceRing>,
+}
+
+impl PriceHistory {
+    pub fn new<S>(prefix: S) -> Self
+    where
+        S: IntoStorageKey,
+    {
+        Self {
+            size: DEFAULT_PRICE_HISTORY_SIZE,
+            rings: LookupMap::new(prefix),
+        }
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn set_size(&mut self, size: u16) {
+        require!(
+            size <= MAX_PRICE_HISTORY_SIZE,
+            format!("Price history size exceeds {}", MAX_PRICE_HISTORY_SIZE)
+        );
+        self.size = size;
+    }
+
+    /// Records the executed price, once per block if it's unchanged.
+    pub fn record(&mut self, asset_id: &AssetId, price: ExchangePrice) {
+        if self.size == 0 {
+            return;
+        }
+        let price = CachedPrice::new(price);
+        let mut ring = self.rings.get(asset_id).unwrap_or_default();
+        if ring.last().is_some_and(|last| {
+            last.timestamp == price.timestamp
+                && last.price.multiplier == price.price.multiplier
+                && last.price.decimals == price.price.decimals
+        }) {
+            return;
+        }
+        ring.push(price, self.size);
+        self.rings.insert(asset_id, &ring);
+    }
+
+    pub fn get(&self, asset_id: &AssetId) -> Vec<CachedPrice> {
+        let prices = self.rings.get(asset_id).unwrap_or_default().to_vec();
+        let skip = prices.len().saturating_sub(usize::from(self.size));
+        prices.into_iter().skip(skip).collect()
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Sets the number of the executed prices kept per asset, zero stops recording them.
+    pub fn set_price_history_size(&mut self, size: u16) {
+        self.assert_owner();
+        self.price_history.set_size(size);
+    }
+
+    pub fn get_price_history_size(&self) -> u16 {
+        self.price_history.size()
+    }
+
+    /// Last prices trades of the asset were executed at, from the oldest to the newest.
+    pub fn get_price_history(&self, asset_id: AssetId) -> Vec<CachedPrice> {
+        self.price_history.get(&asset_id)
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    use crate::oracle::ExchangePrice;
+    use crate::price_history::PriceHistory;
+    use crate::StorageKey;
+
+    #[test]
+    fn test_price_history_ring() {
+        let mut context = VMContextBuilder::new();
+        let mut history = PriceHistory::new(StorageKey::PriceHistory);
+        history.set_size(3);
+        for i in 1..=5 {
+            testing_env!(context.block_timestamp(i).build());
+            history.record(&accounts(3), ExchangePrice::new(10000 + u128::from(i), 10));
+            // Unchanged price in the same block is skipped
+            history.record(&accounts(3), ExchangePrice::new(10000 + u128::from(i), 10));
+        }
+        let multipliers = |history: &PriceHistory| -> Vec<u128> {
+            history
+                .get(&accounts(3))
+                .iter()
+                .map(|price| price.price.multiplier)
+                .collect()
+        };
+        assert_eq!(multipliers(&history), vec![10003, 10004, 10005]);
+
+        history.set_size(2);
+        assert_eq!(multipliers(&history), vec![10004, 10005]);
+        testing_env!(context.block_timestamp(6).build());
+        history.record(&accounts(3), ExchangePrice::new(10006, 10));
+        assert_eq!(multipliers(&history), vec![10005, 10006]);
+    }
+}
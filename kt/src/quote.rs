@@ -0,0 +1,137 @@
+//! Sell quotes at the cached prices, for frontends to pick the sell asset.
+
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{near_bindgen, AccountId};
+use schemars::JsonSchema;
+
+use crate::oracle::ExchangePrice;
+use crate::price::{exchange_asset_to_kt, exchange_kt_to_asset};
+use crate::treasury::AssetId;
+use crate::{Contract, ContractExt};
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct SellQuote {
+    pub asset_id: AssetId,
+    /// Asset amount paid out after the fee.
+    pub asset_amount: U128,
+    /// Holding fee of the seller taken from the asset amount.
+    pub fee: U128,
+    /// Cached price the quote is made at, after the spread.
+    pub price: ExchangePrice,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Sellable asset paying out the most for the KT amount at the cached prices after
+    /// the spread, among the ones within the sell limits and the sell share of the
+    /// available treasury balance.
+    /// The holding fee is taken into account if the seller is set.
+    /// Ties go to the asset with the most available balance.
+    pub fn best_sell_quote(
+        &self,
+        amount: U128,
+        account_id: Option<AccountId>,
+    ) -> Option<SellQuote> {
+        self.treasury
+            .supported_assets()
+            .into_iter()
+            .filter(|(_, asset)| asset.status.can_sell())
+            .filter_map(|(asset_id, asset)| {
+                let price = asset.price?.price;
+                let sell_price = self.sell_price(&asset_id, price);
+                let asset_amount = exchange_kt_to_asset(amount.0, asset.decimals, sell_price)?;
+                let available = self.available_balance(&asset_id, asset.balance);
+                if asset_amount == 0
+                    || asset_amount < asset.min_sell
+                    || asset
+                        .max_sell
+                        .is_some_and(|max_sell| asset_amount > max_sell)
+                    || asset_amount > self.sell_limit(available)
+                {
+                    return None;
+                }
+                let fee = account_id.as_ref().map_or(0, |account_id| {
+                    self.holding_fee_of(account_id, asset_amount)
+                });
+                let asset_amount = asset_amount - fee;
+                // Payouts in different assets are compared by their value in KT
+                let value = exchange_asset_to_kt(asset_amount, asset.decimals, price)?;
+                let liquidity =
+                    exchange_asset_to_kt(available, asset.decimals, price).unwrap_or(u128::MAX);
+                let quote = SellQuote {
+                    asset_id,
+                    asset_amount: asset_amount.into(),
+                    fee: fee.into(),
+                    price: sell_price,
+                };
+                Some(((value, liquidity), quote))
+            })
+            .max_by_key(|(key, _)| *key)
+            .map(|(_, quote)| quote)
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    use crate::oracle::ExchangePrice;
+    use crate::{BuyOptions, Contract};
+
+    #[test]
+    fn test_best_sell_quote() {
+        let mut context = VMContextBuilder::new();
+        context
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        let price = ExchangePrice::new(10000, 10);
+        for asset_id in [accounts(2), accounts(3)] {
+            contract.internal_add_asset(&asset_id, 6);
+            contract.treasury.set_asset_price(&asset_id, price);
+        }
+        contract.internal_buy(
+            &accounts(1),
+            &accounts(2),
+            1_000_000,
+            6,
+            price,
+            &BuyOptions::default(),
+        );
+        contract.internal_buy(
+            &accounts(1),
+            &accounts(3),
+            3_000_000,
+            6,
+            price,
+            &BuyOptions::default(),
+        );
+
+        let kt = 2_000_000_000_000_000_000;
+        let quote = contract.best_sell_quote(kt.into(), None).unwrap();
+        assert_eq!(quote.asset_id, accounts(3));
+        assert_eq!(quote.asset_amount.0, 2_000_000);
+
+        // Neither asset covers the payout
+        assert!(contract
+            .best_sell_quote((4 * kt).into(), Some(accounts(1)))
+            .is_none());
+
+        // The spread lowers the payout
+        contract.set_asset_spread(&accounts(3), 100);
+        let quote = contract.best_sell_quote(kt.into(), None).unwrap();
+        assert_eq!(quote.asset_id, accounts(3));
+        assert_eq!(quote.asset_amount.0, 1_980_000);
+
+        // Neither asset allows a sell of more than half its balance
+        contract.set_max_sell_share(5000);
+        assert!(contract.best_sell_quote(kt.into(), None).is_none());
+        let quote = contract.best_sell_quote((kt / 4).into(), None).unwrap();
+        assert_eq!(quote.asset_id, accounts(2));
+    }
+}
@@ -0,0 +1,508 @@
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{
+    env, ext_contract, log, near_bindgen, require, AccountId, Balance, Promise, PromiseResult,
+    ONE_YOCTO,
+};
+use schemars::JsonSchema;
+
+use crate::events::{KtEvent, RefundOperation, RefundReason};
+use crate::oracle::{ExchangePrice, PriceData};
+use crate::price::{exchange_asset_to_asset, exchange_asset_to_kt, exchange_kt_to_asset};
+use crate::roles::Role;
+use crate::treasury::{AssetId, AssetInfo, AssetStatus};
+use crate::{ext_ft_transfer, Contract, ContractExt, BASIS_POINTS};
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+pub enum AllocationStatus {
+    Balanced,
+    Overweight,
+    Underweight,
+    /// The asset has no cached price yet.
+    Unpriced,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct AssetAllocation {
+    pub asset_id: AssetId,
+    pub balance: U128,
+    /// Value of the balance in KT.
+    pub value: Option<U128>,
+    /// Current share of the treasury value, in basis points.
+    pub weight: Option<u16>,
+    pub target_weight: u16,
+    pub status: AllocationStatus,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct RebalanceStatus {
+    /// Value of all priced assets in KT.
+    pub total_value: U128,
+    pub tolerance: u16,
+    pub assets: Vec<AssetAllocation>,
+}
+
+/// Values the asset principal in KT with its cached price.
+pub fn asset_value(asset: &AssetInfo) -> Option<Balance> {
+    let cached = asset.price?;
+    exchange_asset_to_kt(asset.principal(), asset.decimals, cached.price)
+}
+
+/// Share of the value in the total, in basis points.
+fn weight_of(value: Balance, total: Balance) -> u16 {
+    if total == 0 {
+        return 0;
+    }
+    let bps = Balance::from(BASIS_POINTS);
+    let weight = match value.checked_mul(bps) {
+        Some(value) => value / total,
+        None => value / (total / bps),
+    };
+    // value <= total, so the weight fits in basis points
+    weight.min(bps) as u16
+}
+
+pub fn rebalance_status(assets: Vec<(AssetId, AssetInfo)>, tolerance: u16) -> RebalanceStatus {
+    let assets: Vec<_> = assets
+        .into_iter()
+        .filter(|(_, asset)| asset.status != AssetStatus::Deprecated)
+        .map(|(asset_id, asset)| {
+            let value = asset_value(&asset);
+            (asset_id, asset, value)
+        })
+        .collect();
+    let total_value = assets
+        .iter()
+        .filter_map(|(_, _, value)| *value)
+        .fold(0, Balance::saturating_add);
+
+    let assets = assets
+        .into_iter()
+        .map(|(asset_id, asset, value)| {
+            let weight = value.map(|value| weight_of(value, total_value));
+            let status = match weight {
+                None => AllocationStatus::Unpriced,
+                Some(weight) if weight > asset.target_weight.saturating_add(tolerance) => {
+                    AllocationStatus::Overweight
+                }
+                Some(weight) if weight < asset.target_weight.saturating_sub(tolerance) => {
+                    AllocationStatus::Underweight
+                }
+                Some(_) => AllocationStatus::Balanced,
+            };
+            AssetAllocation {
+                asset_id,
+                balance: asset.principal().into(),
+                value: value.map(Into::into),
+                weight,
+                target_weight: asset.target_weight,
+                status,
+            }
+        })
+        .collect();
+
+    RebalanceStatus {
+        total_value: total_value.into(),
+        tolerance,
+        assets,
+    }
+}
+
+impl Contract {
+    /// Fetches the prices of both assets and swaps the keeper's deposit for the overweight asset.
+    pub(crate) fn start_rebalance(
+        &mut self,
+        account_id: AccountId,
+        asset_in: AssetId,
+        amount_in: U128,
+        asset_out: AssetId,
+        min_amount_out: Option<U128>,
+    ) -> Promise {
+        require!(
+            env::prepaid_gas() > self.gas.rebalance(),
+            "More gas is required"
+        );
+        if !self.keeper_incentives.open_rebalance {
+            self.roles.assert_role(&account_id, Role::Keeper);
+        }
+        require!(
+            asset_in != asset_out,
+            "Rebalance assets should be different"
+        );
+        self.treasury.assert_asset(&asset_in);
+        self.treasury.assert_asset(&asset_out);
+
+        self.get_price(&asset_in, None)
+            .and(self.get_price(&asset_out, None))
+            .then(
+                ext_rebalance_resolver::ext(env::current_account_id())
+                    .with_static_gas(self.gas.rebalance_with_prices())
+                    .rebalance_with_prices(
+                        account_id,
+                        asset_in,
+                        amount_in,
+                        asset_out,
+                        min_amount_out,
+                    ),
+            )
+    }
+
+    /// Moves the treasury from the underweight `asset_in` to the overweight `asset_out`,
+    /// no further than both assets reach their target weights.
+    /// Returns the used amount of `asset_in` and the asset amount to pay out, including
+    /// the keeper incentive.
+    pub(crate) fn internal_rebalance(
+        &mut self,
+        asset_in: &AssetId,
+        amount_in: Balance,
+        price_in: ExchangePrice,
+        asset_out: &AssetId,
+        price_out: ExchangePrice,
+    ) -> (Balance, Balance) {
+        self.treasury.set_asset_price(asset_in, price_in);
+        self.treasury.set_asset_price(asset_out, price_out);
+
+        let status = self.rebalance_status();
+        let allocation = |asset_id: &AssetId| {
+            status
+                .assets
+                .iter()
+                .find(|allocation| &allocation.asset_id == asset_id)
+        };
+        let (Some(allocation_in), Some(allocation_out)) =
+            (allocation(asset_in), allocation(asset_out))
+        else {
+            env::panic_str("Rebalance asset is deprecated")
+        };
+        require!(
+            allocation_in.status == AllocationStatus::Underweight,
+            format!("Asset {} is not underweight", asset_in)
+        );
+        require!(
+            allocation_out.status == AllocationStatus::Overweight,
+            format!("Asset {} is not overweight", asset_out)
+        );
+
+        // Value moving both assets to their target weights, whichever is reached first
+        let target_value = |allocation: &AssetAllocation| {
+            let bps = Balance::from(BASIS_POINTS);
+            let weight = Balance::from(allocation.target_weight);
+            match status.total_value.0.checked_mul(weight) {
+                Some(value) => value / bps,
+                None => status.total_value.0 / bps * weight,
+            }
+        };
+        let value_of = |allocation: &AssetAllocation| allocation.value.map_or(0, |value| value.0);
+        let deviation = target_value(allocation_in)
+            .saturating_sub(value_of(allocation_in))
+            .min(value_of(allocation_out).saturating_sub(target_value(allocation_out)));
+        let decimals_in = self.treasury.assert_asset(asset_in).decimals;
+        let max_in = exchange_kt_to_asset(deviation, decimals_in, price_in)
+            .unwrap_or_else(|| env::panic_str("Exchange amount overflow"));
+        let amount_in = amount_in.min(max_in);
+        require!(amount_in > 0, "Rebalance amount is too small");
+
+        let incentive = Balance::from(self.treasury.rebalance_incentive());
+        let bps = Balance::from(BASIS_POINTS);
+        let amount_out = exchange_asset_to_asset(
+            amount_in,
+            decimals_in,
+            price_in,
+            self.treasury.assert_asset(asset_out).decimals,
+            price_out,
+        )
+        .and_then(|amount| amount.checked_mul(bps + incentive))
+        .map(|amount| amount / bps)
+        .unwrap_or_else(|| env::panic_str("Exchange amount overflow"));
+
+        self.treasury.internal_deposit(asset_in, amount_in);
+        self.treasury.internal_withdraw(asset_out, amount_out);
+
+        (amount_in, amount_out)
+    }
+}
+
+#[ext_contract(ext_rebalance_resolver)]
+#[allow(dead_code)]
+#[allow(clippy::too_many_arguments)]
+pub trait RebalanceResolver {
+    fn rebalance_with_prices(
+        &mut self,
+        account_id: AccountId,
+        asset_in: AssetId,
+        amount_in: U128,
+        asset_out: AssetId,
+        min_amount_out: Option<U128>,
+        #[callback_unwrap] price_in: PriceData,
+        #[callback_unwrap] price_out: PriceData,
+    ) -> Promise;
+    fn resolve_rebalance(
+        &mut self,
+        account_id: AccountId,
+        asset_in: AssetId,
+        amount_in: U128,
+        asset_out: AssetId,
+        amount_out: U128,
+        unused: U128,
+    ) -> U128;
+}
+
+#[near_bindgen]
+impl RebalanceResolver for Contract {
+    #[private]
+    #[allow(clippy::too_many_arguments)]
+    fn rebalance_with_prices(
+        &mut self,
+        account_id: AccountId,
+        asset_in: AssetId,
+        amount_in: U128,
+        asset_out: AssetId,
+        min_amount_out: Option<U128>,
+        #[callback_unwrap] price_in: PriceData,
+        #[callback_unwrap] price_out: PriceData,
+    ) -> Promise {
+        let price_in =
+            ExchangePrice::from_price_data(&self.treasury.assert_asset(&asset_in), price_in);
+        let price_out =
+            ExchangePrice::from_price_data(&self.treasury.assert_asset(&asset_out), price_out);
+
+        let (used, amount_out) =
+            self.internal_rebalance(&asset_in, amount_in.into(), price_in, &asset_out, price_out);
+        let unused = amount_in.0 - used;
+        if let Some(min_amount_out) = min_amount_out {
+            // The expected amount shrinks with the clamped trade
+            let min_amount_out = match min_amount_out.0.checked_mul(used) {
+                Some(value) => value / amount_in.0,
+                None => min_amount_out.0 / amount_in.0 * used,
+            };
+            require!(
+                amount_out >= min_amount_out,
+                format!(
+                    "Rebalance amount {} is less than the expected {}",
+                    amount_out, min_amount_out
+                )
+            );
+        }
+
+        let amount_in = U128(used);
+        KtEvent::Rebalance {
+            account_id: &account_id,
+            asset_in: &asset_in,
+            amount_in: &amount_in,
+            asset_out: &asset_out,
+            amount_out: &amount_out.into(),
+        }
+        .emit();
+
+        self.internal_start_payout(&asset_out, amount_out);
+        ext_ft_transfer::ext(asset_out.clone())
+            .with_static_gas(self.gas.transfer)
+            .with_attached_deposit(ONE_YOCTO)
+            .ft_transfer(account_id.clone(), amount_out.into(), None)
+            .then(
+                ext_rebalance_resolver::ext(env::current_account_id())
+                    .with_static_gas(self.gas.resolve_rebalance)
+                    .resolve_rebalance(
+                        account_id,
+                        asset_in,
+                        amount_in,
+                        asset_out,
+                        amount_out.into(),
+                        unused.into(),
+                    ),
+            )
+    }
+
+    /// Returns the unused amount of `asset_in` beyond the deviation, along with the used
+    /// amount when the payout failed.
+    #[private]
+    fn resolve_rebalance(
+        &mut self,
+        account_id: AccountId,
+        asset_in: AssetId,
+        amount_in: U128,
+        asset_out: AssetId,
+        amount_out: U128,
+        unused: U128,
+    ) -> U128 {
+        self.internal_finish_payout(&asset_out, amount_out.0);
+        match env::promise_result(0) {
+            PromiseResult::NotReady => env::abort(),
+            PromiseResult::Successful(_) => unused,
+            PromiseResult::Failed => {
+                self.treasury.internal_withdraw(&asset_in, amount_in.into());
+                self.treasury
+                    .internal_deposit(&asset_out, amount_out.into());
+                log!("Rebalance of @{} is refunded", account_id);
+                KtEvent::KtRefund {
+                    operation: RefundOperation::Rebalance,
+                    reason: RefundReason::PayoutFailed,
+                    account_id: &account_id,
+                    asset_id: Some(&asset_in),
+                    amount: None,
+                    asset_amount: Some(&amount_in),
+                }
+                .emit();
+                U128(amount_in.0 + unused.0)
+            }
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Sets the target weights in basis points, they must sum up to 100%.
+    pub fn set_target_weights(&mut self, weights: Vec<(AccountId, u16)>) {
+        self.assert_owner();
+        self.treasury.set_target_weights(&weights);
+    }
+
+    pub fn set_rebalance_tolerance(&mut self, tolerance: u16) {
+        self.assert_owner();
+        self.treasury.set_rebalance_tolerance(tolerance);
+    }
+
+    /// Sets the bonus paid to keepers on rebalances, in basis points.
+    pub fn set_rebalance_incentive(&mut self, incentive: u16) {
+        self.assert_owner();
+        self.treasury.set_rebalance_incentive(incentive);
+    }
+
+    /// Returns the current vs target allocation of the treasury, valued with cached prices.
+    pub fn rebalance_status(&self) -> RebalanceStatus {
+        rebalance_status(
+            self.treasury.supported_assets(),
+            self.treasury.rebalance_tolerance(),
+        )
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    use crate::oracle::{CachedPrice, ExchangePrice};
+    use crate::rebalance::{rebalance_status, AllocationStatus};
+    use crate::treasury::AssetInfo;
+    use crate::Contract;
+
+    fn setup_contract() -> Contract {
+        let mut context = VMContextBuilder::new();
+        context
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(1), 6);
+        contract.internal_add_asset(&accounts(2), 18);
+        contract.treasury.internal_deposit(&accounts(1), 1_000_000);
+        contract
+            .treasury
+            .internal_deposit(&accounts(2), 3_000_000_000_000_000_000);
+        contract.set_target_weights(vec![(accounts(1), 5_000), (accounts(2), 5_000)]);
+        contract
+    }
+
+    fn asset(balance: u128, target_weight: u16, price: Option<ExchangePrice>) -> AssetInfo {
+        let mut asset = AssetInfo::new(6);
+        asset.balance = balance;
+        asset.target_weight = target_weight;
+        asset.price = price.map(CachedPrice::new);
+        asset
+    }
+
+    #[test]
+    fn test_rebalance_status() {
+        let price = Some(ExchangePrice::new(10000, 10));
+        let status = rebalance_status(
+            vec![
+                (accounts(1), asset(3_000_000, 5_000, price)),
+                (accounts(2), asset(1_000_000, 5_000, price)),
+                (accounts(3), asset(1_000_000, 0, None)),
+            ],
+            100,
+        );
+
+        assert_eq!(status.total_value.0, 4_000_000_000_000_000_000);
+        assert_eq!(status.assets[0].weight, Some(7_500));
+        assert_eq!(status.assets[0].status, AllocationStatus::Overweight);
+        assert_eq!(status.assets[1].weight, Some(2_500));
+        assert_eq!(status.assets[1].status, AllocationStatus::Underweight);
+        assert_eq!(status.assets[2].weight, None);
+        assert_eq!(status.assets[2].status, AllocationStatus::Unpriced);
+    }
+
+    #[test]
+    fn test_rebalance_status_within_tolerance() {
+        let price = Some(ExchangePrice::new(10000, 10));
+        let status = rebalance_status(
+            vec![
+                (accounts(1), asset(5_100_000, 5_000, price)),
+                (accounts(2), asset(4_900_000, 5_000, price)),
+            ],
+            100,
+        );
+
+        assert_eq!(status.assets[0].status, AllocationStatus::Balanced);
+        assert_eq!(status.assets[1].status, AllocationStatus::Balanced);
+    }
+
+    #[test]
+    fn test_internal_rebalance() {
+        let mut contract = setup_contract();
+        contract.set_rebalance_incentive(100);
+
+        let amount_out = contract.internal_rebalance(
+            &accounts(1),
+            1_000_000,
+            ExchangePrice::new(10000, 10),
+            &accounts(2),
+            ExchangePrice::new(10000, 22),
+        );
+        assert_eq!(amount_out, (1_000_000, 1_010_000_000_000_000_000));
+
+        let assets = contract.treasury.supported_assets();
+        assert_eq!(assets[0].1.balance, 2_000_000);
+        assert_eq!(assets[1].1.balance, 1_990_000_000_000_000_000);
+    }
+
+    #[test]
+    fn test_internal_rebalance_clamped() {
+        let mut contract = setup_contract();
+        // Half of the amount moves both assets to their target weights
+        let amount_out = contract.internal_rebalance(
+            &accounts(1),
+            2_000_000,
+            ExchangePrice::new(10000, 10),
+            &accounts(2),
+            ExchangePrice::new(10000, 22),
+        );
+        assert_eq!(amount_out, (1_000_000, 1_000_000_000_000_000_000));
+    }
+
+    #[test]
+    #[should_panic(expected = "Incentive is out of bounds")]
+    fn test_rebalance_incentive_cap() {
+        let mut contract = setup_contract();
+        contract.set_rebalance_incentive(301);
+    }
+
+    #[test]
+    #[should_panic(expected = "Asset charlie is not underweight")]
+    fn test_internal_rebalance_wrong_direction() {
+        let mut contract = setup_contract();
+        contract.internal_rebalance(
+            &accounts(2),
+            1_000_000_000_000_000_000,
+            ExchangePrice::new(10000, 22),
+            &accounts(1),
+            ExchangePrice::new(10000, 10),
+        );
+    }
+}
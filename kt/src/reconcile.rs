@@ -0,0 +1,291 @@
+//! Reconciliation of the asset bookkeeping with the balance the asset contract holds
+//! for KT, which can drift through donations or failed bookkeeping.
+
+use near_contract_standards::fungible_token::core::ext_ft_core;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{I128, U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, ext_contract, near_bindgen, require, Balance, Promise};
+use schemars::JsonSchema;
+
+use crate::events::KtEvent;
+use crate::roles::Role;
+use crate::treasury::AssetId;
+use crate::{promise_result_u128, Contract, ContractExt};
+
+/// Asset balance held for KT compared to the bookkeeping at the last reconciliation.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct AssetDrift {
+    /// Treasury balance along with the insurance fund and the claims.
+    pub expected: U128,
+    /// `ft_balance_of` KT on the asset contract.
+    pub actual: U128,
+    pub checked_at: U64,
+    /// Applied to the treasury balance.
+    pub resolved: bool,
+}
+
+impl AssetDrift {
+    /// Surplus if positive, shortfall if negative.
+    pub fn drift(&self) -> i128 {
+        if self.actual.0 >= self.expected.0 {
+            i128::try_from(self.actual.0 - self.expected.0).unwrap_or(i128::MAX)
+        } else {
+            i128::try_from(self.expected.0 - self.actual.0).map_or(i128::MIN, |value| -value)
+        }
+    }
+}
+
+impl Contract {
+    /// Records an asset amount taken out of the bookkeeping, which the asset contract
+    /// still holds until its transfer resolves.
+    pub(crate) fn internal_start_payout(&mut self, asset_id: &AssetId, amount: Balance) {
+        let in_flight = self.payouts_in_flight.get(asset_id).unwrap_or_default();
+        self.payouts_in_flight
+            .insert(asset_id, &in_flight.saturating_add(amount));
+    }
+
+    /// Clears the payout once its transfer resolved, whatever the outcome.
+    pub(crate) fn internal_finish_payout(&mut self, asset_id: &AssetId, amount: Balance) {
+        let in_flight = self
+            .payouts_in_flight
+            .get(asset_id)
+            .unwrap_or_default()
+            .saturating_sub(amount);
+        if in_flight == 0 {
+            self.payouts_in_flight.remove(asset_id);
+        } else {
+            self.payouts_in_flight.insert(asset_id, &in_flight);
+        }
+    }
+
+    /// Rejects adopting a surplus while payouts or operations of the asset are in flight,
+    /// as their amounts show up as a surplus which doesn't back anything.
+    fn assert_nothing_in_flight(&self, asset_id: &AssetId) {
+        require!(
+            self.payouts_in_flight.get(asset_id).unwrap_or_default() == 0
+                && !self.operations.has_pending_asset(asset_id),
+            "Payouts or operations of the asset are in flight"
+        );
+    }
+
+    /// Asset amount the asset contract should hold for KT.
+    fn expected_holding(&self, asset_id: &AssetId) -> Balance {
+        let asset = self.treasury.assert_asset(asset_id);
+        asset
+            .balance
+            .saturating_add(asset.insurance)
+            .saturating_add(self.claims.total_of(asset_id))
+    }
+
+    /// Records the drift of the asset, and with `resolve` moves a surplus to the
+    /// treasury balance or deducts a shortfall from it.
+    pub(crate) fn internal_reconcile(
+        &mut self,
+        asset_id: &AssetId,
+        actual: Balance,
+        resolve: bool,
+    ) -> AssetDrift {
+        let expected = self.expected_holding(asset_id);
+        if resolve {
+            self.assert_nothing_in_flight(asset_id);
+            if actual > expected {
+                self.treasury.internal_deposit(asset_id, actual - expected);
+            } else if actual < expected {
+                let balance = self.treasury.assert_asset(asset_id).balance;
+                self.treasury
+                    .internal_withdraw(asset_id, (expected - actual).min(balance));
+            }
+        }
+        let drift = AssetDrift {
+            expected: expected.into(),
+            actual: actual.into(),
+            checked_at: env::block_timestamp().into(),
+            resolved: resolve,
+        };
+        self.asset_drifts.insert(asset_id, &drift);
+        KtEvent::AssetReconciled {
+            asset_id,
+            expected: &drift.expected,
+            actual: &drift.actual,
+            drift: &I128::from(drift.drift()),
+            resolved: resolve,
+        }
+        .emit();
+        drift
+    }
+
+    /// Adopts the asset amount held beyond the bookkeeping into the treasury balance,
+    /// a shortfall is only recorded. Returns the adopted amount.
+    pub(crate) fn internal_skim(&mut self, asset_id: &AssetId, actual: Balance) -> Balance {
+        self.assert_nothing_in_flight(asset_id);
+        let surplus = actual.saturating_sub(self.expected_holding(asset_id));
+        if surplus > 0 {
+            self.treasury.internal_deposit(asset_id, surplus);
+            KtEvent::AssetSkimmed {
+                asset_id,
+                amount: &surplus.into(),
+            }
+            .emit();
+        }
+        self.internal_reconcile(asset_id, actual, false);
+        surplus
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Compares the asset bookkeeping with the balance the asset contract holds for KT.
+    /// Allowed to the owner and keepers, only the owner can `resolve` the drift.
+    /// The drift can't be resolved while payouts or operations of the asset are in flight.
+    pub fn reconcile_asset(&mut self, asset_id: AssetId, resolve: Option<bool>) -> Promise {
+        let resolve = resolve.unwrap_or(false);
+        if resolve {
+            require!(
+                env::predecessor_account_id() == self.owner_id,
+                "Only the owner can resolve the drift"
+            );
+            self.assert_nothing_in_flight(&asset_id);
+        } else {
+            self.assert_owner_or_role(Role::Keeper);
+        }
+        self.treasury.assert_asset(&asset_id);
+        ext_ft_core::ext(asset_id.clone())
+            .with_static_gas(self.gas.ft_balance_of)
+            .ft_balance_of(env::current_account_id())
+            .then(
+                ext_reconcile_resolver::ext(env::current_account_id())
+                    .with_static_gas(self.gas.resolve_reconcile)
+                    .resolve_reconcile(asset_id, resolve),
+            )
+    }
+
+    /// Adopts the asset tokens sent to KT without `ft_transfer_call` into the treasury
+    /// as backing. Allowed to the owner and keepers, when no payouts are in flight.
+    pub fn skim(&mut self, asset_id: AssetId) -> Promise {
+        self.assert_owner_or_role(Role::Keeper);
+        self.treasury.assert_asset(&asset_id);
+        self.assert_nothing_in_flight(&asset_id);
+        ext_ft_core::ext(asset_id.clone())
+            .with_static_gas(self.gas.ft_balance_of)
+            .ft_balance_of(env::current_account_id())
+            .then(
+                ext_reconcile_resolver::ext(env::current_account_id())
+                    .with_static_gas(self.gas.resolve_reconcile)
+                    .resolve_skim(asset_id),
+            )
+    }
+
+    /// Drift of the asset found by the last reconciliation.
+    pub fn get_asset_drift(&self, asset_id: AssetId) -> Option<AssetDrift> {
+        self.asset_drifts.get(&asset_id)
+    }
+}
+
+#[ext_contract(ext_reconcile_resolver)]
+#[allow(dead_code)]
+pub trait ReconcileResolver {
+    fn resolve_reconcile(&mut self, asset_id: AssetId, resolve: bool) -> Option<AssetDrift>;
+    fn resolve_skim(&mut self, asset_id: AssetId) -> U128;
+}
+
+#[near_bindgen]
+impl ReconcileResolver for Contract {
+    #[private]
+    fn resolve_reconcile(&mut self, asset_id: AssetId, resolve: bool) -> Option<AssetDrift> {
+        promise_result_u128().map(|actual| self.internal_reconcile(&asset_id, actual, resolve))
+    }
+
+    #[private]
+    fn resolve_skim(&mut self, asset_id: AssetId) -> U128 {
+        promise_result_u128()
+            .map_or(0, |actual| self.internal_skim(&asset_id, actual))
+            .into()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    use crate::operations::{OperationKind, OperationStage};
+    use crate::Contract;
+
+    #[test]
+    fn test_reconcile() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(3), 6);
+        contract.treasury.internal_deposit(&accounts(3), 1_000);
+        contract.treasury.internal_insure(&accounts(3), 100);
+        contract.claims.internal_add(&accounts(1), &accounts(3), 50);
+
+        let drift = contract.internal_reconcile(&accounts(3), 1_070, false);
+        assert_eq!(drift.expected.0, 1_050);
+        assert_eq!(drift.drift(), 20);
+        assert_eq!(contract.treasury.assert_asset(&accounts(3)).balance, 900);
+
+        contract.internal_reconcile(&accounts(3), 1_070, true);
+        assert_eq!(contract.treasury.assert_asset(&accounts(3)).balance, 920);
+        let drift = contract.internal_reconcile(&accounts(3), 1_000, true);
+        assert_eq!(drift.drift(), -70);
+        assert_eq!(contract.treasury.assert_asset(&accounts(3)).balance, 850);
+        assert_eq!(
+            contract.get_asset_drift(accounts(3)).unwrap().expected.0,
+            1_070
+        );
+    }
+
+    #[test]
+    fn test_skim() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(3), 6);
+        contract.treasury.internal_deposit(&accounts(3), 1_000);
+
+        assert_eq!(contract.internal_skim(&accounts(3), 1_300), 300);
+        assert_eq!(contract.treasury.assert_asset(&accounts(3)).balance, 1_300);
+        assert_eq!(contract.get_asset_drift(accounts(3)).unwrap().drift(), 0);
+
+        // A shortfall isn't deducted
+        assert_eq!(contract.internal_skim(&accounts(3), 1_200), 0);
+        assert_eq!(contract.treasury.assert_asset(&accounts(3)).balance, 1_300);
+    }
+
+    #[test]
+    #[should_panic(expected = "Payouts or operations of the asset are in flight")]
+    fn test_reconcile_operation_in_flight() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(3), 6);
+        contract.operations.start(
+            &accounts(1),
+            OperationKind::Buy,
+            Some(accounts(3)),
+            1_000.into(),
+            OperationStage::Pricing,
+        );
+
+        contract.internal_reconcile(&accounts(3), 1_000, true);
+    }
+
+    #[test]
+    #[should_panic(expected = "Payouts or operations of the asset are in flight")]
+    fn test_skim_payout_in_flight() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(3), 6);
+        contract.treasury.internal_deposit(&accounts(3), 1_000);
+        contract.treasury.internal_withdraw(&accounts(3), 300);
+        contract.internal_start_payout(&accounts(3), 300);
+
+        contract.internal_skim(&accounts(3), 1_000);
+    }
+}
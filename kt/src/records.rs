@@ -0,0 +1,140 @@
+//! Records of two-party agreements, e.g. escrows, streams and subscriptions, stored by id
+//! and indexed by the accounts they belong to for the paginated views.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::{LookupMap, UnorderedMap};
+use near_sdk::{AccountId, IntoStorageKey};
+
+pub type RecordId = u64;
+
+/// Record belonging to some accounts.
+pub trait Parties {
+    fn parties(&self) -> [&AccountId; 2];
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Records<T> {
+    /// RecordID -> Record.
+    records: UnorderedMap<RecordId, T>,
+    /// AccountID -> Ids of the records of the account.
+    accounts: LookupMap<AccountId, Vec<RecordId>>,
+    next_id: RecordId,
+}
+
+impl<T> Records<T>
+where
+    T: BorshSerialize + BorshDeserialize + Parties,
+{
+    pub fn new<S>(prefix: S) -> Self
+    where
+        S: IntoStorageKey,
+    {
+        let prefix = prefix.into_storage_key();
+        Self {
+            records: UnorderedMap::new([prefix.clone(), b"r".to_vec()].concat()),
+            accounts: LookupMap::new([prefix, b"a".to_vec()].concat()),
+            next_id: 0,
+        }
+    }
+
+    pub fn get(&self, id: RecordId) -> Option<T> {
+        self.records.get(&id)
+    }
+
+    pub fn push(&mut self, record: &T) -> RecordId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.records.insert(&id, record);
+        for account_id in record.parties() {
+            let mut ids = self.accounts.get(account_id).unwrap_or_default();
+            ids.push(id);
+            self.accounts.insert(account_id, &ids);
+        }
+        id
+    }
+
+    /// Replaces the record, its parties can't change.
+    pub fn update(&mut self, id: RecordId, record: &T) {
+        self.records.insert(&id, record);
+    }
+
+    pub fn remove(&mut self, id: RecordId) -> Option<T> {
+        let record = self.records.remove(&id)?;
+        for account_id in record.parties() {
+            let Some(mut ids) = self.accounts.get(account_id) else {
+                continue;
+            };
+            ids.retain(|record_id| *record_id != id);
+            if ids.is_empty() {
+                self.accounts.remove(account_id);
+            } else {
+                self.accounts.insert(account_id, &ids);
+            }
+        }
+        Some(record)
+    }
+
+    /// Records of the account, in the creation order.
+    pub fn records_of(
+        &self,
+        account_id: &AccountId,
+        from_index: u64,
+        limit: u64,
+    ) -> Vec<(RecordId, T)> {
+        self.accounts
+            .get(account_id)
+            .unwrap_or_default()
+            .into_iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .filter_map(|id| self.records.get(&id).map(|record| (id, record)))
+            .collect()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+    use near_sdk::test_utils::accounts;
+    use near_sdk::AccountId;
+
+    use crate::records::{Parties, Records};
+    use crate::StorageKey;
+
+    #[derive(BorshDeserialize, BorshSerialize)]
+    struct Deal(AccountId, AccountId);
+
+    impl Parties for Deal {
+        fn parties(&self) -> [&AccountId; 2] {
+            [&self.0, &self.1]
+        }
+    }
+
+    fn ids(
+        records: &Records<Deal>,
+        account_id: AccountId,
+        from_index: u64,
+        limit: u64,
+    ) -> Vec<u64> {
+        records
+            .records_of(&account_id, from_index, limit)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect()
+    }
+
+    #[test]
+    fn test_records_of() {
+        let mut records = Records::new(StorageKey::Escrows);
+        let first = records.push(&Deal(accounts(1), accounts(2)));
+        let second = records.push(&Deal(accounts(2), accounts(3)));
+        let third = records.push(&Deal(accounts(1), accounts(3)));
+        assert_eq!(ids(&records, accounts(2), 0, 10), vec![first, second]);
+        assert_eq!(ids(&records, accounts(3), 1, 10), vec![third]);
+        assert_eq!(ids(&records, accounts(1), 0, 1), vec![first]);
+
+        records.remove(first);
+        assert_eq!(ids(&records, accounts(1), 0, 10), vec![third]);
+        assert_eq!(ids(&records, accounts(2), 0, 10), vec![second]);
+    }
+}
@@ -0,0 +1,428 @@
+use near_contract_standards::fungible_token::events::FtBurn;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::{LookupMap, UnorderedMap};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{
+    assert_one_yocto, env, ext_contract, near_bindgen, require, AccountId, Balance, IntoStorageKey,
+    Promise, PromiseResult, ONE_YOCTO,
+};
+use schemars::JsonSchema;
+
+use crate::events::KtEvent;
+use crate::oracle::ExchangePrice;
+use crate::price::{exchange_asset_to_kt, exchange_kt_to_asset};
+use crate::treasury::AssetId;
+use crate::{ext_ft_transfer, Contract, ContractExt};
+
+pub type RedemptionId = u64;
+
+/// How a sell handles a treasury balance shortfall of the asset.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub enum Shortfall {
+    /// Sells the rest for the most liquid sellable asset.
+    Fallback,
+    /// Queues the rest of the asset amount to be claimed later.
+    Queue,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct Redemption {
+    pub account_id: AccountId,
+    pub asset_id: AssetId,
+    /// Asset amount owed to the account.
+    pub amount: U128,
+    /// Total amount of the asset ever queued before the redemption.
+    pub position: U128,
+    pub created_at: U64,
+}
+
+/// Running totals of the queued asset amounts.
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct QueueTotals {
+    /// Amount ever queued.
+    pub pushed: Balance,
+    /// Amount ever paid out.
+    pub paid: Balance,
+}
+
+/// Asset payouts owed for sells which exceeded the treasury balance, paid in FIFO order.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct RedemptionQueue {
+    /// RedemptionID -> Redemption, ids are increasing.
+    redemptions: UnorderedMap<RedemptionId, Redemption>,
+    /// AssetID -> Running totals of the asset.
+    totals: LookupMap<AssetId, QueueTotals>,
+    next_id: RedemptionId,
+}
+
+impl RedemptionQueue {
+    pub fn new<S>(prefix: S) -> Self
+    where
+        S: IntoStorageKey,
+    {
+        let prefix = prefix.into_storage_key();
+        Self {
+            redemptions: UnorderedMap::new([prefix.clone(), b"r".to_vec()].concat()),
+            totals: LookupMap::new([prefix, b"t".to_vec()].concat()),
+            next_id: 0,
+        }
+    }
+
+    /// Total asset amount reserved for the queued redemptions.
+    pub fn queued(&self, asset_id: &AssetId) -> Balance {
+        let totals = self.totals.get(asset_id).unwrap_or_default();
+        totals.pushed - totals.paid
+    }
+
+    /// Queued amount of the asset which has to be paid before the redemption.
+    /// Redemptions claimed out of order were covered together with everything
+    /// queued before them, so counting them as paid keeps the earlier ones covered.
+    pub fn queued_before(&self, redemption: &Redemption) -> Balance {
+        let totals = self.totals.get(&redemption.asset_id).unwrap_or_default();
+        redemption.position.0.saturating_sub(totals.paid)
+    }
+
+    pub fn get(&self, id: RedemptionId) -> Option<Redemption> {
+        self.redemptions.get(&id)
+    }
+
+    /// Puts the paid out redemption back at its place.
+    pub fn reinsert(&mut self, id: RedemptionId, redemption: &Redemption) {
+        let mut totals = self.totals.get(&redemption.asset_id).unwrap_or_default();
+        totals.paid -= redemption.amount.0;
+        self.totals.insert(&redemption.asset_id, &totals);
+        self.redemptions.insert(&id, redemption);
+    }
+
+    /// Queues the redemption after all redemptions of its asset.
+    pub fn push(&mut self, mut redemption: Redemption) -> RedemptionId {
+        let id = self.next_id;
+        self.next_id += 1;
+        let mut totals = self.totals.get(&redemption.asset_id).unwrap_or_default();
+        redemption.position = totals.pushed.into();
+        totals.pushed = totals
+            .pushed
+            .checked_add(redemption.amount.0)
+            .unwrap_or_else(|| env::panic_str("Queued amount overflow"));
+        self.totals.insert(&redemption.asset_id, &totals);
+        self.redemptions.insert(&id, &redemption);
+        id
+    }
+
+    pub fn remove(&mut self, id: RedemptionId) -> Option<Redemption> {
+        let redemption = self.redemptions.remove(&id)?;
+        let mut totals = self.totals.get(&redemption.asset_id).unwrap_or_default();
+        totals.paid += redemption.amount.0;
+        self.totals.insert(&redemption.asset_id, &totals);
+        Some(redemption)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (RedemptionId, Redemption)> + '_ {
+        self.redemptions.iter()
+    }
+}
+
+impl Contract {
+    /// Treasury balance of the asset which is not reserved for queued redemptions.
+    pub(crate) fn available_balance(&self, asset_id: &AssetId, balance: Balance) -> Balance {
+        balance.saturating_sub(self.redemptions.queued(asset_id))
+    }
+
+    /// Burns KT and queues the asset amount owed for it.
+    pub(crate) fn internal_queue_redemption(
+        &mut self,
+        account_id: &AccountId,
+        asset_id: &AssetId,
+        kt_amount: Balance,
+        asset_decimals: u8,
+        price: ExchangePrice,
+        memo: Option<&str>,
+    ) -> RedemptionId {
+        self.compliance.assert_not_frozen(account_id);
+        self.sell_cooldown.assert_elapsed(account_id);
+        self.volume_limits.record_burn(account_id, kt_amount);
+        let cost_basis = self.token.cost_basis_of(account_id);
+        self.token
+            .internal_withdraw(account_id, kt_amount, price.to_decimals());
+
+        FtBurn {
+            owner_id: account_id,
+            amount: &U128::from(kt_amount),
+            memo: Some("redemption"),
+        }
+        .emit();
+
+        let asset_amount = exchange_kt_to_asset(kt_amount, asset_decimals, price)
+            .unwrap_or_else(|| env::panic_str("Exchange amount overflow"));
+        // The treasury may not cover the split yet, the holding fee is kept whole
+        let fee = self.holding_fee_of(account_id, asset_amount);
+        let kt_value = exchange_asset_to_kt(fee, asset_decimals, price).unwrap_or_default();
+        self.fees
+            .record_collected(asset_id, (fee, 0, 0, 0), kt_value);
+        let asset_amount = asset_amount - fee;
+        let redemption = Redemption {
+            account_id: account_id.clone(),
+            asset_id: asset_id.clone(),
+            amount: asset_amount.into(),
+            position: 0.into(),
+            created_at: env::block_timestamp().into(),
+        };
+        let id = self.redemptions.push(redemption);
+        self.stats.record_sell(asset_id, asset_amount, kt_amount);
+        self.price_history.record(asset_id, price);
+        self.emit_kt_sell(
+            account_id,
+            asset_id,
+            kt_amount,
+            (asset_amount, fee),
+            price,
+            cost_basis,
+            memo,
+        );
+
+        KtEvent::RedemptionQueued {
+            id,
+            account_id,
+            asset_id,
+            amount: &asset_amount.into(),
+        }
+        .emit();
+
+        id
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Pays out the queued redemption once the treasury covers it
+    /// and all redemptions of the asset queued before it.
+    #[payable]
+    pub fn claim_redemption(&mut self, id: U64) -> Promise {
+        assert_one_yocto();
+        let id = id.0;
+        let redemption = self
+            .redemptions
+            .get(id)
+            .unwrap_or_else(|| env::panic_str(format!("Redemption {} is not found", id).as_str()));
+        require!(
+            redemption.account_id == env::predecessor_account_id(),
+            "Redemption belongs to another account"
+        );
+        let asset = self.treasury.assert_asset(&redemption.asset_id);
+        let required = self
+            .redemptions
+            .queued_before(&redemption)
+            .saturating_add(redemption.amount.0);
+        require!(
+            asset.balance >= required,
+            "The treasury doesn't have enough balance"
+        );
+
+        self.redemptions.remove(id);
+        self.treasury
+            .internal_withdraw(&redemption.asset_id, redemption.amount.0);
+        self.internal_start_payout(&redemption.asset_id, redemption.amount.0);
+
+        ext_ft_transfer::ext(redemption.asset_id.clone())
+            .with_static_gas(self.gas.transfer)
+            .with_attached_deposit(ONE_YOCTO)
+            .ft_transfer(redemption.account_id.clone(), redemption.amount, None)
+            .then(
+                ext_redemption_resolver::ext(env::current_account_id())
+                    .with_static_gas(self.gas.resolve_claim_redemption)
+                    .resolve_claim_redemption(id.into(), redemption),
+            )
+    }
+
+    /// Queued redemptions, their queue order is given by the positions.
+    pub fn get_redemption_queue(
+        &self,
+        from_index: Option<U64>,
+        limit: Option<U64>,
+    ) -> Vec<(U64, Redemption)> {
+        self.redemptions
+            .iter()
+            .skip(from_index.map_or(0, |index| index.0 as usize))
+            .take(limit.map_or(usize::MAX, |limit| limit.0 as usize))
+            .map(|(id, redemption)| (id.into(), redemption))
+            .collect()
+    }
+
+    pub fn get_redemptions(
+        &self,
+        account_id: AccountId,
+        from_index: Option<U64>,
+        limit: Option<U64>,
+    ) -> Vec<(U64, Redemption)> {
+        self.redemptions
+            .iter()
+            .filter(|(_, redemption)| redemption.account_id == account_id)
+            .skip(from_index.map_or(0, |index| index.0 as usize))
+            .take(limit.map_or(usize::MAX, |limit| limit.0 as usize))
+            .map(|(id, redemption)| (id.into(), redemption))
+            .collect()
+    }
+}
+
+#[ext_contract(ext_redemption_resolver)]
+#[allow(dead_code)]
+pub trait RedemptionResolver {
+    fn resolve_claim_redemption(&mut self, id: U64, redemption: Redemption);
+}
+
+#[near_bindgen]
+impl RedemptionResolver for Contract {
+    /// Queues the redemption back at its place if the transfer failed.
+    #[private]
+    fn resolve_claim_redemption(&mut self, id: U64, redemption: Redemption) {
+        self.internal_finish_payout(&redemption.asset_id, redemption.amount.0);
+        match env::promise_result(0) {
+            PromiseResult::NotReady => env::abort(),
+            PromiseResult::Successful(_) => KtEvent::RedemptionClaimed {
+                id: id.0,
+                account_id: &redemption.account_id,
+                asset_id: &redemption.asset_id,
+                amount: &redemption.amount,
+            }
+            .emit(),
+            PromiseResult::Failed => {
+                self.treasury
+                    .internal_deposit(&redemption.asset_id, redemption.amount.0);
+                self.redemptions.reinsert(id.0, &redemption);
+            }
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_contract_standards::fungible_token::core::FungibleTokenCore;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, ONE_YOCTO};
+
+    use crate::oracle::ExchangePrice;
+    use crate::test_utils;
+    use crate::Contract;
+
+    fn setup_contract(context: &mut VMContextBuilder) -> Contract {
+        test_utils::setup_contract_with_kt(context)
+    }
+
+    #[test]
+    fn test_queue_redemption() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        let price = ExchangePrice::new(10000, 10);
+        let id = contract.internal_queue_redemption(
+            &accounts(1),
+            &accounts(3),
+            400_000_000_000_000_000,
+            6,
+            price,
+            None,
+        );
+        assert_eq!(id, 0);
+        assert_eq!(
+            contract.ft_balance_of(accounts(1)).0,
+            600_000_000_000_000_000
+        );
+        assert_eq!(contract.available_balance(&accounts(3), 1_000_000), 600_000);
+
+        let queue = contract.get_redemptions(accounts(1), None, None);
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue[0].1.amount.0, 400_000);
+    }
+
+    #[test]
+    fn test_claim_redemption() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        let price = ExchangePrice::new(10000, 10);
+        contract.internal_queue_redemption(
+            &accounts(1),
+            &accounts(3),
+            400_000_000_000_000_000,
+            6,
+            price,
+            None,
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        contract.claim_redemption(0.into());
+        assert!(contract.get_redemption_queue(None, None).is_empty());
+        assert_eq!(
+            contract.treasury.assert_asset(&accounts(3)).balance,
+            600_000
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "The treasury doesn't have enough balance")]
+    fn test_claim_redemption_in_order() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        let price = ExchangePrice::new(10000, 10);
+        contract.internal_queue_redemption(
+            &accounts(1),
+            &accounts(3),
+            800_000_000_000_000_000,
+            6,
+            price,
+            None,
+        );
+        contract.internal_queue_redemption(
+            &accounts(1),
+            &accounts(3),
+            200_000_000_000_000_000,
+            6,
+            price,
+            None,
+        );
+        contract.treasury.internal_withdraw(&accounts(3), 500_000);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        contract.claim_redemption(1.into());
+    }
+
+    #[test]
+    fn test_queued_before() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        let price = ExchangePrice::new(10000, 10);
+        for kt_amount in [300, 200, 100] {
+            contract.internal_queue_redemption(
+                &accounts(1),
+                &accounts(3),
+                kt_amount * 1_000_000_000_000_000,
+                6,
+                price,
+                None,
+            );
+        }
+        let queue = contract.get_redemptions(accounts(1), Some(1.into()), Some(1.into()));
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue[0].1.position.0, 300_000);
+        assert_eq!(contract.redemptions.queued(&accounts(3)), 600_000);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        contract.claim_redemption(1.into());
+        let last = contract.redemptions.get(2).unwrap();
+        assert_eq!(contract.redemptions.queued_before(&last), 300_000);
+        assert_eq!(contract.redemptions.queued(&accounts(3)), 400_000);
+        assert_eq!(contract.get_redemption_queue(None, Some(5.into())).len(), 2);
+    }
+}
@@ -0,0 +1,194 @@
+//! Relayed calls (NEP-366 meta transactions).
+//!
+//! The protocol verifies the signed delegate action and executes it with the user as
+//! the predecessor and the relayer as the signer. The user picks a registered relayer
+//! to sponsor its gas, and then pays it the relay fee in KT on every buy, sell and
+//! transfer the relayer signs for it, until it opts out.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::{LookupMap, UnorderedSet};
+use near_sdk::json_types::U128;
+use near_sdk::{assert_one_yocto, env, near_bindgen, require, AccountId, Balance, IntoStorageKey};
+
+use crate::events::KtEvent;
+use crate::{Contract, ContractExt};
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Relayers {
+    accounts: UnorderedSet<AccountId>,
+    /// AccountID -> Relayer chosen by the account.
+    chosen: LookupMap<AccountId, AccountId>,
+    /// KT charged per relayed call.
+    fee: Balance,
+}
+
+impl Relayers {
+    pub fn new<S>(prefix: S) -> Self
+    where
+        S: IntoStorageKey,
+    {
+        let prefix = prefix.into_storage_key();
+        Self {
+            accounts: UnorderedSet::new([prefix.clone(), b"a".to_vec()].concat()),
+            chosen: LookupMap::new([prefix, b"c".to_vec()].concat()),
+            fee: 0,
+        }
+    }
+
+    /// Relayer chosen by the account, if it's still a registered one.
+    pub fn relayer_of(&self, account_id: &AccountId) -> Option<AccountId> {
+        self.chosen
+            .get(account_id)
+            .filter(|relayer_id| self.accounts.contains(relayer_id))
+    }
+}
+
+impl Contract {
+    /// Pays the relay fee from the account to the relayer if the call is relayed, i.e. the
+    /// transaction is signed by the relayer chosen by the account rather than by itself.
+    pub(crate) fn charge_relay_fee(&mut self, account_id: &AccountId) {
+        let fee = self.relayers.fee;
+        let signer_id = env::signer_account_id();
+        if fee == 0 || &signer_id == account_id {
+            return;
+        }
+        if let Some(relayer_id) = self
+            .relayers
+            .relayer_of(account_id)
+            .filter(|relayer_id| relayer_id == &signer_id)
+        {
+            self.token.internal_transfer(
+                account_id,
+                &relayer_id,
+                fee,
+                Some("relay fee".to_string()),
+            );
+            KtEvent::RelayFeeCharged {
+                account_id,
+                relayer_id: &relayer_id,
+                amount: &fee.into(),
+            }
+            .emit();
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn add_relayer(&mut self, account_id: AccountId) {
+        self.assert_owner();
+        self.relayers.accounts.insert(&account_id);
+    }
+
+    pub fn remove_relayer(&mut self, account_id: AccountId) {
+        self.assert_owner();
+        self.relayers.accounts.remove(&account_id);
+    }
+
+    pub fn set_relay_fee(&mut self, fee: U128) {
+        self.assert_owner();
+        self.relayers.fee = fee.into();
+    }
+
+    /// Sets the relayer sponsoring the caller's gas, which is paid the relay fee on every
+    /// buy, sell and transfer it relays for the caller. `None` opts out.
+    #[payable]
+    pub fn set_relayer(&mut self, relayer_id: Option<AccountId>) {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        match relayer_id {
+            Some(relayer_id) => {
+                require!(
+                    self.relayers.accounts.contains(&relayer_id),
+                    "The relayer isn't registered"
+                );
+                self.relayers.chosen.insert(&account_id, &relayer_id);
+            }
+            None => {
+                self.relayers.chosen.remove(&account_id);
+            }
+        }
+    }
+
+    pub fn get_relayer(&self, account_id: AccountId) -> Option<AccountId> {
+        self.relayers.relayer_of(&account_id)
+    }
+
+    pub fn get_relayers(&self) -> Vec<AccountId> {
+        self.relayers.accounts.to_vec()
+    }
+
+    pub fn get_relay_fee(&self) -> U128 {
+        self.relayers.fee.into()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_contract_standards::fungible_token::core::FungibleTokenCore;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, ONE_YOCTO};
+
+    use crate::Contract;
+
+    #[test]
+    fn test_relayed_transfer() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.add_relayer(accounts(3));
+        contract.set_relay_fee(10.into());
+        contract.token.internal_deposit(&accounts(1), 1_000, 1);
+
+        // Calls without a chosen relayer are not charged, whoever signs them
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .signer_account_id(accounts(3))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        contract.ft_transfer(accounts(2), 100.into(), None);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 900);
+
+        contract.set_relayer(Some(accounts(3)));
+        contract.ft_transfer(accounts(2), 100.into(), None);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 790);
+        assert_eq!(contract.ft_balance_of(accounts(3)).0, 10);
+
+        // Calls signed by the account itself are not relayed
+        testing_env!(context.signer_account_id(accounts(1)).build());
+        contract.ft_transfer(accounts(2), 100.into(), None);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 690);
+        assert_eq!(contract.ft_balance_of(accounts(3)).0, 10);
+
+        // Neither are the calls signed by another account
+        testing_env!(context.signer_account_id(accounts(2)).build());
+        contract.ft_transfer(accounts(2), 100.into(), None);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 590);
+        assert_eq!(contract.ft_balance_of(accounts(3)).0, 10);
+
+        // A removed relayer isn't paid
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.remove_relayer(accounts(3));
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .signer_account_id(accounts(3))
+            .build());
+        contract.ft_transfer(accounts(2), 100.into(), None);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 490);
+        assert!(contract.get_relayer(accounts(1)).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "The relayer isn't registered")]
+    fn test_set_unregistered_relayer() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        contract.set_relayer(Some(accounts(3)));
+    }
+}
@@ -0,0 +1,160 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::UnorderedMap;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen, require, AccountId, IntoStorageKey};
+use schemars::JsonSchema;
+
+use crate::{Contract, ContractExt};
+
+#[derive(
+    BorshDeserialize,
+    BorshSerialize,
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    JsonSchema,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum Role {
+    /// Allowed to run treasury maintenance, e.g. rebalancing.
+    Keeper,
+    /// Allowed to evacuate the treasury in an emergency.
+    Guardian,
+    /// Allowed to freeze accounts and seize their balances.
+    Compliance,
+    /// Allowed to mint and burn bridged KT within its bridge limits.
+    Bridge,
+    /// Allowed to withdraw the protocol fees kept in the treasury.
+    Treasurer,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Roles {
+    /// AccountID -> Granted roles.
+    accounts: UnorderedMap<AccountId, Vec<Role>>,
+}
+
+impl Roles {
+    pub fn new<S>(prefix: S) -> Self
+    where
+        S: IntoStorageKey,
+    {
+        Self {
+            accounts: UnorderedMap::new(prefix),
+        }
+    }
+
+    pub fn has_role(&self, account_id: &AccountId, role: Role) -> bool {
+        matches!(self.accounts.get(account_id), Some(roles) if roles.contains(&role))
+    }
+
+    pub fn assert_role(&self, account_id: &AccountId, role: Role) {
+        if !self.has_role(account_id, role) {
+            env::panic_str(format!("Account {} is not a {:?}", account_id, role).as_str())
+        }
+    }
+
+    pub fn grant_role(&mut self, account_id: &AccountId, role: Role) {
+        let mut roles = self.accounts.get(account_id).unwrap_or_default();
+        if !roles.contains(&role) {
+            roles.push(role);
+            self.accounts.insert(account_id, &roles);
+        }
+    }
+
+    pub fn revoke_role(&mut self, account_id: &AccountId, role: Role) {
+        if let Some(mut roles) = self.accounts.get(account_id) {
+            roles.retain(|r| *r != role);
+            if roles.is_empty() {
+                self.accounts.remove(account_id);
+            } else {
+                self.accounts.insert(account_id, &roles);
+            }
+        }
+    }
+
+    pub fn roles_of(&self, account_id: &AccountId) -> Vec<Role> {
+        self.accounts.get(account_id).unwrap_or_default()
+    }
+
+    pub fn members_of(&self, role: Role) -> Vec<AccountId> {
+        self.accounts
+            .iter()
+            .filter(|(_, roles)| roles.contains(&role))
+            .map(|(account_id, _)| account_id)
+            .collect()
+    }
+}
+
+impl Contract {
+    /// Panics unless the predecessor is the owner.
+    pub(crate) fn assert_owner(&self) {
+        require!(
+            env::predecessor_account_id() == self.owner_id,
+            "Owner must be predecessor"
+        );
+    }
+
+    pub(crate) fn assert_owner_or_role(&self, role: Role) {
+        let account_id = env::predecessor_account_id();
+        if account_id != self.owner_id {
+            self.roles.assert_role(&account_id, role);
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn grant_role(&mut self, account_id: AccountId, role: Role) {
+        self.assert_owner();
+        self.roles.grant_role(&account_id, role);
+    }
+
+    pub fn revoke_role(&mut self, account_id: AccountId, role: Role) {
+        self.assert_owner();
+        self.roles.revoke_role(&account_id, role);
+    }
+
+    pub fn get_roles(&self, account_id: AccountId) -> Vec<Role> {
+        self.roles.roles_of(&account_id)
+    }
+
+    pub fn get_role_members(&self, role: Role) -> Vec<AccountId> {
+        self.roles.members_of(role)
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::accounts;
+
+    use crate::roles::{Role, Roles};
+    use crate::StorageKey;
+
+    #[test]
+    fn test_grant_revoke_role() {
+        let mut roles = Roles::new(StorageKey::Roles);
+        assert!(!roles.has_role(&accounts(1), Role::Keeper));
+
+        roles.grant_role(&accounts(1), Role::Keeper);
+        roles.grant_role(&accounts(1), Role::Keeper);
+        assert!(roles.has_role(&accounts(1), Role::Keeper));
+        assert_eq!(roles.roles_of(&accounts(1)), vec![Role::Keeper]);
+        assert_eq!(roles.members_of(Role::Keeper), vec![accounts(1)]);
+
+        roles.revoke_role(&accounts(1), Role::Keeper);
+        assert!(!roles.has_role(&accounts(1), Role::Keeper));
+        assert!(roles.members_of(Role::Keeper).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Account bob is not a Keeper")]
+    fn test_assert_role() {
+        let roles = Roles::new(StorageKey::Roles);
+        roles.assert_role(&accounts(1), Role::Keeper);
+    }
+}
@@ -0,0 +1,360 @@
+//! Scheduled buys for dollar-cost averaging.
+//!
+//! The asset deposited with the `ScheduleBuy` message is held outside the treasury and
+//! converted to KT in equal parts at the oracle price once every interval. Execution is
+//! open to anyone, so a cron service like croncat can trigger `execute_scheduled_buy`
+//! for the ids returned by `get_due_scheduled_buys`.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::UnorderedMap;
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{
+    assert_one_yocto, env, ext_contract, near_bindgen, require, AccountId, IntoStorageKey, Promise,
+};
+use schemars::JsonSchema;
+
+use crate::events::{KtEvent, RefundOperation, RefundReason};
+use crate::oracle::{ExchangePrice, PriceData};
+use crate::treasury::AssetId;
+use crate::{BuyOptions, Contract, ContractExt};
+
+pub type ScheduleId = u64;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct ScheduledBuy {
+    pub account_id: AccountId,
+    pub asset_id: AssetId,
+    /// Asset amount left to buy with.
+    pub remaining: U128,
+    pub amount_per_buy: U128,
+    /// Nanoseconds between buys.
+    pub interval: U64,
+    pub next_buy_at: U64,
+}
+
+impl ScheduledBuy {
+    pub fn is_due(&self) -> bool {
+        env::block_timestamp() >= self.next_buy_at.0
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct ScheduledBuys {
+    schedules: UnorderedMap<ScheduleId, ScheduledBuy>,
+    next_id: ScheduleId,
+}
+
+impl ScheduledBuys {
+    pub fn new<S>(prefix: S) -> Self
+    where
+        S: IntoStorageKey,
+    {
+        Self {
+            schedules: UnorderedMap::new(prefix),
+            next_id: 0,
+        }
+    }
+
+    pub fn get(&self, id: ScheduleId) -> Option<ScheduledBuy> {
+        self.schedules.get(&id)
+    }
+
+    pub fn assert_schedule(&self, id: ScheduleId) -> ScheduledBuy {
+        self.get(id).unwrap_or_else(|| {
+            env::panic_str(format!("Scheduled buy {} is not found", id).as_str())
+        })
+    }
+
+    pub fn push(&mut self, schedule: &ScheduledBuy) -> ScheduleId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.schedules.insert(&id, schedule);
+        id
+    }
+
+    pub fn insert(&mut self, id: ScheduleId, schedule: &ScheduledBuy) {
+        self.schedules.insert(&id, schedule);
+    }
+
+    pub fn remove(&mut self, id: ScheduleId) -> Option<ScheduledBuy> {
+        self.schedules.remove(&id)
+    }
+}
+
+impl Contract {
+    /// Schedules buys for the asset deposited by the account, the first one is due at once.
+    pub(crate) fn internal_schedule_buy(
+        &mut self,
+        account_id: &AccountId,
+        asset_id: &AssetId,
+        amount: U128,
+        amount_per_buy: U128,
+        interval: U64,
+    ) -> ScheduleId {
+        self.assert_buys_enabled();
+        let asset = self.treasury.assert_can_buy(asset_id);
+        self.compliance.assert_not_frozen(account_id);
+        require!(
+            amount_per_buy.0 >= asset.min_buy.max(1),
+            "Amount per buy is below the minimum buy"
+        );
+        require!(interval.0 > 0, "Interval should be positive");
+        let schedule = ScheduledBuy {
+            account_id: account_id.clone(),
+            asset_id: asset_id.clone(),
+            remaining: amount,
+            amount_per_buy,
+            interval,
+            next_buy_at: env::block_timestamp().into(),
+        };
+        let id = self.scheduled_buys.push(&schedule);
+
+        KtEvent::BuyScheduled {
+            id,
+            account_id,
+            asset_id,
+            amount: &amount,
+            amount_per_buy: &amount_per_buy,
+            interval: &interval,
+        }
+        .emit();
+
+        id
+    }
+
+    /// Buys the next part of the schedule at the price.
+    pub(crate) fn internal_execute_scheduled_buy(&mut self, id: ScheduleId, price: ExchangePrice) {
+        let mut schedule = self.scheduled_buys.assert_schedule(id);
+        require!(schedule.is_due(), "Scheduled buy is not due yet");
+        let asset = self.treasury.assert_can_buy(&schedule.asset_id);
+        self.treasury.set_asset_price(&schedule.asset_id, price);
+
+        let amount = schedule.amount_per_buy.0.min(schedule.remaining.0);
+        self.internal_buy(
+            &schedule.account_id,
+            &schedule.asset_id,
+            amount,
+            asset.decimals,
+            price,
+            &BuyOptions::default(),
+        );
+        schedule.remaining = (schedule.remaining.0 - amount).into();
+        // Missed intervals are skipped rather than bought at once
+        schedule.next_buy_at = (env::block_timestamp() + schedule.interval.0).into();
+
+        KtEvent::ScheduledBuyExecuted {
+            id,
+            account_id: &schedule.account_id,
+            amount: &amount.into(),
+            remaining: &schedule.remaining,
+        }
+        .emit();
+
+        // The rest below the minimum buy is left for the account to claim
+        if schedule.remaining.0 < asset.min_buy.max(1) {
+            self.scheduled_buys.remove(id);
+            self.internal_refund_schedule(&schedule, RefundReason::BelowMinimum);
+        } else {
+            self.scheduled_buys.insert(id, &schedule);
+        }
+    }
+
+    fn internal_refund_schedule(&mut self, schedule: &ScheduledBuy, reason: RefundReason) {
+        if schedule.remaining.0 == 0 {
+            return;
+        }
+        self.claims.internal_add(
+            &schedule.account_id,
+            &schedule.asset_id,
+            schedule.remaining.0,
+        );
+        KtEvent::AssetClaimAdded {
+            account_id: &schedule.account_id,
+            asset_id: &schedule.asset_id,
+            amount: &schedule.remaining,
+        }
+        .emit();
+        KtEvent::KtRefund {
+            operation: RefundOperation::ScheduledBuy,
+            reason,
+            account_id: &schedule.account_id,
+            asset_id: Some(&schedule.asset_id),
+            amount: None,
+            asset_amount: Some(&schedule.remaining),
+        }
+        .emit();
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Executes the due scheduled buy at the oracle price, callable by anyone.
+    pub fn execute_scheduled_buy(&mut self, id: U64) -> Promise {
+        let schedule = self.scheduled_buys.assert_schedule(id.0);
+        require!(
+            env::prepaid_gas() > self.price_gas(&schedule.asset_id) + self.gas.buy_with_price,
+            "More gas is required"
+        );
+        require!(schedule.is_due(), "Scheduled buy is not due yet");
+
+        self.get_price(&schedule.asset_id, None).then(
+            ext_schedule_resolver::ext(env::current_account_id())
+                .with_static_gas(self.gas.buy_with_price)
+                .scheduled_buy_with_price(id),
+        )
+    }
+
+    /// Stops the scheduled buy, the remaining asset is left to claim.
+    #[payable]
+    pub fn cancel_scheduled_buy(&mut self, id: U64) {
+        assert_one_yocto();
+        let schedule = self.scheduled_buys.assert_schedule(id.0);
+        require!(
+            schedule.account_id == env::predecessor_account_id(),
+            "Scheduled buy belongs to another account"
+        );
+        self.scheduled_buys.remove(id.0);
+        self.internal_refund_schedule(&schedule, RefundReason::Cancelled);
+        KtEvent::ScheduledBuyCancelled {
+            id: id.0,
+            account_id: &schedule.account_id,
+        }
+        .emit();
+    }
+
+    pub fn get_scheduled_buy(&self, id: U64) -> Option<ScheduledBuy> {
+        self.scheduled_buys.get(id.0)
+    }
+
+    pub fn get_scheduled_buys(
+        &self,
+        account_id: AccountId,
+        from_index: Option<U64>,
+        limit: Option<U64>,
+    ) -> Vec<(U64, ScheduledBuy)> {
+        let from_index = from_index.map_or(0, |index| index.0 as usize);
+        let limit = limit.map_or(usize::MAX, |limit| limit.0 as usize);
+        self.scheduled_buys
+            .schedules
+            .iter()
+            .filter(|(_, schedule)| schedule.account_id == account_id)
+            .skip(from_index)
+            .take(limit)
+            .map(|(id, schedule)| (id.into(), schedule))
+            .collect()
+    }
+
+    /// Ids of the scheduled buys which can be executed now, among `limit` schedules
+    /// starting at `from_index`, so the keepers can page through all of them.
+    pub fn get_due_scheduled_buys(&self, from_index: Option<U64>, limit: Option<U64>) -> Vec<U64> {
+        let from_index = from_index.map_or(0, |index| index.0 as usize);
+        let limit = limit.map_or(usize::MAX, |limit| limit.0 as usize);
+        self.scheduled_buys
+            .schedules
+            .iter()
+            .skip(from_index)
+            .take(limit)
+            .filter(|(_, schedule)| schedule.is_due())
+            .map(|(id, _)| id.into())
+            .collect()
+    }
+}
+
+#[ext_contract(ext_schedule_resolver)]
+#[allow(dead_code)]
+pub trait ScheduleResolver {
+    fn scheduled_buy_with_price(&mut self, id: U64, #[callback_unwrap] price: PriceData);
+}
+
+#[near_bindgen]
+impl ScheduleResolver for Contract {
+    #[private]
+    fn scheduled_buy_with_price(&mut self, id: U64, #[callback_unwrap] price: PriceData) {
+        let schedule = self.scheduled_buys.assert_schedule(id.0);
+        let asset = self.treasury.assert_asset(&schedule.asset_id);
+        let price = ExchangePrice::from_price_data(&asset, price);
+        self.internal_execute_scheduled_buy(id.0, price);
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_contract_standards::fungible_token::core::FungibleTokenCore;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, ONE_YOCTO};
+
+    use crate::oracle::ExchangePrice;
+    use crate::test_utils;
+    use crate::Contract;
+
+    fn setup_contract(context: &mut VMContextBuilder) -> Contract {
+        let mut contract = test_utils::setup_contract(context);
+        contract.internal_schedule_buy(
+            &accounts(1),
+            &accounts(3),
+            2_500_000.into(),
+            1_000_000.into(),
+            100.into(),
+        );
+        contract
+    }
+
+    #[test]
+    fn test_scheduled_buys() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        let price = ExchangePrice::new(10000, 10);
+
+        contract.internal_execute_scheduled_buy(0, price);
+        assert_eq!(
+            contract.ft_balance_of(accounts(1)).0,
+            1_000_000_000_000_000_000
+        );
+        assert!(contract.get_due_scheduled_buys(None, None).is_empty());
+
+        testing_env!(context.block_timestamp(100).build());
+        assert_eq!(contract.get_due_scheduled_buys(None, None), vec![0.into()]);
+        assert!(contract
+            .get_due_scheduled_buys(Some(1.into()), None)
+            .is_empty());
+        contract.internal_execute_scheduled_buy(0, price);
+        testing_env!(context.block_timestamp(200).build());
+        contract.internal_execute_scheduled_buy(0, price);
+        assert_eq!(
+            contract.ft_balance_of(accounts(1)).0,
+            2_500_000_000_000_000_000
+        );
+        assert!(contract.get_scheduled_buy(0.into()).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Scheduled buy is not due yet")]
+    fn test_scheduled_buy_not_due() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        let price = ExchangePrice::new(10000, 10);
+        contract.internal_execute_scheduled_buy(0, price);
+        contract.internal_execute_scheduled_buy(0, price);
+    }
+
+    #[test]
+    fn test_cancel_scheduled_buy() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        contract.cancel_scheduled_buy(0.into());
+        assert!(contract
+            .get_scheduled_buys(accounts(1), None, None)
+            .is_empty());
+        assert_eq!(
+            contract.claims.claims_of(&accounts(1))[&accounts(3)],
+            2_500_000
+        );
+    }
+}
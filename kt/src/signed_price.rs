@@ -0,0 +1,357 @@
+//! Prices signed off-chain by the oracle keys.
+//!
+//! A signed price is bound to the contract, the account and the asset, and is used once:
+//! its nonce has to exceed the last one used by the account. Buys and sells with a signed
+//! price execute in the same transaction, without the oracle call.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::json_types::{Base64VecU8, U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{
+    assert_one_yocto, env, near_bindgen, require, AccountId, CurveType, Gas, IntoStorageKey,
+    PromiseOrValue, PublicKey,
+};
+use schemars::JsonSchema;
+
+use crate::events::KtEvent;
+use crate::oracle::{Price, PriceData};
+use crate::price::ExpectedPrice;
+use crate::treasury::AssetId;
+use crate::{BuyOptions, Contract, ContractExt, ContractResolver, SellOptions};
+
+#[derive(BorshSerialize, Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PricePayload {
+    pub account_id: AccountId,
+    pub asset_id: AssetId,
+    pub price: Price,
+    pub timestamp: U64,
+    pub expires_at: U64,
+    pub nonce: U64,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SignedPrice {
+    pub payload: PricePayload,
+    pub public_key: PublicKey,
+    /// Ed25519 signature of `borsh((contract_id, payload))`.
+    pub signature: Base64VecU8,
+}
+
+impl SignedPrice {
+    pub fn message(&self) -> Vec<u8> {
+        (env::current_account_id(), &self.payload)
+            .try_to_vec()
+            .unwrap_or_else(|_| env::abort())
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct SignedPrices {
+    /// Ed25519 keys of the oracle allowed to sign prices.
+    keys: Vec<PublicKey>,
+    /// AccountID -> Last used nonce.
+    nonces: LookupMap<AccountId, u64>,
+}
+
+impl SignedPrices {
+    pub fn new<S>(prefix: S) -> Self
+    where
+        S: IntoStorageKey,
+    {
+        Self {
+            keys: Vec::new(),
+            nonces: LookupMap::new(prefix),
+        }
+    }
+
+    pub fn nonce_of(&self, account_id: &AccountId) -> u64 {
+        self.nonces.get(account_id).unwrap_or_default()
+    }
+
+    /// Checks the signed price of the asset is valid for the account and consumes its nonce.
+    pub fn verify(
+        &mut self,
+        account_id: &AccountId,
+        asset_id: &AssetId,
+        signed: SignedPrice,
+    ) -> PriceData {
+        require!(
+            self.keys.contains(&signed.public_key),
+            "Price signer is not registered"
+        );
+        let payload = &signed.payload;
+        require!(
+            payload.account_id == *account_id,
+            "Signed price is for another account"
+        );
+        require!(
+            payload.asset_id == *asset_id,
+            "Signed price is for another asset"
+        );
+        require!(
+            payload.nonce.0 > self.nonce_of(account_id),
+            "Signed price nonce is already used"
+        );
+        let signature: [u8; 64] = signed
+            .signature
+            .0
+            .as_slice()
+            .try_into()
+            .unwrap_or_else(|_| env::panic_str("Invalid price signature"));
+        let public_key: [u8; 32] = signed.public_key.as_bytes()[1..]
+            .try_into()
+            .unwrap_or_else(|_| env::abort());
+        require!(
+            ed25519_verify(&signature, &signed.message(), &public_key),
+            "Invalid price signature"
+        );
+        self.nonces.insert(account_id, &payload.nonce.0);
+
+        let SignedPrice { payload, .. } = signed;
+        PriceData {
+            timestamp: Some(payload.timestamp),
+            expiration: payload.expires_at,
+            price: Some(payload.price),
+            pegged: false,
+        }
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn ed25519_verify(signature: &[u8; 64], message: &[u8], public_key: &[u8; 32]) -> bool {
+    unsafe {
+        near_sys::ed25519_verify(
+            signature.len() as _,
+            signature.as_ptr() as _,
+            message.len() as _,
+            message.as_ptr() as _,
+            public_key.len() as _,
+            public_key.as_ptr() as _,
+        ) == 1
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn ed25519_verify(signature: &[u8; 64], message: &[u8], public_key: &[u8; 32]) -> bool {
+    use ed25519_dalek::Verifier;
+
+    match (
+        ed25519_dalek::PublicKey::from_bytes(public_key),
+        ed25519_dalek::Signature::from_bytes(signature),
+    ) {
+        (Ok(public_key), Ok(signature)) => public_key.verify(message, &signature).is_ok(),
+        _ => false,
+    }
+}
+
+impl Contract {
+    /// Buys KT for the deposit at the signed price.
+    pub(crate) fn internal_buy_signed(
+        &mut self,
+        account_id: AccountId,
+        asset_id: AssetId,
+        amount: U128,
+        price: SignedPrice,
+        expected: Option<ExpectedPrice>,
+        referrer_id: Option<AccountId>,
+    ) -> U128 {
+        self.assert_buys_enabled();
+        let asset = self.treasury.assert_can_buy(&asset_id);
+        if amount.0 < asset.min_buy {
+            return amount;
+        }
+        self.kyc.assert_verified(&account_id);
+        let data = self.signed_prices.verify(&account_id, &asset_id, price);
+        self.buy_with_price(
+            account_id,
+            asset_id,
+            amount,
+            expected,
+            BuyOptions {
+                referrer_id,
+                ..Default::default()
+            },
+            data,
+        )
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn add_price_signer(&mut self, public_key: PublicKey) {
+        self.assert_owner();
+        require!(
+            public_key.curve_type() == CurveType::ED25519,
+            "Price signer should be an ed25519 key"
+        );
+        require!(
+            !self.signed_prices.keys.contains(&public_key),
+            "Price signer is already registered"
+        );
+        self.signed_prices.keys.push(public_key.clone());
+        KtEvent::PriceSignerAdded {
+            public_key: &public_key,
+        }
+        .emit();
+    }
+
+    pub fn remove_price_signer(&mut self, public_key: PublicKey) {
+        self.assert_owner();
+        let len = self.signed_prices.keys.len();
+        self.signed_prices.keys.retain(|key| *key != public_key);
+        require!(
+            self.signed_prices.keys.len() < len,
+            "Price signer is not registered"
+        );
+        KtEvent::PriceSignerRemoved {
+            public_key: &public_key,
+        }
+        .emit();
+    }
+
+    pub fn get_price_signers(&self) -> Vec<PublicKey> {
+        self.signed_prices.keys.clone()
+    }
+
+    /// Last signed price nonce used by the account.
+    pub fn get_price_nonce(&self, account_id: AccountId) -> U64 {
+        self.signed_prices.nonce_of(&account_id).into()
+    }
+
+    /// Sells KT for the asset at the signed price, without the oracle call.
+    /// The asset is paid out to `receiver_id` if set.
+    #[payable]
+    pub fn sell_signed(
+        &mut self,
+        asset_id: AssetId,
+        amount: U128,
+        price: SignedPrice,
+        expected: Option<ExpectedPrice>,
+        receiver_id: Option<AccountId>,
+    ) -> PromiseOrValue<()> {
+        assert_one_yocto();
+        require!(
+            env::prepaid_gas() > Gas(self.gas.sell_with_price().0),
+            "More gas is required"
+        );
+        let account_id = env::predecessor_account_id();
+        self.treasury.assert_can_sell(&asset_id);
+        self.compliance.assert_not_frozen(&account_id);
+        self.operations.assert_no_pending_sell(&account_id);
+        self.kyc.assert_verified(&account_id);
+        self.charge_relay_fee(&account_id);
+        let data = self.signed_prices.verify(&account_id, &asset_id, price);
+        self.sell_with_price(
+            account_id,
+            asset_id,
+            amount,
+            expected,
+            SellOptions {
+                receiver_id,
+                ..Default::default()
+            },
+            data,
+        )
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use ed25519_dalek::{Keypair, PublicKey as DalekPublicKey, SecretKey, Signer};
+    use near_contract_standards::fungible_token::core::FungibleTokenCore;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::PublicKey;
+
+    use super::{PricePayload, SignedPrice};
+    use crate::oracle::Price;
+    use crate::test_utils;
+    use crate::Contract;
+
+    fn keypair() -> Keypair {
+        let secret = SecretKey::from_bytes(&[7; 32]).unwrap();
+        let public = DalekPublicKey::from(&secret);
+        Keypair { secret, public }
+    }
+
+    fn signed_price(nonce: u64) -> SignedPrice {
+        let keypair = keypair();
+        let mut signed = SignedPrice {
+            payload: PricePayload {
+                account_id: accounts(1),
+                asset_id: accounts(3),
+                price: Price::new(10000, 16),
+                timestamp: 0.into(),
+                expires_at: 10.into(),
+                nonce: nonce.into(),
+            },
+            public_key: PublicKey::try_from([&[0], keypair.public.as_bytes().as_slice()].concat())
+                .unwrap(),
+            signature: Vec::new().into(),
+        };
+        signed.signature = keypair.sign(&signed.message()).to_bytes().to_vec().into();
+        signed
+    }
+
+    fn setup_contract(context: &mut VMContextBuilder) -> Contract {
+        let mut contract = test_utils::setup_contract(context);
+        contract.add_price_signer(signed_price(1).public_key);
+        contract
+    }
+
+    #[test]
+    fn test_buy_signed() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        let unused = contract.internal_buy_signed(
+            accounts(1),
+            accounts(3),
+            1_000_000.into(),
+            signed_price(1),
+            None,
+            None,
+        );
+        assert_eq!(unused.0, 0);
+        assert_eq!(
+            contract.ft_balance_of(accounts(1)).0,
+            1_000_000_000_000_000_000
+        );
+        assert_eq!(contract.get_price_nonce(accounts(1)).0, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid price signature")]
+    fn test_buy_signed_tampered() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        let mut price = signed_price(1);
+        price.payload.price = Price::new(5000, 16);
+        contract.internal_buy_signed(
+            accounts(1),
+            accounts(3),
+            1_000_000.into(),
+            price,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Signed price nonce is already used")]
+    fn test_buy_signed_replayed() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        for _ in 0..2 {
+            contract.internal_buy_signed(
+                accounts(1),
+                accounts(3),
+                1_000_000.into(),
+                signed_price(1),
+                None,
+                None,
+            );
+        }
+    }
+}
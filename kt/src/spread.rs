@@ -0,0 +1,103 @@
+//! Per-asset spread around the oracle mid-price: buys pay a markup and sells get a
+//! markdown. The spread stays in the treasury balance, covering the oracle latency.
+
+use near_sdk::{near_bindgen, require, AccountId};
+
+use crate::events::KtEvent;
+use crate::oracle::ExchangePrice;
+use crate::treasury::AssetId;
+use crate::{Contract, ContractExt, BASIS_POINTS};
+
+/// Maximum spread of an asset, in basis points.
+pub const MAX_SPREAD: u16 = 500;
+
+impl ExchangePrice {
+    /// Price raised by the spread, rounded up.
+    pub fn with_markup(self, spread: u16) -> Self {
+        let bps = u128::from(BASIS_POINTS);
+        let factor = bps + u128::from(spread);
+        let multiplier = match self.multiplier.checked_mul(factor) {
+            Some(value) => value.div_ceil(bps),
+            None => (self.multiplier / bps + 1).saturating_mul(factor),
+        };
+        Self { multiplier, ..self }
+    }
+
+    /// Price lowered by the spread, rounded down.
+    pub fn with_markdown(self, spread: u16) -> Self {
+        let bps = u128::from(BASIS_POINTS);
+        let factor = bps - u128::from(spread);
+        let multiplier = match self.multiplier.checked_mul(factor) {
+            Some(value) => value / bps,
+            None => self.multiplier / bps * factor,
+        };
+        Self { multiplier, ..self }
+    }
+}
+
+impl Contract {
+    /// Price paid by the buys of the asset.
+    pub(crate) fn buy_price(&self, asset_id: &AssetId, price: ExchangePrice) -> ExchangePrice {
+        match self.treasury.assert_asset(asset_id).spread {
+            0 => price,
+            spread => price.with_markup(spread),
+        }
+    }
+
+    /// Price received by the sells of the asset.
+    pub(crate) fn sell_price(&self, asset_id: &AssetId, price: ExchangePrice) -> ExchangePrice {
+        match self.treasury.assert_asset(asset_id).spread {
+            0 => price,
+            spread => price.with_markdown(spread),
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Sets the spread of the asset around the oracle price, in basis points.
+    pub fn set_asset_spread(&mut self, asset_id: &AccountId, spread: u16) {
+        self.assert_owner();
+        require!(
+            spread <= MAX_SPREAD,
+            format!("Spread exceeds the maximum of {}", MAX_SPREAD)
+        );
+        self.treasury.set_spread(asset_id, spread);
+        KtEvent::SpreadChanged { asset_id, spread }.emit();
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_contract_standards::fungible_token::core::FungibleTokenCore;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    use crate::oracle::ExchangePrice;
+    use crate::{BuyOptions, Contract};
+
+    #[test]
+    fn test_spread() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(3), 6);
+        contract.set_asset_spread(&accounts(3), 100);
+
+        let price = ExchangePrice::new(10000, 10);
+        let (_, minted) = contract.internal_buy(
+            &accounts(1),
+            &accounts(3),
+            1_010_000,
+            6,
+            price,
+            &BuyOptions::default(),
+        );
+        assert_eq!(minted, 1_000_000_000_000_000_000);
+
+        let paid = contract.internal_sell(&accounts(1), &accounts(3), minted, 6, price, None);
+        assert_eq!(paid.0, 990_000);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 0);
+        assert_eq!(contract.treasury.assert_asset(&accounts(3)).balance, 20_000);
+    }
+}
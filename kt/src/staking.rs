@@ -0,0 +1,530 @@
+//! KT staking.
+//!
+//! Staked KT is held by the contract account and locked for one of the configured periods,
+//! longer periods get more reward shares. Unlocked KT is unstaked through a cooldown before
+//! it can be withdrawn. Rewards are paid out of the collected fees, valued in KT and
+//! distributed per reward share, minted when claimed.
+
+use near_contract_standards::fungible_token::events::FtMint;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{assert_one_yocto, env, near_bindgen, require, AccountId, Balance, IntoStorageKey};
+use schemars::JsonSchema;
+
+use crate::distribution::Rewards;
+use crate::events::KtEvent;
+use crate::ft::AccountBalance;
+use crate::price::exchange_asset_to_kt;
+use crate::treasury::AssetId;
+use crate::{Contract, ContractExt, BASIS_POINTS};
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct LockPeriod {
+    /// Lock duration, in nanoseconds.
+    pub duration: U64,
+    /// Reward shares per staked KT, in basis points.
+    pub multiplier: u16,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct Stake {
+    pub amount: U128,
+    /// Weighted mean cost basis of the staked KT, restored on withdrawal.
+    pub price: U128,
+    pub shares: U128,
+    pub unlock_at: U64,
+    /// Unstaked amount waiting for the cooldown.
+    pub unstaking: U128,
+    pub cooldown_end: U64,
+}
+
+#[derive(Serialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StakeView {
+    #[serde(flatten)]
+    pub stake: Stake,
+    pub rewards: U128,
+}
+
+#[derive(Serialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StakingInfo {
+    pub total_staked: U128,
+    pub total_shares: U128,
+    pub lock_periods: Vec<LockPeriod>,
+    pub cooldown: U64,
+    pub max_epoch_rewards: U128,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Staking {
+    /// AccountID -> Stake.
+    stakes: LookupMap<AccountId, Stake>,
+    /// Rewards per reward share.
+    rewards: Rewards,
+    total_staked: Balance,
+    total_shares: Balance,
+    lock_periods: Vec<LockPeriod>,
+    /// Unstaking cooldown, in nanoseconds.
+    cooldown: u64,
+    /// KT which can be distributed per epoch.
+    max_epoch_rewards: Balance,
+    /// Epoch height and the KT distributed in it.
+    epoch_rewards: (u64, Balance),
+}
+
+impl Staking {
+    pub fn new<S>(prefix: S) -> Self
+    where
+        S: IntoStorageKey,
+    {
+        let prefix = prefix.into_storage_key();
+        Self {
+            stakes: LookupMap::new([prefix.clone(), b"s".to_vec()].concat()),
+            rewards: Rewards::new([prefix, b"r".to_vec()].concat()),
+            total_staked: 0,
+            total_shares: 0,
+            lock_periods: vec![],
+            cooldown: 0,
+            max_epoch_rewards: 0,
+            epoch_rewards: (0, 0),
+        }
+    }
+
+    pub fn set_config(
+        &mut self,
+        lock_periods: Vec<LockPeriod>,
+        cooldown: u64,
+        max_epoch_rewards: Balance,
+    ) {
+        require!(
+            lock_periods
+                .iter()
+                .all(|period| period.multiplier >= BASIS_POINTS),
+            "Lock period multiplier is out of bounds"
+        );
+        self.lock_periods = lock_periods;
+        self.cooldown = cooldown;
+        self.max_epoch_rewards = max_epoch_rewards;
+    }
+
+    pub fn info(&self) -> StakingInfo {
+        StakingInfo {
+            total_staked: self.total_staked.into(),
+            total_shares: self.total_shares.into(),
+            lock_periods: self.lock_periods.clone(),
+            cooldown: self.cooldown.into(),
+            max_epoch_rewards: self.max_epoch_rewards.into(),
+        }
+    }
+
+    pub fn stake_of(&self, account_id: &AccountId) -> Option<Stake> {
+        self.stakes.get(account_id)
+    }
+
+    fn assert_stake(&self, account_id: &AccountId) -> Stake {
+        self.stake_of(account_id)
+            .unwrap_or_else(|| env::panic_str("The account has no stake"))
+    }
+
+    fn set_shares(&mut self, account_id: &AccountId, stake: &mut Stake, shares: Balance) {
+        self.rewards.checkpoint(account_id, stake.shares.0);
+        self.total_shares = self.total_shares - stake.shares.0 + shares;
+        stake.shares = shares.into();
+    }
+
+    /// Locks the amount for the period, extending the lock of the existing stake.
+    pub fn internal_stake(
+        &mut self,
+        account_id: &AccountId,
+        amount: Balance,
+        price: Balance,
+        period: usize,
+    ) -> Stake {
+        require!(amount > 0, "The amount should be a positive number");
+        let period = self
+            .lock_periods
+            .get(period)
+            .cloned()
+            .unwrap_or_else(|| env::panic_str("Lock period is not found"));
+        let mut stake = self.stake_of(account_id).unwrap_or(Stake {
+            amount: 0.into(),
+            price: 0.into(),
+            shares: 0.into(),
+            unlock_at: 0.into(),
+            unstaking: 0.into(),
+            cooldown_end: 0.into(),
+        });
+
+        let balance = AccountBalance::new(stake.amount.0, stake.price.0)
+            .checked_add(amount, price)
+            .unwrap_or_else(|| env::panic_str("Stake amount overflow"));
+        stake.amount = balance.amount.into();
+        stake.price = balance.price.into();
+        let shares = match amount.checked_mul(u128::from(period.multiplier)) {
+            Some(value) => value / u128::from(BASIS_POINTS),
+            None => amount / u128::from(BASIS_POINTS) * u128::from(period.multiplier),
+        };
+        let shares = stake.shares.0 + shares;
+        self.set_shares(account_id, &mut stake, shares);
+        stake.unlock_at = stake
+            .unlock_at
+            .0
+            .max(env::block_timestamp().saturating_add(period.duration.0))
+            .into();
+        self.total_staked += amount;
+
+        self.stakes.insert(account_id, &stake);
+        stake
+    }
+
+    /// Starts the cooldown of the unlocked amount, its reward shares are removed.
+    pub fn internal_unstake(&mut self, account_id: &AccountId, amount: Balance) -> Stake {
+        let mut stake = self.assert_stake(account_id);
+        require!(amount > 0, "The amount should be a positive number");
+        require!(
+            amount <= stake.amount.0,
+            "The stake doesn't have enough balance"
+        );
+        require!(
+            env::block_timestamp() >= stake.unlock_at.0,
+            "The stake is locked"
+        );
+
+        let removed = match stake.shares.0.checked_mul(amount) {
+            Some(value) => value / stake.amount.0,
+            None => stake.shares.0 / stake.amount.0 * amount,
+        };
+        let shares = stake.shares.0 - removed;
+        self.set_shares(account_id, &mut stake, shares);
+        stake.amount = (stake.amount.0 - amount).into();
+        stake.unstaking = (stake.unstaking.0 + amount).into();
+        stake.cooldown_end = env::block_timestamp().saturating_add(self.cooldown).into();
+        self.total_staked -= amount;
+
+        self.stakes.insert(account_id, &stake);
+        stake
+    }
+
+    /// Takes the unstaked amount once the cooldown is over, returns it with its price.
+    pub fn internal_withdraw(&mut self, account_id: &AccountId) -> (Balance, Balance) {
+        let mut stake = self.assert_stake(account_id);
+        require!(stake.unstaking.0 > 0, "Nothing to withdraw");
+        require!(
+            env::block_timestamp() >= stake.cooldown_end.0,
+            "The cooldown is not over"
+        );
+        let amount = stake.unstaking.0;
+        let price = stake.price.0;
+        stake.unstaking = 0.into();
+        if stake.amount.0 == 0 && self.rewards.accrued(account_id, 0) == 0 {
+            self.stakes.remove(account_id);
+        } else {
+            self.stakes.insert(account_id, &stake);
+        }
+        (amount, price)
+    }
+
+    /// Distributes the amount per reward share, within the rewards cap of the epoch.
+    pub fn distribute(&mut self, amount: Balance) {
+        let epoch = env::epoch_height();
+        let (last_epoch, distributed) = self.epoch_rewards;
+        let distributed = if last_epoch == epoch { distributed } else { 0 }.saturating_add(amount);
+        require!(
+            distributed <= self.max_epoch_rewards,
+            "Amount exceeds the staking rewards cap of the epoch"
+        );
+        self.rewards.distribute(amount, self.total_shares);
+        self.epoch_rewards = (epoch, distributed);
+    }
+
+    pub fn accrued(&self, account_id: &AccountId) -> Balance {
+        let shares = self.stake_of(account_id).map_or(0, |stake| stake.shares.0);
+        self.rewards.accrued(account_id, shares)
+    }
+
+    pub fn claim(&mut self, account_id: &AccountId) -> Balance {
+        let shares = self.stake_of(account_id).map_or(0, |stake| stake.shares.0);
+        self.rewards.claim(account_id, shares)
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Sets the lock periods, the unstaking cooldown in nanoseconds and the KT which can be
+    /// distributed to the stakers per epoch.
+    pub fn set_staking_config(
+        &mut self,
+        lock_periods: Vec<LockPeriod>,
+        cooldown: U64,
+        max_epoch_rewards: U128,
+    ) {
+        self.assert_owner();
+        self.staking
+            .set_config(lock_periods, cooldown.0, max_epoch_rewards.0);
+    }
+
+    /// Takes the amount out of the withdrawable treasury fees of the asset and distributes
+    /// its KT value at the cached price to the stakers, which must not be older than the
+    /// max price age of the asset. The fees stay in the treasury to back the rewards
+    /// minted on claim. Returns the distributed KT amount.
+    pub fn distribute_staking_rewards(&mut self, asset_id: AssetId, amount: U128) -> U128 {
+        self.assert_owner();
+        let asset = self.treasury.assert_asset(&asset_id);
+        require!(!asset.is_price_stale(), "The cached asset price is stale");
+        let kt_amount = asset
+            .price
+            .and_then(|cached| exchange_asset_to_kt(amount.0, asset.decimals, cached.price))
+            .unwrap_or_else(|| env::panic_str("The asset price is not cached"));
+        self.fees.record_withdrawn(&asset_id, amount.0);
+        self.staking.distribute(kt_amount);
+
+        KtEvent::StakingRewardsDistributed {
+            asset_id: &asset_id,
+            amount: &amount,
+            kt_amount: &kt_amount.into(),
+        }
+        .emit();
+        kt_amount.into()
+    }
+
+    /// Locks KT of the caller for the lock period at the index.
+    #[payable]
+    pub fn stake(&mut self, amount: U128, lock_period: u8) -> Stake {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        self.compliance.assert_not_frozen(&account_id);
+        self.operations.assert_no_pending_sell(&account_id);
+        let price = self.token.cost_basis_of(&account_id);
+        self.token.internal_transfer_at(
+            &account_id,
+            &env::current_account_id(),
+            amount.into(),
+            price,
+            Some("stake".to_string()),
+        );
+        let stake =
+            self.staking
+                .internal_stake(&account_id, amount.into(), price, lock_period.into());
+
+        KtEvent::Staked {
+            account_id: &account_id,
+            amount: &amount,
+            unlock_at: &stake.unlock_at,
+        }
+        .emit();
+        stake
+    }
+
+    /// Unstakes the unlocked KT, which can be withdrawn after the cooldown.
+    #[payable]
+    pub fn unstake(&mut self, amount: U128) -> Stake {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let stake = self.staking.internal_unstake(&account_id, amount.into());
+
+        KtEvent::Unstaked {
+            account_id: &account_id,
+            amount: &amount,
+            cooldown_end: &stake.cooldown_end,
+        }
+        .emit();
+        stake
+    }
+
+    /// Returns the unstaked KT to the caller once the cooldown is over.
+    #[payable]
+    pub fn withdraw_stake(&mut self) -> U128 {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        self.compliance.assert_not_frozen(&account_id);
+        let (amount, price) = self.staking.internal_withdraw(&account_id);
+        self.token.internal_transfer_at(
+            &env::current_account_id(),
+            &account_id,
+            amount,
+            price,
+            Some("unstake".to_string()),
+        );
+        amount.into()
+    }
+
+    /// Mints the accrued staking rewards to the caller.
+    pub fn claim_staking_rewards(&mut self) -> U128 {
+        let account_id = env::predecessor_account_id();
+        let amount = self.staking.claim(&account_id);
+        require!(amount > 0, "No rewards to claim");
+
+        // Rewards have no cost basis
+        self.token.internal_deposit(&account_id, amount, 0);
+        FtMint {
+            owner_id: &account_id,
+            amount: &amount.into(),
+            memo: Some("staking rewards"),
+        }
+        .emit();
+
+        amount.into()
+    }
+
+    pub fn get_stake(&self, account_id: AccountId) -> Option<StakeView> {
+        self.staking.stake_of(&account_id).map(|stake| StakeView {
+            stake,
+            rewards: self.staking.accrued(&account_id).into(),
+        })
+    }
+
+    pub fn get_staking_info(&self) -> StakingInfo {
+        self.staking.info()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_contract_standards::fungible_token::core::FungibleTokenCore;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, ONE_YOCTO};
+
+    use crate::oracle::ExchangePrice;
+    use crate::staking::LockPeriod;
+    use crate::Contract;
+
+    fn setup_contract(context: &mut VMContextBuilder) -> Contract {
+        testing_env!(context
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(0))
+            .build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.set_staking_config(
+            vec![
+                LockPeriod {
+                    duration: 0.into(),
+                    multiplier: 10_000,
+                },
+                LockPeriod {
+                    duration: 1_000.into(),
+                    multiplier: 20_000,
+                },
+            ],
+            100.into(),
+            400_000_000_000_000.into(),
+        );
+        contract.internal_add_asset(&accounts(3), 6);
+        contract
+            .treasury
+            .set_asset_price(&accounts(3), ExchangePrice::new(10000, 10));
+        contract
+            .fees
+            .record_collected(&accounts(3), (1_000, 0, 0, 0), 0);
+        contract
+            .token
+            .internal_deposit(&accounts(1), 1_000, 1_000_000);
+        contract
+            .token
+            .internal_deposit(&accounts(2), 1_000, 2_000_000);
+        testing_env!(context.attached_deposit(ONE_YOCTO).build());
+        contract
+    }
+
+    #[test]
+    fn test_stake_rewards() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.stake(500.into(), 1);
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.stake(1_000.into(), 0);
+        assert_eq!(contract.ft_balance_of(accounts(0)).0, 1_500);
+        assert_eq!(contract.get_staking_info().total_shares.0, 2_000);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let kt_amount = contract.distribute_staking_rewards(accounts(3), 400.into());
+        assert_eq!(kt_amount.0, 400_000_000_000_000);
+        assert_eq!(contract.fees.balance_of(&accounts(3)).withdrawable.0, 600);
+        assert_eq!(
+            contract.get_stake(accounts(1)).unwrap().rewards.0,
+            200_000_000_000_000
+        );
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        assert_eq!(contract.claim_staking_rewards().0, 200_000_000_000_000);
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 200_000_000_000_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "Amount exceeds the staking rewards cap of the epoch")]
+    fn test_staking_rewards_epoch_cap() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.stake(1_000.into(), 0);
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.distribute_staking_rewards(accounts(3), 300.into());
+        contract.distribute_staking_rewards(accounts(3), 300.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "The cached asset price is stale")]
+    fn test_staking_rewards_stale_price() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.stake(1_000.into(), 0);
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.set_asset_max_price_age(&accounts(3), Some(1_000.into()));
+        testing_env!(context.block_timestamp(1_001).build());
+        contract.distribute_staking_rewards(accounts(3), 300.into());
+    }
+
+    #[test]
+    fn test_unstake_cooldown() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.stake(1_000.into(), 1);
+        testing_env!(context.block_timestamp(1_000).build());
+        let stake = contract.unstake(1_000.into());
+        assert_eq!(stake.shares.0, 0);
+        assert_eq!(stake.cooldown_end.0, 1_100);
+
+        testing_env!(context.block_timestamp(1_100).build());
+        assert_eq!(contract.withdraw_stake().0, 1_000);
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 1_000);
+        assert_eq!(contract.ft_balance_detail(accounts(2)).price.0, 2_000_000);
+        assert!(contract.get_stake(accounts(2)).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "The stake is locked")]
+    fn test_unstake_locked() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.stake(1_000.into(), 1);
+        testing_env!(context.block_timestamp(999).build());
+        contract.unstake(1_000.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "The cooldown is not over")]
+    fn test_withdraw_stake_cooldown() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.stake(1_000.into(), 0);
+        contract.unstake(1_000.into());
+        contract.withdraw_stake();
+    }
+}
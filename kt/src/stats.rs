@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+
+use near_contract_standards::fungible_token::core::FungibleTokenCore;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::UnorderedMap;
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{near_bindgen, Balance, IntoStorageKey};
+use schemars::JsonSchema;
+
+use crate::treasury::AssetId;
+use crate::{Contract, ContractExt};
+
+#[derive(BorshDeserialize, BorshSerialize, Default, Clone, Copy)]
+pub struct AssetVolume {
+    /// Asset amount paid in by buys.
+    bought: Balance,
+    /// Asset amount paid out by sells.
+    sold: Balance,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct AssetVolumeView {
+    pub bought: U128,
+    pub sold: U128,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct StatsView {
+    pub total_minted: U128,
+    pub total_burned: U128,
+    pub buy_count: U64,
+    pub sell_count: U64,
+    pub volumes: HashMap<AssetId, AssetVolumeView>,
+    /// KT minted by bridges for the transfers from other chains.
+    pub bridge_minted: U128,
+    /// KT burned by bridges for the transfers to other chains.
+    pub bridge_burned: U128,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct SupplyView {
+    /// KT on this chain.
+    pub local_supply: U128,
+    /// KT moved to other chains and not returned, still backed by the treasury.
+    pub bridged_out: U128,
+    /// KT minted from other chains above the KT moved out, not backed by the treasury.
+    pub bridged_in: U128,
+    /// KT backed by the treasury on all chains.
+    pub backed_supply: U128,
+}
+
+/// Cumulative counters of buys and sells.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Stats {
+    total_minted: Balance,
+    total_burned: Balance,
+    buy_count: u64,
+    sell_count: u64,
+    /// AssetID -> Cumulative volume.
+    volumes: UnorderedMap<AssetId, AssetVolume>,
+    bridge_minted: Balance,
+    bridge_burned: Balance,
+}
+
+impl Stats {
+    pub fn new<S>(prefix: S) -> Self
+    where
+        S: IntoStorageKey,
+    {
+        Self {
+            total_minted: 0,
+            total_burned: 0,
+            buy_count: 0,
+            sell_count: 0,
+            volumes: UnorderedMap::new(prefix),
+            bridge_minted: 0,
+            bridge_burned: 0,
+        }
+    }
+
+    pub fn record_buy(&mut self, asset_id: &AssetId, asset_amount: Balance, kt_amount: Balance) {
+        self.total_minted = self.total_minted.saturating_add(kt_amount);
+        self.buy_count += 1;
+        let mut volume = self.volumes.get(asset_id).unwrap_or_default();
+        volume.bought = volume.bought.saturating_add(asset_amount);
+        self.volumes.insert(asset_id, &volume);
+    }
+
+    pub fn record_sell(&mut self, asset_id: &AssetId, asset_amount: Balance, kt_amount: Balance) {
+        self.total_burned = self.total_burned.saturating_add(kt_amount);
+        self.sell_count += 1;
+        let mut volume = self.volumes.get(asset_id).unwrap_or_default();
+        volume.sold = volume.sold.saturating_add(asset_amount);
+        self.volumes.insert(asset_id, &volume);
+    }
+
+    pub fn record_bridge_mint(&mut self, kt_amount: Balance) {
+        self.bridge_minted = self.bridge_minted.saturating_add(kt_amount);
+    }
+
+    pub fn record_bridge_burn(&mut self, kt_amount: Balance) {
+        self.bridge_burned = self.bridge_burned.saturating_add(kt_amount);
+    }
+
+    /// (bridged out, bridged in) net KT amounts, only one of them is positive.
+    pub fn bridged(&self) -> (Balance, Balance) {
+        (
+            self.bridge_burned.saturating_sub(self.bridge_minted),
+            self.bridge_minted.saturating_sub(self.bridge_burned),
+        )
+    }
+
+    pub fn to_view(&self) -> StatsView {
+        StatsView {
+            total_minted: self.total_minted.into(),
+            total_burned: self.total_burned.into(),
+            buy_count: self.buy_count.into(),
+            sell_count: self.sell_count.into(),
+            volumes: self
+                .volumes
+                .iter()
+                .map(|(asset_id, volume)| {
+                    (
+                        asset_id,
+                        AssetVolumeView {
+                            bought: volume.bought.into(),
+                            sold: volume.sold.into(),
+                        },
+                    )
+                })
+                .collect(),
+            bridge_minted: self.bridge_minted.into(),
+            bridge_burned: self.bridge_burned.into(),
+        }
+    }
+}
+
+impl Contract {
+    /// KT backed by the treasury on all chains: the local supply without the KT
+    /// bridged in, with the KT bridged out.
+    pub(crate) fn backed_supply(&self) -> Balance {
+        let (bridged_out, bridged_in) = self.stats.bridged();
+        self.token
+            .ft_total_supply()
+            .0
+            .saturating_sub(bridged_in)
+            .saturating_add(bridged_out)
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn get_stats(&self) -> StatsView {
+        self.stats.to_view()
+    }
+
+    pub fn get_supply(&self) -> SupplyView {
+        let (bridged_out, bridged_in) = self.stats.bridged();
+        SupplyView {
+            local_supply: self.token.ft_total_supply(),
+            bridged_out: bridged_out.into(),
+            bridged_in: bridged_in.into(),
+            backed_supply: self.backed_supply().into(),
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::accounts;
+
+    use crate::stats::Stats;
+    use crate::StorageKey;
+
+    #[test]
+    fn test_stats() {
+        let mut stats = Stats::new(StorageKey::Stats);
+        stats.record_buy(&accounts(1), 100, 1_000);
+        stats.record_buy(&accounts(2), 50, 500);
+        stats.record_sell(&accounts(1), 30, 300);
+
+        let view = stats.to_view();
+        assert_eq!(view.total_minted.0, 1_500);
+        assert_eq!(view.total_burned.0, 300);
+        assert_eq!(view.buy_count.0, 2);
+        assert_eq!(view.sell_count.0, 1);
+        assert_eq!(view.volumes[&accounts(1)].bought.0, 100);
+        assert_eq!(view.volumes[&accounts(1)].sold.0, 30);
+    }
+
+    #[test]
+    fn test_bridged() {
+        let mut stats = Stats::new(StorageKey::Stats);
+        stats.record_bridge_burn(1_000);
+        stats.record_bridge_mint(400);
+        assert_eq!(stats.bridged(), (600, 0));
+        stats.record_bridge_mint(900);
+        assert_eq!(stats.bridged(), (0, 300));
+    }
+}
@@ -0,0 +1,253 @@
+//! Storage fee charged in KT on the first mint to an account, so the buyers pay for
+//! the account record instead of the contract. A transfer registering the receiver burns
+//! the fee from the sender. Accounts registered ahead with a NEAR `storage_deposit`
+//! don't pay the fee. Other records, e.g. escrows, are paid for
+//! with a NEAR deposit returned once they are removed.
+
+use near_contract_standards::fungible_token::events::FtBurn;
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen, require, AccountId, Balance, Promise};
+use schemars::JsonSchema;
+
+use crate::events::KtEvent;
+use crate::oracle::ExchangePrice;
+use crate::price::exchange_kt_to_asset;
+use crate::treasury::AssetId;
+use crate::{Contract, ContractExt};
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct StorageUsage {
+    /// Number of the KT account records, the ones stored by the first release are
+    /// counted once moved.
+    pub accounts: U64,
+    /// Storage used by the contract, in bytes.
+    pub bytes: U64,
+    /// NEAR locked for the used storage.
+    pub cost: U128,
+    /// KT charged on the first mint to an account.
+    pub account_storage_fee: U128,
+}
+
+impl Contract {
+    /// Storage fee of a mint to the account, zero if the account is already registered.
+    pub(crate) fn storage_fee_of(&self, account_id: &AccountId) -> Balance {
+        if self.token.is_registered(account_id) {
+            0
+        } else {
+            self.account_storage_fee
+        }
+    }
+
+    /// Deducts the storage fee from the KT minted to a new account and collects its asset
+    /// value as a protocol fee. Returns the KT amount left to mint.
+    pub(crate) fn internal_charge_storage_fee(
+        &mut self,
+        account_id: &AccountId,
+        asset_id: &AssetId,
+        kt_amount: Balance,
+        price: ExchangePrice,
+    ) -> Balance {
+        let fee = self.storage_fee_of(account_id);
+        if fee == 0 {
+            return kt_amount;
+        }
+        require!(
+            kt_amount > fee,
+            format!("Buy amount doesn't cover the storage fee of {}", fee)
+        );
+        let decimals = self.treasury.assert_asset(asset_id).decimals;
+        let asset_fee = exchange_kt_to_asset(fee, decimals, price)
+            .unwrap_or_else(|| env::panic_str("Exchange amount overflow"));
+        self.internal_split_fee(asset_id, asset_fee, 0, price);
+
+        KtEvent::StorageFeeCharged {
+            account_id,
+            amount: &fee.into(),
+        }
+        .emit();
+        kt_amount - fee
+    }
+
+    /// Burns the storage fee of the account registered by the credit from the payer,
+    /// e.g. the sender of a transfer to a new account.
+    pub(crate) fn internal_burn_storage_fee(
+        &mut self,
+        payer_id: &AccountId,
+        account_id: &AccountId,
+        fee: Balance,
+    ) {
+        if fee == 0 {
+            return;
+        }
+        let price = self.token.cost_basis_of(payer_id);
+        self.token.internal_withdraw(payer_id, fee, price);
+        FtBurn {
+            owner_id: payer_id,
+            amount: &fee.into(),
+            memo: Some("storage fee"),
+        }
+        .emit();
+        KtEvent::StorageFeeCharged {
+            account_id,
+            amount: &fee.into(),
+        }
+        .emit();
+    }
+
+    /// Registers the account ahead of a later credit, e.g. the receiver of an escrow,
+    /// burning its storage fee from the payer.
+    pub(crate) fn internal_register_for(&mut self, payer_id: &AccountId, account_id: &AccountId) {
+        if self.token.is_registered(account_id) {
+            return;
+        }
+        self.internal_burn_storage_fee(payer_id, account_id, self.account_storage_fee);
+        self.token.internal_register(account_id);
+    }
+
+    /// Takes the NEAR for the storage used above `initial_usage` out of the attached
+    /// deposit and refunds the rest to the caller. Returns the NEAR taken.
+    pub(crate) fn internal_charge_storage(&self, initial_usage: u64) -> Balance {
+        let used = env::storage_usage().saturating_sub(initial_usage);
+        let cost = Balance::from(used) * env::storage_byte_cost();
+        let deposit = env::attached_deposit();
+        require!(
+            deposit >= cost.max(1),
+            format!(
+                "Attached deposit doesn't cover the storage cost of {}",
+                cost
+            )
+        );
+        if deposit > cost {
+            Promise::new(env::predecessor_account_id()).transfer(deposit - cost);
+        }
+        cost
+    }
+
+    /// Returns the storage deposit of a removed record.
+    pub(crate) fn internal_refund_storage(&self, account_id: &AccountId, deposit: Balance) {
+        if deposit > 0 {
+            Promise::new(account_id.clone()).transfer(deposit);
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Sets the KT deducted from the first mint to an account, zero disables the fee.
+    pub fn set_account_storage_fee(&mut self, fee: U128) {
+        self.assert_owner();
+        self.account_storage_fee = fee.0;
+    }
+
+    pub fn get_account_storage_fee(&self) -> U128 {
+        self.account_storage_fee.into()
+    }
+
+    pub fn get_storage_usage(&self) -> StorageUsage {
+        let bytes = env::storage_usage();
+        StorageUsage {
+            accounts: self.token.account_count().into(),
+            bytes: bytes.into(),
+            cost: (Balance::from(bytes) * env::storage_byte_cost()).into(),
+            account_storage_fee: self.account_storage_fee.into(),
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_contract_standards::fungible_token::core::FungibleTokenCore;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    use crate::oracle::ExchangePrice;
+    use crate::{BuyOptions, Contract};
+
+    const FEE: u128 = 10_000_000_000_000_000;
+
+    fn setup_contract() -> Contract {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(3), 6);
+        contract.set_account_storage_fee(FEE.into());
+        contract
+    }
+
+    #[test]
+    fn test_storage_fee() {
+        let mut contract = setup_contract();
+        let price = ExchangePrice::new(10000, 10);
+        let (_, minted) = contract.internal_buy(
+            &accounts(1),
+            &accounts(3),
+            1_000_000,
+            6,
+            price,
+            &BuyOptions::default(),
+        );
+        assert_eq!(minted, 1_000_000_000_000_000_000 - FEE);
+        assert_eq!(contract.get_storage_usage().accounts.0, 1);
+
+        // The registered account pays the fee only once.
+        let (_, minted) = contract.internal_buy(
+            &accounts(1),
+            &accounts(3),
+            1_000_000,
+            6,
+            price,
+            &BuyOptions::default(),
+        );
+        assert_eq!(minted, 1_000_000_000_000_000_000);
+        assert_eq!(
+            contract.ft_balance_of(accounts(1)).0,
+            2_000_000_000_000_000_000 - FEE
+        );
+    }
+
+    #[test]
+    fn test_storage_fee_on_transfer() {
+        let mut contract = setup_contract();
+        let price = ExchangePrice::new(10000, 10);
+        contract.internal_buy(
+            &accounts(1),
+            &accounts(3),
+            1_000_000,
+            6,
+            price,
+            &BuyOptions::default(),
+        );
+        let supply = contract.ft_total_supply().0;
+
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.ft_transfer(accounts(2), 100.into(), None);
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 100);
+        assert_eq!(contract.ft_total_supply().0, supply - FEE);
+        assert_eq!(contract.get_storage_usage().accounts.0, 2);
+
+        // The registered receiver is free to transfer to.
+        contract.ft_transfer(accounts(2), 100.into(), None);
+        assert_eq!(contract.ft_total_supply().0, supply - FEE);
+    }
+
+    #[test]
+    #[should_panic(expected = "Buy amount doesn't cover the storage fee of 10000000000000000")]
+    fn test_storage_fee_not_covered() {
+        let mut contract = setup_contract();
+        let price = ExchangePrice::new(10000, 10);
+        contract.internal_buy(
+            &accounts(1),
+            &accounts(3),
+            10_000,
+            6,
+            price,
+            &BuyOptions::default(),
+        );
+    }
+}
@@ -0,0 +1,353 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, ext_contract, log, near_bindgen, AccountId, Balance, Promise, ONE_YOCTO};
+use schemars::JsonSchema;
+
+use crate::burrow::Burrow;
+use crate::events::KtEvent;
+use crate::gas::GasConfig;
+use crate::roles::Role;
+use crate::treasury::AssetId;
+use crate::{ext_ft_transfer, promise_result_u128, Contract, ContractExt};
+
+/// Yield strategy for idle treasury funds.
+/// Every promise resolves to a `U128` amount.
+pub trait Strategy {
+    /// Deposits the funds into the strategy, resolves to the used amount.
+    fn deposit(&self, gas: &GasConfig, asset_id: &AssetId, amount: Balance) -> Promise;
+    /// Withdraws the funds back to the treasury, resolves to the withdrawn amount.
+    fn withdraw(&self, gas: &GasConfig, asset_id: &AssetId, amount: Balance) -> Promise;
+    /// Withdraws the accrued yield, resolves to the harvested amount.
+    fn harvest(&self, gas: &GasConfig, asset_id: &AssetId) -> Promise;
+    /// Resolves to the current value of the deployed funds, including the yield.
+    fn report(&self, gas: &GasConfig, asset_id: &AssetId) -> Promise;
+}
+
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Eq, JsonSchema,
+)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub enum StrategyKind {
+    /// Contract implementing the [`StrategyContract`] interface.
+    External { account_id: AccountId },
+    /// Supplies the funds to Burrow lending.
+    Burrow {
+        account_id: AccountId,
+        extra_decimals: u8,
+    },
+}
+
+impl StrategyKind {
+    fn burrow(account_id: &AccountId, extra_decimals: u8) -> Burrow<'_> {
+        Burrow {
+            account_id,
+            extra_decimals,
+        }
+    }
+}
+
+impl Strategy for StrategyKind {
+    fn deposit(&self, gas: &GasConfig, asset_id: &AssetId, amount: Balance) -> Promise {
+        match self {
+            StrategyKind::External { account_id } => ext_ft_transfer::ext(asset_id.clone())
+                .with_static_gas(gas.strategy_deposit)
+                .with_attached_deposit(ONE_YOCTO)
+                .ft_transfer_call(account_id.clone(), amount.into(), None, String::new()),
+            StrategyKind::Burrow {
+                account_id,
+                extra_decimals,
+            } => Self::burrow(account_id, *extra_decimals).deposit(gas, asset_id, amount),
+        }
+    }
+
+    fn withdraw(&self, gas: &GasConfig, asset_id: &AssetId, amount: Balance) -> Promise {
+        match self {
+            StrategyKind::External { account_id } => ext_strategy::ext(account_id.clone())
+                .with_static_gas(gas.strategy_withdraw)
+                .with_attached_deposit(ONE_YOCTO)
+                .withdraw(asset_id.clone(), amount.into()),
+            StrategyKind::Burrow {
+                account_id,
+                extra_decimals,
+            } => Self::burrow(account_id, *extra_decimals).withdraw(gas, asset_id, amount),
+        }
+    }
+
+    fn harvest(&self, gas: &GasConfig, asset_id: &AssetId) -> Promise {
+        match self {
+            StrategyKind::External { account_id } => ext_strategy::ext(account_id.clone())
+                .with_static_gas(gas.strategy_harvest)
+                .with_attached_deposit(ONE_YOCTO)
+                .harvest(asset_id.clone()),
+            StrategyKind::Burrow {
+                account_id,
+                extra_decimals,
+            } => Self::burrow(account_id, *extra_decimals).harvest(gas, asset_id),
+        }
+    }
+
+    fn report(&self, gas: &GasConfig, asset_id: &AssetId) -> Promise {
+        match self {
+            StrategyKind::External { account_id } => ext_strategy::ext(account_id.clone())
+                .with_static_gas(gas.strategy_report)
+                .report(asset_id.clone()),
+            StrategyKind::Burrow {
+                account_id,
+                extra_decimals,
+            } => Self::burrow(account_id, *extra_decimals).report(gas, asset_id),
+        }
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct StrategyInfo {
+    pub kind: StrategyKind,
+    /// Last reported value of the deployed funds, including the yield.
+    pub reported: Option<Balance>,
+    pub reported_at: U64,
+    /// Total yield harvested into the treasury.
+    pub harvested: Balance,
+}
+
+impl StrategyInfo {
+    pub fn new(kind: StrategyKind) -> Self {
+        Self {
+            kind,
+            reported: None,
+            reported_at: U64(0),
+            harvested: 0,
+        }
+    }
+}
+
+/// Interface of external strategy contracts, funds are deposited with `ft_transfer_call`.
+#[ext_contract(ext_strategy)]
+#[allow(dead_code)]
+pub trait StrategyContract {
+    /// Transfers the funds back to the caller and returns the transferred amount.
+    fn withdraw(&mut self, token_id: AccountId, amount: U128) -> U128;
+    /// Transfers the accrued yield to the caller and returns its amount.
+    fn harvest(&mut self, token_id: AccountId) -> U128;
+    fn report(&self, token_id: AccountId) -> U128;
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Sets the yield strategy of the asset and the share of the principal it can use,
+    /// in basis points. The strategy can only be replaced when no funds are deployed.
+    pub fn set_asset_strategy(
+        &mut self,
+        asset_id: AssetId,
+        strategy: Option<StrategyKind>,
+        max_deployed: u16,
+    ) {
+        self.assert_owner();
+        self.treasury
+            .set_asset_strategy(&asset_id, strategy, max_deployed);
+    }
+
+    pub fn strategy_deposit(&mut self, asset_id: AssetId, amount: U128) -> Promise {
+        self.assert_owner();
+        let strategy = self.treasury.assert_strategy(&asset_id);
+        self.treasury.internal_deploy(&asset_id, amount.into());
+
+        strategy.deposit(&self.gas, &asset_id, amount.into()).then(
+            ext_strategy_resolver::ext(env::current_account_id())
+                .with_static_gas(self.gas.resolve_strategy)
+                .resolve_strategy_deposit(asset_id, amount),
+        )
+    }
+
+    pub fn strategy_withdraw(&mut self, asset_id: AssetId, amount: U128) -> Promise {
+        self.assert_owner();
+        let strategy = self.treasury.assert_strategy(&asset_id);
+
+        strategy.withdraw(&self.gas, &asset_id, amount.into()).then(
+            ext_strategy_resolver::ext(env::current_account_id())
+                .with_static_gas(self.gas.resolve_strategy)
+                .resolve_strategy_withdraw(asset_id, amount),
+        )
+    }
+
+    pub fn strategy_harvest(&mut self, asset_id: AssetId) -> Promise {
+        self.assert_owner();
+        let strategy = self.treasury.assert_strategy(&asset_id);
+
+        strategy.harvest(&self.gas, &asset_id).then(
+            ext_strategy_resolver::ext(env::current_account_id())
+                .with_static_gas(self.gas.resolve_strategy)
+                .resolve_strategy_harvest(asset_id),
+        )
+    }
+
+    pub fn strategy_report(&mut self, asset_id: AssetId) -> Promise {
+        self.assert_owner_or_role(Role::Keeper);
+        let strategy = self.treasury.assert_strategy(&asset_id);
+
+        strategy.report(&self.gas, &asset_id).then(
+            ext_strategy_resolver::ext(env::current_account_id())
+                .with_static_gas(self.gas.resolve_strategy)
+                .resolve_strategy_report(asset_id),
+        )
+    }
+}
+
+#[ext_contract(ext_strategy_resolver)]
+#[allow(dead_code)]
+pub trait StrategyResolver {
+    fn resolve_strategy_deposit(&mut self, asset_id: AssetId, amount: U128) -> U128;
+    fn resolve_strategy_withdraw(&mut self, asset_id: AssetId, amount: U128) -> U128;
+    fn resolve_strategy_harvest(&mut self, asset_id: AssetId) -> U128;
+    fn resolve_strategy_report(&mut self, asset_id: AssetId) -> Option<U128>;
+}
+
+#[near_bindgen]
+impl StrategyResolver for Contract {
+    /// Returns the amount deployed to the strategy, the rest is returned to the treasury.
+    #[private]
+    fn resolve_strategy_deposit(&mut self, asset_id: AssetId, amount: U128) -> U128 {
+        let used_amount = promise_result_u128().unwrap_or(0).min(amount.0);
+        let unused_amount = amount.0 - used_amount;
+        if unused_amount > 0 {
+            self.treasury.internal_recall(&asset_id, unused_amount);
+        }
+        used_amount.into()
+    }
+
+    /// Returns the amount withdrawn back to the treasury, as reported by the strategy.
+    #[private]
+    fn resolve_strategy_withdraw(&mut self, asset_id: AssetId, amount: U128) -> U128 {
+        let withdrawn = promise_result_u128().map_or(0, |withdrawn| withdrawn.min(amount.0));
+        if withdrawn > 0 {
+            self.treasury.internal_recall(&asset_id, withdrawn);
+        }
+        if withdrawn < amount.0 {
+            log!(
+                "Strategy withdrawal of {} {} failed",
+                amount.0 - withdrawn,
+                asset_id
+            );
+        }
+        withdrawn.into()
+    }
+
+    #[private]
+    fn resolve_strategy_harvest(&mut self, asset_id: AssetId) -> U128 {
+        match promise_result_u128() {
+            Some(amount) if amount > 0 => {
+                self.treasury.internal_harvest(&asset_id, amount);
+                KtEvent::StrategyHarvest {
+                    asset_id: &asset_id,
+                    amount: &amount.into(),
+                }
+                .emit();
+                amount.into()
+            }
+            Some(_) => U128(0),
+            None => {
+                log!("Strategy harvest of {} failed", asset_id);
+                U128(0)
+            }
+        }
+    }
+
+    #[private]
+    fn resolve_strategy_report(&mut self, asset_id: AssetId) -> Option<U128> {
+        let value = promise_result_u128()?;
+        self.treasury.set_strategy_report(&asset_id, value);
+        Some(value.into())
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, PromiseResult, RuntimeFeesConfig, VMConfig};
+
+    use crate::strategy::{StrategyKind, StrategyResolver};
+    use crate::Contract;
+
+    fn setup_contract(context: &mut VMContextBuilder) -> Contract {
+        context
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(1), 6);
+        contract.set_asset_strategy(
+            accounts(1),
+            Some(StrategyKind::External {
+                account_id: accounts(5),
+            }),
+            5_000,
+        );
+        contract.treasury.internal_deposit(&accounts(1), 1_000_000);
+        contract
+    }
+
+    #[test]
+    fn test_strategy_deposit() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        contract.strategy_deposit(accounts(1), 500_000.into());
+
+        let asset = contract.treasury.assert_asset(&accounts(1));
+        assert_eq!(asset.balance, 500_000);
+        assert_eq!(asset.deployed, 500_000);
+    }
+
+    #[test]
+    fn test_resolve_strategy_deposit_refund() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        contract.strategy_deposit(accounts(1), 500_000.into());
+
+        testing_env!(
+            context.build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(b"\"200000\"".to_vec())],
+        );
+        let used = contract.resolve_strategy_deposit(accounts(1), 500_000.into());
+        assert_eq!(used.0, 200_000);
+
+        let asset = contract.treasury.assert_asset(&accounts(1));
+        assert_eq!(asset.balance, 800_000);
+        assert_eq!(asset.deployed, 200_000);
+    }
+
+    #[test]
+    fn test_resolve_strategy_withdraw_partial() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        contract.strategy_deposit(accounts(1), 500_000.into());
+
+        testing_env!(
+            context.build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(b"\"300000\"".to_vec())],
+        );
+        let withdrawn = contract.resolve_strategy_withdraw(accounts(1), 400_000.into());
+        assert_eq!(withdrawn.0, 300_000);
+
+        let asset = contract.treasury.assert_asset(&accounts(1));
+        assert_eq!(asset.balance, 800_000);
+        assert_eq!(asset.deployed, 200_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "Asset charlie has no strategy")]
+    fn test_strategy_deposit_without_strategy() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        contract.internal_add_asset(&accounts(2), 6);
+        contract.strategy_deposit(accounts(2), 1.into());
+    }
+}
@@ -0,0 +1,322 @@
+//! KT streams paying the receiver continuously at a fixed rate.
+//!
+//! The sender locks the whole stream amount upfront, the receiver withdraws the streamed
+//! part anytime. Cancelling the stream pays out the streamed part and refunds the rest.
+//! The streamed part of a frozen receiver stays in the stream until it's unfrozen.
+//! The sender pays the storage of the stream, which is returned once it's paid out.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{assert_one_yocto, env, near_bindgen, require, AccountId, Balance, Timestamp};
+use schemars::JsonSchema;
+
+use crate::events::{KtEvent, RefundOperation, RefundReason};
+use crate::records::{Parties, RecordId, Records};
+use crate::{Contract, ContractExt, NANOS_PER_SECOND};
+
+pub type StreamId = RecordId;
+pub type Streams = Records<Stream>;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct Stream {
+    pub sender_id: AccountId,
+    pub receiver_id: AccountId,
+    /// KT streamed per second.
+    pub rate: U128,
+    /// Cost basis of the streamed KT.
+    pub cost_price: U128,
+    pub start_at: U64,
+    pub end_at: U64,
+    pub withdrawn: U128,
+    /// NEAR paid by the sender for the storage.
+    pub storage_deposit: U128,
+}
+
+impl Stream {
+    pub fn amount(&self) -> Balance {
+        self.streamed_at(self.end_at.0)
+    }
+
+    /// KT streamed by the time, including the withdrawn KT.
+    pub fn streamed_at(&self, timestamp: Timestamp) -> Balance {
+        let elapsed = timestamp.clamp(self.start_at.0, self.end_at.0) - self.start_at.0;
+        self.rate.0 * u128::from(elapsed / NANOS_PER_SECOND)
+    }
+
+    /// Streamed KT the receiver can withdraw now.
+    pub fn withdrawable(&self) -> Balance {
+        self.streamed_at(env::block_timestamp()) - self.withdrawn.0
+    }
+}
+
+impl Parties for Stream {
+    fn parties(&self) -> [&AccountId; 2] {
+        [&self.sender_id, &self.receiver_id]
+    }
+}
+
+impl Streams {
+    pub fn assert_stream(&self, id: StreamId) -> Stream {
+        self.get(id)
+            .unwrap_or_else(|| env::panic_str(format!("Stream {} is not found", id).as_str()))
+    }
+}
+
+impl Contract {
+    /// Pays the withdrawable KT of the stream out to the receiver.
+    fn internal_withdraw_stream(&mut self, id: StreamId, stream: &mut Stream) -> Balance {
+        let amount = stream.withdrawable();
+        if amount == 0 {
+            return 0;
+        }
+        self.internal_before_transfer(&stream.sender_id, &stream.receiver_id, amount);
+        stream.withdrawn = (stream.withdrawn.0 + amount).into();
+        self.token.internal_transfer_at(
+            &env::current_account_id(),
+            &stream.receiver_id,
+            amount,
+            stream.cost_price.0,
+            Some("stream".to_string()),
+        );
+        KtEvent::StreamWithdrawn {
+            id,
+            receiver_id: &stream.receiver_id,
+            amount: &amount.into(),
+        }
+        .emit();
+        amount
+    }
+
+    /// Stores the stream, or removes it once it's paid out.
+    fn internal_save_stream(&mut self, id: StreamId, stream: &Stream) {
+        if stream.withdrawn.0 == stream.amount() {
+            self.streams.remove(id);
+            self.internal_refund_storage(&stream.sender_id, stream.storage_deposit.0);
+        } else {
+            self.streams.update(id, stream);
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Streams KT of the caller to the receiver at the rate per second for the duration
+    /// in seconds, locking `rate * duration` KT. The attached deposit pays the storage
+    /// of the stream, the rest is refunded.
+    #[payable]
+    pub fn create_stream(&mut self, receiver_id: AccountId, rate: U128, duration: U64) -> U64 {
+        let initial_usage = env::storage_usage();
+        require!(rate.0 > 0, "Stream rate should be positive");
+        require!(duration.0 > 0, "Stream duration should be positive");
+        let sender_id = env::predecessor_account_id();
+        require!(sender_id != receiver_id, "Stream receiver is the sender");
+        self.compliance.assert_not_frozen(&sender_id);
+        self.compliance.assert_not_frozen(&receiver_id);
+        self.operations.assert_no_pending_sell(&sender_id);
+
+        let start_at = env::block_timestamp();
+        let end_at = duration
+            .0
+            .checked_mul(NANOS_PER_SECOND)
+            .and_then(|duration| start_at.checked_add(duration))
+            .unwrap_or_else(|| env::panic_str("Stream duration overflow"));
+        self.internal_register_for(&sender_id, &receiver_id);
+        let cost_price = self.token.cost_basis_of(&sender_id);
+        let mut stream = Stream {
+            sender_id,
+            receiver_id,
+            rate,
+            cost_price: cost_price.into(),
+            start_at: start_at.into(),
+            end_at: end_at.into(),
+            withdrawn: 0.into(),
+            storage_deposit: 0.into(),
+        };
+        let amount = rate
+            .0
+            .checked_mul(u128::from(duration.0))
+            .unwrap_or_else(|| env::panic_str("Stream amount overflow"));
+        self.token.internal_transfer_at(
+            &stream.sender_id,
+            &env::current_account_id(),
+            amount,
+            cost_price,
+            Some("stream".to_string()),
+        );
+        let id = self.streams.push(&stream);
+        stream.storage_deposit = self.internal_charge_storage(initial_usage).into();
+        self.streams.update(id, &stream);
+        KtEvent::StreamCreated {
+            id,
+            sender_id: &stream.sender_id,
+            receiver_id: &stream.receiver_id,
+            amount: &amount.into(),
+            rate: &stream.rate,
+            end_at: &stream.end_at,
+        }
+        .emit();
+        id.into()
+    }
+
+    /// Pays the streamed KT out to the receiver, callable by anyone.
+    /// Returns the withdrawn amount.
+    pub fn withdraw_from_stream(&mut self, id: U64) -> U128 {
+        let mut stream = self.streams.assert_stream(id.0);
+        let amount = self.internal_withdraw_stream(id.0, &mut stream);
+        self.internal_save_stream(id.0, &stream);
+        amount.into()
+    }
+
+    /// Stops the stream, paying the streamed KT out to the receiver and refunding
+    /// the rest to the sender. The streamed KT of a frozen receiver is kept in the
+    /// stream. Callable by the sender or the receiver.
+    #[payable]
+    pub fn cancel_stream(&mut self, id: U64) {
+        assert_one_yocto();
+        let mut stream = self.streams.assert_stream(id.0);
+        let account_id = env::predecessor_account_id();
+        require!(
+            account_id == stream.sender_id || account_id == stream.receiver_id,
+            "Stream belongs to other accounts"
+        );
+        let amount = stream.amount();
+        let now = env::block_timestamp().clamp(stream.start_at.0, stream.end_at.0);
+        stream.end_at = now.into();
+        if !self.compliance.is_frozen(&stream.receiver_id) {
+            self.internal_withdraw_stream(id.0, &mut stream);
+        }
+        self.internal_save_stream(id.0, &stream);
+        let refund = amount - stream.amount();
+        if refund > 0 {
+            self.token.internal_transfer_at(
+                &env::current_account_id(),
+                &stream.sender_id,
+                refund,
+                stream.cost_price.0,
+                Some("stream refund".to_string()),
+            );
+            KtEvent::KtRefund {
+                operation: RefundOperation::Stream,
+                reason: RefundReason::Cancelled,
+                account_id: &stream.sender_id,
+                asset_id: None,
+                amount: Some(&refund.into()),
+                asset_amount: None,
+            }
+            .emit();
+        }
+        KtEvent::StreamCancelled {
+            id: id.0,
+            account_id: &account_id,
+        }
+        .emit();
+    }
+
+    pub fn get_stream(&self, id: U64) -> Option<Stream> {
+        self.streams.get(id.0)
+    }
+
+    /// Active streams the account sends or receives, in the creation order.
+    pub fn get_streams(
+        &self,
+        account_id: AccountId,
+        from_index: Option<U64>,
+        limit: Option<U64>,
+    ) -> Vec<(U64, Stream)> {
+        self.streams
+            .records_of(
+                &account_id,
+                from_index.map_or(0, |index| index.0),
+                limit.map_or(u64::MAX, |limit| limit.0),
+            )
+            .into_iter()
+            .map(|(id, stream)| (id.into(), stream))
+            .collect()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_contract_standards::fungible_token::core::FungibleTokenCore;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, ONE_YOCTO};
+
+    use crate::test_utils;
+    use crate::Contract;
+
+    const SECOND: u64 = 1_000_000_000;
+    const STORAGE_DEPOSIT: u128 = 10u128.pow(23);
+
+    fn setup_contract(context: &mut VMContextBuilder) -> Contract {
+        let mut contract = test_utils::setup_contract_with_kt(context);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(STORAGE_DEPOSIT)
+            .build());
+        contract.create_stream(accounts(2), 10.into(), 100.into());
+        testing_env!(context.attached_deposit(ONE_YOCTO).build());
+        contract
+    }
+
+    #[test]
+    fn test_withdraw_from_stream() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+
+        testing_env!(context.block_timestamp(30 * SECOND + 1).build());
+        assert_eq!(contract.withdraw_from_stream(0.into()).0, 300);
+        assert_eq!(contract.withdraw_from_stream(0.into()).0, 0);
+
+        testing_env!(context.block_timestamp(200 * SECOND).build());
+        assert_eq!(contract.withdraw_from_stream(0.into()).0, 700);
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 1_000);
+        assert!(contract.get_streams(accounts(1), None, None).is_empty());
+    }
+
+    #[test]
+    fn test_cancel_stream() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        let balance = contract.ft_balance_of(accounts(1)).0;
+
+        testing_env!(context.block_timestamp(40 * SECOND).build());
+        contract.cancel_stream(0.into());
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 400);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, balance + 600);
+        assert!(contract.get_stream(0.into()).is_none());
+    }
+
+    #[test]
+    fn test_cancel_stream_frozen_receiver() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        let balance = contract.ft_balance_of(accounts(1)).0;
+        contract.compliance.freeze(&accounts(2));
+
+        testing_env!(context.block_timestamp(40 * SECOND).build());
+        contract.cancel_stream(0.into());
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, balance + 600);
+        assert_eq!(contract.get_stream(0.into()).unwrap().amount(), 400);
+
+        contract.compliance.unfreeze(&accounts(2));
+        testing_env!(context.block_timestamp(100 * SECOND).build());
+        assert_eq!(contract.withdraw_from_stream(0.into()).0, 400);
+        assert!(contract.get_stream(0.into()).is_none());
+    }
+
+    #[test]
+    fn test_get_streams() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        testing_env!(context.attached_deposit(STORAGE_DEPOSIT).build());
+        contract.create_stream(accounts(3), 1.into(), 100.into());
+
+        let streams = contract.get_streams(accounts(1), Some(1.into()), Some(10.into()));
+        assert_eq!(streams.len(), 1);
+        assert_eq!(streams[0].0 .0, 1);
+        assert_eq!(contract.get_streams(accounts(2), None, None).len(), 1);
+    }
+}
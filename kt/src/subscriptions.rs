@@ -0,0 +1,254 @@
+//! Recurring pull payments.
+//!
+//! The subscriber authorizes the merchant to charge up to the amount of KT per period.
+//! Periods are counted from the subscription time, the unused part of a period is lost.
+//! The subscriber pays the storage of the subscription, which is returned on cancel.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{assert_one_yocto, env, near_bindgen, require, AccountId};
+use schemars::JsonSchema;
+
+use crate::events::KtEvent;
+use crate::records::{Parties, RecordId, Records};
+use crate::{Contract, ContractExt, NANOS_PER_SECOND};
+
+pub type SubscriptionId = RecordId;
+pub type Subscriptions = Records<Subscription>;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct Subscription {
+    pub account_id: AccountId,
+    pub merchant_id: AccountId,
+    /// Maximum KT charged per period.
+    pub amount: U128,
+    /// Period length in seconds.
+    pub period: U64,
+    pub created_at: U64,
+    /// Index of the last charged period.
+    pub charged_period: U64,
+    /// KT charged in the last charged period.
+    pub charged: U128,
+    /// NEAR paid by the subscriber for the storage.
+    pub storage_deposit: U128,
+}
+
+impl Subscription {
+    pub fn current_period(&self) -> u64 {
+        (env::block_timestamp() - self.created_at.0) / (self.period.0 * NANOS_PER_SECOND)
+    }
+
+    /// KT the merchant can charge in the current period.
+    pub fn chargeable(&self) -> u128 {
+        if self.current_period() == self.charged_period.0 {
+            self.amount.0 - self.charged.0
+        } else {
+            self.amount.0
+        }
+    }
+}
+
+impl Parties for Subscription {
+    fn parties(&self) -> [&AccountId; 2] {
+        [&self.account_id, &self.merchant_id]
+    }
+}
+
+impl Subscriptions {
+    pub fn assert_subscription(&self, id: SubscriptionId) -> Subscription {
+        self.get(id)
+            .unwrap_or_else(|| env::panic_str(format!("Subscription {} is not found", id).as_str()))
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Authorizes the merchant to charge up to the amount of KT of the caller
+    /// every period in seconds. The attached deposit pays the storage of the subscription,
+    /// the rest is refunded.
+    #[payable]
+    pub fn subscribe(&mut self, merchant_id: AccountId, amount: U128, period: U64) -> U64 {
+        let initial_usage = env::storage_usage();
+        require!(amount.0 > 0, "Subscription amount should be positive");
+        require!(period.0 > 0, "Subscription period should be positive");
+        require!(
+            period.0.checked_mul(NANOS_PER_SECOND).is_some(),
+            "Subscription period overflow"
+        );
+        let account_id = env::predecessor_account_id();
+        require!(account_id != merchant_id, "Merchant is the subscriber");
+        let mut subscription = Subscription {
+            account_id,
+            merchant_id,
+            amount,
+            period,
+            created_at: env::block_timestamp().into(),
+            charged_period: 0.into(),
+            charged: 0.into(),
+            storage_deposit: 0.into(),
+        };
+        let id = self.subscriptions.push(&subscription);
+        subscription.storage_deposit = self.internal_charge_storage(initial_usage).into();
+        self.subscriptions.update(id, &subscription);
+        KtEvent::Subscribed {
+            id,
+            account_id: &subscription.account_id,
+            merchant_id: &subscription.merchant_id,
+            amount: &subscription.amount,
+            period: &subscription.period,
+        }
+        .emit();
+        id.into()
+    }
+
+    /// Charges the subscriber, by default the whole amount left in the current period.
+    /// Callable by the merchant, returns the charged amount.
+    #[payable]
+    pub fn charge(&mut self, subscription_id: U64, amount: Option<U128>) -> U128 {
+        assert_one_yocto();
+        let mut subscription = self.subscriptions.assert_subscription(subscription_id.0);
+        require!(
+            subscription.merchant_id == env::predecessor_account_id(),
+            "Only the merchant can charge the subscription"
+        );
+        let chargeable = subscription.chargeable();
+        let amount = amount.map_or(chargeable, |amount| amount.0);
+        require!(amount > 0, "Nothing to charge");
+        require!(
+            amount <= chargeable,
+            "Amount exceeds the subscription limit of the period"
+        );
+
+        let period = subscription.current_period();
+        if period != subscription.charged_period.0 {
+            subscription.charged_period = period.into();
+            subscription.charged = 0.into();
+        }
+        subscription.charged = (subscription.charged.0 + amount).into();
+        self.subscriptions.update(subscription_id.0, &subscription);
+
+        self.internal_before_transfer(&subscription.account_id, &subscription.merchant_id, amount);
+        self.token.internal_transfer(
+            &subscription.account_id,
+            &subscription.merchant_id,
+            amount,
+            Some(format!("subscription {}", subscription_id.0)),
+        );
+        KtEvent::SubscriptionCharged {
+            id: subscription_id.0,
+            account_id: &subscription.account_id,
+            amount: &amount.into(),
+        }
+        .emit();
+        amount.into()
+    }
+
+    /// Ends the subscription, callable by the subscriber or the merchant.
+    #[payable]
+    pub fn cancel_subscription(&mut self, subscription_id: U64) {
+        assert_one_yocto();
+        let subscription = self.subscriptions.assert_subscription(subscription_id.0);
+        let account_id = env::predecessor_account_id();
+        require!(
+            account_id == subscription.account_id || account_id == subscription.merchant_id,
+            "Subscription belongs to other accounts"
+        );
+        self.subscriptions.remove(subscription_id.0);
+        self.internal_refund_storage(&subscription.account_id, subscription.storage_deposit.0);
+        KtEvent::SubscriptionCancelled {
+            id: subscription_id.0,
+            account_id: &account_id,
+        }
+        .emit();
+    }
+
+    pub fn get_subscription(&self, subscription_id: U64) -> Option<Subscription> {
+        self.subscriptions.get(subscription_id.0)
+    }
+
+    /// Subscriptions of the account as the subscriber or the merchant, in the creation order.
+    pub fn get_subscriptions(
+        &self,
+        account_id: AccountId,
+        from_index: Option<U64>,
+        limit: Option<U64>,
+    ) -> Vec<(U64, Subscription)> {
+        self.subscriptions
+            .records_of(
+                &account_id,
+                from_index.map_or(0, |index| index.0),
+                limit.map_or(u64::MAX, |limit| limit.0),
+            )
+            .into_iter()
+            .map(|(id, subscription)| (id.into(), subscription))
+            .collect()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_contract_standards::fungible_token::core::FungibleTokenCore;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, ONE_YOCTO};
+
+    use crate::test_utils;
+    use crate::Contract;
+
+    const SECOND: u64 = 1_000_000_000;
+    const STORAGE_DEPOSIT: u128 = 10u128.pow(23);
+
+    fn setup_contract(context: &mut VMContextBuilder) -> Contract {
+        let mut contract = test_utils::setup_contract_with_kt(context);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(STORAGE_DEPOSIT)
+            .build());
+        contract.subscribe(accounts(2), 100.into(), 30.into());
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        contract
+    }
+
+    #[test]
+    fn test_charge() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        assert_eq!(contract.charge(0.into(), Some(40.into())).0, 40);
+        assert_eq!(contract.charge(0.into(), None).0, 60);
+
+        testing_env!(context.block_timestamp(30 * SECOND).build());
+        assert_eq!(contract.charge(0.into(), None).0, 100);
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 200);
+    }
+
+    #[test]
+    #[should_panic(expected = "Amount exceeds the subscription limit of the period")]
+    fn test_charge_exceeded() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        contract.charge(0.into(), Some(60.into()));
+        testing_env!(context.block_timestamp(29 * SECOND).build());
+        contract.charge(0.into(), Some(60.into()));
+    }
+
+    #[test]
+    fn test_cancel_subscription() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        assert_eq!(contract.get_subscriptions(accounts(1), None, None).len(), 1);
+        assert!(contract
+            .get_subscriptions(accounts(2), Some(1.into()), None)
+            .is_empty());
+
+        contract.cancel_subscription(0.into());
+        assert!(contract.get_subscription(0.into()).is_none());
+        assert!(contract
+            .get_subscriptions(accounts(2), None, None)
+            .is_empty());
+    }
+}
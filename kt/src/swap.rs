@@ -0,0 +1,366 @@
+//! Direct swaps between the treasury assets at the oracle prices, without minting or
+//! burning KT. The swap fee is collected as protocol revenue like the buy fee.
+
+use near_sdk::json_types::U128;
+use near_sdk::{
+    env, ext_contract, log, near_bindgen, require, AccountId, Balance, Promise, PromiseResult,
+    ONE_YOCTO,
+};
+
+use crate::events::{KtEvent, RefundOperation, RefundReason};
+use crate::fees::fee_of;
+use crate::oracle::{ExchangePrice, PriceData};
+use crate::price::{exchange_asset_to_asset, exchange_asset_to_kt};
+use crate::treasury::AssetId;
+use crate::{ext_ft_transfer, Contract, ContractExt, BASIS_POINTS};
+
+impl Contract {
+    /// Fetches the prices of both assets and swaps the deposit for `asset_out`.
+    pub(crate) fn start_swap(
+        &mut self,
+        account_id: AccountId,
+        asset_in: AssetId,
+        amount_in: U128,
+        asset_out: AssetId,
+        min_amount_out: Option<U128>,
+    ) -> Promise {
+        require!(env::prepaid_gas() > self.gas.swap(), "More gas is required");
+        require!(self.swap_fee.is_some(), "Swaps are disabled");
+        require!(asset_in != asset_out, "Swap assets should be different");
+        self.compliance.assert_not_frozen(&account_id);
+        self.allowlist.assert_allowed(&account_id);
+        self.kyc.assert_verified(&account_id);
+        self.treasury.assert_can_buy(&asset_in);
+        self.treasury.assert_can_sell(&asset_out);
+
+        self.get_price(&asset_in, None)
+            .and(self.get_price(&asset_out, None))
+            .then(
+                ext_swap_resolver::ext(env::current_account_id())
+                    .with_static_gas(self.gas.swap_with_prices())
+                    .swap_with_prices(account_id, asset_in, amount_in, asset_out, min_amount_out),
+            )
+    }
+
+    /// Moves `amount_in` into the treasury and takes out its value in `asset_out`, at the
+    /// buy price of `asset_in` and the sell price of `asset_out`. The swap counts as a buy
+    /// and a sell of its KT value for the limits. Returns the asset amount to pay out and
+    /// the fee, which is left in the treasury.
+    pub(crate) fn internal_swap(
+        &mut self,
+        account_id: &AccountId,
+        asset_in: &AssetId,
+        amount_in: Balance,
+        price_in: ExchangePrice,
+        asset_out: &AssetId,
+        price_out: ExchangePrice,
+    ) -> (Balance, Balance) {
+        let swap_fee = self
+            .swap_fee
+            .unwrap_or_else(|| env::panic_str("Swaps are disabled"));
+        let input = self.treasury.assert_can_buy(asset_in);
+        let output = self.treasury.assert_can_sell(asset_out);
+        input.assert_cap(amount_in);
+        input.assert_buy_amount(amount_in);
+        self.treasury.set_asset_price(asset_in, price_in);
+        self.treasury.set_asset_price(asset_out, price_out);
+
+        let buy_price = self.buy_price(asset_in, price_in);
+        let sell_price = self.sell_price(asset_out, price_out);
+        let kt_value = exchange_asset_to_kt(amount_in, input.decimals, buy_price)
+            .unwrap_or_else(|| env::panic_str("Exchange amount overflow"));
+        self.volume_limits.record_mint(account_id, kt_value);
+        self.volume_limits.record_burn(account_id, kt_value);
+
+        let value = exchange_asset_to_asset(
+            amount_in,
+            input.decimals,
+            buy_price,
+            output.decimals,
+            sell_price,
+        )
+        .unwrap_or_else(|| env::panic_str("Exchange amount overflow"));
+        output.assert_sell_amount(value);
+        let fee = fee_of(value, swap_fee);
+        let amount_out = value - fee;
+        require!(amount_out > 0, "Swap amount is too small");
+
+        let available = self.available_balance(asset_out, output.balance);
+        require!(
+            value <= available,
+            "The treasury doesn't have enough balance"
+        );
+        self.assert_sell_impact(asset_out, value, available);
+
+        self.treasury.internal_deposit(asset_in, amount_in);
+        self.treasury.internal_withdraw(asset_out, amount_out);
+        self.internal_split_fee(asset_out, fee, 0, price_out);
+        (amount_out, fee)
+    }
+}
+
+#[ext_contract(ext_swap_resolver)]
+#[allow(dead_code)]
+#[allow(clippy::too_many_arguments)]
+pub trait SwapResolver {
+    fn swap_with_prices(
+        &mut self,
+        account_id: AccountId,
+        asset_in: AssetId,
+        amount_in: U128,
+        asset_out: AssetId,
+        min_amount_out: Option<U128>,
+        #[callback_unwrap] price_in: PriceData,
+        #[callback_unwrap] price_out: PriceData,
+    ) -> Promise;
+    fn resolve_swap(
+        &mut self,
+        account_id: AccountId,
+        asset_in: AssetId,
+        amount_in: U128,
+        asset_out: AssetId,
+        amount_out: U128,
+        fee: U128,
+    ) -> U128;
+}
+
+#[near_bindgen]
+impl SwapResolver for Contract {
+    #[private]
+    #[allow(clippy::too_many_arguments)]
+    fn swap_with_prices(
+        &mut self,
+        account_id: AccountId,
+        asset_in: AssetId,
+        amount_in: U128,
+        asset_out: AssetId,
+        min_amount_out: Option<U128>,
+        #[callback_unwrap] price_in: PriceData,
+        #[callback_unwrap] price_out: PriceData,
+    ) -> Promise {
+        let price_in =
+            ExchangePrice::from_price_data(&self.treasury.assert_asset(&asset_in), price_in);
+        let price_out =
+            ExchangePrice::from_price_data(&self.treasury.assert_asset(&asset_out), price_out);
+
+        let (amount_out, fee) = self.internal_swap(
+            &account_id,
+            &asset_in,
+            amount_in.into(),
+            price_in,
+            &asset_out,
+            price_out,
+        );
+        if let Some(min_amount_out) = min_amount_out {
+            require!(
+                amount_out >= min_amount_out.0,
+                format!(
+                    "Swap amount {} is less than the expected {}",
+                    amount_out, min_amount_out.0
+                )
+            );
+        }
+
+        KtEvent::Swap {
+            account_id: &account_id,
+            asset_in: &asset_in,
+            amount_in: &amount_in,
+            asset_out: &asset_out,
+            amount_out: &amount_out.into(),
+        }
+        .emit();
+
+        self.internal_start_payout(&asset_out, amount_out);
+        ext_ft_transfer::ext(asset_out.clone())
+            .with_static_gas(self.gas.transfer)
+            .with_attached_deposit(ONE_YOCTO)
+            .ft_transfer(account_id.clone(), amount_out.into(), None)
+            .then(
+                ext_swap_resolver::ext(env::current_account_id())
+                    .with_static_gas(self.gas.resolve_swap)
+                    .resolve_swap(
+                        account_id,
+                        asset_in,
+                        amount_in,
+                        asset_out,
+                        amount_out.into(),
+                        fee.into(),
+                    ),
+            )
+    }
+
+    /// Returns the unused amount of `asset_in`, which is refunded when the payout failed.
+    /// The split of the fee is reverted along.
+    #[private]
+    fn resolve_swap(
+        &mut self,
+        account_id: AccountId,
+        asset_in: AssetId,
+        amount_in: U128,
+        asset_out: AssetId,
+        amount_out: U128,
+        fee: U128,
+    ) -> U128 {
+        self.internal_finish_payout(&asset_out, amount_out.0);
+        match env::promise_result(0) {
+            PromiseResult::NotReady => env::abort(),
+            PromiseResult::Successful(_) => U128(0),
+            PromiseResult::Failed => {
+                self.treasury.internal_withdraw(&asset_in, amount_in.into());
+                self.treasury
+                    .internal_deposit(&asset_out, amount_out.into());
+                self.internal_revert_fee(&asset_out, fee.0);
+                log!("Swap of @{} is refunded", account_id);
+                KtEvent::KtRefund {
+                    operation: RefundOperation::Swap,
+                    reason: RefundReason::PayoutFailed,
+                    account_id: &account_id,
+                    asset_id: Some(&asset_in),
+                    amount: None,
+                    asset_amount: Some(&amount_in),
+                }
+                .emit();
+                amount_in
+            }
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Sets the fee of the asset swaps in basis points, `None` disables the swaps.
+    pub fn set_swap_fee(&mut self, fee: Option<u16>) {
+        self.assert_owner();
+        if let Some(fee) = fee {
+            require!(fee < BASIS_POINTS, "Swap fee is out of bounds");
+        }
+        self.swap_fee = fee;
+    }
+
+    pub fn get_swap_fee(&self) -> Option<u16> {
+        self.swap_fee
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, PromiseResult, RuntimeFeesConfig, VMConfig};
+
+    use crate::fees::FeeSplit;
+    use crate::oracle::ExchangePrice;
+    use crate::swap::SwapResolver;
+    use crate::Contract;
+
+    fn setup_contract() -> Contract {
+        let mut context = VMContextBuilder::new();
+        context
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(1), 6);
+        contract.internal_add_asset(&accounts(2), 18);
+        contract
+            .treasury
+            .internal_deposit(&accounts(2), 3_000_000_000_000_000_000);
+        contract
+    }
+
+    #[test]
+    fn test_swap() {
+        let mut contract = setup_contract();
+        contract.set_swap_fee(Some(30));
+        let (amount_out, _) = contract.internal_swap(
+            &accounts(3),
+            &accounts(1),
+            1_000_000,
+            ExchangePrice::new(10000, 10),
+            &accounts(2),
+            ExchangePrice::new(10000, 22),
+        );
+        assert_eq!(amount_out, 997_000_000_000_000_000);
+        assert_eq!(
+            contract.treasury.assert_asset(&accounts(1)).balance,
+            1_000_000
+        );
+        let output = contract.treasury.assert_asset(&accounts(2));
+        assert_eq!(output.balance + output.insurance, 2_003_000_000_000_000_000);
+    }
+
+    #[test]
+    fn test_swap_spread() {
+        let mut contract = setup_contract();
+        contract.set_swap_fee(Some(0));
+        contract.set_asset_spread(&accounts(1), 100);
+        contract.set_asset_spread(&accounts(2), 100);
+        let (amount_out, _) = contract.internal_swap(
+            &accounts(3),
+            &accounts(1),
+            1_000_000,
+            ExchangePrice::new(10000, 10),
+            &accounts(2),
+            ExchangePrice::new(10000, 22),
+        );
+        assert!(amount_out < 990_000_000_000_000_000);
+    }
+
+    #[test]
+    fn test_resolve_swap_failed() {
+        let mut contract = setup_contract();
+        contract.set_swap_fee(Some(30));
+        contract.set_fee_split(FeeSplit {
+            treasury: 5_000,
+            insurance: 5_000,
+            operator: 0,
+            operator_id: None,
+        });
+        let (amount_out, fee) = contract.internal_swap(
+            &accounts(3),
+            &accounts(1),
+            1_000_000,
+            ExchangePrice::new(10000, 10),
+            &accounts(2),
+            ExchangePrice::new(10000, 22),
+        );
+        assert_eq!(
+            contract.treasury.assert_asset(&accounts(2)).insurance,
+            1_500_000_000_000_000
+        );
+
+        testing_env!(
+            VMContextBuilder::new().build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        contract.resolve_swap(
+            accounts(3),
+            accounts(1),
+            1_000_000.into(),
+            accounts(2),
+            amount_out.into(),
+            fee.into(),
+        );
+        let output = contract.treasury.assert_asset(&accounts(2));
+        assert_eq!(output.balance, 3_000_000_000_000_000_000);
+        assert_eq!(output.insurance, 0);
+        assert_eq!(contract.treasury.assert_asset(&accounts(1)).balance, 0);
+        assert_eq!(contract.get_fee_report().assets[&accounts(2)].treasury.0, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Swaps are disabled")]
+    fn test_swap_disabled() {
+        let mut contract = setup_contract();
+        contract.internal_swap(
+            &accounts(3),
+            &accounts(1),
+            1_000_000,
+            ExchangePrice::new(10000, 10),
+            &accounts(2),
+            ExchangePrice::new(10000, 22),
+        );
+    }
+}
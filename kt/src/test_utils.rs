@@ -0,0 +1,38 @@
+//! Fixtures shared by the unit tests of the modules.
+
+use near_sdk::test_utils::{accounts, VMContextBuilder};
+use near_sdk::{testing_env, AccountId};
+
+use crate::oracle::ExchangePrice;
+use crate::{BuyOptions, Contract};
+
+/// Contract owned by `accounts(0)` with the oracle `accounts(4)` and the asset `accounts(3)`
+/// with 6 decimals, the context is left with the owner as the predecessor.
+pub fn setup_contract(context: &mut VMContextBuilder) -> Contract {
+    testing_env!(context
+        .current_account_id(accounts(0))
+        .predecessor_account_id(accounts(0))
+        .build());
+    let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+    contract.internal_add_asset(&accounts(3), 6);
+    contract
+}
+
+/// Buys 1 KT for the account with 1 of the `accounts(3)` asset at 1 USD.
+pub fn buy_kt(contract: &mut Contract, account_id: &AccountId) {
+    contract.internal_buy(
+        account_id,
+        &accounts(3),
+        1_000_000,
+        6,
+        ExchangePrice::new(10000, 10),
+        &BuyOptions::default(),
+    );
+}
+
+/// `setup_contract` with 1 KT bought by `accounts(1)`.
+pub fn setup_contract_with_kt(context: &mut VMContextBuilder) -> Contract {
+    let mut contract = setup_contract(context);
+    buy_kt(&mut contract, &accounts(1));
+    contract
+}
@@ -0,0 +1,156 @@
+//! Execution context forwarded to the `ft_transfer_call` receivers which opted in to it,
+//! so DeFi contracts can account for the cost basis of the transferred KT.
+
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen, AccountId, Balance};
+use schemars::JsonSchema;
+
+use crate::{Contract, ContractExt, KT_DECIMALS};
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct TransferData {
+    /// Weighted mean purchase price of the sender, with 18 decimals.
+    pub sender_price: U128,
+    /// Treasury value per KT with the cached prices, with 18 decimals. `None` if any
+    /// asset of the treasury has no price or a stale one.
+    pub nav: Option<U128>,
+}
+
+/// `msg` of the `ft_transfer_call` as passed to an opted in receiver.
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct DataMessage {
+    /// Original message of the sender.
+    pub msg: String,
+    pub data: TransferData,
+}
+
+impl Contract {
+    /// Treasury value per KT backed by it on all chains, `None` without supply, on overflow
+    /// or if the treasury value is incomplete.
+    pub(crate) fn nav(&self) -> Option<Balance> {
+        let supply = self.backed_supply();
+        if supply == 0 {
+            return None;
+        }
+        let value = self.treasury_value();
+        if !value.complete {
+            return None;
+        }
+        value
+            .total
+            .0
+            .checked_mul(10u128.pow(u32::from(KT_DECIMALS)))?
+            .checked_div(supply)
+    }
+
+    /// Wraps the message with the transfer data if the receiver opted in to it.
+    pub(crate) fn ft_data_to_msg(
+        &self,
+        sender_id: &AccountId,
+        receiver_id: &AccountId,
+        msg: String,
+    ) -> String {
+        if !self.data_receivers.contains(receiver_id) {
+            return msg;
+        }
+        let data = TransferData {
+            sender_price: self
+                .token
+                .internal_unwrap_balance_of(sender_id)
+                .price
+                .into(),
+            nav: self.nav().map(Into::into),
+        };
+        near_sdk::serde_json::to_string(&DataMessage { msg, data })
+            .unwrap_or_else(|_| env::panic_str("Failed to serialize the transfer data"))
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Adds the transfer data to the `ft_transfer_call` messages sent to the receivers.
+    pub fn add_data_receivers(&mut self, account_ids: Vec<AccountId>) {
+        self.assert_owner();
+        for account_id in account_ids.iter() {
+            self.data_receivers.insert(account_id);
+        }
+    }
+
+    pub fn remove_data_receivers(&mut self, account_ids: Vec<AccountId>) {
+        self.assert_owner();
+        for account_id in account_ids.iter() {
+            self.data_receivers.remove(account_id);
+        }
+    }
+
+    pub fn is_data_receiver(&self, account_id: AccountId) -> bool {
+        self.data_receivers.contains(&account_id)
+    }
+
+    pub fn get_nav(&self) -> Option<U128> {
+        self.nav().map(Into::into)
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    use crate::oracle::ExchangePrice;
+    use crate::transfer_data::DataMessage;
+    use crate::{BuyOptions, Contract};
+
+    fn setup_contract() -> Contract {
+        let mut context = VMContextBuilder::new();
+        context
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(3), 6);
+        let price = ExchangePrice::new(10000, 10);
+        contract.treasury.set_asset_price(&accounts(3), price);
+        contract.internal_buy(
+            &accounts(1),
+            &accounts(3),
+            1_000_000,
+            6,
+            price,
+            &BuyOptions::default(),
+        );
+        contract
+    }
+
+    #[test]
+    fn test_ft_data_to_msg() {
+        let mut contract = setup_contract();
+        contract.add_data_receivers(vec![accounts(2)]);
+
+        let msg = contract.ft_data_to_msg(&accounts(1), &accounts(2), "swap".to_string());
+        let message: DataMessage = near_sdk::serde_json::from_str(&msg).unwrap();
+        assert_eq!(message.msg, "swap");
+        assert_eq!(message.data.sender_price.0, 1_000_000_000_000);
+        assert_eq!(message.data.nav.unwrap().0, 1_000_000_000_000_000_000);
+    }
+
+    #[test]
+    fn test_nav_unpriced_asset() {
+        let mut contract = setup_contract();
+        contract.internal_add_asset(&accounts(2), 6);
+        contract.treasury.internal_deposit(&accounts(2), 1_000_000);
+        assert!(contract.get_nav().is_none());
+    }
+
+    #[test]
+    fn test_ft_data_to_msg_not_opted_in() {
+        let contract = setup_contract();
+        let msg = contract.ft_data_to_msg(&accounts(1), &accounts(2), "swap".to_string());
+        assert_eq!(msg, "swap");
+    }
+}
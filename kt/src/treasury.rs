@@ -1,27 +1,113 @@
-use near_contract_standards::upgrade::Ownable;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+
+use near_contract_standards::fungible_token::metadata::{ext_ft_metadata, FungibleTokenMetadata};
+use near_contract_standards::storage_management::StorageBalance;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::collections::UnorderedMap;
+use near_sdk::json_types::{U128, U64};
 use near_sdk::serde::{Deserialize, Serialize};
-use near_sdk::{env, near_bindgen, require, AccountId, Balance, IntoStorageKey};
+use near_sdk::{
+    assert_one_yocto, env, ext_contract, near_bindgen, require, AccountId, Balance, IntoStorageKey,
+    Promise, PromiseResult, ONE_YOCTO,
+};
+use schemars::JsonSchema;
+
+use crate::events::KtEvent;
+use crate::oracle::{CachedPrice, ExchangePrice};
+use crate::rebalance::asset_value;
+use crate::roles::Role;
+use crate::strategy::{StrategyInfo, StrategyKind};
+use crate::{ext_ft_transfer, Contract, ContractExt, BASIS_POINTS, MAX_U128_DECIMALS};
 
-use crate::{Contract, ContractExt, MAX_U128_DECIMALS};
+/// Maximum keeper incentive of a rebalance, in basis points.
+const MAX_REBALANCE_INCENTIVE: u16 = 300;
 
 pub type AssetId = AccountId;
 
-#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[derive(
+    BorshDeserialize,
+    BorshSerialize,
+    Serialize,
+    Deserialize,
+    Debug,
+    Clone,
+    PartialEq,
+    Eq,
+    JsonSchema,
+)]
 #[serde(crate = "near_sdk::serde")]
 pub enum AssetStatus {
     Enabled,
     Disabled,
+    /// Only buys are accepted, e.g. while the asset is being onboarded.
+    BuyOnly,
+    /// Only sells are accepted, e.g. while the asset is being wound down.
+    SellOnly,
+    Deprecated,
+}
+
+/// Where the insurance fund contribution comes from.
+#[derive(Serialize, Clone, Copy, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum InsuranceSource {
+    Fee,
+    /// Asset amount left over by rounding the minted KT down.
+    Rounding,
+    /// Backing of the swept dust balances.
+    Dust,
+}
+
+impl AssetStatus {
+    pub fn can_buy(&self) -> bool {
+        matches!(self, AssetStatus::Enabled | AssetStatus::BuyOnly)
+    }
+
+    pub fn can_sell(&self) -> bool {
+        matches!(self, AssetStatus::Enabled | AssetStatus::SellOnly)
+    }
 }
 
-#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, JsonSchema)]
 #[serde(crate = "near_sdk::serde")]
 #[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
 pub struct AssetInfo {
     pub decimals: u8,
     pub balance: Balance,
     pub status: AssetStatus,
+    /// Minimum asset amount accepted by a single buy.
+    pub min_buy: Balance,
+    /// Maximum asset amount accepted by a single buy.
+    pub max_buy: Option<Balance>,
+    /// Minimum asset amount paid out by a single sell.
+    pub min_sell: Balance,
+    /// Maximum asset amount paid out by a single sell.
+    pub max_sell: Option<Balance>,
+    /// Maximum treasury balance of the asset accepted through buys.
+    pub cap: Option<Balance>,
+    /// Target share of the treasury value, in basis points.
+    pub target_weight: u16,
+    /// Last oracle price used for the asset.
+    pub price: Option<CachedPrice>,
+    /// Amount lent out to the yield strategy, not available for sells.
+    pub deployed: Balance,
+    /// Maximum share of the principal deployed to the strategy, in basis points.
+    pub max_deployed: u16,
+    pub strategy: Option<StrategyInfo>,
+    /// Insurance fund held outside of the balance, to cover shortfalls.
+    pub insurance: Balance,
+    /// Maximum age of an accepted oracle price, in nanoseconds.
+    pub max_price_age_ns: Option<U64>,
+    /// Decimals of the oracle price, pinned at the registration or by the first price.
+    pub price_decimals: Option<u8>,
+    /// Oracle asked when the primary oracle has no valid price.
+    pub fallback_oracle_id: Option<AccountId>,
+    /// Share of the transfers kept by the asset token, in basis points.
+    pub transfer_fee: u16,
+    /// Markup of the buys and markdown of the sells around the oracle price, in basis points.
+    pub spread: u16,
 }
 
 impl AssetInfo {
@@ -35,12 +121,210 @@ impl AssetInfo {
             decimals,
             balance: 0,
             status: AssetStatus::Enabled,
+            min_buy: 0,
+            max_buy: None,
+            min_sell: 0,
+            max_sell: None,
+            cap: None,
+            target_weight: 0,
+            price: None,
+            deployed: 0,
+            max_deployed: 0,
+            strategy: None,
+            insurance: 0,
+            max_price_age_ns: None,
+            price_decimals: None,
+            fallback_oracle_id: None,
+            transfer_fee: 0,
+            spread: 0,
+        }
+    }
+
+    /// Amount actually delivered by a transfer of the stated amount, rounded down.
+    pub fn received_amount(&self, amount: Balance) -> Balance {
+        let fee = u128::from(self.transfer_fee);
+        let bps = u128::from(BASIS_POINTS);
+        let kept = match amount.checked_mul(fee) {
+            Some(value) => value.div_ceil(bps),
+            None => (amount / bps + 1) * fee,
+        };
+        amount.saturating_sub(kept)
+    }
+
+    /// Whether the cached price is older than `max_price_age_ns`.
+    pub fn is_price_stale(&self) -> bool {
+        match (self.price, self.max_price_age_ns) {
+            (Some(cached), Some(max_price_age)) => {
+                env::block_timestamp().saturating_sub(cached.timestamp.0) > max_price_age.0
+            }
+            _ => false,
+        }
+    }
+
+    /// Total amount backing KT, including the funds deployed to the strategy.
+    pub fn principal(&self) -> Balance {
+        self.balance.saturating_add(self.deployed)
+    }
+
+    /// Maximum amount which can be deployed to the strategy.
+    pub fn deploy_limit(&self) -> Balance {
+        let principal = self.principal();
+        let max_deployed = Balance::from(self.max_deployed);
+        let bps = Balance::from(BASIS_POINTS);
+        match principal.checked_mul(max_deployed) {
+            Some(value) => value / bps,
+            None => principal / bps * max_deployed,
+        }
+    }
+
+    /// Largest asset amount accepted by a single buy under the maximum buy and the cap,
+    /// `None` if unlimited.
+    pub fn buy_room(&self) -> Option<Balance> {
+        let cap_room = self.cap.map(|cap| cap.saturating_sub(self.principal()));
+        match (self.max_buy, cap_room) {
+            (Some(max_buy), Some(cap_room)) => Some(max_buy.min(cap_room)),
+            (max_buy, cap_room) => max_buy.or(cap_room),
+        }
+    }
+
+    pub fn assert_cap(&self, amount: Balance) {
+        if let Some(cap) = self.cap {
+            require!(
+                self.principal().saturating_add(amount) <= cap,
+                format!("Treasury cap of {} is exceeded", cap)
+            );
+        }
+    }
+
+    pub fn assert_buy_amount(&self, amount: Balance) {
+        require!(
+            amount >= self.min_buy,
+            format!("Buy amount is below the minimum of {}", self.min_buy)
+        );
+        if let Some(max_buy) = self.max_buy {
+            require!(
+                amount <= max_buy,
+                format!("Buy amount exceeds the maximum of {}", max_buy)
+            );
+        }
+    }
+
+    pub fn assert_sell_amount(&self, amount: Balance) {
+        require!(
+            amount >= self.min_sell,
+            format!("Sell amount is below the minimum of {}", self.min_sell)
+        );
+        if let Some(max_sell) = self.max_sell {
+            require!(
+                amount <= max_sell,
+                format!("Sell amount exceeds the maximum of {}", max_sell)
+            );
         }
     }
 }
+
+/// Asset record stored by the first release, before the versioning. They are all
+/// rewritten as `VAssetInfo` by the state migration.
 #[derive(BorshDeserialize, BorshSerialize)]
+pub(crate) struct LegacyAssetInfo {
+    decimals: u8,
+    balance: Balance,
+    status: AssetStatus,
+}
+
+impl From<LegacyAssetInfo> for AssetInfo {
+    fn from(legacy: LegacyAssetInfo) -> Self {
+        Self {
+            balance: legacy.balance,
+            status: legacy.status,
+            ..AssetInfo::new(legacy.decimals)
+        }
+    }
+}
+
+/// Versioned asset record, older versions are upgraded on access.
+/// New versions are added as variants and converted in `From<VAssetInfo>`.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub enum VAssetInfo {
+    Current(AssetInfo),
+}
+
+impl From<VAssetInfo> for AssetInfo {
+    fn from(asset: VAssetInfo) -> Self {
+        match asset {
+            VAssetInfo::Current(asset) => asset,
+        }
+    }
+}
+
+impl From<AssetInfo> for VAssetInfo {
+    fn from(asset: AssetInfo) -> Self {
+        Self::Current(asset)
+    }
+}
+
+/// Value of an asset principal at its cached price.
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct AssetValue {
+    pub asset_id: AssetId,
+    /// Balance along with the amount deployed to the strategy.
+    pub principal: U128,
+    pub price: Option<CachedPrice>,
+    /// USD value with 18 decimals, `None` without a cached price.
+    pub value: Option<U128>,
+    /// The cached price is older than the maximum price age of the asset.
+    pub stale: bool,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct TreasuryValue {
+    pub assets: Vec<AssetValue>,
+    /// USD value of the assets with a cached price, with 18 decimals.
+    pub total: U128,
+    /// Every asset with a principal has a cached price which isn't stale,
+    /// otherwise the total doesn't cover the whole treasury.
+    pub complete: bool,
+}
+
+struct CachedAsset {
+    asset: AssetInfo,
+    /// Changed since it was read from the storage.
+    dirty: bool,
+}
+
 pub struct Treasury {
-    assets: UnorderedMap<AccountId, AssetInfo>,
+    assets: RefCell<UnorderedMap<AccountId, VAssetInfo>>,
+    /// Allowed deviation from the target weight, in basis points.
+    rebalance_tolerance: u16,
+    /// Bonus paid to keepers on rebalances, in basis points.
+    rebalance_incentive: u16,
+    /// Assets used during the call. Changes are written to the storage once,
+    /// when the contract state is saved at the end of the call or callback.
+    cache: RefCell<BTreeMap<AssetId, CachedAsset>>,
+}
+
+impl BorshSerialize for Treasury {
+    fn serialize<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.flush();
+        BorshSerialize::serialize(&*self.assets.borrow(), writer)?;
+        BorshSerialize::serialize(&self.rebalance_tolerance, writer)?;
+        BorshSerialize::serialize(&self.rebalance_incentive, writer)
+    }
+}
+
+impl BorshDeserialize for Treasury {
+    fn deserialize(buf: &mut &[u8]) -> io::Result<Self> {
+        Ok(Self {
+            assets: RefCell::new(BorshDeserialize::deserialize(buf)?),
+            rebalance_tolerance: BorshDeserialize::deserialize(buf)?,
+            rebalance_incentive: BorshDeserialize::deserialize(buf)?,
+            cache: RefCell::default(),
+        })
+    }
 }
 
 impl Treasury {
@@ -49,12 +333,104 @@ impl Treasury {
         S: IntoStorageKey,
     {
         Self {
-            assets: UnorderedMap::new(prefix),
+            assets: RefCell::new(UnorderedMap::new(prefix)),
+            rebalance_tolerance: 0,
+            rebalance_incentive: 0,
+            cache: RefCell::default(),
+        }
+    }
+
+    /// Treasury with the assets stored by the first release, rewritten as the current
+    /// records under the same prefix.
+    pub(crate) fn from_legacy<S>(
+        prefix: S,
+        mut legacy: UnorderedMap<AccountId, LegacyAssetInfo>,
+    ) -> Self
+    where
+        S: IntoStorageKey,
+    {
+        let assets = legacy.to_vec();
+        legacy.clear();
+        let mut treasury = Self::new(prefix);
+        for (asset_id, asset) in assets {
+            treasury
+                .assets
+                .get_mut()
+                .insert(&asset_id, &AssetInfo::from(asset).into());
+        }
+        treasury
+    }
+
+    pub fn contains(&self, asset_id: &AssetId) -> bool {
+        self.get(asset_id).is_some()
+    }
+
+    pub fn get(&self, asset_id: &AssetId) -> Option<AssetInfo> {
+        if let Some(cached) = self.cache.borrow().get(asset_id) {
+            return Some(cached.asset.clone());
+        }
+        let asset = AssetInfo::from(self.assets.borrow().get(asset_id)?);
+        self.cache.borrow_mut().insert(
+            asset_id.clone(),
+            CachedAsset {
+                asset: asset.clone(),
+                dirty: false,
+            },
+        );
+        Some(asset)
+    }
+
+    fn insert(&mut self, asset_id: &AssetId, asset: &AssetInfo) {
+        self.cache.get_mut().insert(
+            asset_id.clone(),
+            CachedAsset {
+                asset: asset.clone(),
+                dirty: true,
+            },
+        );
+    }
+
+    /// New assets are written through to keep the order of the supported assets.
+    fn insert_new(&mut self, asset_id: &AssetId, asset: &AssetInfo) {
+        self.assets
+            .get_mut()
+            .insert(asset_id, &asset.clone().into());
+        self.cache.get_mut().insert(
+            asset_id.clone(),
+            CachedAsset {
+                asset: asset.clone(),
+                dirty: false,
+            },
+        );
+    }
+
+    fn remove(&mut self, asset_id: &AssetId) {
+        self.cache.get_mut().remove(asset_id);
+        self.assets.get_mut().remove(asset_id);
+    }
+
+    fn to_vec(&self) -> Vec<(AssetId, AssetInfo)> {
+        self.flush();
+        self.assets
+            .borrow()
+            .iter()
+            .map(|(asset_id, asset)| (asset_id, asset.into()))
+            .collect()
+    }
+
+    /// Writes the changed assets to the storage.
+    pub fn flush(&self) {
+        let mut assets = self.assets.borrow_mut();
+        for (asset_id, cached) in self.cache.borrow_mut().iter_mut() {
+            if cached.dirty {
+                assets.insert(asset_id, &cached.asset.clone().into());
+                cached.dirty = false;
+            }
         }
     }
 
     pub fn assert_asset(&self, asset_id: &AssetId) -> AssetInfo {
-        self.assets.get(asset_id).unwrap_or_else(|| {
+        self.get(asset_id).unwrap_or_else(|| {
             env::panic_str(format!("Asset {} is not supported", asset_id).as_str())
         })
     }
@@ -68,10 +444,28 @@ impl Treasury {
         asset
     }
 
+    pub fn assert_can_buy(&self, asset_id: &AssetId) -> AssetInfo {
+        let asset = self.assert_asset(asset_id);
+        require!(
+            asset.status.can_buy(),
+            format!("Asset {} is currently not available for buying", asset_id)
+        );
+        asset
+    }
+
+    pub fn assert_can_sell(&self, asset_id: &AssetId) -> AssetInfo {
+        let asset = self.assert_asset(asset_id);
+        require!(
+            asset.status.can_sell(),
+            format!("Asset {} is currently not available for selling", asset_id)
+        );
+        asset
+    }
+
     pub fn set_asset_status(&mut self, asset_id: &AssetId, status: AssetStatus) {
-        let mut asset = self.assets.get(asset_id).unwrap();
+        let mut asset = self.get(asset_id).unwrap();
         asset.status = status;
-        self.assets.insert(asset_id, &asset);
+        self.insert(asset_id, &asset);
     }
 
     pub fn enable_asset(&mut self, asset_id: &AssetId) {
@@ -85,72 +479,822 @@ impl Treasury {
     }
 
     pub fn add_asset(&mut self, asset_id: &AssetId, decimals: u8) {
+        require!(self.get(asset_id).is_none(), "Asset is already supported");
+        let asset = AssetInfo::new(decimals);
+        self.insert_new(asset_id, &asset);
+    }
+
+    pub fn set_asset_limits(
+        &mut self,
+        asset_id: &AssetId,
+        min_buy: Balance,
+        max_buy: Option<Balance>,
+        min_sell: Balance,
+        max_sell: Option<Balance>,
+    ) {
         require!(
-            self.assets.get(asset_id).is_none(),
+            !matches!(max_buy, Some(max_buy) if min_buy > max_buy),
+            "Minimum buy amount exceeds the maximum"
+        );
+        require!(
+            !matches!(max_sell, Some(max_sell) if min_sell > max_sell),
+            "Minimum sell amount exceeds the maximum"
+        );
+        let mut asset = self.assert_asset(asset_id);
+        asset.min_buy = min_buy;
+        asset.max_buy = max_buy;
+        asset.min_sell = min_sell;
+        asset.max_sell = max_sell;
+        self.insert(asset_id, &asset);
+    }
+
+    pub fn set_asset_cap(&mut self, asset_id: &AssetId, cap: Option<Balance>) {
+        let mut asset = self.assert_asset(asset_id);
+        asset.cap = cap;
+        self.insert(asset_id, &asset);
+    }
+
+    pub fn set_max_price_age(&mut self, asset_id: &AssetId, max_price_age_ns: Option<U64>) {
+        let mut asset = self.assert_asset(asset_id);
+        asset.max_price_age_ns = max_price_age_ns;
+        self.insert(asset_id, &asset);
+    }
+
+    pub fn set_fallback_oracle(&mut self, asset_id: &AssetId, oracle_id: Option<AccountId>) {
+        let mut asset = self.assert_asset(asset_id);
+        asset.fallback_oracle_id = oracle_id;
+        self.insert(asset_id, &asset);
+    }
+
+    pub fn set_transfer_fee(&mut self, asset_id: &AssetId, transfer_fee: u16) {
+        require!(transfer_fee < BASIS_POINTS, "Transfer fee is out of bounds");
+        let mut asset = self.assert_asset(asset_id);
+        asset.transfer_fee = transfer_fee;
+        self.insert(asset_id, &asset);
+    }
+
+    pub fn set_spread(&mut self, asset_id: &AssetId, spread: u16) {
+        let mut asset = self.assert_asset(asset_id);
+        asset.spread = spread;
+        self.insert(asset_id, &asset);
+    }
+
+    pub fn set_price_decimals(&mut self, asset_id: &AssetId, price_decimals: u8) {
+        let mut asset = self.assert_asset(asset_id);
+        require!(
+            price_decimals >= asset.decimals,
+            "Price decimals can't be less than the asset decimals"
+        );
+        asset.price_decimals = Some(price_decimals);
+        self.insert(asset_id, &asset);
+    }
+
+    pub fn set_asset_price(&mut self, asset_id: &AssetId, price: ExchangePrice) {
+        let mut asset = self.assert_asset(asset_id);
+        // The exchange price keeps the decimals above the asset ones
+        asset
+            .price_decimals
+            .get_or_insert(price.decimals + asset.decimals);
+        asset.price = Some(CachedPrice::new(price));
+        self.insert(asset_id, &asset);
+    }
+
+    /// Sets the target weights of the given assets, all other assets are targeted to zero.
+    pub fn set_target_weights(&mut self, weights: &[(AssetId, u16)]) {
+        let total = weights
+            .iter()
+            .try_fold(0u16, |total, (_, weight)| total.checked_add(*weight));
+        require!(
+            total == Some(BASIS_POINTS),
+            format!("Target weights must sum up to {}", BASIS_POINTS)
+        );
+        for (asset_id, _) in weights {
+            self.assert_asset(asset_id);
+        }
+
+        for (asset_id, mut asset) in self.to_vec() {
+            asset.target_weight = weights
+                .iter()
+                .find(|(id, _)| *id == asset_id)
+                .map_or(0, |(_, weight)| *weight);
+            self.insert(&asset_id, &asset);
+        }
+    }
+
+    pub fn rebalance_tolerance(&self) -> u16 {
+        self.rebalance_tolerance
+    }
+
+    pub fn set_rebalance_tolerance(&mut self, tolerance: u16) {
+        require!(tolerance <= BASIS_POINTS, "Tolerance is out of bounds");
+        self.rebalance_tolerance = tolerance;
+    }
+
+    pub fn rebalance_incentive(&self) -> u16 {
+        self.rebalance_incentive
+    }
+
+    pub fn set_rebalance_incentive(&mut self, incentive: u16) {
+        require!(
+            incentive <= MAX_REBALANCE_INCENTIVE,
+            "Incentive is out of bounds"
+        );
+        self.rebalance_incentive = incentive;
+    }
+
+    pub fn remove_asset(&mut self, asset_id: &AssetId) {
+        let asset = self.assert_asset(asset_id);
+        require!(
+            asset.principal() == 0 && asset.insurance == 0,
+            "Asset balance is not empty"
+        );
+        self.remove(asset_id);
+    }
+
+    /// Moves the asset info and balance to the new asset id, and deprecates the old one.
+    /// Returns the migrated balance.
+    pub fn migrate_asset(&mut self, old_asset_id: &AssetId, new_asset_id: &AssetId) -> Balance {
+        let mut asset = self.assert_asset(old_asset_id);
+        require!(
+            asset.status != AssetStatus::Deprecated,
+            format!("Asset {} is deprecated", old_asset_id)
+        );
+        require!(
+            self.get(new_asset_id).is_none(),
             "Asset is already supported"
         );
-        let asset = AssetInfo::new(decimals);
-        self.assets.insert(asset_id, &asset);
+        require!(
+            asset.deployed == 0,
+            "Asset funds are deployed to the strategy"
+        );
+        self.insert_new(new_asset_id, &asset);
+
+        let balance = asset.balance;
+        asset.balance = 0;
+        asset.insurance = 0;
+        asset.status = AssetStatus::Deprecated;
+        self.insert(old_asset_id, &asset);
+
+        balance
+    }
+
+    /// Sets the yield strategy of the asset and the share of the principal it can use.
+    pub fn set_asset_strategy(
+        &mut self,
+        asset_id: &AssetId,
+        strategy: Option<StrategyKind>,
+        max_deployed: u16,
+    ) {
+        require!(
+            max_deployed <= BASIS_POINTS,
+            "Allocation limit is out of bounds"
+        );
+        let mut asset = self.assert_asset(asset_id);
+        if asset.strategy.as_ref().map(|info| &info.kind) != strategy.as_ref() {
+            require!(
+                asset.deployed == 0,
+                "Asset funds are deployed to the strategy"
+            );
+            asset.strategy = strategy.map(StrategyInfo::new);
+        }
+        asset.max_deployed = max_deployed;
+        self.insert(asset_id, &asset);
+    }
+
+    pub fn assert_strategy(&self, asset_id: &AssetId) -> StrategyKind {
+        self.assert_asset(asset_id)
+            .strategy
+            .map(|info| info.kind)
+            .unwrap_or_else(|| {
+                env::panic_str(format!("Asset {} has no strategy", asset_id).as_str())
+            })
+    }
+
+    /// Moves the treasury balance to the strategy.
+    pub fn internal_deploy(&mut self, asset_id: &AssetId, amount: Balance) {
+        let mut asset = self.assert_asset(asset_id);
+        require!(
+            asset.deployed.saturating_add(amount) <= asset.deploy_limit(),
+            format!(
+                "Strategy allocation of {} is exceeded",
+                asset.deploy_limit()
+            )
+        );
+        asset.balance = asset
+            .balance
+            .checked_sub(amount)
+            .unwrap_or_else(|| env::panic_str("The treasury doesn't have enough balance"));
+        asset.deployed += amount;
+        self.insert(asset_id, &asset);
+    }
+
+    /// Moves the funds returned by the strategy back to the treasury balance.
+    /// Amounts above the deployed principal are the strategy yield.
+    pub fn internal_recall(&mut self, asset_id: &AssetId, amount: Balance) {
+        let mut asset = self.assert_asset(asset_id);
+        asset.deployed = asset.deployed.saturating_sub(amount);
+        self.insert(asset_id, &asset);
+        self.internal_deposit(asset_id, amount);
+    }
+
+    /// Adds the harvested strategy yield to the treasury balance.
+    pub fn internal_harvest(&mut self, asset_id: &AssetId, amount: Balance) {
+        let mut asset = self.assert_asset(asset_id);
+        if let Some(info) = asset.strategy.as_mut() {
+            info.harvested = info.harvested.saturating_add(amount);
+        }
+        self.insert(asset_id, &asset);
+        self.internal_deposit(asset_id, amount);
+    }
+
+    pub fn set_strategy_report(&mut self, asset_id: &AssetId, value: Balance) {
+        let mut asset = self.assert_asset(asset_id);
+        if let Some(info) = asset.strategy.as_mut() {
+            info.reported = Some(value);
+            info.reported_at = env::block_timestamp().into();
+        }
+        self.insert(asset_id, &asset);
     }
 
     pub fn supported_assets(&self) -> Vec<(AssetId, AssetInfo)> {
-        self.assets.to_vec()
+        self.to_vec()
+    }
+
+    /// Moves the amount from the asset balance to its insurance fund.
+    pub fn internal_insure(&mut self, asset_id: &AssetId, amount: Balance) {
+        let mut asset = self.assert_asset(asset_id);
+        asset.balance = asset
+            .balance
+            .checked_sub(amount)
+            .unwrap_or_else(|| env::panic_str("The treasury doesn't have enough balance"));
+        asset.insurance = asset
+            .insurance
+            .checked_add(amount)
+            .unwrap_or_else(|| env::panic_str("Insurance fund overflow"));
+        self.insert(asset_id, &asset);
+    }
+
+    /// Moves the amount from the insurance fund of the asset back to its balance.
+    pub fn internal_deploy_insurance(&mut self, asset_id: &AssetId, amount: Balance) {
+        let mut asset = self.assert_asset(asset_id);
+        asset.insurance = asset
+            .insurance
+            .checked_sub(amount)
+            .unwrap_or_else(|| env::panic_str("The insurance fund doesn't have enough balance"));
+        asset.balance = asset
+            .balance
+            .checked_add(amount)
+            .unwrap_or_else(|| env::panic_str("Treasury balance overflow"));
+        self.insert(asset_id, &asset);
     }
 
     pub fn internal_deposit(&mut self, asset_id: &AssetId, amount: Balance) {
-        let mut asset = self.assets.get(asset_id).unwrap();
+        let mut asset = self.get(asset_id).unwrap();
         if let Some(new_balance) = asset.balance.checked_add(amount) {
             asset.balance = new_balance;
-            self.assets.insert(asset_id, &asset);
+            self.insert(asset_id, &asset);
+            KtEvent::TreasuryDeposit {
+                asset_id,
+                amount: &amount.into(),
+            }
+            .emit();
         } else {
             env::panic_str("Treasury balance overflow");
         }
     }
 
     pub fn internal_withdraw(&mut self, asset_id: &AssetId, amount: Balance) {
-        let mut asset = self.assets.get(asset_id).unwrap();
+        let mut asset = self.get(asset_id).unwrap();
         if let Some(new_balance) = asset.balance.checked_sub(amount) {
             asset.balance = new_balance;
-            self.assets.insert(asset_id, &asset);
+            self.insert(asset_id, &asset);
+            KtEvent::TreasuryWithdraw {
+                asset_id,
+                amount: &amount.into(),
+            }
+            .emit();
         } else {
             env::panic_str("The treasury doesn't have enough balance");
         }
     }
 }
 
+impl Contract {
+    pub(crate) fn internal_add_asset(&mut self, asset_id: &AssetId, decimals: u8) {
+        self.treasury.add_asset(asset_id, decimals);
+        KtEvent::AssetAdded { asset_id, decimals }.emit();
+    }
+
+    /// Promise fetching the asset metadata and registering the contract on the asset
+    /// with the deposit, its two results are checked by `internal_list_asset`.
+    pub(crate) fn asset_listing(&self, asset_id: &AssetId, deposit: Balance) -> Promise {
+        ext_ft_metadata::ext(asset_id.clone())
+            .with_static_gas(self.gas.ft_metadata)
+            .with_unused_gas_weight(0)
+            .ft_metadata()
+            .and(
+                ext_storage_management::ext(asset_id.clone())
+                    .with_static_gas(self.gas.storage_deposit)
+                    .with_unused_gas_weight(0)
+                    .with_attached_deposit(deposit)
+                    .storage_deposit(Some(env::current_account_id()), Some(true)),
+            )
+    }
+
+    /// Adds the asset listed by `asset_listing`, checking the decimals against its metadata.
+    pub(crate) fn internal_list_asset(
+        &mut self,
+        asset_id: &AssetId,
+        decimals: Option<u8>,
+        price_decimals: Option<u8>,
+        metadata: &FungibleTokenMetadata,
+        registered: bool,
+    ) {
+        require!(
+            registered,
+            "Storage registration on the asset contract failed"
+        );
+        if let Some(decimals) = decimals {
+            require!(
+                decimals == metadata.decimals,
+                format!(
+                    "Asset decimals {} don't match the metadata decimals {}",
+                    decimals, metadata.decimals
+                )
+            );
+        }
+        self.internal_add_asset(asset_id, metadata.decimals);
+        if let Some(price_decimals) = price_decimals {
+            self.treasury.set_price_decimals(asset_id, price_decimals);
+        }
+    }
+
+    pub(crate) fn internal_set_asset_status(&mut self, asset_id: &AssetId, status: AssetStatus) {
+        let asset = self.treasury.assert_asset(asset_id);
+        require!(
+            asset.status != AssetStatus::Deprecated,
+            format!("Asset {} is deprecated", asset_id)
+        );
+        self.treasury.set_asset_status(asset_id, status);
+        KtEvent::AssetStatusChanged {
+            asset_id,
+            status: &self.treasury.assert_asset(asset_id).status,
+        }
+        .emit();
+    }
+
+    pub(crate) fn internal_fund_insurance(
+        &mut self,
+        asset_id: &AssetId,
+        amount: Balance,
+        source: InsuranceSource,
+    ) {
+        if amount == 0 {
+            return;
+        }
+        self.treasury.internal_insure(asset_id, amount);
+        KtEvent::InsuranceFunded {
+            asset_id,
+            amount: &amount.into(),
+            source,
+        }
+        .emit();
+    }
+}
+
 #[near_bindgen]
 impl Contract {
-    pub fn add_asset(&mut self, asset_id: &AccountId, decimals: u8) {
+    /// Adds the asset once its `ft_metadata` is fetched. The decimals have to match
+    /// the metadata ones, and are taken from the metadata if not set.
+    /// The oracle price decimals are pinned by the first price if not set.
+    /// The attached deposit registers the contract on the asset contract.
+    #[payable]
+    pub fn add_asset(
+        &mut self,
+        asset_id: &AccountId,
+        decimals: Option<u8>,
+        price_decimals: Option<u8>,
+    ) -> Promise {
         self.assert_owner();
-        self.treasury.add_asset(asset_id, decimals);
+        self.asset_listing(asset_id, env::attached_deposit()).then(
+            ext_treasury_resolver::ext(env::current_account_id())
+                .with_static_gas(self.gas.resolve_add_asset)
+                .resolve_add_asset(asset_id.clone(), decimals, price_decimals),
+        )
     }
 
     pub fn disable_asset(&mut self, asset_id: &AccountId) {
         self.assert_owner();
         self.treasury.disable_asset(asset_id);
+        KtEvent::AssetStatusChanged {
+            asset_id,
+            status: &AssetStatus::Disabled,
+        }
+        .emit();
     }
 
     pub fn enable_asset(&mut self, asset_id: &AccountId) {
         self.assert_owner();
         self.treasury.enable_asset(asset_id);
+        KtEvent::AssetStatusChanged {
+            asset_id,
+            status: &AssetStatus::Enabled,
+        }
+        .emit();
+    }
+
+    pub fn set_asset_status(&mut self, asset_id: &AccountId, status: AssetStatus) {
+        self.assert_owner();
+        self.internal_set_asset_status(asset_id, status);
+    }
+
+    pub fn set_asset_limits(
+        &mut self,
+        asset_id: &AccountId,
+        min_buy: U128,
+        max_buy: Option<U128>,
+        min_sell: U128,
+        max_sell: Option<U128>,
+    ) {
+        self.assert_owner();
+        self.treasury.set_asset_limits(
+            asset_id,
+            min_buy.into(),
+            max_buy.map(Into::into),
+            min_sell.into(),
+            max_sell.map(Into::into),
+        );
+    }
+
+    pub fn set_asset_cap(&mut self, asset_id: &AccountId, cap: Option<U128>) {
+        self.assert_owner();
+        self.treasury.set_asset_cap(asset_id, cap.map(Into::into));
+    }
+
+    /// Changes the expected oracle price decimals after the oracle feed changed them.
+    pub fn set_asset_price_decimals(&mut self, asset_id: &AccountId, price_decimals: u8) {
+        self.assert_owner();
+        self.treasury.set_price_decimals(asset_id, price_decimals);
+        KtEvent::PriceDecimalsChanged {
+            asset_id,
+            price_decimals,
+        }
+        .emit();
+    }
+
+    /// Rejects the oracle prices of the asset reported more than `max_price_age_ns` ago,
+    /// whatever their expiration. `None` trusts the oracle expiration.
+    pub fn set_asset_max_price_age(&mut self, asset_id: &AccountId, max_price_age_ns: Option<U64>) {
+        self.assert_owner();
+        self.treasury.set_max_price_age(asset_id, max_price_age_ns);
+        KtEvent::MaxPriceAgeChanged {
+            asset_id,
+            max_price_age_ns: max_price_age_ns.as_ref(),
+        }
+        .emit();
+    }
+
+    /// Sets the oracle retried when the primary one fails or has no valid price
+    /// for the asset, `None` removes it.
+    pub fn set_asset_fallback_oracle(
+        &mut self,
+        asset_id: &AccountId,
+        oracle_id: Option<AccountId>,
+    ) {
+        self.assert_owner();
+        self.treasury
+            .set_fallback_oracle(asset_id, oracle_id.clone());
+        KtEvent::FallbackOracleChanged {
+            asset_id,
+            oracle_id: oracle_id.as_ref(),
+        }
+        .emit();
+    }
+
+    /// Sets the share of the transfers kept by a fee-on-transfer asset, in basis points.
+    /// KT is minted against the amount received after the fee.
+    pub fn set_asset_transfer_fee(&mut self, asset_id: &AccountId, transfer_fee: u16) {
+        self.assert_owner();
+        self.treasury.set_transfer_fee(asset_id, transfer_fee);
+        KtEvent::TransferFeeChanged {
+            asset_id,
+            transfer_fee,
+        }
+        .emit();
+    }
+
+    pub fn remove_asset(&mut self, asset_id: &AccountId) {
+        self.assert_owner();
+        self.treasury.remove_asset(asset_id);
+        KtEvent::AssetRemoved { asset_id }.emit();
+    }
+
+    pub fn migrate_asset(&mut self, old_asset_id: &AccountId, new_asset_id: &AccountId) {
+        self.assert_owner();
+        let balance = self.treasury.migrate_asset(old_asset_id, new_asset_id);
+        KtEvent::AssetMigrated {
+            old_asset_id,
+            new_asset_id,
+            balance: &balance.into(),
+        }
+        .emit();
+    }
+
+    /// Evacuates the asset from the treasury, e.g. if its contract announces a migration
+    /// or is compromised. Allowed to the owner and guardians.
+    #[payable]
+    pub fn emergency_withdraw(
+        &mut self,
+        asset_id: AssetId,
+        amount: U128,
+        receiver_id: AccountId,
+    ) -> Promise {
+        assert_one_yocto();
+        self.assert_owner_or_role(Role::Guardian);
+        self.treasury.internal_withdraw(&asset_id, amount.0);
+        self.internal_start_payout(&asset_id, amount.0);
+
+        KtEvent::EmergencyWithdraw {
+            account_id: &env::predecessor_account_id(),
+            asset_id: &asset_id,
+            amount: &amount,
+            receiver_id: &receiver_id,
+        }
+        .emit();
+
+        ext_ft_transfer::ext(asset_id.clone())
+            .with_static_gas(self.gas.transfer)
+            .with_attached_deposit(ONE_YOCTO)
+            .ft_transfer(receiver_id, amount, Some("emergency withdraw".to_string()))
+            .then(
+                ext_treasury_resolver::ext(env::current_account_id())
+                    .with_static_gas(self.gas.resolve_emergency_withdraw)
+                    .resolve_emergency_withdraw(asset_id, amount),
+            )
+    }
+
+    /// Transfers out the tokens sent to KT by mistake. Supported assets can't be
+    /// rescued, their surplus is adopted with `skim` instead. Neither can wNEAR or tokens
+    /// KT owes to accounts, e.g. asset claims, queued redemptions or DEX swap leftovers.
+    #[payable]
+    pub fn rescue_token(
+        &mut self,
+        token_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+    ) -> Promise {
+        assert_one_yocto();
+        self.assert_owner();
+        require!(token_id != env::current_account_id(), "KT can't be rescued");
+        require!(
+            !self.treasury.contains(&token_id),
+            format!("Token {} is a treasury asset", token_id)
+        );
+        require!(
+            self.wnear_id.as_ref() != Some(&token_id),
+            format!("Token {} is wNEAR", token_id)
+        );
+        require!(
+            self.claims.total_of(&token_id) == 0
+                && self.dex_claims.total_of(&token_id) == 0
+                && self.redemptions.queued(&token_id) == 0
+                && self.payouts_in_flight.get(&token_id).unwrap_or_default() == 0,
+            format!("Token {} is owed to accounts", token_id)
+        );
+
+        KtEvent::TokenRescued {
+            token_id: &token_id,
+            amount: &amount,
+            receiver_id: &receiver_id,
+        }
+        .emit();
+
+        ext_ft_transfer::ext(token_id)
+            .with_static_gas(self.gas.transfer)
+            .with_attached_deposit(ONE_YOCTO)
+            .ft_transfer(receiver_id, amount, Some("rescue".to_string()))
+    }
+
+    /// Moves the insurance fund of the asset to its balance, e.g. to cover a shortfall
+    /// after a depeg. Allowed to the owner and guardians.
+    pub fn deploy_insurance(&mut self, asset_id: AssetId, amount: U128) {
+        self.assert_owner_or_role(Role::Guardian);
+        self.treasury.internal_deploy_insurance(&asset_id, amount.0);
+        let asset = self.treasury.assert_asset(&asset_id);
+        KtEvent::InsuranceDeployed {
+            account_id: &env::predecessor_account_id(),
+            asset_id: &asset_id,
+            amount: &amount,
+            insurance: &asset.insurance.into(),
+            balance: &asset.balance.into(),
+        }
+        .emit();
+    }
+
+    pub fn get_insurance_fund(&self) -> HashMap<AssetId, U128> {
+        self.treasury
+            .supported_assets()
+            .into_iter()
+            .map(|(asset_id, asset)| (asset_id, asset.insurance.into()))
+            .collect()
     }
 
     pub fn supported_assets(&self) -> Vec<(AccountId, AssetInfo)> {
         self.treasury.supported_assets()
     }
+
+    /// Values the treasury assets at the cached prices, in USD as KT is pegged to it.
+    pub fn treasury_value(&self) -> TreasuryValue {
+        let assets: Vec<_> = self
+            .treasury
+            .supported_assets()
+            .into_iter()
+            .map(|(asset_id, asset)| AssetValue {
+                principal: asset.principal().into(),
+                price: asset.price,
+                value: asset_value(&asset).map(Into::into),
+                stale: asset.is_price_stale(),
+                asset_id,
+            })
+            .collect();
+        let complete = assets
+            .iter()
+            .all(|asset| asset.principal.0 == 0 || (asset.value.is_some() && !asset.stale));
+        let total = assets
+            .iter()
+            .filter_map(|asset| asset.value)
+            .fold(0, |total: Balance, value| total.saturating_add(value.0));
+        TreasuryValue {
+            assets,
+            total: total.into(),
+            complete,
+        }
+    }
+}
+
+#[ext_contract(ext_storage_management)]
+#[allow(dead_code)]
+pub trait StorageManagement {
+    fn storage_deposit(
+        &mut self,
+        account_id: Option<AccountId>,
+        registration_only: Option<bool>,
+    ) -> StorageBalance;
+}
+
+#[ext_contract(ext_treasury_resolver)]
+#[allow(dead_code)]
+pub trait TreasuryResolver {
+    fn resolve_add_asset(
+        &mut self,
+        asset_id: AssetId,
+        decimals: Option<u8>,
+        price_decimals: Option<u8>,
+        #[callback_unwrap] metadata: FungibleTokenMetadata,
+    );
+
+    fn resolve_emergency_withdraw(&mut self, asset_id: AssetId, amount: U128);
+}
+
+#[near_bindgen]
+impl TreasuryResolver for Contract {
+    #[private]
+    fn resolve_add_asset(
+        &mut self,
+        asset_id: AssetId,
+        decimals: Option<u8>,
+        price_decimals: Option<u8>,
+        #[callback_unwrap] metadata: FungibleTokenMetadata,
+    ) {
+        self.internal_list_asset(
+            &asset_id,
+            decimals,
+            price_decimals,
+            &metadata,
+            matches!(env::promise_result(1), PromiseResult::Successful(_)),
+        );
+    }
+
+    /// Returns the amount to the treasury if the transfer failed.
+    #[private]
+    fn resolve_emergency_withdraw(&mut self, asset_id: AssetId, amount: U128) {
+        self.internal_finish_payout(&asset_id, amount.0);
+        match env::promise_result(0) {
+            PromiseResult::NotReady => env::abort(),
+            PromiseResult::Successful(_) => {}
+            PromiseResult::Failed => self.treasury.internal_deposit(&asset_id, amount.0),
+        }
+    }
 }
 
 #[cfg(all(test, not(target_arch = "wasm32")))]
 mod tests {
-    use near_sdk::test_utils::accounts;
+    use near_contract_standards::fungible_token::metadata::{
+        FungibleTokenMetadata, FT_METADATA_SPEC,
+    };
+    use near_sdk::borsh::{BorshDeserialize, BorshSerialize};
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
+    use near_sdk::{testing_env, PromiseResult, RuntimeFeesConfig, VMConfig, ONE_YOCTO};
+
+    use crate::oracle::ExchangePrice;
+    use crate::roles::Role;
+    use crate::strategy::StrategyKind;
+    use crate::treasury::{
+        AssetInfo, AssetStatus, LegacyAssetInfo, Treasury, TreasuryResolver, VAssetInfo,
+    };
+    use crate::{Contract, StorageKey, BASIS_POINTS, MAX_U128_DECIMALS};
+
+    fn metadata(decimals: u8) -> FungibleTokenMetadata {
+        FungibleTokenMetadata {
+            spec: FT_METADATA_SPEC.to_string(),
+            name: "USD Coin".to_string(),
+            symbol: "USDC".to_string(),
+            icon: None,
+            reference: None,
+            reference_hash: None,
+            decimals,
+        }
+    }
+
+    fn setup_registration(registered: bool) {
+        let storage = if registered {
+            PromiseResult::Successful(vec![])
+        } else {
+            PromiseResult::Failed
+        };
+        testing_env!(
+            VMContextBuilder::new().build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![]), storage]
+        );
+    }
+
+    #[test]
+    fn test_asset_info_versions() {
+        // Decimals, balance and status of a record stored before the versioning
+        let legacy = (6u8, 1_000u128, AssetStatus::Disabled)
+            .try_to_vec()
+            .unwrap();
+        let asset = AssetInfo::from(LegacyAssetInfo::try_from_slice(&legacy).unwrap());
+        assert_eq!(asset.decimals, 6);
+        assert_eq!(asset.balance, 1_000);
+        assert_eq!(asset.status, AssetStatus::Disabled);
+        assert!(asset.price.is_none());
 
-    use crate::treasury::{AssetStatus, Treasury};
-    use crate::{StorageKey, MAX_U128_DECIMALS};
+        let mut asset = AssetInfo::new(18);
+        asset.spread = 30;
+        let current = VAssetInfo::from(asset.clone()).try_to_vec().unwrap();
+        // The current records are tagged with the variant index
+        assert_eq!(current[0], 0);
+        assert_eq!(current[1..], asset.try_to_vec().unwrap());
+        let asset = AssetInfo::from(VAssetInfo::try_from_slice(&current).unwrap());
+        assert_eq!((asset.decimals, asset.spread), (18, 30));
+    }
+
+    #[test]
+    fn test_resolve_add_asset() {
+        setup_registration(true);
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.resolve_add_asset(accounts(1), Some(6), Some(10), metadata(6));
+        contract.resolve_add_asset(accounts(2), None, None, metadata(18));
+        assert_eq!(contract.treasury.assert_asset(&accounts(1)).decimals, 6);
+        assert_eq!(
+            contract.treasury.assert_asset(&accounts(1)).price_decimals,
+            Some(10)
+        );
+        assert_eq!(contract.treasury.assert_asset(&accounts(2)).decimals, 18);
+
+        contract
+            .treasury
+            .set_asset_price(&accounts(2), ExchangePrice::new(1, 6));
+        assert_eq!(
+            contract.treasury.assert_asset(&accounts(2)).price_decimals,
+            Some(24)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Asset decimals 18 don't match the metadata decimals 6")]
+    fn test_resolve_add_asset_wrong_decimals() {
+        setup_registration(true);
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.resolve_add_asset(accounts(1), Some(18), None, metadata(6));
+    }
+
+    #[test]
+    #[should_panic(expected = "Storage registration on the asset contract failed")]
+    fn test_resolve_add_asset_not_registered() {
+        setup_registration(false);
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.resolve_add_asset(accounts(1), Some(6), None, metadata(6));
+    }
 
     #[test]
     fn test_new() {
         let treasury = Treasury::new(StorageKey::Treasury);
-        assert_eq!(treasury.assets.to_vec().len(), 0);
+        assert_eq!(treasury.to_vec().len(), 0);
     }
 
     #[test]
@@ -164,6 +1308,27 @@ mod tests {
         assert_eq!(asset.status, AssetStatus::Enabled);
     }
 
+    #[test]
+    fn test_received_amount() {
+        let asset_id = &accounts(1);
+        let mut treasury = Treasury::new(StorageKey::Treasury);
+        treasury.add_asset(asset_id, 6);
+        assert_eq!(
+            treasury.assert_asset(asset_id).received_amount(1_000),
+            1_000
+        );
+
+        treasury.set_transfer_fee(asset_id, 30);
+        let asset = treasury.assert_asset(asset_id);
+        assert_eq!(asset.received_amount(1_000_000), 997_000);
+        // The kept fee is rounded up
+        assert_eq!(asset.received_amount(1_001), 997);
+        assert_eq!(
+            asset.received_amount(u128::MAX),
+            u128::MAX - u128::MAX / 10_000 * 30 - 30
+        );
+    }
+
     #[test]
     #[should_panic(expected = "Asset bob is not supported")]
     fn test_unsupported_asset() {
@@ -192,6 +1357,39 @@ mod tests {
         treasury.assert_asset_status(asset_id, AssetStatus::Enabled);
     }
 
+    #[test]
+    fn test_assert_can_buy_and_sell() {
+        let asset_id = &accounts(1);
+        let mut treasury = Treasury::new(StorageKey::Treasury);
+        treasury.add_asset(asset_id, 20);
+        treasury.assert_can_buy(asset_id);
+        treasury.assert_can_sell(asset_id);
+        treasury.set_asset_status(asset_id, AssetStatus::BuyOnly);
+        treasury.assert_can_buy(asset_id);
+        treasury.set_asset_status(asset_id, AssetStatus::SellOnly);
+        treasury.assert_can_sell(asset_id);
+    }
+
+    #[test]
+    #[should_panic(expected = "Asset bob is currently not available for buying")]
+    fn test_assert_can_buy_sell_only() {
+        let asset_id = &accounts(1);
+        let mut treasury = Treasury::new(StorageKey::Treasury);
+        treasury.add_asset(asset_id, 20);
+        treasury.set_asset_status(asset_id, AssetStatus::SellOnly);
+        treasury.assert_can_buy(asset_id);
+    }
+
+    #[test]
+    #[should_panic(expected = "Asset bob is currently not available for selling")]
+    fn test_assert_can_sell_buy_only() {
+        let asset_id = &accounts(1);
+        let mut treasury = Treasury::new(StorageKey::Treasury);
+        treasury.add_asset(asset_id, 20);
+        treasury.set_asset_status(asset_id, AssetStatus::BuyOnly);
+        treasury.assert_can_sell(asset_id);
+    }
+
     #[test]
     fn test_enable_disable_assets() {
         let asset_id = &accounts(1);
@@ -297,6 +1495,337 @@ mod tests {
         assert_eq!(assets[2].0, accounts(3));
     }
 
+    #[test]
+    fn test_remove_asset() {
+        let mut treasury = Treasury::new(StorageKey::Treasury);
+        treasury.add_asset(&accounts(1), 20);
+        treasury.add_asset(&accounts(2), 20);
+        treasury.remove_asset(&accounts(1));
+
+        let assets = treasury.supported_assets();
+        assert_eq!(assets.len(), 1);
+        assert_eq!(assets[0].0, accounts(2));
+    }
+
+    #[test]
+    #[should_panic(expected = "Asset balance is not empty")]
+    fn test_remove_asset_with_balance() {
+        let asset_id = &accounts(1);
+        let mut treasury = Treasury::new(StorageKey::Treasury);
+        treasury.add_asset(asset_id, 20);
+        treasury.internal_deposit(asset_id, 1);
+        treasury.remove_asset(asset_id);
+    }
+
+    #[test]
+    fn test_migrate_asset() {
+        let (old_asset_id, new_asset_id) = (&accounts(1), &accounts(2));
+        let mut treasury = Treasury::new(StorageKey::Treasury);
+        treasury.add_asset(old_asset_id, 6);
+        treasury.internal_deposit(old_asset_id, 100);
+
+        assert_eq!(treasury.migrate_asset(old_asset_id, new_asset_id), 100);
+
+        let old_asset = treasury.assert_asset(old_asset_id);
+        assert_eq!(old_asset.balance, 0);
+        assert_eq!(old_asset.status, AssetStatus::Deprecated);
+        let new_asset = treasury.assert_asset(new_asset_id);
+        assert_eq!(new_asset.balance, 100);
+        assert_eq!(new_asset.decimals, 6);
+        assert_eq!(new_asset.status, AssetStatus::Enabled);
+    }
+
+    #[test]
+    #[should_panic(expected = "Asset is already supported")]
+    fn test_migrate_asset_to_supported_asset() {
+        let mut treasury = Treasury::new(StorageKey::Treasury);
+        treasury.add_asset(&accounts(1), 6);
+        treasury.add_asset(&accounts(2), 6);
+        treasury.migrate_asset(&accounts(1), &accounts(2));
+    }
+
+    #[test]
+    #[should_panic(expected = "Asset bob is deprecated")]
+    fn test_migrate_deprecated_asset() {
+        let mut treasury = Treasury::new(StorageKey::Treasury);
+        treasury.add_asset(&accounts(1), 6);
+        treasury.migrate_asset(&accounts(1), &accounts(2));
+        treasury.migrate_asset(&accounts(1), &accounts(3));
+    }
+
+    #[test]
+    fn test_set_asset_limits() {
+        let asset_id = &accounts(1);
+        let mut treasury = Treasury::new(StorageKey::Treasury);
+        treasury.add_asset(asset_id, 6);
+        treasury.set_asset_limits(asset_id, 10, Some(100), 20, None);
+
+        let asset = treasury.assert_asset(asset_id);
+        asset.assert_buy_amount(10);
+        asset.assert_buy_amount(100);
+        asset.assert_sell_amount(20);
+        asset.assert_sell_amount(u128::MAX);
+    }
+
+    #[test]
+    #[should_panic(expected = "Minimum buy amount exceeds the maximum")]
+    fn test_set_asset_limits_invalid() {
+        let asset_id = &accounts(1);
+        let mut treasury = Treasury::new(StorageKey::Treasury);
+        treasury.add_asset(asset_id, 6);
+        treasury.set_asset_limits(asset_id, 100, Some(10), 0, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Buy amount exceeds the maximum of 100")]
+    fn test_assert_buy_amount_exceeded() {
+        let asset_id = &accounts(1);
+        let mut treasury = Treasury::new(StorageKey::Treasury);
+        treasury.add_asset(asset_id, 6);
+        treasury.set_asset_limits(asset_id, 0, Some(100), 0, None);
+        treasury.assert_asset(asset_id).assert_buy_amount(101);
+    }
+
+    #[test]
+    #[should_panic(expected = "Sell amount is below the minimum of 20")]
+    fn test_assert_sell_amount_below_minimum() {
+        let asset_id = &accounts(1);
+        let mut treasury = Treasury::new(StorageKey::Treasury);
+        treasury.add_asset(asset_id, 6);
+        treasury.set_asset_limits(asset_id, 0, None, 20, None);
+        treasury.assert_asset(asset_id).assert_sell_amount(19);
+    }
+
+    #[test]
+    fn test_assert_cap() {
+        let asset_id = &accounts(1);
+        let mut treasury = Treasury::new(StorageKey::Treasury);
+        treasury.add_asset(asset_id, 6);
+        treasury.assert_asset(asset_id).assert_cap(u128::MAX);
+        treasury.set_asset_cap(asset_id, Some(100));
+        treasury.internal_deposit(asset_id, 60);
+        treasury.assert_asset(asset_id).assert_cap(40);
+    }
+
+    #[test]
+    #[should_panic(expected = "Treasury cap of 100 is exceeded")]
+    fn test_assert_cap_exceeded() {
+        let asset_id = &accounts(1);
+        let mut treasury = Treasury::new(StorageKey::Treasury);
+        treasury.add_asset(asset_id, 6);
+        treasury.set_asset_cap(asset_id, Some(100));
+        treasury.internal_deposit(asset_id, 60);
+        treasury.assert_asset(asset_id).assert_cap(41);
+    }
+
+    #[test]
+    fn test_set_target_weights() {
+        let mut treasury = Treasury::new(StorageKey::Treasury);
+        treasury.add_asset(&accounts(1), 6);
+        treasury.add_asset(&accounts(2), 6);
+        treasury.add_asset(&accounts(3), 6);
+        treasury.set_target_weights(&[(accounts(1), 2_500), (accounts(2), 7_500)]);
+
+        let assets = treasury.supported_assets();
+        assert_eq!(assets[0].1.target_weight, 2_500);
+        assert_eq!(assets[1].1.target_weight, 7_500);
+        assert_eq!(assets[2].1.target_weight, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Target weights must sum up to 10000")]
+    fn test_set_target_weights_invalid_sum() {
+        let mut treasury = Treasury::new(StorageKey::Treasury);
+        treasury.add_asset(&accounts(1), 6);
+        treasury.set_target_weights(&[(accounts(1), 5_000)]);
+    }
+
+    #[test]
+    fn test_internal_deploy_and_recall() {
+        let asset_id = &accounts(1);
+        let mut treasury = Treasury::new(StorageKey::Treasury);
+        treasury.add_asset(asset_id, 6);
+        treasury.set_asset_strategy(
+            asset_id,
+            Some(StrategyKind::External {
+                account_id: accounts(2),
+            }),
+            5_000,
+        );
+        treasury.internal_deposit(asset_id, 1_000);
+        treasury.internal_deploy(asset_id, 500);
+
+        let asset = treasury.assert_asset(asset_id);
+        assert_eq!(asset.balance, 500);
+        assert_eq!(asset.deployed, 500);
+        assert_eq!(asset.principal(), 1_000);
+
+        treasury.internal_recall(asset_id, 520);
+        let asset = treasury.assert_asset(asset_id);
+        assert_eq!(asset.balance, 1_020);
+        assert_eq!(asset.deployed, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Strategy allocation of 500 is exceeded")]
+    fn test_internal_deploy_exceeds_allocation() {
+        let asset_id = &accounts(1);
+        let mut treasury = Treasury::new(StorageKey::Treasury);
+        treasury.add_asset(asset_id, 6);
+        treasury.set_asset_strategy(
+            asset_id,
+            Some(StrategyKind::External {
+                account_id: accounts(2),
+            }),
+            5_000,
+        );
+        treasury.internal_deposit(asset_id, 1_000);
+        treasury.internal_deploy(asset_id, 400);
+        treasury.internal_deploy(asset_id, 101);
+    }
+
+    #[test]
+    #[should_panic(expected = "Asset funds are deployed to the strategy")]
+    fn test_set_asset_strategy_with_deployed_funds() {
+        let asset_id = &accounts(1);
+        let mut treasury = Treasury::new(StorageKey::Treasury);
+        treasury.add_asset(asset_id, 6);
+        treasury.set_asset_strategy(
+            asset_id,
+            Some(StrategyKind::External {
+                account_id: accounts(2),
+            }),
+            BASIS_POINTS,
+        );
+        treasury.internal_deposit(asset_id, 1_000);
+        treasury.internal_deploy(asset_id, 1_000);
+        treasury.set_asset_strategy(asset_id, None, 0);
+    }
+
+    #[test]
+    fn test_emergency_withdraw() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(3), 6);
+        contract.treasury.internal_deposit(&accounts(3), 1_000);
+        contract.grant_role(accounts(1), Role::Guardian);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        contract.emergency_withdraw(accounts(3), 400.into(), accounts(2));
+        assert_eq!(contract.treasury.assert_asset(&accounts(3)).balance, 600);
+        assert!(get_logs()[1].contains(r#""event":"emergency_withdraw""#));
+    }
+
+    #[test]
+    fn test_rescue_token() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        let mut contract = Contract::new(accounts(1), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(3), 6);
+
+        contract.rescue_token(accounts(5), accounts(2), 100.into());
+        assert!(get_logs()[1].contains(r#""event":"token_rescued""#));
+    }
+
+    #[test]
+    fn test_set_asset_max_price_age() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        let mut contract = Contract::new(accounts(1), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(3), 6);
+
+        contract.set_asset_max_price_age(&accounts(3), Some(10.into()));
+        assert_eq!(
+            contract
+                .treasury
+                .assert_asset(&accounts(3))
+                .max_price_age_ns,
+            Some(10.into())
+        );
+        assert!(get_logs()
+            .last()
+            .unwrap()
+            .contains(r#""event":"max_price_age_changed""#));
+    }
+
+    #[test]
+    #[should_panic(expected = "Token danny is a treasury asset")]
+    fn test_rescue_treasury_asset() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        let mut contract = Contract::new(accounts(1), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(3), 6);
+        contract.rescue_token(accounts(3), accounts(2), 100.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "Token fargo is owed to accounts")]
+    fn test_rescue_claimed_token() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        let mut contract = Contract::new(accounts(1), accounts(4), None, None);
+        contract
+            .dex_claims
+            .internal_add(&accounts(2), &accounts(5), 100);
+        contract.rescue_token(accounts(5), accounts(2), 100.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "Account bob is not a Guardian")]
+    fn test_emergency_withdraw_not_guardian() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(3), 6);
+        contract.treasury.internal_deposit(&accounts(3), 1_000);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        contract.emergency_withdraw(accounts(3), 400.into(), accounts(2));
+    }
+
+    #[test]
+    fn test_insurance_fund() {
+        let asset_id = &accounts(1);
+        let mut treasury = Treasury::new(StorageKey::Treasury);
+        treasury.add_asset(asset_id, 6);
+        treasury.internal_deposit(asset_id, 1_000);
+        treasury.internal_insure(asset_id, 300);
+        let asset = treasury.assert_asset(asset_id);
+        assert_eq!((asset.balance, asset.insurance), (700, 300));
+
+        treasury.internal_deploy_insurance(asset_id, 100);
+        let asset = treasury.assert_asset(asset_id);
+        assert_eq!((asset.balance, asset.insurance), (800, 200));
+    }
+
+    #[test]
+    #[should_panic(expected = "The insurance fund doesn't have enough balance")]
+    fn test_deploy_insurance_exceeded() {
+        let asset_id = &accounts(1);
+        let mut treasury = Treasury::new(StorageKey::Treasury);
+        treasury.add_asset(asset_id, 6);
+        treasury.internal_deposit(asset_id, 1_000);
+        treasury.internal_insure(asset_id, 300);
+        treasury.internal_deploy_insurance(asset_id, 301);
+    }
+
     #[test]
     fn test_internal_deposit() {
         let asset_id = &accounts(1);
@@ -304,8 +1833,40 @@ mod tests {
         let mut treasury = Treasury::new(StorageKey::Treasury);
         treasury.add_asset(asset_id, 20);
         treasury.internal_deposit(asset_id, amount);
-        assert_eq!(treasury.assets.to_vec().len(), 1);
-        assert_eq!(treasury.assets.get(asset_id).unwrap().balance, amount);
+        assert_eq!(treasury.to_vec().len(), 1);
+        assert_eq!(treasury.get(asset_id).unwrap().balance, amount);
+    }
+
+    #[test]
+    fn test_cache_flushed_on_save() {
+        let asset_id = &accounts(1);
+        let mut treasury = Treasury::new(StorageKey::Treasury);
+        treasury.add_asset(asset_id, 6);
+        treasury.internal_deposit(asset_id, 100);
+        treasury.internal_withdraw(asset_id, 40);
+        assert_eq!(
+            treasury
+                .assets
+                .borrow()
+                .get(asset_id)
+                .map(AssetInfo::from)
+                .unwrap()
+                .balance,
+            0
+        );
+
+        let saved = Treasury::try_from_slice(&treasury.try_to_vec().unwrap()).unwrap();
+        assert_eq!(
+            saved
+                .assets
+                .borrow()
+                .get(asset_id)
+                .map(AssetInfo::from)
+                .unwrap()
+                .balance,
+            60
+        );
+        assert_eq!(saved.assert_asset(asset_id).balance, 60);
     }
 
     #[test]
@@ -326,8 +1887,8 @@ mod tests {
         treasury.add_asset(asset_id, 20);
         treasury.internal_deposit(asset_id, amount);
         treasury.internal_withdraw(asset_id, amount);
-        assert_eq!(treasury.assets.to_vec().len(), 1);
-        assert_eq!(treasury.assets.get(asset_id).unwrap().balance, 0);
+        assert_eq!(treasury.to_vec().len(), 1);
+        assert_eq!(treasury.get(asset_id).unwrap().balance, 0);
     }
 
     #[test]
@@ -338,4 +1899,35 @@ mod tests {
         treasury.add_asset(asset_id, 20);
         treasury.internal_withdraw(asset_id, 1);
     }
+
+    #[test]
+    fn test_treasury_value() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(2), 6);
+        contract.internal_add_asset(&accounts(3), 6);
+        let price = ExchangePrice::new(10000, 10);
+        contract.treasury.set_asset_price(&accounts(3), price);
+        contract.treasury.internal_deposit(&accounts(2), 1_000_000);
+        contract.treasury.internal_deposit(&accounts(3), 2_000_000);
+
+        let value = contract.treasury_value();
+        assert_eq!(value.assets.len(), 2);
+        assert!(value.assets[0].value.is_none());
+        assert_eq!(value.assets[1].principal.0, 2_000_000);
+        assert_eq!(value.total.0, 2_000_000_000_000_000_000);
+        assert!(!value.complete);
+
+        contract.treasury.set_asset_price(&accounts(2), price);
+        assert!(contract.treasury_value().complete);
+
+        contract
+            .treasury
+            .set_max_price_age(&accounts(3), Some(10.into()));
+        testing_env!(context.block_timestamp(11).build());
+        let value = contract.treasury_value();
+        assert!(value.assets[1].stale);
+        assert!(!value.complete);
+    }
 }
@@ -0,0 +1,234 @@
+//! Migration of the state stored by the first release, which only had the token
+//! and the treasury. Everything added since starts from the defaults of `new`.
+
+use near_contract_standards::fungible_token::metadata::FungibleTokenMetadata;
+use near_sdk::borsh::{self, BorshDeserialize};
+use near_sdk::collections::{LazyOption, LookupMap, UnorderedMap};
+use near_sdk::json_types::U64;
+use near_sdk::{env, near_bindgen, AccountId, Balance};
+
+use crate::ft::{AccountBalance, FungibleToken};
+use crate::treasury::{LegacyAssetInfo, Treasury};
+use crate::{Contract, ContractExt, StorageKey};
+
+#[derive(BorshDeserialize)]
+struct OldFungibleToken {
+    accounts: LookupMap<AccountId, AccountBalance>,
+    total_supply: Balance,
+}
+
+#[derive(BorshDeserialize)]
+struct OldTreasury {
+    assets: UnorderedMap<AccountId, LegacyAssetInfo>,
+}
+
+/// Contract state of the first release.
+#[derive(BorshDeserialize)]
+struct OldContract {
+    owner_id: AccountId,
+    oracle_id: AccountId,
+    token: OldFungibleToken,
+    metadata: LazyOption<FungibleTokenMetadata>,
+    treasury: OldTreasury,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Upgrades the state stored by the first release, called along with the deployment
+    /// of the new code. The asset records are rewritten in the current format, the
+    /// account records are moved on their first write.
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let old: OldContract =
+            env::state_read().unwrap_or_else(|| env::panic_str("The contract isn't initialized"));
+        Self::with_state(
+            old.owner_id,
+            old.oracle_id,
+            FungibleToken::from_legacy(
+                StorageKey::FungibleToken,
+                old.token.accounts,
+                old.token.total_supply,
+            ),
+            old.metadata,
+            Treasury::from_legacy(StorageKey::Treasury, old.treasury.assets),
+        )
+    }
+
+    /// Moves the account records stored by the first release to the current ones, filling
+    /// the holders and the account count. The legacy records can't be listed on-chain, so
+    /// the accounts are taken from an indexer, in as many calls as the gas requires.
+    /// Accounts without a legacy record are skipped. Returns the number of moved records.
+    pub fn migrate_accounts(&mut self, account_ids: Vec<AccountId>) -> U64 {
+        self.assert_owner();
+        let moved = account_ids
+            .iter()
+            .filter(|account_id| self.token.internal_migrate_account(account_id))
+            .count();
+        (moved as u64).into()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_contract_standards::fungible_token::core::FungibleTokenCore;
+    use near_contract_standards::fungible_token::metadata::{
+        FungibleTokenMetadata, FungibleTokenMetadataProvider, FT_METADATA_SPEC,
+    };
+    use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+    use near_sdk::collections::{LazyOption, LookupMap, UnorderedMap};
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{env, testing_env, AccountId, Balance, IntoStorageKey};
+
+    use crate::ft::AccountBalance;
+    use crate::treasury::AssetStatus;
+    use crate::{Contract, StorageKey};
+
+    #[derive(BorshSerialize)]
+    struct BaselineToken {
+        accounts: LookupMap<AccountId, AccountBalance>,
+        total_supply: Balance,
+    }
+
+    #[derive(BorshDeserialize, BorshSerialize)]
+    struct BaselineAssetInfo {
+        decimals: u8,
+        balance: Balance,
+        status: AssetStatus,
+    }
+
+    #[derive(BorshSerialize)]
+    struct BaselineTreasury {
+        assets: UnorderedMap<AccountId, BaselineAssetInfo>,
+    }
+
+    /// Contract state of the first release, written with its own record types.
+    #[derive(BorshSerialize)]
+    struct BaselineContract {
+        owner_id: AccountId,
+        oracle_id: AccountId,
+        token: BaselineToken,
+        metadata: LazyOption<FungibleTokenMetadata>,
+        treasury: BaselineTreasury,
+    }
+
+    fn write_baseline_state() {
+        let mut token = BaselineToken {
+            accounts: LookupMap::new(StorageKey::FungibleToken),
+            total_supply: 1_000,
+        };
+        token
+            .accounts
+            .insert(&accounts(1), &AccountBalance::new(1_000, 1_000_000));
+        let mut treasury = BaselineTreasury {
+            assets: UnorderedMap::new(StorageKey::Treasury),
+        };
+        treasury.assets.insert(
+            &accounts(3),
+            &BaselineAssetInfo {
+                decimals: 6,
+                balance: 1_000,
+                status: AssetStatus::Enabled,
+            },
+        );
+        let metadata = LazyOption::new(
+            StorageKey::Metadata,
+            Some(&FungibleTokenMetadata {
+                spec: FT_METADATA_SPEC.to_string(),
+                name: "K fungible token".to_string(),
+                symbol: "KTK".to_string(),
+                icon: None,
+                reference: None,
+                reference_hash: None,
+                decimals: 18,
+            }),
+        );
+        env::state_write(&BaselineContract {
+            owner_id: accounts(0),
+            oracle_id: accounts(4),
+            token,
+            metadata,
+            treasury,
+        });
+    }
+
+    #[test]
+    fn test_migrate() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(0))
+            .build());
+        write_baseline_state();
+
+        let mut contract = Contract::migrate();
+        assert_eq!(contract.owner_id, accounts(0));
+        assert_eq!(contract.oracle_id, accounts(4));
+        assert_eq!(contract.ft_metadata().symbol, "KTK");
+        assert_eq!(contract.ft_total_supply().0, 1_000);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 1_000);
+        assert_eq!(contract.ft_balance_detail(accounts(1)).price.0, 1_000_000);
+        let asset = contract.treasury.assert_asset(&accounts(3));
+        assert_eq!((asset.decimals, asset.balance), (6, 1_000));
+        assert_eq!(asset.status, AssetStatus::Enabled);
+        // The asset record is stored tagged with its version
+        let raw = env::storage_read(
+            &[
+                StorageKey::Treasury.into_storage_key(),
+                b"v".to_vec(),
+                0u64.to_le_bytes().to_vec(),
+            ]
+            .concat(),
+        )
+        .unwrap();
+        assert_eq!(raw[0], 0);
+
+        // The migrated state is usable and round-trips
+        contract
+            .token
+            .internal_deposit(&accounts(2), 500, 1_000_000);
+        env::state_write(&contract);
+        let contract: Contract = env::state_read().unwrap();
+        assert_eq!(contract.ft_total_supply().0, 1_500);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 1_000);
+        assert_eq!(contract.treasury.supported_assets().len(), 1);
+    }
+
+    #[test]
+    fn test_migrate_accounts() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(0))
+            .build());
+        write_baseline_state();
+
+        let mut contract = Contract::migrate();
+        assert!(contract.get_holders(None, None).is_empty());
+        assert_eq!(contract.get_storage_usage().accounts.0, 0);
+
+        let moved = contract.migrate_accounts(vec![accounts(1), accounts(2)]);
+        assert_eq!(moved.0, 1);
+        assert_eq!(contract.get_holders(None, None), vec![accounts(1)]);
+        assert_eq!(contract.get_storage_usage().accounts.0, 1);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 1_000);
+
+        // Moved accounts are skipped
+        assert_eq!(contract.migrate_accounts(vec![accounts(1)]).0, 0);
+        assert_eq!(contract.get_storage_usage().accounts.0, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Owner must be predecessor")]
+    fn test_migrate_accounts_not_owner() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(0))
+            .build());
+        write_baseline_state();
+        let mut contract = Contract::migrate();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.migrate_accounts(vec![accounts(1)]);
+    }
+}
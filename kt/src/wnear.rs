@@ -0,0 +1,595 @@
+//! Buys and sells in native NEAR. The attached NEAR is wrapped into the wNEAR asset
+//! before the buy, and the sold wNEAR is unwrapped before the payout.
+
+use near_sdk::json_types::{U128, U64};
+use near_sdk::{
+    assert_one_yocto, env, ext_contract, near_bindgen, require, AccountId, Promise, PromiseOrValue,
+    PromiseResult, ONE_YOCTO,
+};
+
+use crate::events::{KtEvent, RefundOperation, RefundReason};
+use crate::operations::{ext_operation_resolver, OperationKind, OperationLeg, OperationStage};
+use crate::oracle::{ExchangePrice, PriceData};
+use crate::price::ExpectedPrice;
+use crate::treasury::AssetId;
+use crate::{ext_self, promise_result_u128, BuyOptions, Contract, ContractExt};
+
+#[ext_contract(ext_wnear)]
+#[allow(dead_code)]
+pub trait WrappedNear {
+    fn near_deposit(&mut self);
+    fn near_withdraw(&mut self, amount: U128);
+}
+
+impl Contract {
+    pub(crate) fn assert_wnear(&self) -> AssetId {
+        self.wnear_id
+            .clone()
+            .unwrap_or_else(|| env::panic_str("wNEAR is not configured"))
+    }
+
+    /// Unwraps the wNEAR and sends the NEAR to the account, the wNEAR is claimable on failure.
+    fn internal_unwrap_near(
+        &self,
+        account_id: AccountId,
+        wnear_id: AssetId,
+        amount: U128,
+    ) -> Promise {
+        ext_wnear::ext(wnear_id.clone())
+            .with_static_gas(self.gas.near_withdraw)
+            .with_attached_deposit(ONE_YOCTO)
+            .near_withdraw(amount)
+            .then(
+                ext_near_resolver::ext(env::current_account_id())
+                    .with_static_gas(self.gas.resolve_near_refund)
+                    .resolve_near_refund(account_id, wnear_id, amount),
+            )
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Sets the wNEAR asset, `None` disables the buys and sells in NEAR.
+    pub fn set_wnear(&mut self, wnear_id: Option<AssetId>) {
+        self.assert_owner();
+        if let Some(wnear_id) = &wnear_id {
+            self.treasury.assert_asset(wnear_id);
+        }
+        self.wnear_id = wnear_id;
+    }
+
+    pub fn get_wnear(&self) -> Option<AssetId> {
+        self.wnear_id.clone()
+    }
+
+    /// Wraps the attached NEAR and buys KT for it at the oracle wNEAR price.
+    /// The unused NEAR is sent back.
+    #[payable]
+    pub fn buy_with_near(
+        &mut self,
+        expected: Option<ExpectedPrice>,
+        referrer_id: Option<AccountId>,
+    ) -> Promise {
+        require!(
+            env::prepaid_gas() > self.gas.buy_with_near(),
+            "More gas is required"
+        );
+        let wnear_id = self.assert_wnear();
+        let account_id = env::predecessor_account_id();
+        let amount = env::attached_deposit();
+        self.assert_buys_enabled();
+        let asset = self.treasury.assert_can_buy(&wnear_id);
+        require!(
+            amount > 0 && amount >= asset.min_buy,
+            "The attached deposit is below the minimum buy"
+        );
+        let operation_id = self.operations.start(
+            &account_id,
+            OperationKind::Buy,
+            Some(wnear_id.clone()),
+            amount.into(),
+            OperationStage::Wrapping,
+        );
+
+        ext_wnear::ext(wnear_id.clone())
+            .with_static_gas(self.gas.near_deposit)
+            .with_attached_deposit(amount)
+            .near_deposit()
+            .then(
+                ext_near_resolver::ext(env::current_account_id())
+                    .with_static_gas(self.gas.on_near_wrapped())
+                    .on_near_wrapped(
+                        account_id,
+                        wnear_id,
+                        amount.into(),
+                        expected,
+                        referrer_id,
+                        operation_id.into(),
+                    ),
+            )
+    }
+
+    /// Sells KT for wNEAR at the oracle price and pays it out unwrapped.
+    #[payable]
+    pub fn sell_for_near(&mut self, amount: U128, expected: Option<ExpectedPrice>) -> Promise {
+        assert_one_yocto();
+        let wnear_id = self.assert_wnear();
+        let mut required = self.price_gas(&wnear_id)
+            + self.gas.sell_for_near_with_price()
+            + self.gas.finish_operation;
+        self.treasury.assert_can_sell(&wnear_id);
+        let account_id = env::predecessor_account_id();
+        self.compliance.assert_not_frozen(&account_id);
+        self.operations.assert_no_pending_sell(&account_id);
+        self.charge_relay_fee(&account_id);
+        let kyc_check = self.kyc_check(&account_id);
+        if kyc_check.is_some() {
+            required += self.gas.kyc_check;
+        }
+        require!(env::prepaid_gas() > required, "More gas is required");
+        let operation_id = self.operations.start(
+            &account_id,
+            OperationKind::Sell,
+            Some(wnear_id.clone()),
+            amount,
+            OperationStage::Pricing,
+        );
+
+        let get_price = self.get_price(&wnear_id, None);
+        let get_price = match kyc_check {
+            Some(kyc_check) => get_price.and(kyc_check),
+            None => get_price,
+        };
+        get_price
+            .then(
+                ext_near_resolver::ext(env::current_account_id())
+                    .with_static_gas(self.gas.sell_for_near_with_price())
+                    .sell_for_near_with_price(
+                        account_id,
+                        wnear_id,
+                        amount,
+                        expected,
+                        operation_id.into(),
+                    ),
+            )
+            .then(
+                ext_operation_resolver::ext(env::current_account_id())
+                    .with_static_gas(self.gas.finish_operation)
+                    .finish_operation(operation_id.into()),
+            )
+    }
+}
+
+#[ext_contract(ext_near_resolver)]
+#[allow(dead_code)]
+pub trait NearResolver {
+    fn on_near_wrapped(
+        &mut self,
+        account_id: AccountId,
+        wnear_id: AssetId,
+        amount: U128,
+        expected: Option<ExpectedPrice>,
+        referrer_id: Option<AccountId>,
+        operation_id: U64,
+    ) -> Promise;
+    fn resolve_near_buy(
+        &mut self,
+        account_id: AccountId,
+        wnear_id: AssetId,
+        amount: U128,
+        operation_id: U64,
+    ) -> PromiseOrValue<U128>;
+    fn resolve_near_refund(&mut self, account_id: AccountId, wnear_id: AssetId, amount: U128);
+    fn sell_for_near_with_price(
+        &mut self,
+        account_id: AccountId,
+        wnear_id: AssetId,
+        amount: U128,
+        expected: Option<ExpectedPrice>,
+        operation_id: U64,
+        #[callback_unwrap] price: PriceData,
+    ) -> Promise;
+    fn resolve_near_sell(
+        &mut self,
+        account_id: AccountId,
+        leg: OperationLeg,
+        operation_id: Option<U64>,
+    );
+}
+
+#[near_bindgen]
+impl NearResolver for Contract {
+    /// Buys KT for the wrapped NEAR, or sends the NEAR back if the wrapping failed.
+    #[private]
+    fn on_near_wrapped(
+        &mut self,
+        account_id: AccountId,
+        wnear_id: AssetId,
+        amount: U128,
+        expected: Option<ExpectedPrice>,
+        referrer_id: Option<AccountId>,
+        operation_id: U64,
+    ) -> Promise {
+        match env::promise_result(0) {
+            PromiseResult::NotReady => env::abort(),
+            PromiseResult::Failed => {
+                self.operations.finish(operation_id.0);
+                KtEvent::KtRefund {
+                    operation: RefundOperation::NearBuy,
+                    reason: RefundReason::WrapFailed,
+                    account_id: &account_id,
+                    asset_id: None,
+                    amount: None,
+                    asset_amount: Some(&amount),
+                }
+                .emit();
+                Promise::new(account_id).transfer(amount.0)
+            }
+            PromiseResult::Successful(_) => {
+                self.operations
+                    .set_stage(operation_id.0, OperationStage::Pricing);
+                self.operations.add_leg(
+                    operation_id.0,
+                    OperationLeg {
+                        asset_id: wnear_id.clone(),
+                        amount: U128(0),
+                        asset_amount: amount,
+                        price: U128(0),
+                        fee: U128(0),
+                    },
+                );
+                let get_price = self.get_price(&wnear_id, None);
+                let get_price = match self.kyc_check(&account_id) {
+                    Some(kyc_check) => get_price.and(kyc_check),
+                    None => get_price,
+                };
+                get_price
+                    .then(
+                        ext_self::ext(env::current_account_id())
+                            .with_static_gas(self.gas.buy_with_price)
+                            .buy_with_price(
+                                account_id.clone(),
+                                wnear_id.clone(),
+                                amount,
+                                expected,
+                                BuyOptions {
+                                    referrer_id,
+                                    operation_id: Some(operation_id),
+                                    ..Default::default()
+                                },
+                            ),
+                    )
+                    .then(
+                        ext_near_resolver::ext(env::current_account_id())
+                            .with_static_gas(self.gas.resolve_near_buy())
+                            .resolve_near_buy(account_id, wnear_id, amount, operation_id),
+                    )
+            }
+        }
+    }
+
+    /// Clears the buy and unwraps the unused wNEAR back to the account.
+    #[private]
+    fn resolve_near_buy(
+        &mut self,
+        account_id: AccountId,
+        wnear_id: AssetId,
+        amount: U128,
+        operation_id: U64,
+    ) -> PromiseOrValue<U128> {
+        self.operations.finish(operation_id.0);
+        let unused = promise_result_u128().map_or(amount.0, |unused| unused.min(amount.0));
+        if unused == 0 {
+            return PromiseOrValue::Value(U128(0));
+        }
+        self.internal_unwrap_near(account_id, wnear_id, unused.into())
+            .into()
+    }
+
+    #[private]
+    fn resolve_near_refund(&mut self, account_id: AccountId, wnear_id: AssetId, amount: U128) {
+        match env::promise_result(0) {
+            PromiseResult::NotReady => env::abort(),
+            PromiseResult::Successful(_) => {
+                KtEvent::KtRefund {
+                    operation: RefundOperation::NearBuy,
+                    reason: RefundReason::Unused,
+                    account_id: &account_id,
+                    asset_id: None,
+                    amount: None,
+                    asset_amount: Some(&amount),
+                }
+                .emit();
+                Promise::new(account_id).transfer(amount.0);
+            }
+            PromiseResult::Failed => {
+                self.claims
+                    .internal_add(&account_id, &wnear_id, amount.into());
+                KtEvent::KtRefund {
+                    operation: RefundOperation::NearBuy,
+                    reason: RefundReason::PayoutFailed,
+                    account_id: &account_id,
+                    asset_id: Some(&wnear_id),
+                    amount: None,
+                    asset_amount: Some(&amount),
+                }
+                .emit();
+
+                KtEvent::AssetClaimAdded {
+                    account_id: &account_id,
+                    asset_id: &wnear_id,
+                    amount: &amount,
+                }
+                .emit();
+            }
+        }
+    }
+
+    #[private]
+    fn sell_for_near_with_price(
+        &mut self,
+        account_id: AccountId,
+        wnear_id: AssetId,
+        amount: U128,
+        expected: Option<ExpectedPrice>,
+        operation_id: U64,
+        #[callback_unwrap] data: PriceData,
+    ) -> Promise {
+        self.resolve_kyc(&account_id, 1);
+        self.operations
+            .set_stage(operation_id.0, OperationStage::Settling);
+        let asset = self.treasury.assert_can_sell(&wnear_id);
+
+        let price = ExchangePrice::from_price_data(&asset, data);
+
+        if let Some(expected) = expected {
+            expected.assert_price(price);
+        }
+
+        self.treasury.set_asset_price(&wnear_id, price);
+        let (_, _, asset_amount, _, fee) = self.internal_sell_leg(
+            &account_id,
+            &wnear_id,
+            amount.into(),
+            asset.decimals,
+            price,
+            None,
+        );
+        self.internal_start_payout(&wnear_id, asset_amount.0);
+        let leg = OperationLeg {
+            asset_id: wnear_id.clone(),
+            amount,
+            asset_amount,
+            price: price.to_decimals().into(),
+            fee: fee.into(),
+        };
+        self.operations.add_leg(operation_id.0, leg.clone());
+
+        ext_wnear::ext(wnear_id)
+            .with_static_gas(self.gas.near_withdraw)
+            .with_attached_deposit(ONE_YOCTO)
+            .near_withdraw(asset_amount)
+            .then(
+                ext_near_resolver::ext(env::current_account_id())
+                    .with_static_gas(self.gas.resolve_sell)
+                    .resolve_near_sell(account_id, leg, Some(operation_id)),
+            )
+    }
+
+    /// Sends the unwrapped NEAR to the seller, or refunds the sell if the unwrapping failed.
+    /// Does nothing if the operation was recovered meanwhile.
+    #[private]
+    fn resolve_near_sell(
+        &mut self,
+        account_id: AccountId,
+        leg: OperationLeg,
+        operation_id: Option<U64>,
+    ) {
+        if let Some(operation_id) = operation_id {
+            if !self
+                .operations
+                .resolve_leg(operation_id.0, &leg.asset_id, leg.asset_amount)
+            {
+                return;
+            }
+        }
+        self.internal_finish_payout(&leg.asset_id, leg.asset_amount.0);
+        match env::promise_result(0) {
+            PromiseResult::NotReady => env::abort(),
+            PromiseResult::Successful(_) => {
+                Promise::new(account_id).transfer(leg.asset_amount.0);
+            }
+            PromiseResult::Failed => self.internal_refund_sell(&account_id, &leg),
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_contract_standards::fungible_token::core::FungibleTokenCore;
+    use near_sdk::json_types::U128;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, PromiseOrValue, PromiseResult, RuntimeFeesConfig, VMConfig};
+
+    use crate::operations::OperationLeg;
+    use crate::oracle::{ExchangePrice, Price, PriceData};
+    use crate::wnear::NearResolver;
+    use crate::{BuyOptions, Contract, ContractResolver};
+
+    fn setup_contract(context: &mut VMContextBuilder) -> Contract {
+        context
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(0), accounts(4), None, None);
+        contract.internal_add_asset(&accounts(3), 24);
+        contract.set_wnear(Some(accounts(3)));
+        contract
+    }
+
+    #[test]
+    #[should_panic(expected = "Asset charlie is not supported")]
+    fn test_set_wnear_not_supported() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        contract.set_wnear(Some(accounts(2)));
+    }
+
+    #[test]
+    fn test_buy_with_near() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        let amount = 10u128.pow(24);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(amount)
+            .build());
+        contract.buy_with_near(None, None);
+        let (operation_id, _) = contract.operations.pending_of(&accounts(1))[0];
+
+        testing_env!(
+            context
+                .predecessor_account_id(accounts(0))
+                .attached_deposit(0)
+                .build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])],
+        );
+        contract.on_near_wrapped(
+            accounts(1),
+            accounts(3),
+            amount.into(),
+            None,
+            None,
+            operation_id.into(),
+        );
+        let unused = contract.buy_with_price(
+            accounts(1),
+            accounts(3),
+            amount.into(),
+            None,
+            BuyOptions {
+                operation_id: Some(operation_id.into()),
+                ..Default::default()
+            },
+            PriceData::new(false, Some(Price::new(10000, 52))),
+        );
+        assert_eq!(unused.0, 0);
+        assert_eq!(
+            contract.ft_balance_of(accounts(1)).0,
+            1_000_000_000_000_000_000
+        );
+        assert_eq!(contract.treasury.assert_asset(&accounts(3)).balance, amount);
+
+        testing_env!(
+            context.build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(b"\"0\"".to_vec())],
+        );
+        let refund =
+            contract.resolve_near_buy(accounts(1), accounts(3), amount.into(), operation_id.into());
+        assert!(matches!(refund, PromiseOrValue::Value(U128(0))));
+        assert!(contract.operations.pending_of(&accounts(1)).is_empty());
+    }
+
+    #[test]
+    fn test_resolve_near_sell() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        let price = ExchangePrice::new(10000, 28);
+        contract.internal_buy(
+            &accounts(1),
+            &accounts(3),
+            10u128.pow(24),
+            24,
+            price,
+            &BuyOptions::default(),
+        );
+        let amount = contract.ft_balance_of(accounts(1));
+        let asset_amount =
+            contract.internal_sell(&accounts(1), &accounts(3), amount.0, 24, price, None);
+        contract.internal_start_payout(&accounts(3), asset_amount.0);
+
+        testing_env!(
+            context.build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])],
+        );
+        let leg = OperationLeg {
+            asset_id: accounts(3),
+            amount,
+            asset_amount,
+            price: price.to_decimals().into(),
+            fee: 0.into(),
+        };
+        contract.resolve_near_sell(accounts(1), leg, None);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 0);
+        assert_eq!(contract.ft_total_supply().0, 0);
+        assert_eq!(
+            contract.treasury.assert_asset(&accounts(3)).balance,
+            10u128.pow(24) - asset_amount.0
+        );
+        assert!(contract.payouts_in_flight.get(&accounts(3)).is_none());
+    }
+
+    #[test]
+    fn test_resolve_near_sell_failed() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        let price = ExchangePrice::new(10000, 28);
+        contract.internal_buy(
+            &accounts(1),
+            &accounts(3),
+            10u128.pow(24),
+            24,
+            price,
+            &BuyOptions::default(),
+        );
+        let amount = contract.ft_balance_of(accounts(1));
+        let asset_amount =
+            contract.internal_sell(&accounts(1), &accounts(3), amount.0, 24, price, None);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 0);
+
+        testing_env!(
+            context.build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        let leg = OperationLeg {
+            asset_id: accounts(3),
+            amount,
+            asset_amount,
+            price: price.to_decimals().into(),
+            fee: 0.into(),
+        };
+        contract.resolve_near_sell(accounts(1), leg, None);
+        assert_eq!(contract.ft_balance_of(accounts(1)), amount);
+        assert_eq!(
+            contract.treasury.assert_asset(&accounts(3)).balance,
+            10u128.pow(24)
+        );
+    }
+
+    #[test]
+    fn test_resolve_near_refund_failed() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup_contract(&mut context);
+        testing_env!(
+            context.build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        contract.resolve_near_refund(accounts(1), accounts(3), 1_000.into());
+        assert_eq!(contract.get_claims(accounts(1))[&accounts(3)].0, 1_000);
+    }
+}
@@ -0,0 +1,341 @@
+//! Sandbox environment for the integration tests of the KT contract: deploys KT along
+//! with the test oracle and asset contracts, and wraps the common calls.
+//! The contracts are read from the `KTOKEN_RES_DIR` directory, the `res` directory of
+//! this repository by default. Run `build.sh` first to build them.
+
+use std::path::PathBuf;
+
+use near_sdk::json_types::{U128, U64};
+use near_units::{parse_gas, parse_near};
+use serde_json::json;
+use workspaces::network::Sandbox;
+use workspaces::prelude::*;
+use workspaces::{Account, AccountId, Contract, Worker};
+
+pub const KT_WASM: &str = "kt.wasm";
+pub const FT_WASM: &str = "ft.wasm";
+pub const ORACLE_WASM: &str = "oracle.wasm";
+
+/// Oracle price recency used by `init`.
+pub const RECENCY_DURATION: u64 = 60_000_000_000;
+/// Asset balance of the user in every asset created by `init`.
+pub const INITIAL_BALANCE: u128 = 1_000_000_000_000_000_000;
+/// Decimals of the test asset contract.
+pub const ASSET_DECIMALS: u8 = 6;
+/// Gas burnt limit of the transaction outcome of `buy_kt`, i.e. the `ft_transfer_call`
+/// on the asset contract before the cross-contract calls it starts.
+pub const MAX_BUY_GAS_BURNT: u64 = 30_000_000_000_000;
+/// Gas burnt limit of the transaction outcome of `sell`. Converting the transaction to its
+/// receipt costs about 2.43 Tgas, the rest covers the arguments.
+pub const MAX_SELL_GAS_BURNT: u64 = 2_500_000_000_000;
+
+/// Reads the contract code from the `KTOKEN_RES_DIR` directory, or the `res` directory
+/// of this repository if it isn't set.
+pub fn read_wasm(name: &str) -> anyhow::Result<Vec<u8>> {
+    let dir = std::env::var_os("KTOKEN_RES_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../res"));
+    let path = dir.join(name);
+    std::fs::read(&path)
+        .map_err(|err| anyhow::anyhow!("Failed to read {}: {}", path.display(), err))
+}
+
+/// Deployed KT with the oracle and the assets, the user owns the asset supply.
+pub struct TestEnv {
+    pub oracle: Contract,
+    pub assets: Vec<Contract>,
+    pub user: Account,
+    pub kt: Contract,
+    pub owner: Account,
+}
+
+/// Create our own custom Oracle contract and setup the initial state.
+pub async fn create_custom_oracle(
+    worker: &Worker<Sandbox>,
+    recency_duration: U64,
+) -> anyhow::Result<Contract> {
+    let oracle = worker.dev_deploy(&read_wasm(ORACLE_WASM)?).await?;
+
+    // Initialize our Oracle contract .
+    oracle
+        .call(worker, "new")
+        .args_json(json!({
+            "recency_duration": recency_duration,
+        }))?
+        .transact()
+        .await?;
+
+    Ok(oracle)
+}
+
+// Set Oracle exchange price
+pub async fn set_exchange_price(
+    worker: &Worker<Sandbox>,
+    contract: &Contract,
+    asset_id: &AccountId,
+    multiplier: U128,
+    decimals: u8,
+) -> anyhow::Result<()> {
+    assert!(contract
+        .call(worker, "set_exchange_price")
+        .args_json(json!({
+            "asset_id": asset_id,
+            "price": {
+                "multiplier": multiplier,
+                "decimals": decimals,
+            }
+        }))?
+        .transact()
+        .await?
+        .is_success());
+
+    Ok(())
+}
+
+// Set Oracle exchange prices in one transaction
+pub async fn set_exchange_prices(
+    worker: &Worker<Sandbox>,
+    contract: &Contract,
+    prices: &[(&AccountId, U128, u8)],
+) -> anyhow::Result<()> {
+    let prices: Vec<_> = prices
+        .iter()
+        .map(|(asset_id, multiplier, decimals)| {
+            json!([asset_id, { "multiplier": multiplier, "decimals": decimals }])
+        })
+        .collect();
+    assert!(contract
+        .call(worker, "set_exchange_prices")
+        .args_json(json!({ "prices": prices }))?
+        .transact()
+        .await?
+        .is_success());
+
+    Ok(())
+}
+
+// Set the Oracle failure mode
+pub async fn set_failure_mode(
+    worker: &Worker<Sandbox>,
+    contract: &Contract,
+    failure_mode: serde_json::Value,
+) -> anyhow::Result<()> {
+    assert!(contract
+        .call(worker, "set_failure_mode")
+        .args_json(json!({ "failure_mode": failure_mode }))?
+        .transact()
+        .await?
+        .is_success());
+
+    Ok(())
+}
+
+pub async fn balance_of(
+    worker: &Worker<Sandbox>,
+    contract_id: &AccountId,
+    account_id: &AccountId,
+) -> anyhow::Result<U128> {
+    worker
+        .view(
+            contract_id,
+            "ft_balance_of",
+            json!({
+                "account_id": account_id,
+            })
+            .to_string()
+            .into_bytes(),
+        )
+        .await?
+        .json::<U128>()
+}
+
+/// Create our own custom Fungible Token contract and setup the initial state.
+pub async fn create_custom_ft(
+    worker: &Worker<Sandbox>,
+    initial_balance: U128,
+) -> anyhow::Result<(Contract, Account)> {
+    // Create accounts.
+    let owner = worker.dev_create_account().await?;
+    let ft = create_ft_for(worker, &owner, initial_balance).await?;
+
+    Ok((ft, owner))
+}
+
+/// Create a Fungible Token contract with the total supply owned by the account.
+pub async fn create_ft_for(
+    worker: &Worker<Sandbox>,
+    owner: &Account,
+    initial_balance: U128,
+) -> anyhow::Result<Contract> {
+    let ft = worker.dev_deploy(&read_wasm(FT_WASM)?).await?;
+
+    // Initialize our FT contract with owner and total supply available
+    // to be traded and transfered into KT contract.
+    ft.call(worker, "new")
+        .args_json(json!({
+            "owner_id": owner.id(),
+            "total_supply": initial_balance,
+        }))?
+        .transact()
+        .await?;
+
+    Ok(ft)
+}
+
+/// Registers the account on the contract implementing the storage management.
+pub async fn storage_deposit(
+    worker: &Worker<Sandbox>,
+    account: &Account,
+    contract_id: &AccountId,
+) -> anyhow::Result<()> {
+    assert!(account
+        .call(worker, contract_id, "storage_deposit")
+        .args_json(json!({}))?
+        .deposit(parse_near!("10 mN"))
+        .transact()
+        .await?
+        .is_success());
+
+    Ok(())
+}
+
+/// Create the KT contract and setup the initial state.
+pub async fn create_kt(
+    worker: &Worker<Sandbox>,
+    oracle_id: &AccountId,
+) -> anyhow::Result<(Contract, Account)> {
+    let kt = worker.dev_deploy(&read_wasm(KT_WASM)?).await?;
+
+    let owner = worker.dev_create_account().await?;
+
+    kt.call(worker, "new")
+        .args_json(json!({"owner_id": owner.id(), "oracle_id": oracle_id}))?
+        .transact()
+        .await?;
+
+    Ok((kt, owner))
+}
+
+/// Register the asset in KT contract, which also registers KT contract as the asset account.
+pub async fn add_asset(
+    worker: &Worker<Sandbox>,
+    owner: &Account,
+    kt_id: &AccountId,
+    asset_id: &AccountId,
+    decimals: u8,
+) -> anyhow::Result<()> {
+    owner
+        .call(worker, kt_id, "add_asset")
+        .args_json(json!({
+            "asset_id": asset_id,
+            "decimals": decimals,
+        }))?
+        .deposit(parse_near!("30 mN"))
+        .gas(parse_gas!("50 Tgas") as u64)
+        .transact()
+        .await?;
+
+    Ok(())
+}
+
+pub async fn init(
+    worker: &Worker<Sandbox>,
+) -> anyhow::Result<(Contract, Contract, Account, Contract, Account)> {
+    let TestEnv {
+        oracle,
+        mut assets,
+        user,
+        kt,
+        owner,
+    } = init_with_assets(worker, 1).await?;
+
+    Ok((oracle, assets.remove(0), user, kt, owner))
+}
+
+/// Deploys KT with the oracle and the number of assets registered in KT,
+/// the user owns the supply of every asset.
+pub async fn init_with_assets(worker: &Worker<Sandbox>, count: usize) -> anyhow::Result<TestEnv> {
+    let oracle = create_custom_oracle(worker, RECENCY_DURATION.into()).await?;
+    let user = worker.dev_create_account().await?;
+    let (kt, owner) = create_kt(worker, oracle.id()).await?;
+
+    let mut assets = Vec::with_capacity(count);
+    for _ in 0..count {
+        let ft = create_ft_for(worker, &user, INITIAL_BALANCE.into()).await?;
+        add_asset(worker, &owner, kt.id(), ft.id(), ASSET_DECIMALS).await?;
+        assets.push(ft);
+    }
+
+    Ok(TestEnv {
+        oracle,
+        assets,
+        user,
+        kt,
+        owner,
+    })
+}
+
+/// Buy KT tokens.
+pub async fn buy_kt(
+    worker: &Worker<Sandbox>,
+    user: &Account,
+    contract_id: &AccountId,
+    receiver_id: &AccountId,
+    amount: U128,
+    // (multiplier, decimals, slippage)
+    expected: Option<(U128, u8, U128)>,
+) -> anyhow::Result<()> {
+    let msg = json!({
+        "Buy": { "expected": expected },
+    })
+    .to_string();
+
+    let res = user
+        .call(worker, contract_id, "ft_transfer_call")
+        .args_json(json!({
+            "receiver_id": receiver_id,
+            "amount": amount,
+            "msg": msg,
+        }))?
+        .gas(parse_gas!("200 Tgas") as u64)
+        .deposit(1)
+        .transact()
+        .await?;
+    assert!(res.is_success());
+    assert!(res.outcome().gas_burnt <= MAX_BUY_GAS_BURNT);
+
+    Ok(())
+}
+
+/// Sell KT tokens.
+pub async fn sell(
+    worker: &Worker<Sandbox>,
+    user: &Account,
+    contract_id: &AccountId,
+    asset_id: &AccountId,
+    amount: U128,
+    // (multiplier, decimals, slippage)
+    expected: Option<(U128, u8, U128)>,
+) -> anyhow::Result<()> {
+    let res = user
+        .call(worker, contract_id, "sell")
+        .args_json(json!({
+           "asset_id": asset_id,
+           "amount": amount,
+              "expected": expected.map(|(multiplier, decimals, slippage)| {
+                  json!({
+                      "multiplier": multiplier,
+                      "decimals": decimals,
+                      "slippage": slippage,
+                  })
+              }),
+        }))?
+        .gas(parse_gas!("200 Tgas") as u64)
+        .deposit(1)
+        .transact()
+        .await?;
+    assert!(res.is_success());
+    assert!(res.outcome().gas_burnt <= MAX_SELL_GAS_BURNT);
+
+    Ok(())
+}
@@ -2,7 +2,7 @@ use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::collections::UnorderedMap;
 use near_sdk::json_types::{U128, U64};
 use near_sdk::serde::{Deserialize, Serialize};
-use near_sdk::{env, near_bindgen, BorshStorageKey, PanicOnDefault, Timestamp};
+use near_sdk::{env, near_bindgen, require, AccountId, BorshStorageKey, PanicOnDefault, Timestamp};
 
 type AssetId = String;
 
@@ -34,11 +34,42 @@ pub struct PriceData {
     pub price: Option<Price>,
 }
 
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AssetOptionalPrice {
+    pub asset_id: AssetId,
+    pub price: Option<Price>,
+}
+
+/// Prices of several assets, as returned by the priceoracle `get_price_data`.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MultiPriceData {
+    pub timestamp: U64,
+    pub recency_duration: U64,
+    pub prices: Vec<AssetOptionalPrice>,
+}
+
+/// Injected failure of `get_exchange_price`, for testing the error handling of the consumers.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Copy)]
+#[serde(crate = "near_sdk::serde")]
+pub enum FailureMode {
+    Panic,
+    NoPrice,
+    Expired,
+    /// Reports the price with the decimals.
+    WrongDecimals(u8),
+    /// Burns the gas by hashing the iterations before returning the price.
+    Delay(u64),
+}
+
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 pub struct Contract {
+    pub owner_id: AccountId,
     pub assets: UnorderedMap<AssetId, Asset>,
     pub recency_duration: Timestamp,
+    pub failure_mode: Option<FailureMode>,
 }
 
 #[near_bindgen]
@@ -46,18 +77,84 @@ impl Contract {
     #[init]
     pub fn new(recency_duration: U64) -> Self {
         Self {
+            owner_id: env::predecessor_account_id(),
             assets: UnorderedMap::new(StorageKey::Assets),
             recency_duration: recency_duration.into(),
+            failure_mode: None,
         }
     }
 
     pub fn get_exchange_price(&self, asset_id: AssetId) -> PriceData {
         let timestamp = env::block_timestamp();
-        PriceData {
+        let asset = self.assets.get(&asset_id);
+        let data = PriceData {
             asset_id: asset_id.clone(),
-            timestamp: timestamp.into(),
+            // Time the price was set, so the consumers can check its age
+            timestamp: asset
+                .as_ref()
+                .map_or(timestamp, |asset| asset.timestamp)
+                .into(),
             expiration: (timestamp + self.recency_duration).into(),
-            price: self.assets.get(&asset_id).map(|asset| asset.price),
+            price: asset.map(|asset| asset.price),
+        };
+        match self.failure_mode {
+            None => data,
+            Some(FailureMode::Panic) => env::panic_str("Oracle failure"),
+            Some(FailureMode::NoPrice) => PriceData {
+                price: None,
+                ..data
+            },
+            Some(FailureMode::Expired) => PriceData {
+                expiration: timestamp.into(),
+                ..data
+            },
+            Some(FailureMode::WrongDecimals(decimals)) => PriceData {
+                price: data.price.map(|price| Price { decimals, ..price }),
+                ..data
+            },
+            Some(FailureMode::Delay(iterations)) => {
+                let mut hash = env::sha256(asset_id.as_bytes());
+                for _ in 0..iterations {
+                    hash = env::sha256(&hash);
+                }
+                data
+            }
+        }
+    }
+
+    /// Sets the failure of the following `get_exchange_price` calls, `None` disables it.
+    pub fn set_failure_mode(&mut self, failure_mode: Option<FailureMode>) {
+        require!(
+            env::predecessor_account_id() == self.owner_id,
+            "Only the owner can set the failure mode"
+        );
+        self.failure_mode = failure_mode;
+    }
+
+    pub fn get_failure_mode(&self) -> Option<FailureMode> {
+        self.failure_mode
+    }
+
+    /// Prices of the assets, of all the assets with a price if not set.
+    pub fn get_price_data(&self, asset_ids: Option<Vec<AssetId>>) -> MultiPriceData {
+        let asset_ids = asset_ids.unwrap_or_else(|| self.assets.keys().collect());
+        let recency_duration = match self.failure_mode {
+            Some(FailureMode::Expired) => 0,
+            _ => self.recency_duration,
+        };
+        MultiPriceData {
+            timestamp: env::block_timestamp().into(),
+            recency_duration: recency_duration.into(),
+            prices: asset_ids
+                .into_iter()
+                .map(|asset_id| {
+                    let data = self.get_exchange_price(asset_id);
+                    AssetOptionalPrice {
+                        asset_id: data.asset_id,
+                        price: data.price,
+                    }
+                })
+                .collect(),
         }
     }
 
@@ -65,4 +162,10 @@ impl Contract {
         let timestamp = env::block_timestamp();
         self.assets.insert(&asset_id, &Asset { timestamp, price });
     }
+
+    pub fn set_exchange_prices(&mut self, prices: Vec<(AssetId, Price)>) {
+        for (asset_id, price) in prices {
+            self.set_exchange_price(asset_id, price);
+        }
+    }
 }